@@ -0,0 +1,52 @@
+//! Drawing onto frames of any size and contents, see `sni_icon::image`
+//!
+//! The first bytes choose the border and the sizes of the frame and of an
+//! overlay, the rest are their data, which need not match their sizes.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sni_icon::image::{self, Border, BorderPlacement, BorderStyle};
+use sni_icon::IconData;
+
+fn valid(frame: &IconData) -> bool {
+    (u64::from(frame.width) * u64::from(frame.height)).checked_mul(4)
+        == Some(frame.data.len() as u64)
+}
+
+fuzz_target!(|input: &[u8]| {
+    let [config, width, height, overlay_width, overlay_height, split, rest @ ..] = input else {
+        return;
+    };
+    let border = Border {
+        width: u32::from(config & 7),
+        style: match config >> 3 & 3 {
+            0 => BorderStyle::Solid,
+            1 => BorderStyle::Dashed,
+            2 => BorderStyle::Corner,
+            _ => BorderStyle::None,
+        },
+        placement: match config >> 5 & 1 {
+            0 => BorderPlacement::Inside,
+            _ => BorderPlacement::Outside,
+        },
+        letter: config >> 6 & 1 != 0,
+    };
+    let (data, overlay_data) = rest.split_at((usize::from(*split) * 4).min(rest.len()));
+    let mut frames = vec![IconData {
+        width: u32::from(*width),
+        height: u32::from(*height),
+        data: data.to_vec(),
+    }];
+    let overlay = [IconData {
+        width: u32::from(*overlay_width),
+        height: u32::from(*overlay_height),
+        data: overlay_data.to_vec(),
+    }];
+    image::border(&mut frames, &border, [1, 2, 3], Some("fuzz"));
+    assert!(frames.iter().all(valid));
+    let inset = border.drawn_width();
+    image::overlay(&mut frames, &overlay, inset);
+    image::attention_badge(&mut frames, [4, 5, 6], inset);
+    image::count_badge(&mut frames, usize::from(*split), [7, 8, 9], inset);
+    assert!(frames.iter().all(valid));
+});
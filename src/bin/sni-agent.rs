@@ -2,10 +2,34 @@
 #![forbid(clippy::cargo)]
 #![forbid(clippy::suspicious)]
 #![forbid(clippy::undocumented_unsafe_blocks)]
-use dbus::channel::{MatchingReceiver as _, Sender as _};
+
+#[path = "sni-agent/bus.rs"]
+mod bus;
+#[path = "sni-agent/bus_names.rs"]
+mod bus_names;
+#[path = "sni-agent/cache.rs"]
+mod cache;
+#[path = "sni-agent/duplicates.rs"]
+mod duplicates;
+#[path = "sni-agent/fetch.rs"]
+mod fetch;
+#[path = "sni-agent/hardening.rs"]
+mod hardening;
+#[path = "sni-agent/ids.rs"]
+mod ids;
+#[path = "sni-agent/menu.rs"]
+mod menu;
+#[path = "sni-agent/publisher.rs"]
+mod publisher;
+#[cfg(test)]
+#[path = "sni-agent/quirks.rs"]
+mod quirks;
+#[path = "sni-agent/timeouts.rs"]
+mod timeouts;
+
+use dbus::channel::MatchingReceiver as _;
 use dbus::nonblock::{MsgMatch, Proxy, SyncConnection};
 use dbus_crossroads::Crossroads;
-use dbus_tokio::connection;
 
 use dbus::message::SignalArgs;
 use dbus::strings::{BusName, Path};
@@ -25,95 +49,227 @@ use core::cell::Cell;
 use std::sync::{Arc, Mutex, MutexGuard};
 
 use crate::client::watcher::StatusNotifierWatcherStatusNotifierItemRegistered;
+use futures_util::future::{AbortHandle, Abortable};
+use futures_util::StreamExt as _;
 use futures_util::TryFutureExt as _;
-use tokio::io::AsyncReadExt;
+use sni_icon::request::Pending;
 
 use bincode::Options;
+use bus::Bus;
+
+/// The size of `s` when sent, not counting the length prefix
+pub(crate) fn encoded_size<T: serde::Serialize>(s: &T) -> u64 {
+    encoding()
+        .serialized_size(s)
+        .expect("Cannot serialize object?")
+}
+
+/// Where messages to the daemon are written
+#[cfg(not(test))]
+fn output() -> std::io::StdoutLock<'static> {
+    std::io::stdout().lock()
+}
 
-fn send_or_panic<T: serde::Serialize>(s: T) {
-    let mut out = std::io::stdout().lock();
-    let options = bincode::DefaultOptions::new()
-        .with_fixint_encoding()
-        .with_native_endian()
-        .reject_trailing_bytes();
-    let v = options.serialize(&s).expect("Cannot serialize object?");
-    eprintln!("Sending {} bytes", v.len());
-    out.write_all(&((v.len() as u32).to_le_bytes())[..])
-        .expect("cannot write to stdout");
-    out.write_all(&v[..]).expect("cannot write to stdout");
+#[cfg(test)]
+use tests::output;
+
+pub(crate) fn send_or_panic<T: sni_icon::Message>(s: T) {
+    write_or_panic(encode(&s).expect("Cannot serialize object?"))
+}
+
+/// Send the message `v`, which is already encoded
+fn write_or_panic(v: Vec<u8>) {
+    let mut out = output();
+    // The Hello itself is sent before the tag is negotiated
+    let integrity = INTEGRITY.get().copied().unwrap_or_default();
+    #[cfg(feature = "testing")]
+    let messages = sni_icon::fault::message(v);
+    #[cfg(not(feature = "testing"))]
+    let messages = [v];
+    for v in messages {
+        eprintln!("Sending {} bytes", v.len());
+        out.write_all(&((v.len() as u32).to_le_bytes())[..])
+            .expect("cannot write to stdout");
+        out.write_all(&v[..]).expect("cannot write to stdout");
+        out.write_all(&integrity.tag(&v))
+            .expect("cannot write to stdout");
+    }
     out.flush().expect("Cannot flush stdout");
 }
 
-struct Watcher {
+/// The event setting the description of icon `typ` of `icon` for screen
+/// readers, if the item has one.  Only Ayatana items do, and only for the
+/// normal and attention icons.
+async fn accessible_desc(icon: &impl StatusNotifierItem, typ: IconType) -> Option<ClientEvent> {
+    let desc = match typ {
+        IconType::Normal => icon.icon_accessible_desc().await,
+        IconType::Attention => icon.attention_accessible_desc().await,
+        _ => return None,
+    };
+    let desc = desc.ok().filter(|desc| !desc.is_empty())?;
+    Some(ClientEvent::AccessibleDesc {
+        typ,
+        desc: Some(SafeText::new(&desc)),
+    })
+}
+
+/// The value of the `ToolTip` property of an item: the name of its icon,
+/// the frames of its icon, its title and its description
+type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+/// The tooltip `tooltip`, as sent to the daemon.  Frames of its icon that
+/// do not fit into the message with the rest are left out.
+fn tooltip_of((_icon_name, pixmap, title, description): ToolTip) -> Tooltip {
+    let title = SafeText::new(&title);
+    let description = SafeText::new(&description);
+    let limits = limits();
+    // Room for everything in the message except frame data
+    const OVERHEAD: usize = 128;
+    let mut budget = (limits.max_message_size as usize)
+        .saturating_sub(OVERHEAD + title.as_str().len() + description.as_str().len());
+    let icon_data = pixmap
+        .into_iter()
+        .map(|(width, height, data)| IconData {
+            width: width as u32,
+            height: height as u32,
+            data,
+        })
+        .filter(|frame| frame.check(&limits).is_ok())
+        .take(limits.max_frames as usize)
+        .filter(|frame| {
+            // Width, height, and the length of the data
+            let size = 16 + frame.data.len();
+            let fits = size <= budget;
+            if fits {
+                budget -= size;
+            }
+            fits
+        })
+        .collect();
+    Tooltip {
+        icon_data,
+        title,
+        description,
+    }
+}
+
+/// Send `pixmap` as the frames of icon `typ` of item `id`, see
+/// [`cache::icon_events`]
+fn send_icon(id: u64, typ: IconType, pixmap: Vec<(i32, i32, Vec<u8>)>) {
+    let frames = pixmap.into_iter().map(|(width, height, data)| IconData {
+        width: width as u32,
+        height: height as u32,
+        data,
+    });
+    for event in cache::icon_events(typ, frames, &limits()) {
+        send_or_panic(IconClientEvent::new(id, event))
+    }
+}
+
+/// The update setting the icons in `icons` of item `id`, after sending the
+/// chunks of frames too large for any message and the icons the daemon
+/// keeps, see [`cache`].  Icons that do not fit into
+/// the update with the others are sent on their own.
+fn icons_update(
+    id: u64,
+    icons: impl IntoIterator<Item = (IconType, Vec<(i32, i32, Vec<u8>)>)>,
+) -> Update {
+    // Room for everything in the message except frame data
+    const OVERHEAD: usize = 128;
+    let limits = limits();
+    let mut budget = (limits.max_message_size as usize).saturating_sub(OVERHEAD);
+    let mut update = Update::default();
+    for (typ, pixmap) in icons {
+        let frames = pixmap.into_iter().map(|(width, height, data)| IconData {
+            width: width as u32,
+            height: height as u32,
+            data,
+        });
+        for event in cache::icon_events(typ, frames, &limits) {
+            match event {
+                ClientEvent::Icon { typ, data } => {
+                    // Width, height, and the length of the data of each frame
+                    let size: usize = data.iter().map(|frame| 16 + frame.data.len()).sum();
+                    if size <= budget {
+                        budget -= size;
+                        update.icons.push((typ, data));
+                    } else {
+                        send_or_panic(IconClientEvent::new(id, ClientEvent::Icon { typ, data }))
+                    }
+                }
+                chunk => send_or_panic(IconClientEvent::new(id, chunk)),
+            }
+        }
+    }
+    update
+}
+
+struct Watcher<B: Bus> {
     items: Arc<Mutex<HashSet<String>>>,
     hosts: Arc<Mutex<HashSet<String>>>,
-    connection: Arc<SyncConnection>,
-    _msg_match: MsgMatch,
+    connection: Arc<B>,
+    _msg_match: B::Match,
 }
 
-fn lock<T>(l: &Mutex<T>) -> MutexGuard<T> {
+fn lock<T>(l: &Mutex<T>) -> MutexGuard<'_, T> {
     l.lock().expect("mutex should not be poisoned")
 }
 
-impl Watcher {
-    fn items(&self) -> MutexGuard<HashSet<String>> {
+impl<B: Bus> Watcher<B> {
+    fn items(&self) -> MutexGuard<'_, HashSet<String>> {
         self.items.lock().expect("mutex should not be poisoned")
     }
 
-    fn hosts(&self) -> MutexGuard<HashSet<String>> {
+    fn hosts(&self) -> MutexGuard<'_, HashSet<String>> {
         self.hosts.lock().expect("mutex should not be poisoned")
     }
 
-    async fn new(connection: Arc<SyncConnection>) -> Result<Watcher, dbus::MethodErr> {
+    /// Create a watcher using `connection`, which must already own the
+    /// watcher name
+    async fn new(connection: Arc<B>) -> Result<Self, dbus::MethodErr> {
         let items = Arc::new(Mutex::new(HashSet::default()));
         let hosts = Arc::new(Mutex::new(HashSet::default()));
         let items2 = items.clone();
         let hosts2 = hosts.clone();
         let connection_ = connection.clone();
-        let name_owner_changed_cb = move |connection_: &Arc<SyncConnection>,
-                                          _msg: Message,
-                                          NameOwnerChanged {
-                                              name,
-                                              old_owner: _,
-                                              new_owner,
-                                          }| {
-            lock(&*hosts2).remove(&name);
-            if new_owner.is_empty() && lock(&*items2).remove(&name) {
+        let name_owner_changed_cb = move |name: &str, change| {
+            if change == bus_names::Change::Appeared {
+                return;
+            }
+            if lock(&*hosts2).remove(name) {
+                eprintln!("Host {:?} unregistered", name);
+                hosts_changed(
+                    &*connection_,
+                    (server::watcher::StatusNotifierWatcherStatusNotifierHostUnregistered {})
+                        .to_emit_message(&path_status_notifier_watcher()),
+                );
+            }
+            if change == bus_names::Change::Lost && lock(&*items2).remove(name) {
                 match connection_.send(
                     (server::watcher::StatusNotifierWatcherStatusNotifierItemUnregistered {
-                        arg0: name.clone(),
+                        arg0: name.to_owned(),
                     })
-                    .to_emit_message(&"/StatusNotifierWatcher".into()),
+                    .to_emit_message(&path_status_notifier_watcher()),
                 ) {
                     Ok(_) => eprintln!("Removed name {:?}", name),
                     Err(()) => eprintln!("Message send failed"),
                 };
                 match connection_.send(
                     dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged {
-                        interface_name: "org.kde.StatusNotifierWatcher".to_owned(),
+                        interface_name: interface_status_notifier_watcher().to_string(),
                         changed_properties: Default::default(),
-                        invalidated_properties: vec!["RegisteredStatusNotifierItems".to_owned()],
+                        invalidated_properties: vec![
+                            registered_status_notifier_items().to_string(),
+                        ],
                     }
-                    .to_emit_message(&"/StatusNotifierWatcher".into()),
+                    .to_emit_message(&path_status_notifier_watcher()),
                 ) {
                     Ok(_) => eprintln!("Properties invalidated to indicate disconnection"),
                     Err(()) => eprintln!("Message send failed"),
                 }
             }
-
-            true
         };
-        connection
-            .request_name(names::name_status_notifier_watcher(), false, true, false)
-            .await?;
-        let x = dbus::message::MatchRule::new_signal(interface_dbus(), name_owner_changed())
-            .with_strict_sender(name_dbus())
-            .with_path(path_dbus());
-        eprintln!("Match rule created");
-        let _msg_match = connection
-            .add_match(x)
-            .await?
-            .cb(move |m, n| name_owner_changed_cb(&connection_, m, n));
+        let _msg_match = bus_names::watch(&*connection, name_owner_changed_cb).await?;
         eprintln!("Match rule added");
 
         Ok(Self {
@@ -125,24 +281,46 @@ impl Watcher {
     }
 }
 
-impl server::watcher::StatusNotifierWatcher for Watcher {
+/// Send `signal`, which tells that a host registered or went away, and
+/// invalidate the properties listing the hosts
+fn hosts_changed<B: Bus>(connection: &B, signal: Message) {
+    if connection.send(signal).is_err() {
+        eprintln!("Message send failed")
+    }
+    let invalidated = dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged {
+        interface_name: interface_status_notifier_watcher().to_string(),
+        changed_properties: Default::default(),
+        invalidated_properties: vec![
+            registered_status_notifier_hosts().to_string(),
+            is_status_notifier_host_registered().to_string(),
+        ],
+    };
+    if connection
+        .send(invalidated.to_emit_message(&path_status_notifier_watcher()))
+        .is_err()
+    {
+        eprintln!("Message send failed")
+    }
+}
+
+impl<B: Bus> server::watcher::StatusNotifierWatcher for Watcher<B> {
     fn register_status_notifier_item(&mut self, service: String) -> Result<(), dbus::MethodErr> {
         // FIXME: validate
         self.items().insert(service.clone());
         match self.connection.send(
             (server::watcher::StatusNotifierWatcherStatusNotifierItemRegistered { arg0: service })
-                .to_emit_message(&"/StatusNotifierWatcher".into()),
+                .to_emit_message(&path_status_notifier_watcher()),
         ) {
             Ok(_) => eprintln!("Item registered"),
             Err(()) => eprintln!("Message send failed"),
         };
         match self.connection.send(
             dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged {
-                interface_name: "org.kde.StatusNotifierWatcher".to_owned(),
+                interface_name: interface_status_notifier_watcher().to_string(),
                 changed_properties: Default::default(),
-                invalidated_properties: vec!["RegisteredStatusNotifierItems".to_owned()],
+                invalidated_properties: vec![registered_status_notifier_items().to_string()],
             }
-            .to_emit_message(&"/StatusNotifierWatcher".into()),
+            .to_emit_message(&path_status_notifier_watcher()),
         ) {
             Ok(_) => eprintln!("Properties invalidated"),
             Err(()) => eprintln!("Message send failed"),
@@ -151,18 +329,19 @@ impl server::watcher::StatusNotifierWatcher for Watcher {
     }
     fn register_status_notifier_host(&mut self, service: String) -> Result<(), dbus::MethodErr> {
         self.hosts().insert(service);
-        match self.connection.send(
+        hosts_changed(
+            &*self.connection,
             (server::watcher::StatusNotifierWatcherStatusNotifierHostRegistered {})
-                .to_emit_message(&"/StatusNotifierWatcher".into()),
-        ) {
-            Ok(_) => {}
-            Err(()) => eprintln!("Message send failed"),
-        };
+                .to_emit_message(&path_status_notifier_watcher()),
+        );
         Ok(())
     }
     fn registered_status_notifier_items(&self) -> Result<Vec<String>, dbus::MethodErr> {
         Ok(self.items().iter().cloned().collect())
     }
+    fn registered_status_notifier_hosts(&self) -> Result<Vec<String>, dbus::MethodErr> {
+        Ok(self.hosts().iter().cloned().collect())
+    }
     fn is_status_notifier_host_registered(&self) -> Result<bool, dbus::MethodErr> {
         Ok(!self.hosts().is_empty())
     }
@@ -171,145 +350,627 @@ impl server::watcher::StatusNotifierWatcher for Watcher {
     }
 }
 
-async fn reader(reverse_name_map: Arc<Mutex<HashMap<u64, String>>>, c: Arc<SyncConnection>) {
-    let mut stdin = tokio::io::stdin();
-    loop {
-        let options = bincode::DefaultOptions::new()
-            .with_fixint_encoding()
-            .with_native_endian()
-            .reject_trailing_bytes();
-        let size = stdin.read_u32_le().await.expect("error reading from stdin");
-        eprintln!("Got something on stdin: length {}!", size);
-        if size > 0x80_000_000 {
-            panic!("Excessive message size {}", size);
+/// Ask the item at `bus_name` and `object_path` for the answer to `body`
+async fn answer<B: Bus>(c: Arc<B>, bus_name: String, object_path: String, body: Request) -> Reply {
+    let icon = Proxy::new(&*bus_name, &*object_path, timeouts::call(), &*c);
+    match body {
+        Request::AboutToShow { id } => {
+            let menu_path = match icon.menu().await {
+                Ok(menu_path) if menu::exists(&menu_path) => menu_path,
+                _ => return Reply::AboutToShow { need_update: false },
+            };
+            let menu = Proxy::new(&*bus_name, menu_path, timeouts::call(), &*c);
+            let need_update = sni_icon::client::menu::Dbusmenu::about_to_show(&menu, id)
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("->server error {:?}", e);
+                    false
+                });
+            Reply::AboutToShow { need_update }
         }
-        let mut buffer = vec![0; size as _];
-        let bytes_read = stdin
-            .read_exact(&mut buffer[..])
+    }
+}
+
+/// The limits negotiated with the daemon
+static LIMITS: std::sync::OnceLock<ProtocolLimits> = std::sync::OnceLock::new();
+
+pub(crate) fn limits() -> ProtocolLimits {
+    *LIMITS.get().expect("handshake should be complete")
+}
+
+/// The tag negotiated with the daemon, see [`sni_icon::integrity`]
+static INTEGRITY: std::sync::OnceLock<Integrity> = std::sync::OnceLock::new();
+
+/// Exchange [`Hello`]s with the daemon, asking for `integrity`, and return
+/// its Hello, with the version both speak, see [`Hello::decode`]
+async fn handshake(
+    stdin: &mut tokio::io::Stdin,
+    integrity: Integrity,
+) -> Result<Hello, Box<dyn Error>> {
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        limits: ProtocolLimits::default(),
+        integrity,
+    };
+    write_or_panic(encoding().serialize(&hello)?);
+    let buffer = read_message(stdin, MIN_MESSAGE_SIZE).await?;
+    Ok(Hello::decode(&buffer)?)
+}
+
+async fn reader(
+    mut stdin: tokio::io::Stdin,
+    name_map: Arc<Mutex<HashMap<String, IconStats>>>,
+    reverse_name_map: Arc<Mutex<HashMap<u64, String>>>,
+    requests: Arc<Mutex<Pending<AbortHandle>>>,
+    connection: Arc<Mutex<Option<Arc<SyncConnection>>>>,
+    routes: publisher::Routes,
+) {
+    loop {
+        let integrity = *INTEGRITY.get().expect("handshake should be complete");
+        let buffer = read_message_tagged(&mut stdin, limits().max_message_size, integrity)
             .await
             .expect("error reading from stdin");
-        assert_eq!(bytes_read, buffer.len());
-        eprintln!("{} bytes read!", bytes_read);
-        let item: sni_icon::IconServerEvent =
-            options.deserialize(&buffer[..]).expect("malformed message");
+        eprintln!("Read a message of {} bytes", buffer.len());
+        let item: sni_icon::IconServerEvent = decode(&buffer).expect("malformed message");
         drop(buffer);
         eprintln!("->server {:?}", item);
-        let lock = lock(&*reverse_name_map).get(&item.id).map(|x| x.to_owned());
-        if let Some(pathname) = lock {
+        if let ServerEvent::Unknown = item.event {
+            eprintln!("Ignoring an event of a later version for item {}", item.id);
+            continue;
+        }
+        if let ServerEvent::Rejected { reason } = &item.event {
+            eprintln!("Daemon discarded an event of item {}: {}", item.id, reason);
+            continue;
+        }
+        if let ServerEvent::Hosts { present } = item.event {
+            eprintln!("Host present in dom0: {}", present);
+            let c = lock(&*connection).clone();
+            set_hosts_present(present, c, &name_map, &reverse_name_map);
+            continue;
+        }
+        if let ServerEvent::CachedIcons { hashes } = item.event {
+            eprintln!("Daemon keeps {} icons", hashes.len());
+            cache::set(hashes);
+            continue;
+        }
+        if let ServerEvent::DestroyAck = item.event {
+            if target(item.id) != Target::Destroying {
+                eprintln!("Unexpected DestroyAck for item {}", item.id);
+            }
+            DESTROYING.with(|destroying| destroying.borrow_mut().remove(&item.id));
+            continue;
+        }
+        let Some(item) = publisher::route(&routes, item) else {
+            continue;
+        };
+        let pathname = lock(&*reverse_name_map).get(&item.id).map(|x| x.to_owned());
+        // A lost connection is forgotten before its items are destroyed, so
+        // items may still be found here without a connection
+        let c = lock(&*connection).clone();
+        if let (Some(pathname), Some(c)) = (pathname, c) {
+            let default_path = path_status_notifier_item();
             let (bus_name, object_path) = match pathname.find('/') {
-                None => (&pathname[..], "/StatusNotifierItem"),
+                None => (&pathname[..], &*default_path),
                 Some(position) => pathname.split_at(position),
             };
             // bus name and object path validated on map entry insertion,
             // no further validation required
-            let icon = Proxy::new(bus_name, object_path, Duration::from_millis(1000), &*c);
+            let icon = Proxy::new(bus_name, object_path, timeouts::call(), &*c);
 
             match item.event {
-                ServerEvent::Activate { x, y } => {
-                    icon.activate(x, y)
-                        .unwrap_or_else(|e| {
-                            eprintln!("->server error {:?}", e);
-                        })
-                        .await
+                ServerEvent::DestroyAck
+                | ServerEvent::Rejected { .. }
+                | ServerEvent::Hosts { .. }
+                | ServerEvent::CachedIcons { .. } => {
+                    unreachable!("handled above")
+                }
+                ServerEvent::CacheMiss { typ, hash } => {
+                    cache::forget(&hash);
+                    if PIXMAPS.contains(&typ) {
+                        handle_cb(
+                            BusName::new(bus_name.to_owned()).expect("validated"),
+                            Path::new(object_path.to_owned()).expect("validated"),
+                            c.clone(),
+                            typ,
+                            name_map.clone(),
+                        )
+                    }
                 }
-                ServerEvent::SecondaryActivate { x, y } => {
-                    icon.secondary_activate(x, y)
-                        .unwrap_or_else(|e| {
-                            eprintln!("->server error {:?}", e);
-                        })
-                        .await
+                ServerEvent::Resync => {
+                    let bus_name = BusName::new(bus_name.to_owned()).expect("validated");
+                    let object_path = Path::new(object_path.to_owned()).expect("validated");
+                    for flag in [
+                        IconType::Normal,
+                        IconType::Attention,
+                        IconType::Overlay,
+                        IconType::Title,
+                        IconType::Status,
+                    ] {
+                        handle_cb(
+                            bus_name.clone(),
+                            object_path.clone(),
+                            c.clone(),
+                            flag,
+                            name_map.clone(),
+                        )
+                    }
                 }
-                ServerEvent::ContextMenu { x, y } => {
-                    icon.context_menu(x, y)
-                        .unwrap_or_else(|e| {
-                            eprintln!("->server error {:?}", e);
-                        })
-                        .await
+                ServerEvent::Activate { x, y } => call_answered(
+                    &name_map,
+                    bus_name,
+                    Call::Activate,
+                    icon.activate(x, y).await,
+                ),
+                ServerEvent::SecondaryActivate { x, y } => call_answered(
+                    &name_map,
+                    bus_name,
+                    Call::SecondaryActivate,
+                    icon.secondary_activate(x, y).await,
+                ),
+                ServerEvent::ContextMenu { x, y } => call_answered(
+                    &name_map,
+                    bus_name,
+                    Call::ContextMenu,
+                    icon.context_menu(x, y).await,
+                ),
+                ServerEvent::Scroll { delta, orientation } => call_answered(
+                    &name_map,
+                    bus_name,
+                    Call::Scroll,
+                    icon.scroll(delta, &orientation).await,
+                ),
+                ServerEvent::Request { request, body } => {
+                    let icon_id = item.id;
+                    let (abort_handle, registration) = AbortHandle::new_pair();
+                    lock(&*requests).track(request, icon_id, abort_handle, None);
+                    let answer =
+                        answer(c.clone(), bus_name.to_owned(), object_path.to_owned(), body);
+                    let requests = requests.clone();
+                    // Do not hold up other events while the item answers
+                    tokio::task::spawn_local(async move {
+                        let Ok(body) = Abortable::new(answer, registration).await else {
+                            // The item was destroyed
+                            return;
+                        };
+                        if lock(&*requests).remove(request).is_some() {
+                            send_or_panic(IconClientEvent::new(
+                                icon_id,
+                                ClientEvent::Reply { request, body },
+                            ))
+                        }
+                    });
                 }
-                ServerEvent::Scroll { delta, orientation } => {
-                    icon.scroll(delta, &orientation)
-                        .unwrap_or_else(|e| {
-                            eprintln!("->server error {:?}", e);
-                        })
-                        .await
+                ServerEvent::MenuClicked { id, timestamp } => {
+                    menu_event(&c, bus_name, &icon, id, Event::Clicked, timestamp).await
                 }
+                ServerEvent::MenuState {
+                    id,
+                    event,
+                    timestamp,
+                } => menu_event(&c, bus_name, &icon, id, event, timestamp).await,
+                // Unknown events are skipped above
+                _ => unreachable!(),
+            }
+        } else {
+            match target(item.id) {
+                Target::Destroying => eprintln!(
+                    "Ignoring event for item {}, which is being destroyed: {:?}",
+                    item.id, item.event
+                ),
+                Target::Destroyed => eprintln!(
+                    "Ignoring event for item {}, which was destroyed: {:?}",
+                    item.id, item.event
+                ),
+                Target::Unknown => eprintln!(
+                    "Ignoring event for item {}, which was never created: {:?}",
+                    item.id, item.event
+                ),
             }
         }
     }
 }
 
-#[derive(Debug)]
-pub struct NameOwnerChanged {
-    pub name: String,
-    pub old_owner: String,
-    pub new_owner: String,
+/// Send `event` for entry `id` to the menu of `icon`, if it has one
+async fn menu_event(
+    c: &SyncConnection,
+    bus_name: &str,
+    icon: &Proxy<'_, &SyncConnection>,
+    id: i32,
+    event: Event,
+    timestamp: u32,
+) {
+    let menu_path = match icon.menu().await {
+        Ok(menu_path) if menu::exists(&menu_path) => menu_path,
+        _ => return,
+    };
+    let menu = Proxy::new(bus_name, menu_path, timeouts::call(), c);
+    sni_icon::client::menu::Dbusmenu::event(
+        &menu,
+        id,
+        event.as_dbus(),
+        dbus::arg::Variant(Box::new(0i32)),
+        timestamp,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("->server error {:?}", e);
+    })
+    .await
 }
 
-impl dbus::arg::ReadAll for NameOwnerChanged {
-    fn read(i: &mut dbus::arg::Iter) -> Result<Self, dbus::arg::TypeMismatchError> {
-        Ok(Self {
-            name: i.read()?,
-            old_owner: i.read()?,
-            new_owner: i.read()?,
-        })
+/// What an ID of an event from the daemon that is not for a current item
+/// refers to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Target {
+    /// An item that was destroyed, which the daemon has not acknowledged
+    /// yet, so it may not have seen the [`ClientEvent::Destroy`]
+    Destroying,
+    /// An item whose destruction the daemon has acknowledged, so it should
+    /// not send events for it anymore
+    Destroyed,
+    /// No item ever had this ID, which the daemon should never send
+    Unknown,
+}
+
+/// IDs are never reused, so those that are not current are either of
+/// destroyed items or were never handed out
+fn target(id: u64) -> Target {
+    if DESTROYING.with(|destroying| destroying.borrow().contains(&id)) {
+        Target::Destroying
+    } else if id != 0 && id <= ID.with(Cell::get) {
+        Target::Destroyed
+    } else {
+        Target::Unknown
+    }
+}
+
+/// Run the checks of `--check`, see [`sni_icon::check`]
+async fn self_check() -> std::process::ExitCode {
+    let mut check = check::Check::new();
+    check.report("D-Bus string validation", check::string_validation());
+    let connected = check.report(
+        "connect to the session bus",
+        session::connect().map_err(|e| (check::Failure::Unavailable, e.to_string())),
+    );
+    if let Some((resource, c)) = connected {
+        tokio::task::spawn_local(resource);
+        check.report(
+            "watcher name available",
+            check::name_available(&c, name_status_notifier_watcher()).await,
+        );
     }
+    check.exit_code()
+}
+
+fn main() -> Result<std::process::ExitCode, Box<dyn Error>> {
+    // Before the runtime opens file descriptors and starts threads
+    hardening::close_inherited_fds();
+    hardening::scrub_environment();
+    start()
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn start() -> Result<std::process::ExitCode, Box<dyn Error>> {
     let local_set = tokio::task::LocalSet::new();
-    // Let's start by starting up a connection to the session bus and request a name.
-    let (resource, c) = connection::new_session_sync()?;
-    local_set.spawn_local(resource);
-    let (resource, c2) = connection::new_session_sync()?;
-    local_set.spawn_local(resource);
-    let _x = local_set.spawn_local(client_server(c, c2));
-    local_set.await;
+    let mut args = cli::Args::parse(std::env::args().skip(1))?;
+    session::set_address(args.session_bus_address.take());
+    if let Some(timeout) = args.timeout {
+        timeouts::set(timeout);
+    }
+    if args.check {
+        return Ok(local_set.run_until(self_check()).await);
+    }
+    #[cfg(feature = "vsock")]
+    if let Some(address) = &args.vsock {
+        use transport::Transport as _;
+        transport::use_as_stdio(transport::Vsock::connect(address)?)?;
+        eprintln!("Connected to daemon at vsock {:?}", address);
+    }
+    check::require_string_validation()?;
+    local_set.run_until(run(&args)).await?;
     eprintln!("Returning from main()");
-    Ok(())
+    Ok(std::process::ExitCode::SUCCESS)
+}
+
+/// How long to wait before reconnecting to the session bus
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Forward items until SIGTERM or SIGINT, as set up by `args`.  If the
+/// session bus goes away, all items are destroyed, and found again once it
+/// is back.
+async fn run(args: &cli::Args) -> Result<(), Box<dyn Error>> {
+    let integrity = args.integrity;
+    use futures_util::future::{select, Either};
+    if let Some(path) = ids::path() {
+        ids::load(path);
+    }
+    let mut stdin = tokio::io::stdin();
+    let peer = handshake(&mut stdin, integrity).await?;
+    let negotiated = ProtocolLimits::default().min(peer.limits);
+    LIMITS.set(negotiated).expect("handshake is only done once");
+    let asked = integrity;
+    let integrity = integrity.negotiate(peer.integrity);
+    eprintln!(
+        "sni-agent {} connected over {} with protocol version {}: limits {:?} (daemon asked for {:?}), integrity tag {} (asked for {}, daemon for {})",
+        env!("CARGO_PKG_VERSION"),
+        transport::Kind::of_stdin().as_str(),
+        peer.version,
+        negotiated,
+        peer.limits,
+        integrity.as_str(),
+        asked.as_str(),
+        peer.integrity.as_str(),
+    );
+    INTEGRITY
+        .set(integrity)
+        .expect("handshake is only done once");
+
+    let name_map = Arc::new(Mutex::new(HashMap::<String, IconStats>::new()));
+    let reverse_name_map = Arc::new(Mutex::new(HashMap::<u64, String>::new()));
+    // Requests from the daemon that are being answered
+    let requests = Arc::new(Mutex::new(Pending::<AbortHandle>::new()));
+    let connection = Arc::new(Mutex::new(None));
+    let routes = publisher::Routes::default();
+    tokio::task::spawn_local(reader(
+        stdin,
+        name_map.clone(),
+        reverse_name_map.clone(),
+        requests.clone(),
+        connection.clone(),
+        routes.clone(),
+    ));
+    eprintln!("Spawned reader future!");
+    if let Some(path) = sni_icon::publisher::socket_path() {
+        match publisher::bind(&path) {
+            Ok(listener) => {
+                eprintln!("Listening for publishers on {}", path.display());
+                tokio::task::spawn_local(publisher::listen(listener, routes));
+            }
+            Err(e) => eprintln!("Cannot listen on {}: {}", path.display(), e),
+        }
+    }
+    #[cfg(feature = "sandbox")]
+    if args.seccomp {
+        hardening::seccomp().map_err(|e| format!("cannot install seccomp filter: {}", e))?;
+        eprintln!("Installed seccomp filter");
+    }
+
+    let mut terminated = std::pin::pin!(sni_icon::signal::terminated());
+    let mut first = true;
+    loop {
+        let connected = session::connect().and_then(|c| Ok((c, session::connect()?)));
+        let ((resource, c), (resource2, c2)) = match connected {
+            Ok(connections) => connections,
+            Err(e) if first => return Err(e.into()),
+            Err(e) => {
+                eprintln!("Cannot reconnect to the session bus: {}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        first = false;
+        let mut resources = (
+            tokio::task::spawn_local(resource),
+            tokio::task::spawn_local(resource2),
+        );
+        let mut lost = select(&mut resources.0, &mut resources.1);
+        *lock(&*connection) = Some(c.clone());
+        let setup = std::pin::pin!(client_server(
+            c.clone(),
+            c2.clone(),
+            name_map.clone(),
+            reverse_name_map.clone(),
+            requests.clone(),
+        ));
+        let matches = match select(setup, &mut lost).await {
+            Either::Left((Ok(matches), _)) => Some(matches),
+            Either::Left((Err(e), _)) => return Err(e),
+            Either::Right(_) => None,
+        };
+        if matches.is_some() {
+            if let Either::Left((signal, _)) = select(terminated.as_mut(), &mut lost).await {
+                eprintln!("Received {}, shutting down", signal);
+                // Let another watcher take over, and stop forwarding
+                c2.release_name(name_status_notifier_watcher()).await?;
+                return Ok(());
+            }
+        }
+        eprintln!("Connection to the session bus lost, reconnecting");
+        // The other connection is still up.  The watcher keeps it open, so
+        // it must give up the name for the next connection to become the
+        // watcher, and stop being served.
+        if !resources.1.is_finished() {
+            let release = c2.release_name(name_status_notifier_watcher());
+            match tokio::time::timeout(timeouts::call(), release).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("Cannot release the watcher name: {}", e),
+                Err(e) => eprintln!("Cannot release the watcher name: {}", e),
+            }
+        }
+        resources.0.abort();
+        resources.1.abort();
+        // Dropping the matches also stops adding new items
+        drop(matches);
+        *lock(&*connection) = None;
+        destroy_all(&name_map, &reverse_name_map, &requests);
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
 }
+
+/// Destroy all items, as the connection they were found on is gone
+fn destroy_all(
+    name_map: &Mutex<HashMap<String, IconStats>>,
+    reverse_name_map: &Mutex<HashMap<u64, String>>,
+    requests: &Mutex<Pending<AbortHandle>>,
+) {
+    let mut ids: Vec<u64> = lock(name_map).drain().map(|(_, stats)| stats.id).collect();
+    lock(reverse_name_map).clear();
+    self::ids::release_all();
+    ids.sort_unstable();
+    for id in ids {
+        for abort_handle in lock(requests).cancel_item(id) {
+            abort_handle.abort()
+        }
+        send_destroy(id)
+    }
+}
+
 thread_local! {
-    static ID: std::cell::Cell<u64> = std::cell::Cell::new(0);
+    static ID: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    /// Items destroyed whose destruction the daemon has not acknowledged
+    static DESTROYING: std::cell::RefCell<HashSet<u64>> = Default::default();
+    /// Whether a host is present in dom0, see [`ServerEvent::Hosts`].
+    /// Assumed until the daemon says otherwise.
+    static HOSTS_PRESENT: Cell<bool> = const { Cell::new(true) };
+}
+
+/// The icons, which are only fetched while a host is present
+const PIXMAPS: [IconType; 3] = [IconType::Normal, IconType::Attention, IconType::Overlay];
+
+/// Note whether a host is present in dom0.  Once one is, forward the icons
+/// left out while none was.
+fn set_hosts_present<B: Bus>(
+    present: bool,
+    c: Option<Arc<B>>,
+    name_map: &Arc<Mutex<HashMap<String, IconStats>>>,
+    reverse_name_map: &Mutex<HashMap<u64, String>>,
+) {
+    if HOSTS_PRESENT.with(|hosts| hosts.replace(present)) || !present {
+        return;
+    }
+    let Some(c) = c else { return };
+    let stale: Vec<(String, u8)> = lock(&**name_map)
+        .values()
+        .filter_map(|stats| {
+            let item = lock(reverse_name_map).get(&stats.id)?.clone();
+            Some((item, stats.stale.take()))
+        })
+        .filter(|&(_, stale)| stale != 0)
+        .collect();
+    let default_path = path_status_notifier_item();
+    for (item, stale) in stale {
+        let (bus_name, object_path) = match item.find('/') {
+            None => (&item[..], &*default_path),
+            Some(position) => item.split_at(position),
+        };
+        // validated on map entry insertion
+        let bus_name = BusName::new(bus_name.to_owned()).expect("validated");
+        let object_path = Path::new(object_path.to_owned()).expect("validated");
+        for flag in PIXMAPS.into_iter().filter(|&flag| stale & flag as u8 != 0) {
+            handle_cb(
+                bus_name.clone(),
+                object_path.clone(),
+                c.clone(),
+                flag,
+                name_map.clone(),
+            )
+        }
+    }
 }
+
+/// Send [`ClientEvent::Destroy`] for item `id`, which must not be used
+/// anymore
+pub(crate) fn send_destroy(id: u64) {
+    DESTROYING.with(|destroying| destroying.borrow_mut().insert(id));
+    send_or_panic(IconClientEvent::new(id, ClientEvent::Destroy))
+}
+
+/// A new ID to forward an item under
+pub(crate) fn next_id() -> u64 {
+    ID.with(|id| {
+        id.set(id.get() + 1);
+        id.get()
+    })
+}
+
 struct IconStats {
     id: u64,
+    /// The application ID, see [`duplicates`]
+    app_id: String,
     state: Cell<u8>,
+    /// The icons left out while no host was present, see
+    /// [`set_hosts_present`]
+    stale: Cell<u8>,
+    /// The match used to forward the item's menu, if it has one
+    menu: Cell<Option<dbus::channel::Token>>,
+    /// Whether the item answered the last call to it, see
+    /// [`ClientEvent::Responsive`]
+    responsive: Cell<bool>,
 }
 
-fn handle_cb(
-    msg: Message,
-    c: Arc<SyncConnection>,
+impl IconStats {
+    /// Tell the daemon whether the item answers, after a call to it ended
+    /// with `result`, if that changed.  An error is an answer too, unless
+    /// the call timed out.
+    fn answered<T>(&self, result: &Result<T, dbus::Error>) {
+        let responsive = !result.as_ref().is_err_and(timeouts::is_timeout);
+        if self.responsive.replace(responsive) != responsive {
+            send_or_panic(IconClientEvent::new(
+                self.id,
+                ClientEvent::Responsive(responsive),
+            ))
+        }
+    }
+}
+
+/// Note the result of `call` to the item called `item`, asked for by the
+/// daemon, and tell the daemon if it failed
+fn call_answered(
+    name_map: &Mutex<HashMap<String, IconStats>>,
+    item: &str,
+    call: Call,
+    result: Result<(), dbus::Error>,
+) {
+    let name_map = lock(name_map);
+    let Some(stats) = name_map.get(item) else {
+        return;
+    };
+    stats.answered(&result);
+    if let Err(e) = &result {
+        eprintln!("->server error {:?}", e);
+        send_or_panic(IconClientEvent::new(
+            stats.id,
+            ClientEvent::CallFailed {
+                call,
+                timed_out: timeouts::is_timeout(e),
+            },
+        ))
+    }
+}
+
+/// Forward the property of the item at `bus_name` and `path` that changed,
+/// as told by a signal
+fn handle_cb<B: Bus>(
+    bus_name: BusName<'static>,
+    path: Path<'static>,
+    c: Arc<B>,
     flag: IconType,
     name_map: Arc<Mutex<HashMap<String, IconStats>>>,
 ) {
-    let sender = msg
-        .sender()
-        .expect("D-Bus will not send a message with no sender");
-    let path = msg
-        .path()
-        .expect("D-Bus will not send a message with no path");
-    let fullpath = format!("{}{}", sender, path);
+    // Items are known by their bus name
+    let key = bus_name.to_string();
     {
         let nm = lock(&*name_map);
-        let nm = match nm.get(&fullpath) {
+        let nm = match nm.get(&key) {
             Some(state) if state.state.get() & (flag as u8) == 0 => state,
             _ => return,
         };
+        if PIXMAPS.contains(&flag) && !HOSTS_PRESENT.with(Cell::get) {
+            nm.stale.set(flag as u8 | nm.stale.get());
+            return;
+        }
         nm.state.set(flag as u8 | nm.state.get());
     }
     let name_map_ = name_map.clone();
+    // Icons wait for the cheap properties of all items, see [`fetch`]
+    let cheap = (!PIXMAPS.contains(&flag)).then(fetch::Cheap::start);
     tokio::task::spawn_local(async move {
-        let icon = Proxy::new(
-            msg.sender()
-                .expect("D-Bus will not send a message with no sender"),
-            msg.path()
-                .expect("D-Bus will not send a message with no path"),
-            Duration::from_millis(1000),
-            &*c,
-        );
+        if cheap.is_none() {
+            fetch::idle().await
+        }
+        let icon = Proxy::new(bus_name, path, timeouts::call(), &*c);
         {
             let nm = lock(&*name_map_);
-            let nm = match nm.get(&fullpath) {
+            let nm = match nm.get(&key) {
                 Some(state) => state,
                 _ => return, // Icon does not exist
             };
@@ -317,98 +978,134 @@ fn handle_cb(
         }
         match flag {
             IconType::Normal | IconType::Overlay | IconType::Attention => {
-                if let Ok(icon_pixmap) = icon.icon_pixmap().await {
+                let icon_pixmap = timeouts::retry(|| match flag {
+                    IconType::Attention => icon.attention_icon_pixmap(),
+                    IconType::Overlay => icon.overlay_icon_pixmap(),
+                    _ => icon.icon_pixmap(),
+                })
+                .await;
+                if let Some(nm) = lock(&*name_map_).get(&key) {
+                    nm.answered(&icon_pixmap)
+                }
+                if let Ok(icon_pixmap) = icon_pixmap {
                     let nm = lock(&*name_map_);
-                    let nm = match nm.get(&fullpath) {
+                    let nm = match nm.get(&key) {
                         Some(state) => state,
                         _ => return, // Icon does not exist
                     };
                     nm.state.set(!(flag as u8) & nm.state.get());
-                    send_or_panic(IconClientEvent {
-                        id: nm.id,
-                        event: ClientEvent::Icon {
-                            typ: flag,
-                            data: icon_pixmap
-                                .into_iter()
-                                .map(|(w, h, data)| IconData {
-                                    width: w as u32,
-                                    height: h as u32,
-                                    data,
-                                })
-                                .collect(),
-                        },
-                    })
-                } else if let Ok(_icon_name) = icon.icon_name().await {
+                    send_icon(nm.id, flag, icon_pixmap)
+                } else if let Ok(_icon_name) = match flag {
+                    IconType::Attention => icon.attention_icon_name(),
+                    IconType::Overlay => icon.overlay_icon_name(),
+                    _ => icon.icon_name(),
+                }
+                .await
+                {
                     let nm = lock(&*name_map_);
-                    let nm = match nm.get(&fullpath) {
+                    let nm = match nm.get(&key) {
                         Some(state) => state,
                         _ => return, // Icon does not exist
                     };
                     nm.state.set(!(flag as u8) & nm.state.get());
                 } else {
                     let nm = lock(&*name_map_);
-                    let nm = match nm.get(&fullpath) {
+                    let nm = match nm.get(&key) {
                         Some(state) => state,
                         _ => return, // Icon does not exist
                     };
                     nm.state.set(!(flag as u8) & nm.state.get());
-                    send_or_panic(IconClientEvent {
-                        id: nm.id,
-                        event: ClientEvent::RemoveIcon(flag),
-                    })
+                    send_or_panic(IconClientEvent::new(nm.id, ClientEvent::RemoveIcon(flag)))
+                }
+                if let Some(event) = accessible_desc(&icon, flag).await {
+                    if let Some(state) = lock(&*name_map_).get(&key) {
+                        send_or_panic(IconClientEvent::new(state.id, event))
+                    }
                 }
             }
             IconType::Title => {
-                let title = icon.title().await;
+                let title = timeouts::retry(|| icon.title()).await;
                 let nm = lock(&*name_map_);
-                let nm = match nm.get(&fullpath) {
+                let nm = match nm.get(&key) {
                     Some(state) => state,
                     _ => return, // Icon does not exist
                 };
-                nm.state.set(!(flag as u8) | nm.state.get());
-                send_or_panic(IconClientEvent {
-                    id: nm.id,
-                    event: ClientEvent::Title(title.ok()),
-                })
+                nm.answered(&title);
+                nm.state.set(!(flag as u8) & nm.state.get());
+                send_or_panic(IconClientEvent::new(nm.id, ClientEvent::Title(title.ok())))
             }
 
             IconType::Status => {
-                let status = StatusNotifierItem::status(&icon).await;
+                let status = timeouts::retry(|| StatusNotifierItem::status(&icon)).await;
                 let nm = lock(&*name_map_);
-                let nm = match nm.get(&fullpath) {
+                let nm = match nm.get(&key) {
                     Some(state) => state,
                     _ => return, // Icon does not exist
                 };
-                nm.state.set(!(flag as u8) | nm.state.get());
-                send_or_panic(IconClientEvent {
-                    id: nm.id,
-                    event: ClientEvent::Status(status.ok()),
-                })
+                nm.answered(&status);
+                nm.state.set(!(flag as u8) & nm.state.get());
+                send_or_panic(IconClientEvent::new(
+                    nm.id,
+                    ClientEvent::Status(status.ok()),
+                ))
             }
+            _ => unreachable!("no signals are watched for other types"),
         }
     });
 }
 
+/// A match callback calling [`handle_cb`] for the sender of each signal
+fn property_changed<B: Bus>(
+    c: Arc<B>,
+    flag: IconType,
+    name_map: Arc<Mutex<HashMap<String, IconStats>>>,
+) -> bus::MatchCallback {
+    Box::new(move |msg| {
+        let sender = msg
+            .sender()
+            .expect("D-Bus will not send a message with no sender")
+            .into_static();
+        let path = msg
+            .path()
+            .expect("D-Bus will not send a message with no path")
+            .into_static();
+        handle_cb(sender, path, c.clone(), flag, name_map.clone());
+        true
+    })
+}
+
+/// Become the watcher on `c2`, and forward the items found on `c`.
+/// Forwarding stops when the returned matches are dropped.
 async fn client_server(
     c: Arc<SyncConnection>,
     c2: Arc<SyncConnection>,
-) -> Result<(MsgMatch, MsgMatch), Box<dyn Error>> {
+    name_map: Arc<Mutex<HashMap<String, IconStats>>>,
+    reverse_name_map: Arc<Mutex<HashMap<u64, String>>>,
+    requests: Arc<Mutex<Pending<AbortHandle>>>,
+) -> Result<Vec<MsgMatch>, Box<dyn Error>> {
     {
-        let cr = Arc::new(Mutex::new(Crossroads::new()));
+        let mut cr = Crossroads::new();
 
         let iface_token_1 =
-            server::watcher::register_status_notifier_watcher::<Watcher>(&mut lock(&*cr));
+            server::watcher::register_status_notifier_watcher::<Watcher<SyncConnection>>(&mut cr);
+        c2.request_name(names::name_status_notifier_watcher(), false, true, false)
+            .await?;
         let watcher = Watcher::new(c2.clone()).await?;
-        lock(&*cr).insert(
+        cr.insert(
             names::path_status_notifier_watcher(),
             &[iface_token_1],
             watcher,
         );
+        // The connection takes the callback out of its list while calling
+        // it, so the crossroads needs no lock: calls are handled one at a
+        // time, and a handler may use the connection freely.
         c2.start_receive(
             dbus::message::MatchRule::new_method_call(),
             Box::new(move |msg, conn| {
-                let mut x = lock(&*cr);
-                (*x).handle_message(msg, conn).is_ok()
+                if let Err(e) = sni_icon::session::dispatch(&mut cr, msg, conn) {
+                    eprintln!("Watcher: {}", e);
+                }
+                true
             }),
         );
     }
@@ -416,216 +1113,748 @@ async fn client_server(
     let watcher = Proxy::new(
         name_status_notifier_watcher(),
         path_status_notifier_watcher(),
-        Duration::from_millis(1000),
+        timeouts::call(),
         c.clone(),
     );
     eprintln!("Created watcher proxy!");
 
-    let name_map = Arc::new(Mutex::new(HashMap::<String, IconStats>::new()));
-    let reverse_name_map = Arc::new(Mutex::new(HashMap::<u64, String>::new()));
-    let reverse_name_map_ = reverse_name_map.clone();
-    tokio::task::spawn_local(reader(reverse_name_map_, c.clone()));
-    eprintln!("Spawned reader future!");
-    let c_ = c.clone();
-    let name_map_ = name_map.clone();
-    let match_rule1 = c
-        .add_match(client::item::StatusNotifierItemNewStatus::match_rule(
-            None, None,
-        ))
-        .await?
-        .cb(move |msg, _: ()| {
-            handle_cb(msg, c_.clone(), IconType::Status, name_map_.clone());
-            true
-        });
+    let match_rule1 = Bus::add_match(
+        &*c,
+        client::item::StatusNotifierItemNewStatus::match_rule(None, None),
+        property_changed(c.clone(), IconType::Status, name_map.clone()),
+    )
+    .await?;
     eprintln!("Added status match!");
-    let c_ = c.clone();
-    let name_map_ = name_map.clone();
-    match c
-        .add_match(client::item::StatusNotifierItemNewTitle::match_rule(
-            None, None,
-        ))
-        .await
+    let match_rule2 = match Bus::add_match(
+        &*c,
+        client::item::StatusNotifierItemNewTitle::match_rule(None, None),
+        property_changed(c.clone(), IconType::Title, name_map.clone()),
+    )
+    .await
     {
-        Ok(rule) => {
-            rule.cb(move |msg, _: ()| {
-                handle_cb(msg, c_.clone(), IconType::Title, name_map_.clone());
-                true
-            });
-        }
+        Ok(rule) => rule,
         Err(e) => {
             let _: Result<_, _> = c.remove_match(match_rule1.token()).await;
             return Err(e.into());
         }
+    };
+
+    // In deterministic mode, items are added one at a time, so that they are
+    // numbered in the order in which they were registered
+    let (queue, mut new_items) = futures_channel::mpsc::unbounded::<String>();
+    let c_ = c.clone();
+    let (name_map_, reverse_name_map_, requests_) =
+        (name_map.clone(), reverse_name_map.clone(), requests.clone());
+    tokio::task::spawn_local(async move {
+        while let Some(item) = new_items.next().await {
+            let go = go(
+                item,
+                c_.clone(),
+                name_map_.clone(),
+                reverse_name_map_.clone(),
+                requests_.clone(),
+            );
+            if deterministic() {
+                let _: Result<_, _> = go.await;
+            } else {
+                tokio::task::spawn_local(go);
+            }
+        }
+    });
+
+    for item in watcher.registered_status_notifier_items().await? {
+        queue.unbounded_send(item).expect("queue is never closed");
     }
 
-    async fn go(
-        item: String,
-        c: Arc<SyncConnection>,
-        name_map: Arc<Mutex<HashMap<String, IconStats>>>,
-        reverse_name_map: Arc<Mutex<HashMap<u64, String>>>,
-    ) -> Result<(), Box<dyn Error>> {
-        eprintln!("Going!");
-        let (bus_name, object_path) = match item.find('/') {
-            None => (&item[..], "/StatusNotifierItem"),
-            Some(position) => item.split_at(position),
-        };
+    let handle_notifier = move |_msg: Message, (s,): (String,)| -> bool {
+        eprintln!("Picked up registered event");
+        queue.unbounded_send(s).expect("queue is never closed");
+        true
+    };
+
+    let matcher1 = c
+        .add_match(StatusNotifierWatcherStatusNotifierItemRegistered::match_rule(None, None))
+        .await?
+        .cb(handle_notifier);
+    let c_ = c.clone();
+    let matcher2 = bus_names::watch(&*c, move |name, change| {
+        if change == bus_names::Change::Lost {
+            handle_name_lost(
+                &c_,
+                name,
+                name_map.clone(),
+                reverse_name_map.clone(),
+                &requests,
+            )
+        }
+    })
+    .await?;
+    // The matches stop when dropped
+    Ok(vec![match_rule1, match_rule2, matcher1, matcher2])
+}
+
+/// Start forwarding `item`, a bus name optionally followed by an object path,
+/// as registered with the watcher
+async fn go<B: Bus>(
+    item: String,
+    c: Arc<B>,
+    name_map: Arc<Mutex<HashMap<String, IconStats>>>,
+    reverse_name_map: Arc<Mutex<HashMap<u64, String>>>,
+    requests: Arc<Mutex<Pending<AbortHandle>>>,
+) -> Result<(), Box<dyn Error>> {
+    eprintln!("Going!");
+    let default_path = path_status_notifier_item();
+    let (bus_name, object_path) = match item.find('/') {
+        None => (&item[..], &*default_path),
+        Some(position) => item.split_at(position),
+    };
+    eprintln!(
+        "Bus name is {:?}, object path is {:?}",
+        bus_name, object_path
+    );
+    let bus_name = BusName::new(bus_name).map_err(|x| {
+        eprintln!("Bad bus name {:?}", x);
+        x
+    })?;
+    let object_path = Path::new(object_path).map_err(|x| {
+        eprintln!("Bad object path {:?}", x);
+        x
+    })?;
+    eprintln!("Object path is {}", object_path);
+    let cheap = fetch::Cheap::start();
+    let icon = Proxy::new(
+        bus_name.clone(),
+        object_path.clone(),
+        timeouts::initial(),
+        c.clone(),
+    );
+    let (app_id, category, is_menu, status, menu_path, icon_theme_path, tooltip) = futures_util::join!(
+        timeouts::retry(|| icon.id()),
+        timeouts::retry(|| icon.category()),
+        timeouts::retry(|| icon.item_is_menu()),
+        timeouts::retry(|| StatusNotifierItem::status(&icon)),
+        timeouts::retry(|| icon.menu()),
+        timeouts::retry(|| icon.icon_theme_path()),
+        timeouts::retry(|| icon.tool_tip())
+    );
+    let title = timeouts::retry(|| icon.title()).await;
+    let app_id = app_id.map_err(|x| {
+        eprintln!("Oops! Cannot obtain app ID: {}", x);
+        x
+    })?;
+    eprintln!("App ID is {:?}", app_id);
+
+    let is_menu = is_menu.unwrap_or(false);
+    eprintln!("Is menu: {}", is_menu);
+    if app_id.starts_with("org.qubes_os.vm.") {
+        return Result::<(), Box<dyn std::error::Error>>::Ok(());
+    }
+    let category = category?;
+    let stale = duplicates::stale(
+        &c,
+        &name_map,
+        &reverse_name_map,
+        &bus_name,
+        &object_path,
+        &app_id,
+    )
+    .await;
+    if let Some(stale) = stale {
         eprintln!(
-            "Bus name is {:?}, object path is {:?}",
-            bus_name, object_path
-        );
-        let bus_name = BusName::new(bus_name).map_err(|x| {
-            eprintln!("Bad bus name {:?}", x);
-            x
-        })?;
-        let object_path = Path::new(object_path).map_err(|x| {
-            eprintln!("Bad object path {:?}", x);
-            x
-        })?;
-        eprintln!("Object path is {}", object_path);
-        let icon = Proxy::new(
-            bus_name.clone(),
-            object_path.clone(),
-            Duration::from_millis(1000),
-            c.clone(),
+            "Item {:?} replaces that of {:?}, which does not answer",
+            item, stale
         );
-        let (app_id, category, is_menu, status) = futures_util::join!(
-            icon.id(),
-            icon.category(),
-            icon.item_is_menu(),
-            StatusNotifierItem::status(&icon)
+        handle_name_lost(
+            &c,
+            &stale,
+            name_map.clone(),
+            reverse_name_map.clone(),
+            &requests,
         );
-        let app_id = app_id.map_err(|x| {
-            eprintln!("Oops! Cannot obtain app ID: {}", x);
-            x
-        })?;
-        eprintln!("App ID is {:?}", app_id);
-
-        let is_menu = is_menu.unwrap_or(false);
-        eprintln!("Is menu: {}", is_menu);
-        if app_id.starts_with("org.qubes_os.vm.") {
-            return Result::<(), Box<dyn std::error::Error>>::Ok(());
-        }
-        let category = category?;
-        let id = ID.with(|id| id.get()) + 1;
-        ID.with(|x| x.set(id));
-        eprintln!("Got new object {:?}, id {}", &item, id);
-        send_or_panic(IconClientEvent {
+    }
+    let menu_path = menu_path.map(|p| p.into_static());
+    // Items that are menus only show them, whatever activating them does
+    let mut capabilities = if is_menu {
+        Capabilities::IS_MENU
+    } else {
+        Capabilities::SUPPORTS_ACTIVATION | Capabilities::SUPPORTS_SECONDARY_ACTIVATE
+    };
+    if menu_path.as_ref().is_ok_and(menu::exists) {
+        capabilities |= Capabilities::HAS_MENU;
+    }
+    if tooltip.is_ok() {
+        capabilities |= Capabilities::HAS_TOOLTIP;
+    }
+    let id = ids::assign(&item);
+    eprintln!("Got new object {:?}, id {}", &item, id);
+    send_or_panic(IconClientEvent::new(
+        id,
+        ClientEvent::Create {
+            category,
+            app_id: app_id.clone(),
+            is_menu: capabilities.contains(Capabilities::IS_MENU),
+            capabilities,
+        },
+    ));
+    lock(&name_map).insert(
+        bus_name.to_string(),
+        IconStats {
             id,
-            event: ClientEvent::Create {
-                category,
-                app_id,
-                is_menu,
-            },
-        });
-        lock(&name_map).insert(
-            bus_name.to_string(),
-            IconStats {
-                id,
-                state: Cell::new(0),
-            },
-        );
-        eprintln!(
-            "Create event sent, {:?} added to reverse name map",
-            &bus_name.to_string()
-        );
-        lock(&*reverse_name_map).insert(id, item);
+            app_id,
+            state: Cell::new(0),
+            stale: Cell::new(0),
+            menu: Cell::new(None),
+            responsive: Cell::new(true),
+        },
+    );
+    eprintln!(
+        "Create event sent, {:?} added to reverse name map",
+        &bus_name.to_string()
+    );
+    lock(&*reverse_name_map).insert(id, item);
 
-        send_or_panic(IconClientEvent {
-            id,
-            event: ClientEvent::Status(status.ok()),
-        });
+    let update = Update {
+        status: Some(status.ok()),
+        title: title.ok().map(Some),
+        tooltip: tooltip.ok().map(|tooltip| Some(tooltip_of(tooltip))),
+        icons: vec![],
+    };
+    send_or_panic(IconClientEvent::new(id, ClientEvent::Update(update)));
+    for typ in [IconType::Normal, IconType::Attention] {
+        if let Some(event) = accessible_desc(&icon, typ).await {
+            send_or_panic(IconClientEvent::new(id, event));
+        }
+    }
+    drop(cheap);
+    fetch::idle().await;
+    if HOSTS_PRESENT.with(Cell::get) {
         let (normal, attention, overlay) = futures_util::join!(
-            icon.icon_pixmap(),
-            icon.attention_icon_pixmap(),
-            icon.overlay_icon_pixmap()
+            timeouts::retry(|| icon.icon_pixmap()),
+            timeouts::retry(|| icon.attention_icon_pixmap()),
+            timeouts::retry(|| icon.overlay_icon_pixmap())
         );
-        for (ty, fun) in [
+        let icons = [
             (IconType::Normal, normal),
             (IconType::Attention, attention),
             (IconType::Overlay, overlay),
-        ] {
-            if let Ok(icon_pixmap) = fun {
-                send_or_panic(IconClientEvent {
-                    id,
-                    event: ClientEvent::Icon {
-                        typ: ty,
-                        data: icon_pixmap
-                            .into_iter()
-                            .map(|(w, h, data)| IconData {
-                                width: w as u32,
-                                height: h as u32,
-                                data,
-                            })
-                            .collect(),
-                    },
-                })
-            }
+        ]
+        .into_iter()
+        .filter_map(|(ty, pixmap)| Some((ty, pixmap.ok()?)));
+        let update = icons_update(id, icons);
+        if !update.is_empty() {
+            send_or_panic(IconClientEvent::new(id, ClientEvent::Update(update)));
         }
-
-        eprintln!("Returning from go()");
-        Ok::<(), _>(())
+    } else if let Some(stats) = lock(&name_map).get(&bus_name.to_string()) {
+        stats
+            .stale
+            .set(PIXMAPS.iter().fold(0, |stale, &ty| stale | ty as u8));
     }
 
-    for item in watcher.registered_status_notifier_items().await? {
-        tokio::task::spawn_local(go(
-            item,
-            c.clone(),
-            name_map.clone(),
-            reverse_name_map.clone(),
-        ));
+    if let Ok(menu_path) = menu_path {
+        let menus = lock(&name_map)
+            .values()
+            .filter(|stats| stats.menu.get().is_some())
+            .count();
+        if menus >= limits().max_menus as usize {
+            eprintln!("Not forwarding menu of item {}: too many menus", id);
+        } else if menu::exists(&menu_path) {
+            let token = menu::watch(
+                id,
+                c.clone(),
+                bus_name.clone().into_static(),
+                menu_path,
+                icon_theme_path.unwrap_or_default(),
+            )
+            .await?;
+            if let Some(stats) = lock(&name_map).get(&bus_name.to_string()) {
+                stats.menu.set(Some(token));
+            }
+        }
     }
 
-    let c_ = c.clone();
-    let (name_map_, reverse_name_map_) = (name_map.clone(), reverse_name_map.clone());
-    let handle_notifier = move |_msg: Message, (s,): (String,)| -> bool {
-        eprintln!("Picked up registered event");
-        tokio::task::spawn_local(go(
-            s,
-            c_.clone(),
-            name_map_.clone(),
-            reverse_name_map_.clone(),
-        ));
-        true
-    };
-
-    let matcher1 = c
-        .add_match(StatusNotifierWatcherStatusNotifierItemRegistered::match_rule(None, None))
-        .await?
-        .cb(handle_notifier);
-    let x = dbus::message::MatchRule::new_signal(interface_dbus(), name_owner_changed())
-        .with_strict_sender(name_dbus())
-        .with_path(path_dbus());
-    let matcher2 = c.add_match(x).await?.cb(move |m, n| {
-        handle_name_lost(&c, m, n, name_map.clone(), reverse_name_map.clone());
-        true
-    });
-    Ok((matcher1, matcher2))
+    eprintln!("Returning from go()");
+    Ok::<(), _>(())
 }
 
-fn handle_name_lost(
-    _c: &Arc<SyncConnection>,
-    _msg: Message,
-    NameOwnerChanged {
-        name,
-        old_owner,
-        new_owner,
-    }: NameOwnerChanged,
+/// Destroy the item owned by `name`, if any, as the name has no owner
+/// anymore
+fn handle_name_lost<B: Bus>(
+    c: &Arc<B>,
+    name: &str,
     name_map: Arc<Mutex<HashMap<String, IconStats>>>,
     reverse_name_map: Arc<Mutex<HashMap<u64, String>>>,
+    requests: &Mutex<Pending<AbortHandle>>,
 ) {
-    if old_owner.is_empty() || !new_owner.is_empty() {
-        return;
-    }
-    let id = match lock(&*name_map).remove(&name) {
-        Some(stats) => stats.id,
+    let id = match lock(&*name_map).remove(name) {
+        Some(stats) => {
+            if let Some(token) = stats.menu.get() {
+                let c = c.clone();
+                tokio::task::spawn_local(async move {
+                    let _: Result<_, _> = c.remove_match(token).await;
+                });
+            }
+            stats.id
+        }
         None => return,
     };
-    eprintln!("Name {} lost, destroying icon {}", &name, id);
-    lock(&*reverse_name_map)
+    eprintln!("Name {} lost, destroying icon {}", name, id);
+    let item = lock(&*reverse_name_map)
         .remove(&id)
         .expect("reverse and forward maps inconsistent");
-    send_or_panic(IconClientEvent {
-        id,
-        event: ClientEvent::Destroy,
-    })
+    ids::release(&item);
+    for abort_handle in lock(requests).cancel_item(id) {
+        abort_handle.abort()
+    }
+    send_destroy(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::MockBus;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(vec![]) };
+    }
+
+    /// Collects the messages to the daemon, instead of stdout
+    pub(super) struct Output;
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            OUTPUT.with(|output| output.borrow_mut().extend_from_slice(buf));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub(super) fn output() -> Output {
+        Output
+    }
+
+    /// The events sent to the daemon since the last call
+    fn sent() -> Vec<ClientEvent> {
+        sent_messages().into_iter().map(|m| m.event).collect()
+    }
+
+    /// The messages sent to the daemon since the last call
+    pub(crate) fn sent_messages() -> Vec<IconClientEvent> {
+        let output = OUTPUT.with(|output| std::mem::take(&mut *output.borrow_mut()));
+        let mut rest = &output[..];
+        let mut events = vec![];
+        while !rest.is_empty() {
+            let (size, tail) = rest.split_at(4);
+            let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+            let (message, tail) = tail.split_at(size);
+            events.push(decode(message).unwrap());
+            rest = tail;
+        }
+        events
+    }
+
+    const ITEM: &str = ":1.7";
+    const PATH: &str = "/StatusNotifierItem";
+    const INTERFACE: &str = "org.kde.StatusNotifierItem";
+
+    type Maps = (
+        Arc<Mutex<HashMap<String, IconStats>>>,
+        Arc<Mutex<HashMap<u64, String>>>,
+    );
+
+    fn setup() -> (Arc<MockBus>, Maps) {
+        LIMITS.get_or_init(ProtocolLimits::default);
+        let bus = MockBus::new();
+        bus.set_property(ITEM, PATH, INTERFACE, "Id", "org.example.App".to_owned());
+        bus.set_property(
+            ITEM,
+            PATH,
+            INTERFACE,
+            "Category",
+            "ApplicationStatus".to_owned(),
+        );
+        bus.set_property(ITEM, PATH, INTERFACE, "Status", "Active".to_owned());
+        bus.set_property(ITEM, PATH, INTERFACE, "Title", "Example".to_owned());
+        (bus, Default::default())
+    }
+
+    #[tokio::test]
+    async fn go_creates_item() {
+        let (bus, (name_map, reverse_name_map)) = setup();
+        go(
+            ITEM.to_owned(),
+            bus,
+            name_map.clone(),
+            reverse_name_map.clone(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        let events = sent();
+        assert!(matches!(
+            &events[..],
+            [
+                ClientEvent::Create { category, app_id, capabilities, .. },
+                ClientEvent::Update(Update {
+                    status: Some(Some(status)),
+                    title: Some(Some(title)),
+                    tooltip: None,
+                    icons,
+                }),
+            ] if category == "ApplicationStatus"
+                && app_id == "org.example.App"
+                && *capabilities
+                    == Capabilities::SUPPORTS_ACTIVATION | Capabilities::SUPPORTS_SECONDARY_ACTIVATE
+                && status == "Active"
+                && title == "Example"
+                && icons.is_empty()
+        ));
+        let id = lock(&name_map)[ITEM].id;
+        assert_eq!(lock(&reverse_name_map)[&id], ITEM);
+    }
+
+    #[tokio::test]
+    async fn go_forwards_accessible_desc() {
+        let (bus, (name_map, reverse_name_map)) = setup();
+        bus.set_property(
+            ITEM,
+            PATH,
+            INTERFACE,
+            "IconAccessibleDesc",
+            "<b>Battery</b> at 50%".to_owned(),
+        );
+        go(
+            ITEM.to_owned(),
+            bus,
+            name_map,
+            reverse_name_map,
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            &sent()[..],
+            [.., ClientEvent::AccessibleDesc { typ: IconType::Normal, desc: Some(desc) }]
+                if desc.as_str() == "Battery at 50%"
+        ));
+    }
+
+    #[tokio::test]
+    async fn go_ignores_forwarded_items() {
+        let (bus, (name_map, reverse_name_map)) = setup();
+        bus.set_property(ITEM, PATH, INTERFACE, "Id", "org.qubes_os.vm.1".to_owned());
+        go(
+            ITEM.to_owned(),
+            bus,
+            name_map.clone(),
+            reverse_name_map,
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        assert!(sent().is_empty());
+        assert!(lock(&name_map).is_empty());
+    }
+
+    #[tokio::test]
+    async fn go_requires_id() {
+        let (_, (name_map, reverse_name_map)) = setup();
+        let bus = MockBus::new();
+        assert!(go(
+            ITEM.to_owned(),
+            bus,
+            name_map.clone(),
+            reverse_name_map,
+            Default::default()
+        )
+        .await
+        .is_err());
+        assert!(sent().is_empty());
+        assert!(lock(&name_map).is_empty());
+    }
+
+    #[tokio::test]
+    async fn go_replaces_stale_duplicate() {
+        const NEW: &str = ":1.8";
+        let (bus, (name_map, reverse_name_map)) = setup();
+        go(
+            ITEM.to_owned(),
+            bus.clone(),
+            name_map.clone(),
+            reverse_name_map.clone(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        let old = lock(&name_map)[ITEM].id;
+        bus.remove_properties(ITEM);
+        bus.set_property(NEW, PATH, INTERFACE, "Id", "org.example.App".to_owned());
+        bus.set_property(
+            NEW,
+            PATH,
+            INTERFACE,
+            "Category",
+            "ApplicationStatus".to_owned(),
+        );
+        sent();
+        go(
+            NEW.to_owned(),
+            bus,
+            name_map.clone(),
+            reverse_name_map.clone(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        let sent = sent_messages();
+        assert!(matches!(
+            &sent[..],
+            [
+                IconClientEvent { id, event: ClientEvent::Destroy, .. },
+                IconClientEvent { event: ClientEvent::Create { .. }, .. },
+                ..
+            ] if *id == old
+        ));
+        assert!(!lock(&name_map).contains_key(ITEM));
+        assert!(!lock(&reverse_name_map).contains_key(&old));
+    }
+
+    #[tokio::test]
+    async fn handle_cb_forwards_title() {
+        let (bus, (name_map, reverse_name_map)) = setup();
+        go(
+            ITEM.to_owned(),
+            bus.clone(),
+            name_map.clone(),
+            reverse_name_map,
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        sent();
+        tokio::task::LocalSet::new()
+            .run_until(async {
+                for title in ["First", "Second"] {
+                    bus.set_property(ITEM, PATH, INTERFACE, "Title", title.to_owned());
+                    handle_cb(
+                        BusName::new(ITEM).unwrap(),
+                        Path::new(PATH).unwrap(),
+                        bus.clone(),
+                        IconType::Title,
+                        name_map.clone(),
+                    );
+                    tokio::task::yield_now().await;
+                    assert!(matches!(
+                        &sent()[..],
+                        [ClientEvent::Title(Some(t))] if t == title
+                    ));
+                }
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn icons_wait_for_host() {
+        let (bus, (name_map, reverse_name_map)) = setup();
+        let pixmap = vec![(1, 1, vec![0xffu8; 4])];
+        bus.set_property(ITEM, PATH, INTERFACE, "IconPixmap", pixmap);
+        set_hosts_present(false, None::<Arc<MockBus>>, &name_map, &reverse_name_map);
+        go(
+            ITEM.to_owned(),
+            bus.clone(),
+            name_map.clone(),
+            reverse_name_map.clone(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        assert!(!sent()
+            .iter()
+            .any(|event| matches!(event, ClientEvent::Icon { .. })));
+        tokio::task::LocalSet::new()
+            .run_until(async {
+                set_hosts_present(true, Some(bus), &name_map, &reverse_name_map);
+                tokio::task::yield_now().await;
+                let mut sent = sent();
+                sent.sort_by_key(|event| match event {
+                    ClientEvent::Icon { typ, .. } | ClientEvent::RemoveIcon(typ) => *typ as u8,
+                    _ => 0,
+                });
+                assert!(matches!(
+                    &sent[..],
+                    [
+                        ClientEvent::Icon { typ: IconType::Normal, data },
+                        ClientEvent::RemoveIcon(IconType::Overlay),
+                        ClientEvent::RemoveIcon(IconType::Attention),
+                    ] if data.len() == 1
+                ));
+            })
+            .await;
+    }
+
+    #[test]
+    fn answered_reports_changes() {
+        let stats = IconStats {
+            id: 1,
+            app_id: String::new(),
+            state: Cell::new(0),
+            stale: Cell::new(0),
+            menu: Cell::new(None),
+            responsive: Cell::new(true),
+        };
+        let timeout = || {
+            Err::<(), _>(dbus::Error::new_custom(
+                "org.freedesktop.DBus.Error.Timeout",
+                "Timeout waiting for reply",
+            ))
+        };
+        stats.answered(&Ok(()));
+        stats.answered(&timeout());
+        stats.answered(&timeout());
+        stats.answered(&Err::<(), _>(dbus::Error::new_failed("no such method")));
+        assert!(matches!(
+            &sent()[..],
+            [
+                ClientEvent::Responsive(false),
+                ClientEvent::Responsive(true)
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_name_lost_destroys_item() {
+        let (bus, (name_map, reverse_name_map)) = setup();
+        go(
+            ITEM.to_owned(),
+            bus.clone(),
+            name_map.clone(),
+            reverse_name_map.clone(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        sent();
+        let requests = Mutex::new(Pending::new());
+        for name in [":1.8", ITEM] {
+            handle_name_lost(
+                &bus,
+                name,
+                name_map.clone(),
+                reverse_name_map.clone(),
+                &requests,
+            );
+        }
+        assert!(matches!(&sent()[..], [ClientEvent::Destroy]));
+        assert!(lock(&name_map).is_empty());
+        assert!(lock(&reverse_name_map).is_empty());
+    }
+
+    #[tokio::test]
+    async fn target_tells_destroyed_from_unknown() {
+        let (bus, (name_map, reverse_name_map)) = setup();
+        go(
+            ITEM.to_owned(),
+            bus.clone(),
+            name_map.clone(),
+            reverse_name_map.clone(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        let id = lock(&name_map)[ITEM].id;
+        let requests = Mutex::new(Pending::new());
+        handle_name_lost(&bus, ITEM, name_map, reverse_name_map, &requests);
+        assert_eq!(target(id), Target::Destroying);
+        DESTROYING.with(|destroying| destroying.borrow_mut().remove(&id));
+        assert_eq!(target(id), Target::Destroyed);
+        assert_eq!(target(id + 1), Target::Unknown);
+        assert_eq!(target(0), Target::Unknown);
+    }
+
+    #[tokio::test]
+    async fn answer_calls_about_to_show() {
+        let (bus, _) = setup();
+        let menu = "/MenuBar";
+        bus.set_property(ITEM, PATH, INTERFACE, "Menu", Path::new(menu).unwrap());
+        bus.on_call(ITEM, menu, "com.canonical.dbusmenu", "AboutToShow", |msg| {
+            msg.return_with_args((true,))
+        });
+        let reply = answer(
+            bus.clone(),
+            ITEM.to_owned(),
+            PATH.to_owned(),
+            Request::AboutToShow { id: 5 },
+        )
+        .await;
+        assert!(matches!(reply, Reply::AboutToShow { need_update: true }));
+        let calls = bus.calls();
+        let call = calls.last().unwrap();
+        assert_eq!(call.member().as_deref(), Some("AboutToShow"));
+        assert_eq!(call.read1::<i32>().unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn watcher_unregisters_lost_items() {
+        use server::watcher::StatusNotifierWatcher as _;
+        let bus = MockBus::new();
+        let mut watcher = Watcher::new(bus.clone()).await.unwrap();
+        assert_eq!(bus.match_count(), 1);
+        watcher
+            .register_status_notifier_item(ITEM.to_owned())
+            .unwrap();
+        assert_eq!(watcher.registered_status_notifier_items().unwrap(), [ITEM]);
+        let registered = bus.sent();
+        assert_eq!(
+            registered[0].member().as_deref(),
+            Some("StatusNotifierItemRegistered")
+        );
+        bus.deliver(
+            Message::signal(&path_dbus(), &interface_dbus(), &name_owner_changed())
+                .append3(ITEM, ITEM, ""),
+        );
+        assert!(watcher
+            .registered_status_notifier_items()
+            .unwrap()
+            .is_empty());
+        let sent = bus.sent();
+        assert_eq!(
+            sent[registered.len()].member().as_deref(),
+            Some("StatusNotifierItemUnregistered")
+        );
+    }
+
+    #[tokio::test]
+    async fn watcher_unregisters_lost_hosts() {
+        use server::watcher::StatusNotifierWatcher as _;
+        const HOST: &str = "org.kde.StatusNotifierHost-1234";
+        let bus = MockBus::new();
+        let mut watcher = Watcher::new(bus.clone()).await.unwrap();
+        watcher
+            .register_status_notifier_host(HOST.to_owned())
+            .unwrap();
+        assert_eq!(watcher.registered_status_notifier_hosts().unwrap(), [HOST]);
+        assert!(watcher.is_status_notifier_host_registered().unwrap());
+        let registered = bus.sent();
+        assert_eq!(
+            registered[0].member().as_deref(),
+            Some("StatusNotifierHostRegistered")
+        );
+        bus.deliver(
+            Message::signal(&path_dbus(), &interface_dbus(), &name_owner_changed())
+                .append3(HOST, ":1.9", ""),
+        );
+        assert!(watcher
+            .registered_status_notifier_hosts()
+            .unwrap()
+            .is_empty());
+        assert!(!watcher.is_status_notifier_host_registered().unwrap());
+        let sent = bus.sent();
+        assert_eq!(
+            sent[registered.len()].member().as_deref(),
+            Some("StatusNotifierHostUnregistered")
+        );
+    }
 }
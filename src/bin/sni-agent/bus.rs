@@ -0,0 +1,315 @@
+//! The D-Bus operations the agent uses, so that the code using them can be
+//! run against an in-memory bus in tests.
+//!
+//! Method calls and property reads go through [`dbus::nonblock::Proxy`],
+//! which works with any [`NonblockReply`] implementation, so they need
+//! nothing beyond that supertrait.
+
+use dbus::channel::Token;
+use dbus::message::MatchRule;
+use dbus::nonblock::{MsgMatch, NonblockReply, SyncConnection};
+use dbus::Message;
+use std::future::Future;
+
+/// Called with every message matching a rule, until it returns `false`
+pub(crate) type MatchCallback = Box<dyn FnMut(Message) -> bool + Send>;
+
+/// A connection to D-Bus
+pub(crate) trait Bus: NonblockReply + Send + Sync + 'static {
+    /// Keeps a match alive: its callback is dropped with it
+    type Match: Send + 'static;
+
+    /// Send `msg`, not expecting a reply
+    fn send(&self, msg: Message) -> Result<u32, ()>;
+
+    /// Call `cb` with every message matching `rule`
+    fn add_match(
+        &self,
+        rule: MatchRule<'static>,
+        cb: MatchCallback,
+    ) -> impl Future<Output = Result<Self::Match, dbus::Error>>;
+
+    /// The token with which the match can be removed
+    fn token(m: &Self::Match) -> Token;
+
+    /// Remove the match with token `token`
+    fn remove_match(&self, token: Token) -> impl Future<Output = Result<(), dbus::Error>>;
+}
+
+/// A [`MatchCallback`] receiving the arguments of the message as `R`.
+/// Messages with other arguments are ignored.
+pub(crate) fn typed<R, F>(mut f: F) -> MatchCallback
+where
+    R: dbus::arg::ReadAll,
+    F: FnMut(Message, R) -> bool + Send + 'static,
+{
+    Box::new(move |msg| match R::read(&mut msg.iter_init()) {
+        Ok(args) => f(msg, args),
+        Err(_) => true,
+    })
+}
+
+impl Bus for SyncConnection {
+    type Match = MsgMatch;
+
+    fn send(&self, msg: Message) -> Result<u32, ()> {
+        dbus::channel::Sender::send(self, msg)
+    }
+
+    async fn add_match(
+        &self,
+        rule: MatchRule<'static>,
+        cb: MatchCallback,
+    ) -> Result<MsgMatch, dbus::Error> {
+        Ok(SyncConnection::add_match(self, rule).await?.msg_cb(cb))
+    }
+
+    fn token(m: &MsgMatch) -> Token {
+        m.token()
+    }
+
+    async fn remove_match(&self, token: Token) -> Result<(), dbus::Error> {
+        SyncConnection::remove_match(self, token).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mock::MockBus;
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use dbus::arg::{RefArg, Variant};
+    use dbus::nonblock::{TimeoutMakerCb, WakerCb};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, Weak};
+
+    type Key = (String, String, String, String);
+    type Handler = Box<dyn Fn(&Message) -> Message + Send + Sync>;
+    type Matches = Vec<(Token, MatchRule<'static>, Weak<Mutex<MatchCallback>>)>;
+
+    /// The callback of a match on a [`MockBus`]
+    pub(crate) struct MockMatch {
+        token: Token,
+        _cb: Arc<Mutex<MatchCallback>>,
+    }
+
+    /// An in-memory bus, answering property reads and method calls from
+    /// values and handlers set up by the test, and recording everything
+    /// sent.
+    ///
+    /// Messages created in tests have no sender, so match rules are applied
+    /// without regard to the sender.
+    #[derive(Default)]
+    pub(crate) struct MockBus {
+        serial: Mutex<u32>,
+        properties: Mutex<HashMap<Key, Box<dyn RefArg>>>,
+        handlers: Mutex<HashMap<Key, Handler>>,
+        matches: Mutex<Matches>,
+        calls: Mutex<Vec<Message>>,
+        sent: Mutex<Vec<Message>>,
+    }
+
+    fn key(destination: &str, path: &str, interface: &str, name: &str) -> Key {
+        (
+            destination.to_owned(),
+            path.to_owned(),
+            interface.to_owned(),
+            name.to_owned(),
+        )
+    }
+
+    impl MockBus {
+        pub fn new() -> Arc<Self> {
+            Arc::new(Self::default())
+        }
+
+        fn next_serial(&self) -> u32 {
+            let mut serial = self.serial.lock().unwrap();
+            *serial += 1;
+            *serial
+        }
+
+        /// Make property `name` of `interface` on `destination` and `path`
+        /// read as `value`
+        pub fn set_property(
+            &self,
+            destination: &str,
+            path: &str,
+            interface: &str,
+            name: &str,
+            value: impl RefArg + 'static,
+        ) {
+            self.properties
+                .lock()
+                .unwrap()
+                .insert(key(destination, path, interface, name), Box::new(value));
+        }
+
+        /// Make no properties on `destination` readable anymore, as if it
+        /// stopped answering
+        pub fn remove_properties(&self, destination: &str) {
+            self.properties
+                .lock()
+                .unwrap()
+                .retain(|(d, ..), _| d != destination);
+        }
+
+        /// Answer calls of method `member` of `interface` on `destination`
+        /// and `path` with `handler`
+        pub fn on_call(
+            &self,
+            destination: &str,
+            path: &str,
+            interface: &str,
+            member: &str,
+            handler: impl Fn(&Message) -> Message + Send + Sync + 'static,
+        ) {
+            self.handlers
+                .lock()
+                .unwrap()
+                .insert(key(destination, path, interface, member), Box::new(handler));
+        }
+
+        /// Deliver `msg` to the matches it matches, as if it came from the bus
+        pub fn deliver(&self, msg: Message) {
+            let callbacks: Vec<_> = self
+                .matches
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, rule, _)| {
+                    let mut rule = rule.clone();
+                    rule.sender = None;
+                    rule.matches(&msg)
+                })
+                .map(|(token, _, cb)| (*token, cb.clone()))
+                .collect();
+            for (token, cb) in callbacks {
+                let keep = match cb.upgrade() {
+                    Some(cb) => (cb.lock().unwrap())(msg.duplicate().unwrap()),
+                    None => false,
+                };
+                if !keep {
+                    self.matches.lock().unwrap().retain(|(t, _, _)| *t != token)
+                }
+            }
+        }
+
+        /// Method calls made so far
+        pub fn calls(&self) -> Vec<Message> {
+            let calls = self.calls.lock().unwrap();
+            calls.iter().map(|m| m.duplicate().unwrap()).collect()
+        }
+
+        /// Messages sent so far with [`Bus::send`]
+        pub fn sent(&self) -> Vec<Message> {
+            let sent = self.sent.lock().unwrap();
+            sent.iter().map(|m| m.duplicate().unwrap()).collect()
+        }
+
+        /// The number of matches that are in place
+        pub fn match_count(&self) -> usize {
+            self.matches.lock().unwrap().len()
+        }
+
+        fn answer(&self, msg: &Message) -> Message {
+            let destination = msg.destination().map(|d| d.to_string()).unwrap_or_default();
+            let path = msg.path().map(|p| p.to_string()).unwrap_or_default();
+            let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+            let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+            if interface == "org.freedesktop.DBus.Properties" && member == "Get" {
+                let (property_interface, name): (String, String) = msg.read2().unwrap();
+                let properties = self.properties.lock().unwrap();
+                return match properties.get(&key(&destination, &path, &property_interface, &name)) {
+                    Some(value) => msg.return_with_args((Variant(value.box_clone()),)),
+                    None => msg.error(
+                        &"org.freedesktop.DBus.Error.UnknownProperty".into(),
+                        &std::ffi::CString::new(format!("no property {}", name)).unwrap(),
+                    ),
+                };
+            }
+            let handlers = self.handlers.lock().unwrap();
+            match handlers.get(&key(&destination, &path, &interface, &member)) {
+                Some(handler) => handler(msg),
+                None => msg.error(
+                    &"org.freedesktop.DBus.Error.UnknownMethod".into(),
+                    &std::ffi::CString::new(format!("no method {}", member)).unwrap(),
+                ),
+            }
+        }
+    }
+
+    impl NonblockReply for MockBus {
+        type F = Box<dyn FnOnce(Message, &MockBus) + Send>;
+
+        fn send_with_reply(&self, mut msg: Message, f: Self::F) -> Result<Token, ()> {
+            let serial = self.next_serial();
+            msg.set_serial(serial);
+            let reply = self.answer(&msg);
+            self.calls.lock().unwrap().push(msg);
+            f(reply, self);
+            Ok(Token(serial as usize))
+        }
+
+        fn cancel_reply(&self, _id: Token) -> Option<Self::F> {
+            // Replies are delivered right away
+            None
+        }
+
+        fn make_f<G: FnOnce(Message, &Self) + Send + 'static>(g: G) -> Self::F {
+            Box::new(g)
+        }
+
+        fn set_timeout_maker(&mut self, _f: Option<TimeoutMakerCb>) -> Option<TimeoutMakerCb> {
+            None
+        }
+
+        fn timeout_maker(&self) -> Option<TimeoutMakerCb> {
+            None
+        }
+
+        fn set_waker(&mut self, _f: Option<WakerCb>) -> Option<WakerCb> {
+            None
+        }
+    }
+
+    impl Bus for MockBus {
+        type Match = MockMatch;
+
+        fn send(&self, mut msg: Message) -> Result<u32, ()> {
+            let serial = self.next_serial();
+            msg.set_serial(serial);
+            self.sent.lock().unwrap().push(msg);
+            Ok(serial)
+        }
+
+        async fn add_match(
+            &self,
+            rule: MatchRule<'static>,
+            cb: MatchCallback,
+        ) -> Result<MockMatch, dbus::Error> {
+            let token = Token(self.next_serial() as usize);
+            let cb = Arc::new(Mutex::new(cb));
+            self.matches
+                .lock()
+                .unwrap()
+                .push((token, rule, Arc::downgrade(&cb)));
+            Ok(MockMatch { token, _cb: cb })
+        }
+
+        fn token(m: &MockMatch) -> Token {
+            m.token
+        }
+
+        async fn remove_match(&self, token: Token) -> Result<(), dbus::Error> {
+            let mut matches = self.matches.lock().unwrap();
+            let len = matches.len();
+            matches.retain(|(t, _, _)| *t != token);
+            if matches.len() == len {
+                return Err(dbus::Error::new_failed("No match with that id found"));
+            }
+            Ok(())
+        }
+    }
+}
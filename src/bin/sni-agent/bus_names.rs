@@ -0,0 +1,139 @@
+//! Tracking of bus names appearing and disappearing, from the bus's
+//! `NameOwnerChanged` signals
+
+use dbus::arg::{Iter, ReadAll, TypeMismatchError};
+
+use crate::bus::{self, Bus, MatchCallback};
+
+/// The arguments of a `NameOwnerChanged` signal.  An empty owner means the
+/// name had or has none.
+#[derive(Debug)]
+struct NameOwnerChanged {
+    name: String,
+    old_owner: String,
+    new_owner: String,
+}
+
+impl ReadAll for NameOwnerChanged {
+    fn read(i: &mut Iter) -> Result<Self, TypeMismatchError> {
+        Ok(Self {
+            name: i.read()?,
+            old_owner: i.read()?,
+            new_owner: i.read()?,
+        })
+    }
+}
+
+/// What happened to a bus name
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Change {
+    /// The name was acquired, and had no owner before
+    Appeared,
+    /// The name passed from one owner to another
+    Replaced,
+    /// The name was released, or its owner disconnected from the bus
+    Lost,
+}
+
+impl NameOwnerChanged {
+    /// [`None`] for signals that would not change anything
+    fn change(&self) -> Option<Change> {
+        match (self.old_owner.is_empty(), self.new_owner.is_empty()) {
+            (true, true) => None,
+            (true, false) => Some(Change::Appeared),
+            (false, true) => Some(Change::Lost),
+            (false, false) => Some(Change::Replaced),
+        }
+    }
+}
+
+/// A match callback calling `f` with every bus name that changed, and how
+fn callback<F>(mut f: F) -> MatchCallback
+where
+    F: FnMut(&str, Change) + Send + 'static,
+{
+    bus::typed(move |_msg, args: NameOwnerChanged| {
+        if let Some(change) = args.change() {
+            f(&args.name, change)
+        }
+        true
+    })
+}
+
+/// Call `f` with every change of any bus name, until the returned match
+/// is dropped
+pub(crate) async fn watch<B, F>(c: &B, f: F) -> Result<B::Match, dbus::Error>
+where
+    B: Bus,
+    F: FnMut(&str, Change) + Send + 'static,
+{
+    c.add_match(sni_icon::names::name_owner_changed_rule(), callback(f))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MockBus;
+    use dbus::Message;
+    use sni_icon::names::{interface_dbus, name_owner_changed, path_dbus};
+    use std::sync::{Arc, Mutex};
+
+    fn signal(name: &str, old_owner: &str, new_owner: &str) -> Message {
+        Message::signal(&path_dbus(), &interface_dbus(), &name_owner_changed())
+            .append3(name, old_owner, new_owner)
+    }
+
+    async fn changes(signals: &[Message]) -> Vec<(String, Change)> {
+        let bus = MockBus::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_ = seen.clone();
+        let _match = watch(&*bus, move |name, change| {
+            seen_.lock().unwrap().push((name.to_owned(), change))
+        })
+        .await
+        .unwrap();
+        for msg in signals {
+            bus.deliver(msg.duplicate().unwrap())
+        }
+        let seen = seen.lock().unwrap();
+        seen.clone()
+    }
+
+    #[tokio::test]
+    async fn classifies_changes() {
+        let name = "org.example.A";
+        let expected = [Change::Appeared, Change::Replaced, Change::Lost]
+            .map(|change| (name.to_owned(), change));
+        let seen = changes(&[
+            signal(name, "", ":1.1"),
+            signal(name, ":1.1", ":1.2"),
+            signal(name, ":1.2", ""),
+        ])
+        .await;
+        assert_eq!(seen, expected);
+    }
+
+    #[tokio::test]
+    async fn ignores_malformed_and_empty_signals() {
+        let malformed = Message::signal(&path_dbus(), &interface_dbus(), &name_owner_changed())
+            .append2("org.example.A", "");
+        let seen = changes(&[malformed, signal("org.example.A", "", "")]).await;
+        assert!(seen.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_when_dropped() {
+        let bus = MockBus::new();
+        let seen = Arc::new(Mutex::new(0));
+        let seen_ = seen.clone();
+        let m = watch(&*bus, move |_, _| *seen_.lock().unwrap() += 1)
+            .await
+            .unwrap();
+        bus.deliver(signal(":1.1", ":1.1", ""));
+        drop(m);
+        bus.deliver(signal(":1.2", ":1.2", ""));
+        assert_eq!(*seen.lock().unwrap(), 1);
+        assert_eq!(bus.match_count(), 0);
+    }
+}
@@ -0,0 +1,97 @@
+//! The icons the daemon keeps on disk, which are sent by their hash alone
+//!
+//! The daemon lists the icons it keeps after the handshake
+//! ([`ServerEvent::CachedIcons`]), and keeps every icon sent to it later.
+//! Until it did, or if it keeps none, icons are always sent in full.  If it
+//! lost an icon after all, it asks for it ([`ServerEvent::CacheMiss`]) and
+//! the icon is fetched and sent in full again.
+//!
+//! [`ServerEvent::CachedIcons`]: sni_icon::ServerEvent::CachedIcons
+//! [`ServerEvent::CacheMiss`]: sni_icon::ServerEvent::CacheMiss
+
+use sni_icon::{ClientEvent, IconData, IconHash, IconType, ProtocolLimits};
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+thread_local! {
+    /// The hashes of the icons the daemon keeps, if it keeps any
+    static CACHED: RefCell<Option<HashSet<IconHash>>> = const { RefCell::new(None) };
+}
+
+/// Note that the daemon keeps the icons with `hashes`
+pub(crate) fn set(hashes: Vec<IconHash>) {
+    CACHED.with(|cached| *cached.borrow_mut() = Some(hashes.into_iter().collect()))
+}
+
+/// Note that the daemon no longer has the icon with `hash`
+pub(crate) fn forget(hash: &IconHash) {
+    CACHED.with(|cached| {
+        if let Some(cached) = &mut *cached.borrow_mut() {
+            cached.remove(hash);
+        }
+    })
+}
+
+/// The events setting the frames of icon `typ` to `frames`: only its hash if
+/// the daemon keeps it, and otherwise those of [`sni_icon::icon_events`]
+pub(crate) fn icon_events(
+    typ: IconType,
+    frames: impl IntoIterator<Item = IconData>,
+    limits: &ProtocolLimits,
+) -> Vec<ClientEvent> {
+    // The frames the daemon would get
+    let frames: Vec<IconData> = frames
+        .into_iter()
+        .filter(|frame| frame.check(limits).is_ok())
+        .take(limits.max_frames as usize)
+        .collect();
+    if !frames.is_empty() {
+        let hash = sni_icon::icon_hash(&frames);
+        let known = CACHED.with(|cached| {
+            // From now on the daemon keeps it
+            Some(!cached.borrow_mut().as_mut()?.insert(hash))
+        });
+        if known == Some(true) {
+            return vec![ClientEvent::CachedIcon { typ, hash }];
+        }
+    }
+    sni_icon::icon_events(typ, frames, limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames() -> Vec<IconData> {
+        vec![IconData {
+            width: 1,
+            height: 1,
+            data: vec![255, 1, 2, 3],
+        }]
+    }
+
+    #[test]
+    fn cached_icons_are_sent_by_hash() {
+        let limits = ProtocolLimits::default();
+        // The daemon has not said that it keeps icons
+        assert!(matches!(
+            &icon_events(IconType::Normal, frames(), &limits)[..],
+            [ClientEvent::Icon { .. }]
+        ));
+        set(vec![]);
+        assert!(matches!(
+            &icon_events(IconType::Normal, frames(), &limits)[..],
+            [ClientEvent::Icon { .. }]
+        ));
+        let hash = sni_icon::icon_hash(&frames());
+        assert!(matches!(
+            &icon_events(IconType::Attention, frames(), &limits)[..],
+            [ClientEvent::CachedIcon { typ: IconType::Attention, hash: h }] if *h == hash
+        ));
+        forget(&hash);
+        assert!(matches!(
+            &icon_events(IconType::Normal, frames(), &limits)[..],
+            [ClientEvent::Icon { .. }]
+        ));
+    }
+}
@@ -0,0 +1,58 @@
+//! Detection of items that an application registered again
+//!
+//! Some applications, such as those built on Electron, sometimes register a
+//! second item from a new connection while the first one stays on the bus
+//! without answering anymore.  A new item with the same application ID and
+//! object path as a forwarded item of another connection replaces it if
+//! that one does not answer within [`TIMEOUT`], instead of both being
+//! shown.  If the older one answers, both are forwarded, as an application
+//! may well have more than one item.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dbus::nonblock::Proxy;
+use sni_icon::client::item::StatusNotifierItem as _;
+use sni_icon::names::path_status_notifier_item;
+
+use crate::bus::Bus;
+use crate::{lock, IconStats};
+
+/// How long an older item has to answer
+const TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The bus name of the forwarded item that a new item of `bus_name` at
+/// `object_path` replaces, if any.  The new item's application ID is
+/// `app_id`.
+pub(crate) async fn stale<B: Bus>(
+    c: &Arc<B>,
+    name_map: &Mutex<HashMap<String, IconStats>>,
+    reverse_name_map: &Mutex<HashMap<u64, String>>,
+    bus_name: &str,
+    object_path: &str,
+    app_id: &str,
+) -> Option<String> {
+    let default_path = path_status_notifier_item();
+    let candidates: Vec<String> = {
+        let reverse_name_map = lock(reverse_name_map);
+        lock(name_map)
+            .iter()
+            .filter(|&(name, stats)| name != bus_name && stats.app_id == app_id)
+            .filter(|(_, stats)| {
+                reverse_name_map.get(&stats.id).is_some_and(|item| {
+                    let path = item.find('/').map_or(&*default_path, |at| &item[at..]);
+                    path == object_path
+                })
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    };
+    for name in candidates {
+        let icon = Proxy::new(&*name, object_path, TIMEOUT, &**c);
+        if icon.id().await.is_err() {
+            return Some(name);
+        }
+    }
+    None
+}
@@ -0,0 +1,81 @@
+//! The order in which properties of items are fetched
+//!
+//! When many items appear at once, such as when the session starts or the
+//! daemon asks for a resync, the cheap properties of all of them (status,
+//! title, and what is sent when an item is created) are fetched before the
+//! icons of any, so that the tray is usable as soon as possible.  A fetch of
+//! cheap properties holds a [`Cheap`] until it is done, and fetching icons
+//! waits for [`idle`] first.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use tokio::sync::Notify;
+
+thread_local! {
+    /// The number of [`Cheap`] fetches in progress
+    static CHEAP: Cell<usize> = const { Cell::new(0) };
+    /// Notified when no [`Cheap`] fetches are in progress anymore
+    static IDLE: Rc<Notify> = Rc::new(Notify::new());
+}
+
+/// A fetch of cheap properties in progress, which icons wait for
+pub(crate) struct Cheap(());
+
+impl Cheap {
+    pub(crate) fn start() -> Self {
+        CHEAP.with(|cheap| cheap.set(cheap.get() + 1));
+        Self(())
+    }
+}
+
+impl Drop for Cheap {
+    fn drop(&mut self) {
+        let left = CHEAP.with(|cheap| {
+            cheap.set(cheap.get() - 1);
+            cheap.get()
+        });
+        if left == 0 {
+            IDLE.with(|idle| idle.notify_waiters())
+        }
+    }
+}
+
+/// Wait until no [`Cheap`] fetches are in progress
+pub(crate) async fn idle() {
+    loop {
+        let idle = IDLE.with(Rc::clone);
+        // Created before checking, so that no notification is missed
+        let notified = idle.notified();
+        if CHEAP.with(Cell::get) == 0 {
+            return;
+        }
+        notified.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn idle_waits_for_cheap_fetches() {
+        let cheap = Cheap::start();
+        let done = Rc::new(Cell::new(false));
+        tokio::task::LocalSet::new()
+            .run_until(async {
+                let waiter = tokio::task::spawn_local({
+                    let done = done.clone();
+                    async move {
+                        idle().await;
+                        done.set(true)
+                    }
+                });
+                tokio::task::yield_now().await;
+                assert!(!done.get());
+                drop(cheap);
+                waiter.await.unwrap();
+                assert!(done.get());
+            })
+            .await;
+    }
+}
@@ -0,0 +1,196 @@
+//! Hardening the agent at startup
+//!
+//! The agent runs in every VM and parses messages from dom0, so it starts
+//! with as little as it can: file descriptors it inherited other than stdin,
+//! stdout and stderr are closed, and only the environment variables it uses
+//! are kept.  With `--seccomp`, it also restricts itself to the system calls
+//! needed for socket, pipe and file I/O once it is set up, see
+//! [`sni_icon::seccomp`].
+
+/// The environment variables that are kept, see [`scrub_environment`]
+const KEPT_VARIABLES: &[&str] = &[
+    // The session bus, see sni_icon::session
+    "DBUS_SESSION_BUS_ADDRESS",
+    sni_icon::session::ADDRESS_VARIABLE,
+    // Files of the agent and the publisher socket
+    "XDG_RUNTIME_DIR",
+    crate::ids::FILE_VARIABLE,
+    sni_icon::publisher::SOCKET_VARIABLE,
+    // Icon themes of menu entries
+    "HOME",
+    "XDG_DATA_HOME",
+    "XDG_DATA_DIRS",
+    "SNI_ICON_DETERMINISTIC",
+    #[cfg(feature = "testing")]
+    sni_icon::fault::VARIABLE,
+    "RUST_BACKTRACE",
+];
+
+/// Close all file descriptors other than stdin, stdout and stderr.  Must be
+/// called before anything else opens file descriptors, which rules out an
+/// async runtime.
+pub(crate) fn close_inherited_fds() {
+    // SAFETY: nothing else owns file descriptors yet
+    let closed = unsafe { libc::syscall(libc::SYS_close_range, 3, u32::MAX, 0) };
+    if closed == 0 {
+        return;
+    }
+    // Before Linux 5.9
+    let Ok(entries) = std::fs::read_dir("/proc/self/fd") else {
+        return eprintln!("Cannot list inherited file descriptors");
+    };
+    let fds: Vec<libc::c_int> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|&fd| fd > 2)
+        .collect();
+    for fd in fds {
+        // SAFETY: as above.  The descriptor of the directory is closed
+        // already, which makes this fail harmlessly.
+        unsafe { libc::close(fd) };
+    }
+}
+
+/// Remove all environment variables except [`KEPT_VARIABLES`].  Must be
+/// called before any threads are started.
+pub(crate) fn scrub_environment() {
+    for (name, _) in std::env::vars_os() {
+        if !KEPT_VARIABLES.iter().any(|&kept| name == kept) {
+            std::env::remove_var(name)
+        }
+    }
+}
+
+/// The system calls allowed by [`seccomp`]
+#[cfg(feature = "sandbox")]
+const ALLOWED: &[libc::c_long] = &[
+    // Reading and writing
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_close,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_lseek,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    // Sockets: the session bus, and publishers
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_shutdown,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    // Files: icons of menu entries, and the IDs of items
+    libc::SYS_openat,
+    libc::SYS_newfstatat,
+    libc::SYS_fstat,
+    libc::SYS_statx,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_mkdirat,
+    libc::SYS_ftruncate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    // Memory and threads
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_membarrier,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_prctl,
+    // Signals, including aborting on panic
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_restart_syscall,
+    // Time
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    // Identity, for authenticating to the session bus
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getrandom,
+    libc::SYS_uname,
+    libc::SYS_prlimit64,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Older variants, which only x86-64 has
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_select,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_getdents,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+];
+
+/// Allow only the system calls in [`ALLOWED`] from now on
+#[cfg(feature = "sandbox")]
+pub(crate) fn seccomp() -> std::io::Result<()> {
+    sni_icon::seccomp::install(sni_icon::seccomp::Mode::AllowOnly, ALLOWED)
+}
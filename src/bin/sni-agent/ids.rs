@@ -0,0 +1,162 @@
+//! IDs of items that are kept when the agent restarts
+//!
+//! The IDs of the current items are saved by the string each item
+//! registered with, in a file in `XDG_RUNTIME_DIR`.  A restarted agent gives
+//! the items that register again their old IDs, so that a daemon that
+//! restored them (see [`sni_icon::ServerEvent::Resync`]) updates its icons
+//! in place instead of replacing them, which would reorder them in the
+//! tray.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::{next_id, ID};
+
+/// The environment variable overriding the path of the file
+pub(crate) const FILE_VARIABLE: &str = "SNI_AGENT_ID_FILE";
+
+#[derive(Debug, Default)]
+struct Ids {
+    path: Option<PathBuf>,
+    /// IDs saved by the previous agent, of items not registered again yet
+    previous: HashMap<String, u64>,
+    /// IDs of the current items
+    current: HashMap<String, u64>,
+}
+
+thread_local! {
+    static IDS: RefCell<Ids> = RefCell::default();
+}
+
+/// The path of the file: that given by `SNI_AGENT_ID_FILE`, or else
+/// `sni-agent-ids.toml` in `XDG_RUNTIME_DIR`.  [`None`] if neither is set.
+pub(crate) fn path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os(FILE_VARIABLE) {
+        return Some(path.into());
+    }
+    std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("sni-agent-ids.toml"))
+}
+
+/// Keep IDs in the file at `path`, reusing those saved there.  No new item
+/// gets any of the saved IDs.
+pub(crate) fn load(path: PathBuf) {
+    let previous = match std::fs::read_to_string(&path) {
+        Ok(data) => match parse(&data) {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("Ignoring invalid {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", path.display(), e);
+            HashMap::new()
+        }
+    };
+    let last = previous.values().copied().max().unwrap_or(0);
+    ID.with(|id| id.set(id.get().max(last)));
+    IDS.with(|ids| {
+        *ids.borrow_mut() = Ids {
+            path: Some(path),
+            previous,
+            current: HashMap::new(),
+        }
+    })
+}
+
+/// The IDs in `data`, a TOML table `ids` of IDs by item
+fn parse(data: &str) -> Result<HashMap<String, u64>, String> {
+    let mut file: toml::Table = data.parse().map_err(|e| format!("{}", e))?;
+    let Some(ids) = file.remove("ids") else {
+        return Ok(HashMap::new());
+    };
+    let toml::Value::Table(ids) = ids else {
+        return Err("ids is not a table".to_owned());
+    };
+    ids.into_iter()
+        .map(|(item, id)| match id.as_integer().map(u64::try_from) {
+            Some(Ok(id)) => Ok((item, id)),
+            _ => Err(format!("invalid ID for {:?}", item)),
+        })
+        .collect()
+}
+
+impl Ids {
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let ids = self
+            .current
+            .iter()
+            .map(|(item, &id)| (item.clone(), toml::Value::Integer(id as i64)))
+            .collect();
+        let data =
+            toml::Table::from_iter([("ids".to_owned(), toml::Value::Table(ids))]).to_string();
+        let temporary = path.with_extension("toml.new");
+        let result =
+            std::fs::write(&temporary, data).and_then(|()| std::fs::rename(&temporary, path));
+        if let Err(e) = result {
+            eprintln!("Cannot save IDs to {}: {}", path.display(), e)
+        }
+    }
+}
+
+/// The ID of `item`, which just registered: the one saved for it, if any,
+/// or else a new one
+pub(crate) fn assign(item: &str) -> u64 {
+    IDS.with(|ids| {
+        let ids = &mut *ids.borrow_mut();
+        let id = ids.previous.remove(item).unwrap_or_else(next_id);
+        ids.current.insert(item.to_owned(), id);
+        ids.save();
+        id
+    })
+}
+
+/// Forget the ID of `item`, which is gone
+pub(crate) fn release(item: &str) {
+    IDS.with(|ids| {
+        let ids = &mut *ids.borrow_mut();
+        if ids.current.remove(item).is_some() {
+            ids.save()
+        }
+    })
+}
+
+/// Forget the IDs of all items, as the bus they registered on is gone
+pub(crate) fn release_all() {
+    IDS.with(|ids| {
+        let ids = &mut *ids.borrow_mut();
+        ids.previous.clear();
+        ids.current.clear();
+        ids.save()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_saved_ids() {
+        let path = std::env::temp_dir().join(format!("sni-agent-ids-{}.toml", std::process::id()));
+        std::fs::write(&path, "[ids]\n\":1.5/StatusNotifierItem\" = 7\n").unwrap();
+        load(path.clone());
+        // New items never get a saved ID
+        assert_eq!(assign(":1.6/StatusNotifierItem"), 8);
+        assert_eq!(assign(":1.5/StatusNotifierItem"), 7);
+        release(":1.6/StatusNotifierItem");
+        let saved = parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            saved,
+            HashMap::from([(":1.5/StatusNotifierItem".to_owned(), 7)])
+        );
+        release_all();
+        let saved = parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(saved.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
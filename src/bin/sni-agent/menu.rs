@@ -0,0 +1,279 @@
+//! Forwarding of dbusmenu menus to the daemon
+//!
+//! Menus are always sent as a whole, starting at the root.  Entry icons are
+//! decoded here, so the daemon never parses image formats or looks at the
+//! VM's icon themes.
+
+use dbus::arg::RefArg;
+use dbus::channel::Token;
+use dbus::nonblock::Proxy;
+use dbus::strings::{BusName, Path};
+use futures_util::StreamExt as _;
+use std::sync::Arc;
+
+use crate::bus::Bus;
+
+use sni_icon::client::menu::Dbusmenu as _;
+use sni_icon::menu::{Shortcut, ToggleState, ToggleType};
+use sni_icon::{names, ClientEvent, IconClientEvent, IconData, MenuItem, SafeText};
+
+/// Largest width or height of an entry icon that is forwarded
+const MAX_ICON_SIZE: u32 = 256;
+/// Largest PNG file that is read when resolving an icon name
+const MAX_ICON_FILE_SIZE: u64 = 1 << 20;
+/// Sizes searched when resolving an icon name, best first
+const ICON_SIZES: &[&str] = &["16x16", "22x22", "24x24", "32x32", "48x48", "64x64"];
+const ICON_CONTEXTS: &[&str] = &[
+    "actions",
+    "apps",
+    "categories",
+    "devices",
+    "emblems",
+    "mimetypes",
+    "places",
+    "status",
+];
+
+/// Whether `path`, the value of an item's `Menu` property, refers to a menu.
+/// Some toolkits use `/NO_DBUSMENU` for items without one.
+pub(crate) fn exists(path: &Path<'_>) -> bool {
+    *path != names::path_root() && *path != names::path_no_dbusmenu()
+}
+
+/// Send the menu at `bus_name` and `path` as that of item `id`, and again
+/// whenever it changes.  Forwarding stops when the returned match is
+/// removed.
+pub(crate) async fn watch<B: Bus>(
+    id: u64,
+    c: Arc<B>,
+    bus_name: BusName<'static>,
+    path: Path<'static>,
+    icon_theme_path: String,
+) -> Result<Token, dbus::Error> {
+    let rule = names::dbusmenu_signals(bus_name.clone(), path.clone());
+    let (sender, mut updates) = futures_channel::mpsc::unbounded();
+    let msg_match = c
+        .add_match(
+            rule,
+            Box::new(move |msg| sender.unbounded_send(msg).is_ok()),
+        )
+        .await?;
+    let token = B::token(&msg_match);
+    tokio::task::spawn_local(async move {
+        // Updates stop when the match is removed, dropping its callback
+        let _msg_match = msg_match;
+        let menu = Proxy::new(bus_name, path, crate::timeouts::call(), c);
+        loop {
+            match menu.get_layout(0, -1, vec![]).await {
+                Ok((_revision, (root, props, children))) => {
+                    let mut root = item(root, &props, &children, &icon_theme_path);
+                    if root.limit() {
+                        eprintln!("Menu of item {} exceeds limits, truncating it", id);
+                    }
+                    let mut event = IconClientEvent::new(id, ClientEvent::Menu(root));
+                    if crate::encoded_size(&event) > crate::limits().max_message_size.into() {
+                        eprintln!("Menu of item {} is too large, removing its icons", id);
+                        if let ClientEvent::Menu(root) = &mut event.event {
+                            remove_icons(root)
+                        }
+                    }
+                    crate::send_or_panic(event)
+                }
+                Err(e) => eprintln!("Cannot get menu layout of item {}: {}", id, e),
+            }
+            if updates.next().await.is_none() {
+                break;
+            }
+            // Coalesce updates that arrived in the meantime
+            while let Ok(Some(_)) = updates.try_next() {}
+        }
+        eprintln!("Stopped forwarding menu of item {}", id);
+    });
+    Ok(token)
+}
+
+fn item(id: i32, props: &dyn RefArg, children: &dyn RefArg, icon_theme_path: &str) -> MenuItem {
+    let mut item = MenuItem {
+        id,
+        separator: false,
+        label: SafeText::default(),
+        enabled: true,
+        visible: true,
+        toggle_type: None,
+        toggle_state: ToggleState::Off,
+        shortcuts: vec![],
+        icon: None,
+        children: vec![],
+    };
+    let mut icon_name = None;
+    let mut icon_data = None;
+    let mut props = props.as_iter().into_iter().flatten();
+    while let (Some(key), Some(value)) = (props.next(), props.next()) {
+        match key.as_str() {
+            Some("type") => item.separator = value.as_str() == Some("separator"),
+            Some("label") => item.label = SafeText::new(value.as_str().unwrap_or_default()),
+            Some("enabled") => item.enabled = value.as_i64() != Some(0),
+            Some("visible") => item.visible = value.as_i64() != Some(0),
+            Some("toggle-type") => {
+                item.toggle_type = value.as_str().and_then(ToggleType::from_dbusmenu)
+            }
+            Some("toggle-state") => {
+                item.toggle_state = ToggleState::from_dbusmenu(value.as_i64().unwrap_or(-1))
+            }
+            Some("shortcut") => item.shortcuts = shortcuts(value),
+            Some("icon-name") => icon_name = value.as_str().map(str::to_owned),
+            Some("icon-data") => icon_data = bytes(value),
+            _ => {}
+        }
+    }
+    item.icon = match (icon_data, icon_name) {
+        (Some(data), _) => decode_png(&data[..]),
+        (None, Some(name)) => resolve_icon(&name, icon_theme_path).and_then(|path| {
+            let file = std::fs::File::open(&path).ok()?;
+            let mut data = vec![];
+            std::io::Read::read_to_end(
+                &mut std::io::Read::take(file, MAX_ICON_FILE_SIZE),
+                &mut data,
+            )
+            .ok()?;
+            decode_png(&data[..])
+        }),
+        (None, None) => None,
+    };
+    item.children = children
+        .as_iter()
+        .into_iter()
+        .flatten()
+        .filter_map(|child| {
+            // Each child is a variant containing an (ia{sv}av) struct
+            let mut fields = child.as_iter()?.next()?.as_iter()?;
+            let id = i32::try_from(fields.next()?.as_i64()?).ok()?;
+            let (props, children) = (fields.next()?, fields.next()?);
+            Some(self::item(id, props, children, icon_theme_path))
+        })
+        .collect();
+    item
+}
+
+fn remove_icons(item: &mut MenuItem) {
+    item.icon = None;
+    item.children.iter_mut().for_each(remove_icons)
+}
+
+/// The contents of a variant containing a byte array
+fn bytes(value: &dyn RefArg) -> Option<Vec<u8>> {
+    value
+        .as_iter()?
+        .next()?
+        .as_iter()?
+        .map(|b| u8::try_from(b.as_u64()?).ok())
+        .collect()
+}
+
+/// The valid shortcuts in a variant containing the `aas` value of the
+/// `shortcut` property
+fn shortcuts(value: &dyn RefArg) -> Vec<Shortcut> {
+    let Some(shortcuts) = value.as_iter().and_then(|mut v| v.next()?.as_iter()) else {
+        return vec![];
+    };
+    shortcuts
+        .filter_map(|shortcut| {
+            let tokens: Option<Vec<&str>> = shortcut.as_iter()?.map(|t| t.as_str()).collect();
+            Shortcut::new(&tokens?)
+        })
+        .collect()
+}
+
+/// Find the PNG file for the icon called `name`.  Only PNG icons in the
+/// `hicolor` theme, `icon_theme_path`, and the pixmaps directory are found.
+fn resolve_icon(name: &str, icon_theme_path: &str) -> Option<std::path::PathBuf> {
+    use std::path::PathBuf;
+    if name.starts_with('/') {
+        return Some(PathBuf::from(name)).filter(|p| p.extension() == Some("png".as_ref()));
+    }
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return None;
+    }
+    let file_name = format!("{}.png", name);
+    let mut themes = vec![];
+    if !icon_theme_path.is_empty() {
+        themes.push(PathBuf::from(icon_theme_path));
+    }
+    if let Some(home) = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share")))
+    {
+        themes.push(home.join("icons"));
+    }
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_owned());
+    themes.extend(data_dirs.split(':').map(|d| PathBuf::from(d).join("icons")));
+    for base in &themes {
+        for size in ICON_SIZES {
+            for context in ICON_CONTEXTS {
+                let path = base
+                    .join("hicolor")
+                    .join(size)
+                    .join(context)
+                    .join(&file_name);
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+        }
+        let path = base.join(&file_name);
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+    Some(PathBuf::from("/usr/share/pixmaps").join(&file_name)).filter(|p| p.is_file())
+}
+
+/// Decode a PNG image into an ARGB32 pixmap, rejecting images larger than
+/// [`MAX_ICON_SIZE`] and animated ones.  Only the pixels are kept: text and
+/// color profile chunks are not even parsed, and the daemon encodes the
+/// pixmap again itself.
+fn decode_png(data: &[u8]) -> Option<IconData> {
+    // Room for the image and the decoder's own buffers
+    let limits = png::Limits {
+        bytes: (MAX_ICON_SIZE * MAX_ICON_SIZE * 4 * 2) as usize,
+    };
+    let mut decoder = png::Decoder::new_with_limits(data, limits);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    decoder.set_ignore_text_chunk(true);
+    decoder.set_ignore_iccp_chunk(true);
+    let mut reader = decoder.read_info().ok()?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    let max_size = MAX_ICON_SIZE.min(crate::limits().max_icon_size);
+    if width == 0 || height == 0 || width > max_size || height > max_size {
+        return None;
+    }
+    if reader.info().animation_control.is_some() {
+        return None;
+    }
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).ok()?;
+    let pixels = &buffer[..frame.buffer_size()];
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    let mut push = |a: u8, r: u8, g: u8, b: u8| data.extend_from_slice(&[a, r, g, b]);
+    match frame.color_type {
+        png::ColorType::Rgba => pixels
+            .chunks_exact(4)
+            .for_each(|p| push(p[3], p[0], p[1], p[2])),
+        png::ColorType::Rgb => pixels
+            .chunks_exact(3)
+            .for_each(|p| push(255, p[0], p[1], p[2])),
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks_exact(2)
+            .for_each(|p| push(p[1], p[0], p[0], p[0])),
+        png::ColorType::Grayscale => pixels.iter().for_each(|&p| push(255, p, p, p)),
+        png::ColorType::Indexed => return None,
+    }
+    Some(IconData {
+        width,
+        height,
+        data,
+    })
+}
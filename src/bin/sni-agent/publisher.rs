@@ -0,0 +1,207 @@
+//! Items published over the socket of [`sni_icon::publisher`], rather than
+//! found on D-Bus
+
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::StreamExt as _;
+use sni_icon::{read_message, write_message, ClientEvent, IconClientEvent, IconServerEvent};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::net::unix::OwnedReadHalf;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{limits, lock, next_id, send_destroy, send_or_panic};
+
+/// Where events for a published item go
+pub(crate) struct Route {
+    /// The ID of the item on the connection of its publisher
+    local_id: u64,
+    events: UnboundedSender<IconServerEvent>,
+}
+
+/// The routes of all published items, by the ID they are forwarded under
+pub(crate) type Routes = Arc<Mutex<HashMap<u64, Route>>>;
+
+/// Pass `event` on to the publisher of its item.  The event is given back if
+/// its item was not published.
+pub(crate) fn route(routes: &Routes, event: IconServerEvent) -> Option<IconServerEvent> {
+    let routes = lock(routes);
+    let Some(route) = routes.get(&event.id) else {
+        return Some(event);
+    };
+    // If the publisher is gone, its items are about to be destroyed
+    let _: Result<_, _> = route.events.unbounded_send(IconServerEvent {
+        id: route.local_id,
+        event: event.event,
+    });
+    None
+}
+
+/// Listen on `path`, replacing a socket left behind by an agent that is gone
+pub(crate) fn bind(path: &Path) -> std::io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(e);
+            }
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// Serve every publisher connecting to `listener`
+pub(crate) async fn listen(listener: UnixListener, routes: Routes) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::task::spawn_local(serve(stream, routes.clone()));
+            }
+            Err(e) => eprintln!("Cannot accept publisher: {}", e),
+        }
+    }
+}
+
+/// Forward the items of the publisher at the other end of `stream`, until
+/// it disconnects or breaks the protocol, and then destroy them
+pub(crate) async fn serve(stream: UnixStream, routes: Routes) {
+    let (mut reader, mut writer) = stream.into_split();
+    let limits = match sni_icon::publisher::handshake(&mut reader, &mut writer, limits()).await {
+        Ok(limits) => limits,
+        Err(e) => return eprintln!("Publisher handshake failed: {}", e),
+    };
+    let (events, mut to_send) = futures_channel::mpsc::unbounded::<IconServerEvent>();
+    tokio::task::spawn_local(async move {
+        while let Some(event) = to_send.next().await {
+            let message = sni_icon::encode(&event).expect("Cannot serialize object?");
+            if write_message(&mut writer, &message).await.is_err() {
+                break;
+            }
+        }
+    });
+    // The IDs the items are forwarded under, by their IDs on the connection
+    let mut ids = HashMap::new();
+    if let Err(e) = forward(
+        &mut reader,
+        limits.max_message_size,
+        &mut ids,
+        &routes,
+        events,
+    )
+    .await
+    {
+        eprintln!("Publisher disconnected: {}", e);
+    }
+    let mut ids: Vec<u64> = ids.into_values().collect();
+    ids.sort_unstable();
+    for id in ids {
+        lock(&routes).remove(&id);
+        send_destroy(id)
+    }
+}
+
+async fn forward(
+    reader: &mut OwnedReadHalf,
+    max_size: u32,
+    ids: &mut HashMap<u64, u64>,
+    routes: &Routes,
+    events: UnboundedSender<IconServerEvent>,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let buffer = read_message(reader, max_size).await?;
+        let IconClientEvent {
+            id: local_id,
+            event,
+            timestamp,
+        } = sni_icon::decode(&buffer)?;
+        let id = match (&event, ids.get(&local_id)) {
+            (ClientEvent::Create { .. }, None) => {
+                let id = next_id();
+                ids.insert(local_id, id);
+                let events = events.clone();
+                lock(routes).insert(id, Route { local_id, events });
+                id
+            }
+            (ClientEvent::Create { .. }, Some(_)) => {
+                return Err(format!("item {} created twice", local_id).into())
+            }
+            (_, Some(&id)) => id,
+            (_, None) => return Err(format!("no item {}", local_id).into()),
+        };
+        if let ClientEvent::Destroy = event {
+            ids.remove(&local_id);
+            lock(routes).remove(&id);
+            send_destroy(id);
+            continue;
+        }
+        // Latency is measured from when the publisher sent the event
+        send_or_panic(IconClientEvent {
+            id,
+            event,
+            timestamp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sni_icon::publisher::Publisher;
+    use sni_icon::ServerEvent;
+
+    #[tokio::test]
+    async fn forwards_published_items() {
+        crate::LIMITS.get_or_init(sni_icon::ProtocolLimits::default);
+        let routes = Routes::default();
+        let (agent, app) = UnixStream::pair().unwrap();
+        tokio::task::LocalSet::new()
+            .run_until(async {
+                let served = tokio::task::spawn_local(serve(agent, routes.clone()));
+                let (mut publisher, mut events) = Publisher::new(app).await.unwrap();
+                let local_id = publisher
+                    .create("org.example.App", "ApplicationStatus", Default::default())
+                    .await
+                    .unwrap();
+                publisher
+                    .set_title(local_id, Some("Title".to_owned()))
+                    .await
+                    .unwrap();
+                // Let the agent catch up
+                let mut sent = vec![];
+                while sent.len() < 2 {
+                    tokio::task::yield_now().await;
+                    sent.extend(crate::tests::sent_messages());
+                }
+                let id = sent[0].id;
+                assert!(matches!(
+                    &sent[0].event,
+                    ClientEvent::Create { app_id, .. } if app_id == "org.example.App"
+                ));
+                assert!(matches!(
+                    &sent[1],
+                    IconClientEvent { id: i, event: ClientEvent::Title(Some(t)), .. }
+                        if *i == id && t == "Title"
+                ));
+                let event = IconServerEvent {
+                    id,
+                    event: ServerEvent::Activate { x: 1, y: 2 },
+                };
+                assert!(route(&routes, event).is_none());
+                let event = events.next().await.unwrap();
+                assert_eq!(event.id, local_id);
+                assert!(matches!(event.event, ServerEvent::Activate { x: 1, y: 2 }));
+
+                drop(publisher);
+                served.await.unwrap();
+                assert!(lock(&routes).is_empty());
+                let sent = crate::tests::sent_messages();
+                assert!(matches!(
+                    &sent[..],
+                    [IconClientEvent { id: i, event: ClientEvent::Destroy, .. }] if *i == id
+                ));
+            })
+            .await;
+    }
+}
@@ -0,0 +1,207 @@
+//! What the agent sends for items with the quirks of common toolkits
+//!
+//! Each fixture in `quirks/` holds the properties of an item as a toolkit
+//! exports them, and the events the agent is expected to send when the item
+//! appears.  Toolkits differ in which properties they leave out, how they
+//! name and register items, and how many frames their icons have, and it is
+//! easy to break one of them while fixing another.  The fixtures are written
+//! by hand, so their bus names, paths and titles are made up.
+
+use dbus::strings::Path;
+use sni_icon::{Capabilities, ClientEvent, Update};
+use toml::{Table, Value};
+
+use crate::bus::MockBus;
+use crate::tests::sent_messages;
+
+const INTERFACE: &str = "org.kde.StatusNotifierItem";
+
+/// The capabilities of an item, by name, as fixtures list them
+const CAPABILITIES: [(&str, Capabilities); 5] = [
+    ("HAS_MENU", Capabilities::HAS_MENU),
+    ("HAS_TOOLTIP", Capabilities::HAS_TOOLTIP),
+    ("SUPPORTS_ACTIVATION", Capabilities::SUPPORTS_ACTIVATION),
+    (
+        "SUPPORTS_SECONDARY_ACTIVATE",
+        Capabilities::SUPPORTS_SECONDARY_ACTIVATE,
+    ),
+    ("IS_MENU", Capabilities::IS_MENU),
+];
+
+/// The frames of a pixmap in a fixture, each a table with its `width` and
+/// `height`, and the `len` of its data if not that of its pixels.  Pixels
+/// are opaque white.
+fn pixmap(frames: &Value) -> Vec<(i32, i32, Vec<u8>)> {
+    let frames = frames.as_array().expect("pixmaps are arrays");
+    frames
+        .iter()
+        .map(|frame| {
+            let field = |name| frame.get(name).and_then(Value::as_integer);
+            let width = field("width").expect("frames have a width");
+            let height = field("height").expect("frames have a height");
+            let len = field("len").unwrap_or(4 * width.max(0) * height.max(0));
+            (width as i32, height as i32, vec![0xff; len as usize])
+        })
+        .collect()
+}
+
+/// Make the properties in `properties` readable on `bus` for the item
+/// registered as `item`.  Properties are strings, unless their type says
+/// otherwise.
+fn export(bus: &MockBus, item: &str, properties: &Table) {
+    let default_path = sni_icon::names::path_status_notifier_item();
+    let (name, path) = match item.find('/') {
+        None => (item, &*default_path),
+        Some(position) => item.split_at(position),
+    };
+    for (property, value) in properties {
+        let string = |value: &Value| value.as_str().unwrap_or_default().to_owned();
+        match &**property {
+            "IconPixmap" | "AttentionIconPixmap" | "OverlayIconPixmap" => {
+                bus.set_property(name, path, INTERFACE, property, pixmap(value))
+            }
+            "ItemIsMenu" => bus.set_property(
+                name,
+                path,
+                INTERFACE,
+                property,
+                value.as_bool().expect("ItemIsMenu is a boolean"),
+            ),
+            "Menu" => bus.set_property(
+                name,
+                path,
+                INTERFACE,
+                property,
+                Path::new(string(value)).expect("menus are object paths"),
+            ),
+            "ToolTip" => {
+                let field = |name| value.get(name).map(string).unwrap_or_default();
+                let icon = value.get("icon").map(pixmap).unwrap_or_default();
+                let tooltip = (
+                    field("icon_name"),
+                    icon,
+                    field("title"),
+                    field("description"),
+                );
+                bus.set_property(name, path, INTERFACE, property, tooltip)
+            }
+            _ => bus.set_property(name, path, INTERFACE, property, string(value)),
+        }
+    }
+}
+
+/// What the daemon learns of an item from `events`, in the form of the
+/// expectations of fixtures
+fn observed(events: impl IntoIterator<Item = ClientEvent>) -> Table {
+    let mut observed = Table::new();
+    let mut icons = Table::new();
+    let mut frames = |typ, count: usize| {
+        icons.insert(format!("{:?}", typ), Value::Integer(count as i64));
+    };
+    for event in events {
+        match event {
+            ClientEvent::Create {
+                category,
+                app_id,
+                capabilities,
+                ..
+            } => {
+                observed.insert("app_id".to_owned(), app_id.into());
+                observed.insert("category".to_owned(), category.into());
+                let capabilities: Vec<Value> = CAPABILITIES
+                    .iter()
+                    .filter(|(_, capability)| capabilities.contains(*capability))
+                    .map(|(name, _)| Value::from(*name))
+                    .collect();
+                observed.insert("capabilities".to_owned(), capabilities.into());
+            }
+            ClientEvent::Update(Update {
+                status,
+                title,
+                tooltip,
+                icons,
+            }) => {
+                if let Some(Some(status)) = status {
+                    observed.insert("status".to_owned(), status.into());
+                }
+                if let Some(Some(title)) = title {
+                    observed.insert("title".to_owned(), title.into());
+                }
+                if let Some(Some(tooltip)) = tooltip {
+                    let mut table = Table::new();
+                    table.insert("title".to_owned(), tooltip.title.as_str().into());
+                    let description = tooltip.description.as_str();
+                    table.insert("description".to_owned(), description.into());
+                    let count = tooltip.icon_data.len() as i64;
+                    table.insert("frames".to_owned(), count.into());
+                    observed.insert("tooltip".to_owned(), table.into());
+                }
+                for (typ, data) in icons {
+                    frames(typ, data.len())
+                }
+            }
+            ClientEvent::Icon { typ, data } => frames(typ, data.len()),
+            ClientEvent::AccessibleDesc {
+                typ,
+                desc: Some(desc),
+            } => {
+                let key = format!("{:?}_accessible_desc", typ).to_lowercase();
+                observed.insert(key, desc.as_str().into());
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    if !icons.is_empty() {
+        observed.insert("icons".to_owned(), icons.into());
+    }
+    observed
+}
+
+/// Check that the agent sends what `fixture` expects: a table with the
+/// `item` the application registers, its `properties` and the `expected`
+/// result of [`observed`]
+async fn check(fixture: &str) {
+    let fixture: Table = fixture.parse().expect("fixtures are valid");
+    let table = |name| fixture[name].as_table().expect("fixtures have tables");
+    let item = fixture["item"].as_str().expect("fixtures name their item");
+    crate::LIMITS.get_or_init(sni_icon::ProtocolLimits::default);
+    let bus = MockBus::new();
+    export(&bus, item, table("properties"));
+    tokio::task::LocalSet::new()
+        .run_until(crate::go(
+            item.to_owned(),
+            bus,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        ))
+        .await
+        .unwrap();
+    let events = sent_messages().into_iter().map(|message| message.event);
+    assert_eq!(&observed(events), table("expected"));
+}
+
+#[tokio::test]
+async fn ayatana() {
+    check(include_str!("quirks/ayatana.toml")).await
+}
+
+#[tokio::test]
+async fn qt() {
+    check(include_str!("quirks/qt.toml")).await
+}
+
+#[tokio::test]
+async fn qt_tooltip_markup() {
+    check(include_str!("quirks/qt-tooltip-markup.toml")).await
+}
+
+#[tokio::test]
+async fn ayatana_theme_path() {
+    check(include_str!("quirks/ayatana-theme-path.toml")).await
+}
+
+#[tokio::test]
+async fn chromium() {
+    check(include_str!("quirks/chromium.toml")).await
+}
@@ -0,0 +1,94 @@
+//! How long the agent waits for items to answer
+//!
+//! Calls to items time out after [`DEFAULT`], or as set with `--timeout`.
+//! Reading the properties of a new item waits [`INITIAL_FACTOR`] times as
+//! long, as slow applications, such as Java ones, take longest right after
+//! creating their item.  A property read that times out is retried once,
+//! after [`BACKOFF`], before the property is given up on.
+
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// How long calls wait for an answer, if not set
+const DEFAULT: Duration = Duration::from_millis(1000);
+/// How many times as long the properties of a new item are waited for
+const INITIAL_FACTOR: u32 = 3;
+/// How long to wait before retrying a call that timed out
+const BACKOFF: Duration = Duration::from_millis(250);
+
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Make calls wait for `timeout`.  Only the first call has an effect.
+pub(crate) fn set(timeout: Duration) {
+    let _: Result<_, _> = TIMEOUT.set(timeout);
+}
+
+/// How long calls to items wait for an answer
+pub(crate) fn call() -> Duration {
+    TIMEOUT.get().copied().unwrap_or(DEFAULT)
+}
+
+/// How long reading the properties of a new item waits for an answer
+pub(crate) fn initial() -> Duration {
+    call() * INITIAL_FACTOR
+}
+
+/// Whether `e` means that no answer came in time
+pub(crate) fn is_timeout(e: &dbus::Error) -> bool {
+    matches!(
+        e.name(),
+        Some("org.freedesktop.DBus.Error.Timeout" | "org.freedesktop.DBus.Error.NoReply")
+    )
+}
+
+/// Call `f`, and call it again after [`BACKOFF`] if it timed out
+pub(crate) async fn retry<T, F, Fut>(mut f: F) -> Result<T, dbus::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, dbus::Error>>,
+{
+    match f().await {
+        Err(e) if is_timeout(&e) => {
+            eprintln!("Call timed out, retrying: {}", e);
+            tokio::time::sleep(BACKOFF).await;
+            f().await
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn retry_retries_timeouts_once() {
+        let calls = Cell::new(0);
+        let result: Result<(), _> = retry(|| {
+            calls.set(calls.get() + 1);
+            async {
+                Err(dbus::Error::new_custom(
+                    "org.freedesktop.DBus.Error.Timeout",
+                    "Timeout waiting for reply",
+                ))
+            }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_keeps_other_errors() {
+        let calls = Cell::new(0);
+        let result: Result<(), _> = retry(|| {
+            calls.set(calls.get() + 1);
+            async { Err(dbus::Error::new_failed("no such property")) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
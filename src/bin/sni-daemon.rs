@@ -1,43 +1,256 @@
+#[path = "sni-daemon/logging.rs"]
+#[macro_use]
+mod logging;
+
+#[path = "sni-daemon/activation.rs"]
+mod activation;
+#[path = "sni-daemon/activity.rs"]
+mod activity;
+#[path = "sni-daemon/chunks.rs"]
+mod chunks;
+#[path = "sni-daemon/config.rs"]
+mod config;
+#[path = "sni-daemon/confirm.rs"]
+mod confirm;
+#[path = "sni-daemon/connection.rs"]
+mod connection;
+#[path = "sni-daemon/context.rs"]
+mod context;
+#[path = "sni-daemon/control.rs"]
+mod control;
+#[path = "sni-daemon/failures.rs"]
+mod failures;
+#[path = "sni-daemon/hosts.rs"]
+mod hosts;
+#[path = "sni-daemon/icon_cache.rs"]
+mod icon_cache;
+#[path = "sni-daemon/ids.rs"]
+mod ids;
 #[path = "sni-daemon/item.rs"]
 mod item;
+#[path = "sni-daemon/label.rs"]
+mod label;
+#[path = "sni-daemon/linger.rs"]
+mod linger;
+#[path = "sni-daemon/menu.rs"]
+mod menu;
+#[path = "sni-daemon/order.rs"]
+mod order;
+#[path = "sni-daemon/output.rs"]
+mod output;
+#[path = "sni-daemon/panic.rs"]
+mod panic;
+#[path = "sni-daemon/peer.rs"]
+mod peer;
+#[path = "sni-daemon/policy.rs"]
+mod policy;
+#[path = "sni-daemon/queue.rs"]
+mod queue;
+#[path = "sni-daemon/redact.rs"]
+mod redact;
+#[path = "sni-daemon/registry.rs"]
+mod registry;
+#[cfg(feature = "sandbox")]
+#[path = "sni-daemon/sandbox.rs"]
+mod sandbox;
+#[path = "sni-daemon/scroll.rs"]
+mod scroll;
+#[path = "sni-daemon/state.rs"]
+mod state;
+#[path = "sni-daemon/stats.rs"]
+mod stats;
+#[path = "sni-daemon/status.rs"]
+mod status;
+#[path = "sni-daemon/throttle.rs"]
+mod throttle;
+#[path = "sni-daemon/tooltip.rs"]
+mod tooltip;
 
+use dbus::channel::MatchingReceiver as _;
 use dbus::nonblock::Proxy;
 
 use dbus_crossroads::Crossroads;
-use dbus_tokio::connection;
-use item::{NotifierIcon, NotifierIconWrapper};
-use std::collections::HashMap;
+use item::{Items, NotifierIcon};
+use redact::Redacted;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::io::Write as _;
 use std::time::Duration;
-use tokio::io::AsyncReadExt;
 
-use sni_icon::{names, server, ClientEvent, IconType};
+use sni_icon::error::ProtocolError;
+use sni_icon::{
+    encoding, read_message, read_message_tagged, Hello, Integrity, MIN_MESSAGE_SIZE,
+    PROTOCOL_VERSION,
+};
+use sni_icon::{names, Capabilities, ClientEvent, IconType};
 use std::sync::{Arc, Mutex};
 
 use bincode::Options as _;
 use sha2::{Digest as _, Sha256};
 
-thread_local! {
-    static WRAPPER: Arc<Mutex<HashMap<u64, NotifierIcon>>> = Arc::new(Mutex::new(<HashMap<u64, NotifierIcon>>::new()));
-    static ID: std::cell::Cell<u64> = std::cell::Cell::new(0);
+/// Destroy all existing items that `filter` does not permit, adding them to
+/// `suppressed`, the IDs of items rejected by the filter.  Events for these
+/// are ignored.
+fn apply_filter(filter: &config::Filter, items: &Items, suppressed: &Mutex<HashSet<u64>>) {
+    let mut items = items.lock().unwrap();
+    let denied: Vec<u64> = items
+        .iter()
+        .filter(|(_, ni)| !filter.permits(ni.vm_app_id()))
+        .map(|(id, _)| *id)
+        .collect();
+    for id in denied {
+        log!(
+            Info,
+            id = id,
+            "Item {} no longer permitted by filter, removing it",
+            id
+        );
+        items.remove(&id);
+        suppressed.lock().unwrap().insert(id);
+    }
 }
 
-async fn client_server() -> Result<(), Box<dyn Error>> {
-    let items = WRAPPER.with(|w| w.clone());
-    let mut last_index = 0u64;
-    let (resource, c) = connection::new_session_sync().unwrap();
-    tokio::task::spawn_local(async { panic!("D-Bus connection lost: {}", resource.await) });
-    let cr_only_sni = Arc::new(Mutex::new(Crossroads::new()));
-    {
-        let iface_token_1 = server::item::register_status_notifier_item::<NotifierIconWrapper>(
-            &mut cr_only_sni.lock().unwrap(),
-        );
-        let bus_name = names::path_status_notifier_item();
-        cr_only_sni
-            .lock()
-            .unwrap()
-            .insert(bus_name.clone(), &[iface_token_1], NotifierIconWrapper);
+/// Check `frames` as [`sni_icon::check_frames`] does, and that their data
+/// adds up to no more than [`config::Limits::max_icon_bytes`]
+fn check_icon(frames: &[sni_icon::IconData], limits: &config::Limits) -> Result<(), String> {
+    sni_icon::check_frames(frames, &limits.protocol()).map_err(|e| e.to_string())?;
+    let size: usize = frames.iter().map(|frame| frame.data.len()).sum();
+    if size > limits.max_icon_bytes {
+        return Err(format!(
+            "{} bytes of frames exceed the limit of {}",
+            size, limits.max_icon_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Draw the border onto `data`, the frames of icon `typ` of `ni`, and show
+/// them
+fn set_icon(
+    ni: &mut NotifierIcon,
+    typ: IconType,
+    mut data: Vec<sni_icon::IconData>,
+    settings: &config::ItemSettings,
+) {
+    sni_icon::image::border(
+        &mut data,
+        &settings.border,
+        settings.label_color,
+        settings.vm.as_deref(),
+    );
+    match typ {
+        IconType::Normal => ni.set_icon(Some(data)),
+        IconType::Attention => ni.set_attention_icon(Some(data)),
+        IconType::Overlay => ni.set_overlay_icon(Some(data)),
+        _ => panic!("guest sent bad icon type"),
+    }
+}
+
+/// Exchange [`Hello`]s with the agent, asking for `integrity`, and return its
+/// Hello, with the version both speak, see [`Hello::decode`]
+async fn handshake(
+    stdin: &mut tokio::io::Stdin,
+    output: &output::Output,
+    limits: &config::Limits,
+    integrity: Integrity,
+) -> Result<Hello, Box<dyn Error>> {
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        limits: limits.protocol(),
+        integrity,
+    };
+    output.write(encoding().serialize(&hello)?);
+    let buffer = read_message(stdin, MIN_MESSAGE_SIZE).await?;
+    Ok(Hello::decode(&buffer)?)
+}
+
+async fn client_server(integrity: Integrity) -> Result<(), Box<dyn Error>> {
+    sni_icon::check::require_string_validation()?;
+    let context = context::Context::default();
+    let items = context.items.clone();
+    let suppressed = Arc::new(Mutex::new(HashSet::new()));
+    let mut ids = ids::Ids::default();
+    let config = config::load()?;
+    redact::set_raw_strings(config.log.raw_strings);
+    logging::set_max_level(config.log.level);
+    let vm = std::env::var("QREXEC_REMOTE_DOMAIN").ok();
+    if let Some(vm) = &vm {
+        logging::set_vm(vm);
+    }
+    logging::cycle_on_sigusr2()?;
+    let transport = sni_icon::transport::Kind::of_stdin();
+    connection::starting(transport);
+    let mut limits = config.limits_for(vm.as_deref());
+    if config.policy.enabled {
+        let vm = vm
+            .as_deref()
+            .ok_or("qrexec policy enabled, but not started by qrexec")?;
+        if !policy::apply(&config.policy, vm, &mut limits).await? {
+            return Err(
+                format!("qrexec policy does not permit VM {:?} to export icons", vm).into(),
+            );
+        }
     }
+    log!(Info, "Limits for VM {:?}: {:?}", vm, limits);
+    let mut throttle = throttle::Throttle::new(limits.max_updates_per_second);
+    let label = match &vm {
+        Some(vm) if config.labels.enabled => match label::color(&config.labels, vm).await {
+            Ok(color) => Some(color),
+            Err(e) => {
+                log!(Warning, "Cannot look up the label of VM {:?}: {}", vm, e);
+                None
+            }
+        },
+        _ => None,
+    };
+    let settings = Arc::new(config.item_settings(vm.as_deref(), &limits, label));
+    let hosts_only = config.access.hosts_only;
+    let sandboxed = config.sandbox.enabled;
+    let store = state::Store::new(&config.state, vm.as_deref());
+    let mut order = order::Order::new(&config.order, vm.as_deref());
+    let mut icon_cache = icon_cache::IconCache::new(&config.icon_cache, vm.as_deref());
+    let mut failures = failures::Failures::default();
+    let config = Arc::new(Mutex::new(config));
+    let (resource, c) = sni_icon::session::connect().unwrap();
+    tokio::spawn(async { panic!("D-Bus connection lost: {}", resource.await) });
+    // never unregistered, as this connection lives as long as the process
+    panic::register(&c);
+    let _hosts_matches = context.hosts.track(&c, hosts_only).await?;
+    let agreed = Arc::new(Mutex::new(None));
+    let (control_token, name) = {
+        let mut cr = Crossroads::new();
+        let iface_token = control::register_control(&mut cr);
+        cr.insert(
+            names::path_sni_icon_control(),
+            &[iface_token],
+            control::Control {
+                config: config.clone(),
+                items: items.clone(),
+                suppressed: suppressed.clone(),
+                connection: agreed.clone(),
+            },
+        );
+        let token = c.start_receive(
+            dbus::message::MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                if let Err(e) = sni_icon::session::dispatch(&mut cr, msg, conn) {
+                    log!(Warning, "Control interface: {}", e);
+                }
+                true
+            }),
+        );
+        match names::name_sni_icon_daemon(vm.as_deref()) {
+            Ok(name) => {
+                c.request_name(name.clone(), false, true, true).await?;
+                (token, Some(name))
+            }
+            Err(e) => {
+                log!(Warning, "Not requesting a bus name for VM {:?}: {}", vm, e);
+                (token, None)
+            }
+        }
+    };
+    let _registry = registry::Registry::new(items.clone(), c.clone(), control_token, name);
 
     let watcher = Proxy::new(
         names::name_status_notifier_watcher(),
@@ -46,60 +259,209 @@ async fn client_server() -> Result<(), Box<dyn Error>> {
         c.clone(),
     );
 
-    dbus::strings::Interface::new("bogus").expect_err("no-string-validation must be off!");
+    // Reading stdin starts a thread, which Landlock would not restrict
+    if sandboxed {
+        #[cfg(feature = "sandbox")]
+        sandbox::apply(
+            &config::path(),
+            &[store.directory(), order.directory(), icon_cache.directory()]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+        )?;
+        #[cfg(not(feature = "sandbox"))]
+        return Err("sandbox enabled, but sni-daemon was built without the sandbox feature".into());
+    }
     let mut stdin = tokio::io::stdin();
-    loop {
-        let size = stdin.read_u32_le().await.expect("error reading from stdin");
-        eprintln!("Got something on stdin: length {}!", size);
-        if size > 0x80_000_000 {
-            panic!("Excessive message size {}", size);
+    let peer = handshake(&mut stdin, &context.output, &limits, integrity).await?;
+    limits.negotiate(peer.limits);
+    let integrity = integrity.negotiate(peer.integrity);
+    let connection = connection::Connection {
+        transport,
+        version: peer.version,
+        peer_limits: peer.limits,
+        limits: limits.protocol(),
+        peer_integrity: peer.integrity,
+        integrity,
+    };
+    connection::established(&connection);
+    *agreed.lock().unwrap() = Some(connection);
+    context.output.set_integrity(integrity);
+    context.hosts.announce();
+    if icon_cache.enabled() {
+        context.output.send(sni_icon::IconServerEvent {
+            id: 0,
+            event: sni_icon::ServerEvent::CachedIcons {
+                hashes: icon_cache.hashes(),
+            },
+        });
+    }
+    for saved in store.load() {
+        if items.lock().unwrap().len() >= limits.max_icons
+            || !config.lock().unwrap().filter.permits(&saved.vm_app_id)
+        {
+            continue;
         }
-        let mut buffer = vec![0; size as _];
-        let bytes_read = stdin
-            .read_exact(&mut buffer[..])
-            .await
-            .expect("error reading from stdin");
-        assert_eq!(bytes_read, buffer.len());
-        eprintln!("{} bytes read!", bytes_read);
-        let options = bincode::DefaultOptions::new()
-            .with_fixint_encoding()
-            .with_native_endian()
-            .reject_trailing_bytes();
-        let item = options.deserialize(&buffer[..])?;
-        drop(buffer);
-        match &item {
-            sni_icon::IconClientEvent {
-                id,
-                event: ClientEvent::Icon { .. },
-            } => {
-                eprintln!("->client Create {}", id);
-            }
-            _ => {
-                eprintln!("->client {:?}", item);
-            }
+        let id = saved.id;
+        log!(Info, id = id, "Restoring item {}", Redacted(&saved.app_id));
+        let capabilities = item_capabilities(&limits, saved.capabilities);
+        let mut notifier = NotifierIcon::new(
+            id,
+            saved.app_id.clone(),
+            saved.vm_app_id.clone(),
+            saved.category.clone(),
+            &context,
+            capabilities,
+            settings.clone(),
+        );
+        notifier.set_ordering_index(order.index(&saved.vm_app_id));
+        notifier.restore(saved);
+        register(notifier, &items, &watcher).await?;
+        context.output.send(sni_icon::IconServerEvent {
+            id,
+            event: sni_icon::ServerEvent::Resync,
+        });
+    }
+    store.save(&items.lock().unwrap());
+    tokio::spawn(context.stats.clone().run());
+    tokio::spawn({
+        let (items, store) = (items.clone(), store.clone());
+        async move {
+            tokio::time::sleep(state::RESTORE_TIMEOUT).await;
+            let mut items = items.lock().unwrap();
+            items.retain(|id, item| {
+                if item.is_restored() {
+                    log!(
+                        Info,
+                        id = *id,
+                        "Removing restored item {} not taken over",
+                        id
+                    );
+                }
+                !item.is_restored()
+            });
+            update_counts(&mut items);
+            store.save(&items);
+        }
+    });
+    let queue = Arc::new(queue::Queue::new(limits.max_queued_events));
+    let reader = tokio::spawn({
+        let (queue, stats) = (queue.clone(), context.stats.clone());
+        let max_message_size = limits.max_message_size;
+        async move {
+            let result = read_events(stdin, &queue, &stats, max_message_size, integrity).await;
+            queue.close();
+            result
+        }
+    });
+    loop {
+        let Some(queued) = queue.pop().await else {
+            reader.await.expect("panics abort the daemon")?;
+            // Returning drops the registry, destroying all of the VM's icons
+            // at once.
+            log!(Info, "Agent disconnected, removing all icons");
+            store.clear();
+            return Ok(());
         };
+        let (item, queued_size) = (queued.event, queued.size);
+        if !matches!(item.event, ClientEvent::Destroy) {
+            throttle.wait().await;
+        }
+        log!(
+            Debug,
+            id = item.id,
+            event = redact::event_name(&item.event),
+            "->client {}",
+            redact::RedactedEvent(&item)
+        );
+        if let ClientEvent::Unknown = item.event {
+            log!(Debug, id = item.id, "Ignoring an event of a later version");
+            continue;
+        }
         if let ClientEvent::Create {
             category,
             app_id,
-            is_menu,
+            capabilities,
+            ..
         } = &item.event
         {
             const PREFIX: &str = "org.qubes_os.vm.app_id.";
+            let vm_app_id = app_id.clone();
             let app_id = PREFIX.to_owned() + app_id;
-            if item.id <= last_index {
-                panic!("Item ID not monotonically increasing");
+            if !ids.is_new(item.id) {
+                panic!("Item ID reused");
             }
             if category.is_empty() {
-                eprintln!("Empty category for ID {:?}!", app_id);
+                log!(
+                    Warning,
+                    id = item.id,
+                    "Empty category for ID {}!",
+                    Redacted(&app_id)
+                );
+                continue;
+            }
+            ids.created(item.id);
+            let capabilities = item_capabilities(&limits, *capabilities);
+            // The placeholder of the ID is replaced, and only taken over by an
+            // item of the same app
+            let restored = {
+                let mut items = items.lock().unwrap();
+                match items.get(&item.id) {
+                    Some(ni) if ni.is_restored() => items.remove(&item.id),
+                    _ => None,
+                }
+            };
+            let replaced = restored.is_some();
+            let placeholder = restored.filter(|ni| ni.vm_app_id() == vm_app_id);
+            // Nor is an item of the same app that just went away
+            let lingering = if placeholder.is_none() {
+                let mut items = items.lock().unwrap();
+                linger::matching(&items, &vm_app_id).and_then(|id| items.remove(&id))
+            } else {
+                None
+            };
+            // The items replaced are gone even if the item is rejected
+            let rejected = || {
+                suppressed.lock().unwrap().insert(item.id);
+                if replaced || lingering.is_some() {
+                    let mut items = items.lock().unwrap();
+                    update_counts(&mut items);
+                    store.save(&items);
+                }
+            };
+            if !config.lock().unwrap().filter.permits(&vm_app_id) {
+                log!(
+                    Info,
+                    id = item.id,
+                    "Item {} with app id {} denied by filter",
+                    item.id,
+                    Redacted(&app_id)
+                );
+                rejected();
+                continue;
+            }
+            if items.lock().unwrap().len() >= limits.max_icons {
+                log!(
+                    Warning,
+                    id = item.id,
+                    "Item {} exceeds the limit of {} icons",
+                    item.id,
+                    limits.max_icons
+                );
+                rejected();
                 continue;
             }
-            last_index = item.id;
             // FIXME: sanitize the ID
             // FIXME: this is C code (libdbus) and can be disabled (wtf???)
             let app_id = match dbus::strings::Interface::new(&app_id) {
                 Ok(_) => app_id,
                 _ => {
-                    eprintln!("Name {:?} is invalid", app_id);
+                    log!(
+                        Warning,
+                        id = item.id,
+                        "Name {} is invalid",
+                        Redacted(&app_id)
+                    );
                     let mut h = Sha256::new();
                     h.update(app_id.as_bytes());
                     let result = h.finalize();
@@ -139,108 +501,480 @@ async fn client_server() -> Result<(), Box<dyn Error>> {
                 }
             };
 
-            eprintln!(
-                "Registering new item {}, app id is {:?}, is_menu {}",
+            log!(
+                Info,
+                id = item.id,
+                event = "Create",
+                "Registering new item {}, app id is {}, capabilities {:#x}",
                 &c.unique_name(),
+                Redacted(&app_id),
+                capabilities.0
+            );
+            if let Some(mut notifier) = placeholder {
+                log!(Info, id = item.id, "Taking over restored item");
+                notifier.take_over(category.clone(), capabilities);
+                let mut items = items.lock().unwrap();
+                items.insert(item.id, notifier);
+                update_counts(&mut items);
+                store.save(&items);
+                continue;
+            }
+            if let Some(mut notifier) = lingering {
+                log!(
+                    Info,
+                    id = item.id,
+                    "Reattaching item {} that went away",
+                    notifier.id()
+                );
+                notifier.reattach(item.id, category.clone(), capabilities);
+                let mut items = items.lock().unwrap();
+                items.insert(item.id, notifier);
+                update_counts(&mut items);
+                store.save(&items);
+                continue;
+            }
+            let mut notifier = NotifierIcon::new(
+                item.id,
                 app_id,
-                is_menu
+                vm_app_id,
+                category.clone(),
+                &context,
+                capabilities,
+                settings.clone(),
             );
-            let cr_ = cr_only_sni.clone();
-            let notifier =
-                NotifierIcon::new(item.id, app_id, category.clone(), cr_.clone(), *is_menu);
-            let path = notifier.bus_path();
-
-            items.lock().unwrap().insert(item.id, notifier);
-            watcher
-                .method_call(
-                    names::interface_status_notifier_watcher(),
-                    names::register_status_notifier_item(),
-                    (path.to_string(),),
-                )
-                .await
-                .expect("Could not register status notifier item")
+            notifier.set_ordering_index(order.index(notifier.vm_app_id()));
+            register(notifier, &items, &watcher).await?;
+            store.save(&items.lock().unwrap());
+        } else if suppressed.lock().unwrap().contains(&item.id) {
+            if let ClientEvent::Destroy = item.event {
+                suppressed.lock().unwrap().remove(&item.id);
+                acknowledge_destroy(&context.output, item.id);
+            } else {
+                context.stats.dropped();
+            }
         } else {
-            let mut outer_ni = items.lock().unwrap();
-            let ni = outer_ni.get_mut(&item.id).unwrap();
-            match item.event {
-                ClientEvent::Create { .. } => unreachable!(),
-                ClientEvent::Title(title) => {
-                    ni.set_title(title);
+            // Changes of the item queued together are applied together
+            let mut queued = vec![(item.event, queued.replaced)];
+            let mut timestamps = vec![item.timestamp];
+            let mut size = queued_size;
+            if queue::changes_properties(&queued[0].0) {
+                while let Some(next) = queue.pop_related(item.id) {
+                    throttle.wait().await;
+                    log!(
+                        Debug,
+                        id = item.id,
+                        event = redact::event_name(&next.event.event),
+                        "->client {}",
+                        redact::RedactedEvent(&next.event)
+                    );
+                    timestamps.push(next.event.timestamp);
+                    size += next.size;
+                    queued.push((next.event.event, next.replaced));
                 }
-                ClientEvent::Status(status) => {
-                    ni.set_status(status);
+            }
+            let handled = queued.len();
+            let mut events = vec![];
+            for (event, replaced) in queued {
+                match event {
+                    ClientEvent::Update(update) => {
+                        events.extend(update.into_events().into_iter().map(|event| (event, false)))
+                    }
+                    event => events.push((event, replaced)),
                 }
-                ClientEvent::Icon { typ, mut data } => {
-                    for item in &mut data {
-                        let mut set_pixel = |x: u32, y: u32| {
-                            let base = ((y * item.width + x) * 4) as usize;
-                            item.data[base] = 255;
-                            item.data[base + 1] = 255;
-                            item.data[base + 2] = 0;
-                            item.data[base + 3] = 0;
-                        };
-
-                        for x in 0..2 {
-                            for y in 0..item.height {
-                                set_pixel(x, y);
-                                set_pixel(item.width - 1 - x, y);
-                            }
+            }
+            let (grace, notify_failures) = {
+                let config = config.lock().unwrap();
+                (config.reattach.grace(), config.notifications.failed_calls)
+            };
+            let mut outer_ni = items.lock().unwrap();
+            let menus = outer_ni.values().filter(|ni| ni.menu().is_some()).count();
+            // Hosts see the item once all of the changes are applied
+            outer_ni.get_mut(&item.id).unwrap().begin_update();
+            for (event, replaced) in events {
+                let Some(ni) = outer_ni.get_mut(&item.id) else {
+                    // Destroyed
+                    break;
+                };
+                match event {
+                    ClientEvent::Create { .. } => unreachable!(),
+                    ClientEvent::Title(title) => {
+                        ni.set_title(title);
+                        store.save(&outer_ni);
+                    }
+                    ClientEvent::Status(status) => {
+                        if let Some(delay) = ni.offer_status(status) {
+                            let (id, items, store) = (item.id, items.clone(), store.clone());
+                            tokio::spawn(async move {
+                                let mut delay = Some(delay);
+                                while let Some(wait) = delay {
+                                    tokio::time::sleep(wait).await;
+                                    let mut items = items.lock().unwrap();
+                                    let Some(ni) = items.get_mut(&id) else {
+                                        return;
+                                    };
+                                    delay = ni.show_held_status();
+                                    store.save(&items);
+                                }
+                            });
                         }
-
-                        for y in 0..2 {
-                            for x in 0..item.width {
-                                set_pixel(x, y);
-                                set_pixel(x, item.height - 1 - y);
-                            }
+                        store.save(&outer_ni);
+                    }
+                    ClientEvent::IconChunk {
+                        typ,
+                        width,
+                        height,
+                        offset,
+                        data,
+                    } => {
+                        let chunk = chunks::Chunk {
+                            width,
+                            height,
+                            offset,
+                            data,
+                        };
+                        if let Err(e) = ni.chunks().add(typ, chunk, &limits) {
+                            reject(
+                                &context,
+                                ni,
+                                format!("chunked {:?} icon frames: {}", typ, e),
+                            );
                         }
                     }
-                    match typ {
-                        IconType::Normal => {
-                            ni.set_icon(Some(data));
+                    ClientEvent::Icon { typ, mut data } => {
+                        if replaced {
+                            ni.chunks().clear(typ);
                         }
-                        IconType::Attention => {
-                            ni.set_attention_icon(Some(data));
+                        data.extend(ni.chunks().take(typ));
+                        if let Err(e) = check_icon(&data, &limits) {
+                            reject(&context, ni, format!("{:?} icon: {}", typ, e));
+                            continue;
                         }
-                        IconType::Overlay => {
-                            ni.set_overlay_icon(Some(data));
+                        icon_cache.insert(&data);
+                        set_icon(ni, typ, data, &settings);
+                    }
+                    ClientEvent::CachedIcon { typ, hash } => match icon_cache.get(&hash, &limits) {
+                        Some(data) => set_icon(ni, typ, data, &settings),
+                        None => context.output.send(sni_icon::IconServerEvent {
+                            id: item.id,
+                            event: sni_icon::ServerEvent::CacheMiss { typ, hash },
+                        }),
+                    },
+                    ClientEvent::RemoveIcon(typ) => {
+                        ni.chunks().clear(typ);
+                        match typ {
+                            IconType::Normal => ni.set_icon(None),
+                            IconType::Attention => ni.set_attention_icon(None),
+                            IconType::Overlay => ni.set_overlay_icon(None),
+                            _ => panic!("guest sent bad icon type"),
                         }
-                        IconType::Title | IconType::Status => panic!("guest sent bad icon type"),
                     }
-                }
-                ClientEvent::RemoveIcon(typ) => match typ {
-                    IconType::Normal => ni.set_icon(None),
-                    IconType::Attention => ni.set_attention_icon(None),
-                    IconType::Overlay => ni.set_overlay_icon(None),
-                    IconType::Title | IconType::Status => panic!("guest sent bad icon type"),
-                },
-                ClientEvent::Tooltip {
-                    icon_data,
-                    title,
-                    description,
-                } => {
-                    ni.set_tooltip(Some(sni_icon::Tooltip {
+                    ClientEvent::Tooltip {
+                        icon_data,
                         title,
                         description,
-                        icon_data,
-                    }));
-                }
-                ClientEvent::RemoveTooltip => {
-                    ni.set_tooltip(None);
-                }
-                ClientEvent::Destroy => {
-                    eprintln!("Releasing ID {}", item.id);
-                    outer_ni.remove(&item.id).expect("Removed nonexistent ID?");
+                    } => {
+                        if !limits.allow_tooltips {
+                            context.stats.dropped();
+                            continue;
+                        }
+                        if let Err(e) = check_icon(&icon_data, &limits) {
+                            reject(&context, ni, format!("tooltip icon: {}", e));
+                            continue;
+                        }
+                        ni.set_tooltip(Some(sni_icon::Tooltip {
+                            title,
+                            description,
+                            icon_data,
+                        }));
+                    }
+                    ClientEvent::RemoveTooltip => {
+                        ni.set_tooltip(None);
+                    }
+                    ClientEvent::AccessibleDesc { typ, desc } => {
+                        ni.set_accessible_desc(typ, desc);
+                    }
+                    ClientEvent::Responsive(responsive) => {
+                        ni.set_responsive(responsive);
+                    }
+                    ClientEvent::CallFailed { call, timed_out } => {
+                        log!(
+                            Info,
+                            id = item.id,
+                            event = "CallFailed",
+                            "Item {} failed {:?}{}",
+                            item.id,
+                            call,
+                            if timed_out { " (timed out)" } else { "" }
+                        );
+                        context.stats.call_failed(timed_out);
+                        failures.report(&c, vm.as_deref(), ni, call, timed_out, notify_failures);
+                    }
+                    ClientEvent::Menu(root) => {
+                        if !ni.capabilities().contains(Capabilities::HAS_MENU) {
+                            context.stats.dropped();
+                            continue;
+                        }
+                        if ni.menu().is_none() && menus >= limits.max_menus as usize {
+                            context.stats.dropped();
+                            log!(
+                                Warning,
+                                id = item.id,
+                                "Item {} exceeds the limit of {} menus",
+                                item.id,
+                                limits.max_menus
+                            );
+                            continue;
+                        }
+                        ni.set_menu(Some(menu::Menu::new(root, limits.max_icon_size)));
+                    }
+                    ClientEvent::RemoveMenu => {
+                        ni.set_menu(None);
+                    }
+                    ClientEvent::Reply { request, body } => {
+                        ni.reply(request, body);
+                    }
+                    ClientEvent::Destroy if !grace.is_zero() && !ni.is_restored() => {
+                        log!(
+                            Info,
+                            id = item.id,
+                            event = "Destroy",
+                            "Keeping item {} for {:?} in case it comes back",
+                            item.id,
+                            grace
+                        );
+                        ni.linger();
+                        store.save(&outer_ni);
+                        linger::expire(item.id, grace, items.clone(), store.clone());
+                        acknowledge_destroy(&context.output, item.id);
+                    }
+                    ClientEvent::Destroy => {
+                        log!(
+                            Info,
+                            id = item.id,
+                            event = "Destroy",
+                            "Releasing ID {}",
+                            item.id
+                        );
+                        outer_ni.remove(&item.id).expect("Removed nonexistent ID?");
+                        update_counts(&mut outer_ni);
+                        store.save(&outer_ni);
+                        acknowledge_destroy(&context.output, item.id);
+                    }
+                    // Unknown events are skipped above
+                    _ => unreachable!(),
                 }
             }
+            if let Some(ni) = outer_ni.get_mut(&item.id) {
+                ni.activity().handled(handled, size);
+                ni.end_update();
+            }
+            for timestamp in timestamps {
+                context.stats.handled(timestamp);
+            }
         }
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let local_set = tokio::task::LocalSet::new();
+/// Read events from the agent into `queue` until it disconnects
+async fn read_events(
+    mut stdin: tokio::io::Stdin,
+    queue: &queue::Queue,
+    stats: &stats::Stats,
+    max_message_size: u32,
+    integrity: Integrity,
+) -> Result<(), ProtocolError> {
+    loop {
+        let buffer = match read_message_tagged(&mut stdin, max_message_size, integrity).await {
+            Ok(buffer) => {
+                log!(Debug, "Read a message of {} bytes", buffer.len());
+                buffer
+            }
+            Err(e) if e.is_closed() => return Ok(()),
+            Err(e) => panic!("error reading from stdin: {}", e),
+        };
+        let item: sni_icon::IconClientEvent = sni_icon::decode(&buffer)?;
+        let size = buffer.len();
+        stats.received(size);
+        drop(buffer);
+        if queue.push(item, size).await {
+            stats.coalesced();
+        }
+    }
+}
 
-    local_set.spawn_local(client_server());
-    local_set.await;
+/// The capabilities of an item created with `capabilities`, less a menu if
+/// menus are not allowed
+fn item_capabilities(limits: &config::Limits, capabilities: Capabilities) -> Capabilities {
+    if limits.allow_menus {
+        capabilities
+    } else {
+        capabilities.without(Capabilities::HAS_MENU | Capabilities::IS_MENU)
+    }
+}
+
+/// Add `notifier` to `items`, and register it with the watcher
+async fn register(
+    notifier: NotifierIcon,
+    items: &Mutex<HashMap<u64, NotifierIcon>>,
+    watcher: &Proxy<'_, Arc<dbus::nonblock::SyncConnection>>,
+) -> Result<(), Box<dyn Error>> {
+    let id = notifier.id();
+    let service = if sni_icon::deterministic() {
+        let name = names::name_sni_icon_item(notifier.vm(), id);
+        notifier.request_name(name.clone()).await?;
+        name.to_string()
+    } else {
+        notifier.bus_name()
+    };
+    // Watchers take the bus name followed by the path of an item not at
+    // the default path
+    let path = format!("{}{}", service, notifier.path());
+    {
+        let mut items = items.lock().unwrap();
+        items.insert(id, notifier);
+        update_counts(&mut items);
+    }
+    watcher
+        .method_call::<(), _, _, _>(
+            names::interface_status_notifier_watcher(),
+            names::register_status_notifier_item(),
+            (path,),
+        )
+        .await
+        .expect("Could not register status notifier item");
     Ok(())
 }
+
+/// Tell each of `items` how many items the VM exports, for the count badge
+fn update_counts(items: &mut HashMap<u64, NotifierIcon>) {
+    let count = items.len();
+    for item in items.values_mut() {
+        item.set_count(count)
+    }
+}
+
+/// Discard an event of `ni` for breaking the protocol, telling the agent why
+fn reject(context: &context::Context, ni: &mut NotifierIcon, reason: String) {
+    let id = ni.id();
+    ni.activity().rejected();
+    context.stats.dropped();
+    log!(Warning, id = id, "Discarding event: {}", reason);
+    context.output.send(sni_icon::IconServerEvent {
+        id,
+        event: sni_icon::ServerEvent::Rejected { reason },
+    })
+}
+
+/// Tell the agent that item `id` is gone, once it has been dropped
+fn acknowledge_destroy(output: &output::Output, id: u64) {
+    output.send(sni_icon::IconServerEvent {
+        id,
+        event: sni_icon::ServerEvent::DestroyAck,
+    })
+}
+
+/// Run the checks of `--check`, see [`sni_icon::check`]
+async fn self_check() -> std::process::ExitCode {
+    use sni_icon::check::{self, Failure};
+    let mut check = check::Check::new();
+    check.report(
+        "configuration",
+        config::load().map_err(|e| (Failure::Config, e.to_string())),
+    );
+    check.report("D-Bus string validation", check::string_validation());
+    let connected = check.report(
+        "connect to the session bus",
+        sni_icon::session::connect().map_err(|e| (Failure::Unavailable, e.to_string())),
+    );
+    let vm = std::env::var("QREXEC_REMOTE_DOMAIN").ok();
+    let name = check.report(
+        "daemon name",
+        names::name_sni_icon_daemon(vm.as_deref()).map_err(|e| (Failure::Config, e.to_string())),
+    );
+    if let (Some((resource, c)), Some(name)) = (connected, name) {
+        tokio::task::spawn_local(resource);
+        check.report(
+            "daemon name available",
+            check::name_available(&c, name).await,
+        );
+    }
+    check.exit_code()
+}
+
+/// Serve the agents connecting over `transport`, until SIGTERM or SIGINT
+async fn serve(
+    transport: impl sni_icon::transport::Transport,
+) -> Result<std::process::ExitCode, Box<dyn Error>> {
+    let config = config::load()?;
+    logging::set_max_level(config.log.level);
+    logging::cycle_on_sigusr2()?;
+    let resolver = peer::resolver(&config.peers);
+    tokio::select! {
+        result = activation::serve(transport, resolver, config.runtime.max_connections) => result?,
+        signal = sni_icon::signal::terminated() => {
+            log!(Info, "Received {}, no longer accepting connections", signal);
+        }
+    }
+    Ok(std::process::ExitCode::SUCCESS)
+}
+
+fn main() -> Result<std::process::ExitCode, Box<dyn Error>> {
+    // Errors in the configuration are reported once it is loaded again
+    let worker_threads = config::load().map_or(0, |config| config.runtime.worker_threads);
+    let runtime = match worker_threads {
+        0 => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?,
+        threads => tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .enable_all()
+            .build()?,
+    };
+    runtime.block_on(run())
+}
+
+async fn run() -> Result<std::process::ExitCode, Box<dyn Error>> {
+    let local_set = tokio::task::LocalSet::new();
+    let args = sni_icon::cli::Args::parse(std::env::args().skip(1))?;
+    sni_icon::session::set_address(args.session_bus_address);
+    if args.check {
+        return Ok(local_set.run_until(self_check()).await);
+    }
+    #[cfg(feature = "sandbox")]
+    if args.seccomp {
+        return Err(
+            "--seccomp is only for the agent, the daemon is sandboxed by [sandbox] instead".into(),
+        );
+    }
+    if args.timeout.is_some() {
+        return Err("--timeout is only for the agent".into());
+    }
+    panic::install_hook();
+    #[cfg(feature = "vsock")]
+    if let Some(address) = &args.vsock {
+        use sni_icon::transport::Transport as _;
+        let transport = sni_icon::transport::Vsock::bind(address)?;
+        log!(Info, "Listening on vsock {:?}", address);
+        return local_set.run_until(serve(transport)).await;
+    }
+    if let Some(listener) = activation::listener()? {
+        let transport = sni_icon::transport::Unix::new(listener)?;
+        return local_set.run_until(serve(transport)).await;
+    }
+
+    // Errors (such as a VM denied by policy) must terminate the daemon
+    let result = local_set
+        .run_until(async {
+            tokio::select! {
+                result = client_server(args.integrity) => result,
+                signal = sni_icon::signal::terminated() => {
+                    log!(Info, "Received {}, shutting down", signal);
+                    Ok(())
+                }
+            }
+        })
+        .await;
+    // client_server() has been dropped by now, and with it its registry
+    std::io::stdout().flush()?;
+    result.map(|()| std::process::ExitCode::SUCCESS)
+}
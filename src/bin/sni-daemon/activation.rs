@@ -0,0 +1,147 @@
+//! Serving agents that connect to a socket
+//!
+//! If the daemon is started with a listening Unix socket (`LISTEN_FDS`, see
+//! sd_listen_fds(3)), or told to listen on a vsock port, it accepts agent
+//! connections instead of talking to a single agent on stdin and stdout.
+//! Each connection is served by a new process of the daemon with the
+//! connection as its stdin and stdout, so that connections share no items,
+//! IDs or bus connections.  At most `runtime.max_connections` are served at
+//! once, and their processes are reaped as they exit.  The VM of each agent
+//! is found from what the transport tells about it, see [`crate::peer`].
+
+use std::error::Error;
+use std::os::fd::{FromRawFd as _, OwnedFd};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use sni_icon::transport::Transport;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::peer::Resolver;
+
+/// The first file descriptor passed by systemd
+const LISTEN_FDS_START: i32 = 3;
+
+/// The socket passed by systemd, if any
+pub(super) fn listener() -> Result<Option<UnixListener>, Box<dyn Error>> {
+    let for_us = std::env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let fds = match std::env::var("LISTEN_FDS") {
+        Ok(fds) if for_us => fds,
+        _ => return Ok(None),
+    };
+    if fds != "1" {
+        return Err(format!("expected one socket from systemd, got {}", fds).into());
+    }
+    // SAFETY: systemd passes the socket as file descriptor 3, which nothing
+    // else in the process owns, and this is only reached once, as the
+    // variables are checked before any connection is accepted and the
+    // process never sets them itself.
+    let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+    let socket = socket2::Socket::from(fd);
+    if !socket.local_addr()?.is_unix() || socket.r#type()? != socket2::Type::STREAM {
+        return Err("socket from systemd is not a Unix stream socket".into());
+    }
+    // Not for the daemons serving each connection
+    socket.set_cloexec(true)?;
+    Ok(Some(UnixListener::from(OwnedFd::from(socket))))
+}
+
+/// Serve each agent connecting over `transport` with a new daemon process,
+/// for the VM found by `resolver`, serving at most `max_connections` at once
+pub(super) async fn serve(
+    transport: impl Transport,
+    resolver: Option<Box<dyn Resolver>>,
+    max_connections: usize,
+) -> Result<(), Box<dyn Error>> {
+    let exe: Arc<Path> = std::env::current_exe()?.into();
+    let resolver: Option<Arc<dyn Resolver>> = resolver.map(Arc::from);
+    let connections = Arc::new(Semaphore::new(max_connections.min(Semaphore::MAX_PERMITS)));
+    loop {
+        let (stream, peer) = transport.accept().await?;
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            log!(
+                Warning,
+                "Refusing agent {:?}: already serving {} connections",
+                peer,
+                max_connections
+            );
+            continue;
+        };
+        log!(Info, "Agent connected: {:?}", peer);
+        let Some(resolver) = resolver.clone() else {
+            spawn(&exe, stream, None, permit);
+            continue;
+        };
+        // Resolving may run a command, which must not hold up the accept loop
+        let exe = exe.clone();
+        tokio::task::spawn_local(async move {
+            match tokio::task::spawn_blocking(move || resolver.resolve(&peer)).await {
+                Ok(Ok(Some(vm))) => {
+                    log!(Info, "Agent {:?} is VM {:?}", peer, vm);
+                    spawn(&exe, stream, Some(&vm), permit)
+                }
+                Ok(Ok(None)) => log!(Error, "Refusing agent {:?}: not a VM", peer),
+                Ok(Err(e)) => log!(
+                    Error,
+                    "Refusing agent {:?}: cannot find its VM: {}",
+                    peer,
+                    e
+                ),
+                Err(e) => log!(Error, "Resolving VM of agent panicked: {}", e),
+            }
+        });
+    }
+}
+
+/// The arguments of this daemon, without those telling it to listen, as the
+/// daemon for a connection must not listen itself
+fn connection_args() -> Vec<std::ffi::OsString> {
+    let mut args = vec![];
+    let mut all = std::env::args_os().skip(1);
+    while let Some(arg) = all.next() {
+        if arg == "--vsock" {
+            all.next();
+        } else {
+            args.push(arg)
+        }
+    }
+    args
+}
+
+/// Start a daemon serving the agent at the other end of `stream`, for `vm`.
+/// `permit` is given back once it exits.
+fn spawn(exe: &Path, stream: OwnedFd, vm: Option<&str>, permit: OwnedSemaphorePermit) {
+    let result = (|| {
+        let output = stream.try_clone()?;
+        let mut command = tokio::process::Command::new(exe);
+        command
+            .args(connection_args())
+            .env_remove("LISTEN_PID")
+            .env_remove("LISTEN_FDS")
+            .env_remove("LISTEN_FDNAMES")
+            .stdin(Stdio::from(stream))
+            .stdout(Stdio::from(output));
+        // The daemon takes the VM from qrexec, as if qrexec had started it
+        match vm {
+            Some(vm) => command.env("QREXEC_REMOTE_DOMAIN", vm),
+            None => command.env_remove("QREXEC_REMOTE_DOMAIN"),
+        };
+        command.spawn()
+    })();
+    let mut child = match result {
+        Ok(child) => child,
+        Err(e) => return log!(Error, "Cannot start daemon for connection: {}", e),
+    };
+    // Only unknown once it has been reaped
+    let pid = child.id().unwrap_or_default();
+    log!(Info, "Started daemon {} for connection", pid);
+    tokio::task::spawn_local(async move {
+        match child.wait().await {
+            Ok(status) => log!(Info, "Daemon {} exited: {}", pid, status),
+            Err(e) => log!(Warning, "Cannot wait for daemon {}: {}", pid, e),
+        }
+        drop(permit)
+    });
+}
@@ -0,0 +1,47 @@
+//! What each item received from the VM, listed by the control interface
+//!
+//! When an item looks stale, [`crate::control`] tells whether the VM stopped
+//! sending events for it, or sent events that were rejected for breaking
+//! the protocol.  Only events after the item was created are counted.
+
+use dbus::arg::{PropMap, Variant};
+
+#[derive(Debug, Default)]
+pub(super) struct Activity {
+    /// When the last event was handled, see [`sni_icon::timestamp`], or 0
+    last_event: u64,
+    /// The size of the events received, including those replaced while
+    /// queued
+    bytes: u64,
+    /// The events handled
+    updates: u64,
+    /// The events rejected, see [`crate::reject`]
+    rejections: u64,
+}
+
+impl Activity {
+    /// Count `events` events of `bytes` bytes in all, handled just now
+    pub fn handled(&mut self, events: usize, bytes: usize) {
+        self.last_event = sni_icon::timestamp();
+        self.updates += events as u64;
+        self.bytes += bytes as u64;
+    }
+
+    /// Count an event that was rejected
+    pub fn rejected(&mut self) {
+        self.rejections += 1;
+    }
+
+    /// Add the counts to the details of the item listed by the control
+    /// interface
+    pub fn describe(&self, details: &mut PropMap) {
+        for (key, value) in [
+            ("LastEvent", self.last_event),
+            ("Bytes", self.bytes),
+            ("Updates", self.updates),
+            ("Rejections", self.rejections),
+        ] {
+            details.insert(key.to_owned(), Variant(Box::new(value)));
+        }
+    }
+}
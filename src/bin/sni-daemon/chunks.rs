@@ -0,0 +1,113 @@
+//! Reassembly of icon frames sent as [`sni_icon::ClientEvent::IconChunk`]s
+
+use sni_icon::{IconData, IconType};
+use std::collections::HashMap;
+
+use crate::config::Limits;
+
+/// A chunk as received from the VM
+pub(super) struct Chunk {
+    pub width: u32,
+    pub height: u32,
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+/// Frames being reassembled, by icon type.  Only the last frame of each type
+/// can be incomplete.
+#[derive(Default)]
+pub(super) struct Chunks(HashMap<IconType, Vec<IconData>>);
+
+impl Chunks {
+    /// Add `chunk` to the frames of icon `typ`.  On error, all frames of that
+    /// type are discarded.
+    pub fn add(&mut self, typ: IconType, chunk: Chunk, limits: &Limits) -> Result<(), String> {
+        let result = self.try_add(typ, chunk, limits);
+        if result.is_err() {
+            self.clear(typ);
+        }
+        result
+    }
+
+    fn try_add(&mut self, typ: IconType, chunk: Chunk, limits: &Limits) -> Result<(), String> {
+        let Chunk {
+            width,
+            height,
+            offset,
+            data,
+        } = chunk;
+        if !matches!(
+            typ,
+            IconType::Normal | IconType::Attention | IconType::Overlay
+        ) {
+            return Err(format!("bad icon type {:?}", typ));
+        }
+        let max_icon_size = limits.max_icon_size;
+        if width == 0 || height == 0 || width > max_icon_size || height > max_icon_size {
+            return Err(format!("bad frame size {}x{}", width, height));
+        }
+        let size = frame_size(width, height).ok_or("frame too large")?;
+        let frames = self.0.entry(typ).or_default();
+        let received: usize = frames.iter().map(|frame| frame.data.len()).sum();
+        if received + data.len() > limits.max_icon_bytes {
+            return Err(format!(
+                "frames exceed the limit of {} bytes",
+                limits.max_icon_bytes
+            ));
+        }
+        if offset == 0 {
+            if frames.len() >= limits.max_frames as usize {
+                return Err(format!("more than {} frames", limits.max_frames));
+            }
+            frames.push(IconData {
+                width,
+                height,
+                // Not preallocated: the VM has to actually send the data
+                data: vec![],
+            });
+        }
+        let frame = match frames.last_mut() {
+            Some(frame) if frame.width == width && frame.height == height => frame,
+            _ => return Err("chunk does not continue a frame".to_owned()),
+        };
+        if offset as usize != frame.data.len() {
+            return Err(format!(
+                "chunk at offset {}, expected {}",
+                offset,
+                frame.data.len()
+            ));
+        }
+        if data.len() > size - frame.data.len() {
+            return Err("chunk extends past the end of the frame".to_owned());
+        }
+        frame.data.extend_from_slice(&data);
+        Ok(())
+    }
+
+    /// Remove the frames of icon `typ`, returning those that are complete
+    pub fn take(&mut self, typ: IconType) -> Vec<IconData> {
+        let mut frames = self.0.remove(&typ).unwrap_or_default();
+        let len = frames.len();
+        frames.retain(|f| Some(f.data.len()) == frame_size(f.width, f.height));
+        if frames.len() != len {
+            log!(
+                Warning,
+                "Discarded {} incomplete chunked icon frames",
+                len - frames.len()
+            );
+        }
+        frames
+    }
+
+    /// Discard the frames of icon `typ`
+    pub fn clear(&mut self, typ: IconType) {
+        self.0.remove(&typ);
+    }
+}
+
+fn frame_size(width: u32, height: u32) -> Option<usize> {
+    usize::try_from(width)
+        .ok()?
+        .checked_mul(usize::try_from(height).ok()?)?
+        .checked_mul(4)
+}
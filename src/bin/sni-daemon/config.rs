@@ -0,0 +1,797 @@
+//! Daemon configuration
+//!
+//! The configuration is read from a TOML file, by default
+//! `/etc/qubes/sni-daemon.toml`.  The `SNI_DAEMON_CONFIG` environment
+//! variable overrides the path.  A missing file is equivalent to an empty one.
+
+use sni_icon::image::Border;
+use sni_icon::spec::{Category, Status as ItemStatus};
+use sni_icon::ProtocolLimits;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+const DEFAULT_PATH: &str = "/etc/qubes/sni-daemon.toml";
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Config {
+    pub filter: Filter,
+    pub policy: Policy,
+    pub labels: Labels,
+    pub log: Log,
+    pub defaults: Defaults,
+    pub icons: Icons,
+    pub properties: Properties,
+    pub border: Border,
+    pub scroll: Scroll,
+    pub status: Status,
+    pub tooltips: Tooltips,
+    pub reattach: Reattach,
+    pub notifications: Notifications,
+    pub categories: Categories,
+    pub access: Access,
+    pub peers: Peers,
+    pub state: State,
+    pub order: Order,
+    pub icon_cache: IconCache,
+    pub sandbox: Sandbox,
+    pub runtime: Runtime,
+    /// Limits applied to all VMs, unless overridden
+    limits: LimitsOverride,
+    /// Named classes of VMs sharing the same limits
+    class: HashMap<String, Class>,
+    /// Per-VM settings
+    vm: HashMap<String, Vm>,
+}
+
+/// Resource limits for a single VM.  These are determined once, when the
+/// daemon for that VM starts.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Limits {
+    /// Maximum number of icons the VM may export at once
+    pub max_icons: usize,
+    /// Maximum width and height of any icon frame, in pixels
+    pub max_icon_size: u32,
+    /// Maximum number of frames of a single icon
+    pub max_frames: u32,
+    /// Most bytes of frame data in a single icon or tooltip, including
+    /// chunked frames, which frames that are allowed one by one could
+    /// otherwise add up to many times over
+    pub max_icon_bytes: usize,
+    /// Maximum number of items that have a menu at the same time
+    pub max_menus: u32,
+    /// Largest message the VM may send, in bytes.  Values outside of
+    /// [`sni_icon::MIN_MESSAGE_SIZE`] and [`sni_icon::MAX_MESSAGE_SIZE`] are
+    /// clamped.
+    pub max_message_size: u32,
+    /// Maximum number of update events per second.  The daemon stops
+    /// handling events from the VM while this is exceeded.  0 means no limit.
+    pub max_updates_per_second: u32,
+    /// Most events read from the VM and waiting to be handled, see
+    /// [`crate::queue`]
+    pub max_queued_events: usize,
+    /// Whether the VM's items may be menus
+    pub allow_menus: bool,
+    /// Whether the VM's items may have tooltips
+    pub allow_tooltips: bool,
+    /// Whether clicks, scrolling and menus of the VM's items are forwarded
+    /// to it.  Without it, the items are only displayed: calls from panels
+    /// are accepted, but the VM never learns of them.
+    pub allow_interaction: bool,
+    /// Whether clicks on the VM's items and requests for their context
+    /// menus are only forwarded once the user allowed each of them, see
+    /// [`crate::confirm`]
+    pub confirm_interaction: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_icons: 64,
+            max_icon_size: 1024,
+            max_frames: 8,
+            max_icon_bytes: 16 << 20,
+            max_menus: 16,
+            max_message_size: sni_icon::MAX_MESSAGE_SIZE,
+            max_updates_per_second: 50,
+            max_queued_events: 64,
+            allow_menus: true,
+            allow_tooltips: true,
+            allow_interaction: true,
+            confirm_interaction: false,
+        }
+    }
+}
+
+impl Limits {
+    /// The limits sent to the VM in the handshake
+    pub fn protocol(&self) -> ProtocolLimits {
+        ProtocolLimits {
+            max_message_size: self.max_message_size,
+            max_icon_size: self.max_icon_size,
+            max_frames: self.max_frames,
+            max_menus: if self.allow_menus { self.max_menus } else { 0 },
+        }
+    }
+
+    /// Apply the limits negotiated with the VM, which are never less strict
+    /// than [`Limits::protocol`]
+    pub fn negotiate(&mut self, peer: ProtocolLimits) {
+        let limits = self.protocol().min(peer);
+        self.max_message_size = limits.max_message_size;
+        self.max_icon_size = limits.max_icon_size;
+        self.max_frames = limits.max_frames;
+        self.max_menus = limits.max_menus;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsOverride {
+    max_icons: Option<usize>,
+    max_icon_size: Option<u32>,
+    max_frames: Option<u32>,
+    max_icon_bytes: Option<usize>,
+    max_menus: Option<u32>,
+    max_message_size: Option<u32>,
+    max_updates_per_second: Option<u32>,
+    max_queued_events: Option<usize>,
+    allow_menus: Option<bool>,
+    allow_tooltips: Option<bool>,
+    allow_interaction: Option<bool>,
+    confirm_interaction: Option<bool>,
+}
+
+impl LimitsOverride {
+    fn apply(&self, limits: &mut Limits) {
+        let Self {
+            max_icons,
+            max_icon_size,
+            max_frames,
+            max_icon_bytes,
+            max_menus,
+            max_message_size,
+            max_updates_per_second,
+            max_queued_events,
+            allow_menus,
+            allow_tooltips,
+            allow_interaction,
+            confirm_interaction,
+        } = *self;
+        limits.max_icons = max_icons.unwrap_or(limits.max_icons);
+        limits.max_icon_size = max_icon_size.unwrap_or(limits.max_icon_size);
+        limits.max_frames = max_frames.unwrap_or(limits.max_frames);
+        limits.max_icon_bytes = max_icon_bytes.unwrap_or(limits.max_icon_bytes);
+        limits.max_menus = max_menus.unwrap_or(limits.max_menus);
+        limits.max_message_size = max_message_size.unwrap_or(limits.max_message_size);
+        limits.max_updates_per_second =
+            max_updates_per_second.unwrap_or(limits.max_updates_per_second);
+        limits.max_queued_events = max_queued_events.unwrap_or(limits.max_queued_events);
+        limits.allow_menus = allow_menus.unwrap_or(limits.allow_menus);
+        limits.allow_tooltips = allow_tooltips.unwrap_or(limits.allow_tooltips);
+        limits.allow_interaction = allow_interaction.unwrap_or(limits.allow_interaction);
+        limits.confirm_interaction = confirm_interaction.unwrap_or(limits.confirm_interaction);
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Class {
+    limits: LimitsOverride,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Vm {
+    /// Name of the class this VM belongs to
+    class: Option<String>,
+    limits: LimitsOverride,
+    /// Replaces the color of the Qubes label of the VM for the attention,
+    /// count and corner badges, e.g. where the label cannot be looked up,
+    /// see [`Labels`]
+    label_color: Option<Color>,
+    /// Replaces the global border, e.g. to draw none for trusted VMs
+    border: Option<Border>,
+    /// Replaces the global category remapping, e.g. to force all items of
+    /// untrusted VMs into `ApplicationStatus`
+    categories: Option<Categories>,
+}
+
+impl Config {
+    /// Resolve the limits for `vm`: global limits first, then those of the
+    /// VM's class, then those of the VM itself.
+    pub fn limits_for(&self, vm: Option<&str>) -> Limits {
+        let mut limits = Limits::default();
+        self.limits.apply(&mut limits);
+        if let Some(vm) = vm.and_then(|vm| self.vm.get(vm)) {
+            if let Some(class) = &vm.class {
+                // checked in load()
+                self.class[class].limits.apply(&mut limits);
+            }
+            vm.limits.apply(&mut limits);
+        }
+        limits
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for border in std::iter::once(&self.border)
+            .chain(self.vm.values().filter_map(|vm| vm.border.as_ref()))
+        {
+            if border.width > Border::MAX_WIDTH {
+                return Err(format!(
+                    "border width {} exceeds the limit of {}",
+                    border.width,
+                    Border::MAX_WIDTH
+                ));
+            }
+        }
+        if ItemStatus::parse(&self.defaults.status).is_none() {
+            return Err(format!(
+                "unknown default status {:?}, expected one of {:?}",
+                self.defaults.status,
+                ItemStatus::ALL.map(ItemStatus::as_str)
+            ));
+        }
+        for categories in std::iter::once(&self.categories)
+            .chain(self.vm.values().filter_map(|vm| vm.categories.as_ref()))
+        {
+            categories.validate()?;
+        }
+        if self.sandbox.enabled && self.runtime.worker_threads > 0 {
+            return Err(
+                "the sandbox cannot restrict worker threads, which start before it is applied"
+                    .to_owned(),
+            );
+        }
+        for (name, vm) in &self.vm {
+            match &vm.class {
+                Some(class) if !self.class.contains_key(class) => {
+                    return Err(format!("VM {:?} has undefined class {:?}", name, class))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Values of properties that the VM did not provide.  Some hosts consider
+/// items broken if reading a property fails, so these are used instead.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Defaults {
+    /// The `Status` of an item
+    pub status: String,
+    /// The `Title` of an item.  If not set, the VM-provided application ID
+    /// is used.
+    pub title: Option<String>,
+    /// The `IconThemePath` of every item.  Icon themes of VMs are never
+    /// used, so this is the same for all items.
+    pub icon_theme_path: String,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Self {
+            status: ItemStatus::Passive.as_str().to_owned(),
+            title: None,
+            icon_theme_path: String::new(),
+        }
+    }
+}
+
+/// The parts of the configuration used by each item
+#[derive(Debug, Clone)]
+pub(super) struct ItemSettings {
+    pub defaults: Defaults,
+    pub icons: Icons,
+    pub properties: Properties,
+    pub scroll: Scroll,
+    pub status: Status,
+    pub tooltips: Tooltips,
+    pub border: Border,
+    pub categories: Categories,
+    /// The color of the label of the VM, as red, green and blue
+    pub label_color: [u8; 3],
+    /// The name of the VM, which tooltips name, if known
+    pub vm: Option<String>,
+    /// Whether the items are only displayed, see
+    /// [`Limits::allow_interaction`]
+    pub read_only: bool,
+    /// See [`Notifications::read_only`]
+    pub notify_read_only: bool,
+    /// See [`Limits::confirm_interaction`]
+    pub confirm_interaction: bool,
+}
+
+impl Config {
+    /// The settings of the items of `vm`, whose Qubes label has the color
+    /// `label`, if it could be looked up
+    pub fn item_settings(
+        &self,
+        vm: Option<&str>,
+        limits: &Limits,
+        label: Option<[u8; 3]>,
+    ) -> ItemSettings {
+        let settings = vm.and_then(|vm| self.vm.get(vm));
+        let label_color = settings
+            .and_then(|vm| vm.label_color)
+            .or(label.map(Color))
+            .unwrap_or(Color::DEFAULT_LABEL);
+        ItemSettings {
+            defaults: self.defaults.clone(),
+            icons: self.icons.clone(),
+            properties: self.properties,
+            scroll: self.scroll.clone(),
+            status: self.status.clone(),
+            tooltips: self.tooltips.clone(),
+            border: settings.and_then(|vm| vm.border).unwrap_or(self.border),
+            categories: settings
+                .and_then(|vm| vm.categories.clone())
+                .unwrap_or_else(|| self.categories.clone()),
+            label_color: label_color.0,
+            vm: vm.map(str::to_owned),
+            read_only: !limits.allow_interaction,
+            notify_read_only: self.notifications.read_only,
+            confirm_interaction: limits.confirm_interaction,
+        }
+    }
+}
+
+/// A color written as `#rrggbb`
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(try_from = "String")]
+struct Color([u8; 3]);
+
+impl Color {
+    /// The color used for VMs whose label is not known, that of the border
+    /// drawn around their icons
+    const DEFAULT_LABEL: Self = Self([255, 0, 0]);
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        let invalid = || format!("invalid color {:?}, expected #rrggbb", s);
+        let hex = s
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6)
+            .ok_or_else(invalid)?;
+        let mut color = [0; 3];
+        for (i, component) in color.iter_mut().enumerate() {
+            *component = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(color))
+    }
+}
+
+/// Changes made to the icons of items before they are exported
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Icons {
+    /// Draw the overlay icon onto the bottom-right quadrant of the icon,
+    /// instead of exporting it, for hosts that ignore `OverlayIconPixmap`
+    pub composite_overlay: bool,
+    /// Draw an exclamation mark badge in the color of the label of the VM
+    /// onto the icon while the item needs attention but has no attention
+    /// icon, for hosts that ignore `Status`
+    pub attention_badge: bool,
+    /// Draw the number of items the VM exports onto the top-left quadrant
+    /// of the icon of each of them, in the color of the label of the VM, so
+    /// that a VM suddenly adding items is noticed
+    pub count_badge: bool,
+    /// How the icons of items that went away or do not answer are drawn
+    pub fade: sni_icon::image::Fade,
+}
+
+/// How getting a property that an item lacks is answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Missing {
+    /// With an error, which also leaves the property out of `GetAll`
+    Error,
+    /// With an empty value
+    Empty,
+}
+
+/// How the properties that items lack are answered.  Some hosts drop an
+/// item when getting a property fails, while others show an empty value as
+/// a broken menu or icon.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Properties {
+    /// `Menu` of items without a menu.  Its empty value is the path `/`.
+    pub menu: Missing,
+    /// `IconName`, `OverlayIconName`, `AttentionIconName` and
+    /// `AttentionMovieName`, which items never have, as their icons are
+    /// sent as pixmaps
+    pub icon_names: Missing,
+    /// `OverlayIconPixmap` of items without an overlay icon, or whose
+    /// overlay icon is drawn onto the icon
+    pub overlay_icon_pixmap: Missing,
+    /// `AttentionIconPixmap` of items without an attention icon
+    pub attention_icon_pixmap: Missing,
+}
+
+impl Default for Properties {
+    fn default() -> Self {
+        Self {
+            menu: Missing::Error,
+            icon_names: Missing::Empty,
+            overlay_icon_pixmap: Missing::Empty,
+            attention_icon_pixmap: Missing::Empty,
+        }
+    }
+}
+
+/// The categories items are exported with.  Some hosts group or sort items
+/// by category, which a VM could use to pass its items off as part of the
+/// system.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Categories {
+    /// Categories exported instead of those the VM sends, by the category
+    /// the VM sends
+    pub map: HashMap<String, String>,
+    /// The category of all items, whatever the VM sends
+    pub force: Option<String>,
+}
+
+impl Categories {
+    /// The category an item the VM created with `category` is exported with
+    pub fn apply<'a>(&'a self, category: &'a str) -> &'a str {
+        match &self.force {
+            Some(force) => force,
+            None => self.map.get(category).map_or(category, String::as_str),
+        }
+    }
+
+    /// Check that only categories of the specification are exported instead
+    /// of those of the VM
+    fn validate(&self) -> Result<(), String> {
+        for category in self.map.values().chain(&self.force) {
+            if Category::parse(category).is_none() {
+                return Err(format!(
+                    "unknown category {:?}, expected one of {:?}",
+                    category,
+                    Category::ALL.map(Category::as_str)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Normalization of the deltas of `Scroll` calls, see [`crate::scroll`]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Scroll {
+    /// The delta of one step of the wheel, as sent by the panel, e.g. 120
+    /// for panels using Qt.  Deltas are forwarded in whole steps.
+    pub step: u32,
+    /// Most steps forwarded in a single event.  0 means no limit.
+    pub max_steps: u32,
+    /// How long partial steps are kept without scrolling, in milliseconds
+    pub partial_timeout_ms: u64,
+    /// How long steps are added up before they are forwarded as one event,
+    /// in milliseconds.  0 forwards every call on its own.
+    pub coalesce_ms: u64,
+}
+
+impl Default for Scroll {
+    fn default() -> Self {
+        Self {
+            step: 1,
+            max_steps: 0,
+            partial_timeout_ms: 500,
+            coalesce_ms: 50,
+        }
+    }
+}
+
+impl Scroll {
+    pub fn partial_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.partial_timeout_ms)
+    }
+
+    pub fn coalesce_window(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.coalesce_ms)
+    }
+}
+
+/// Hysteresis of the status of items, see [`crate::status`]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Status {
+    /// How long a status is shown before it changes again, in milliseconds.
+    /// 0 shows every change at once.
+    pub hold_ms: u64,
+    /// Most time a change to `NeedsAttention` is held back, in milliseconds
+    pub attention_hold_ms: u64,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            hold_ms: 500,
+            attention_hold_ms: 200,
+        }
+    }
+}
+
+impl Status {
+    pub fn hold(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.hold_ms)
+    }
+
+    pub fn attention_hold(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.attention_hold_ms)
+    }
+}
+
+/// Limits on the text of tooltips, see [`crate::tooltip`]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Tooltips {
+    /// Most characters of the title of a tooltip
+    pub max_title_chars: usize,
+    /// Most bytes of the description of a tooltip, before it is broken into
+    /// lines
+    pub max_description_len: usize,
+    /// Most characters of each line of the description
+    pub max_line_chars: usize,
+    /// Most lines of the description, not counting the line naming the VM
+    pub max_lines: usize,
+}
+
+impl Default for Tooltips {
+    fn default() -> Self {
+        Self {
+            max_title_chars: 80,
+            max_description_len: 1024,
+            max_line_chars: 80,
+            max_lines: 10,
+        }
+    }
+}
+
+/// Keeping items whose application went away, see [`crate::linger`]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Reattach {
+    /// How long an item whose application went away is kept, greyed out,
+    /// for the application to come back, in milliseconds.  0 removes items
+    /// at once.
+    pub grace_ms: u64,
+}
+
+impl Default for Reattach {
+    fn default() -> Self {
+        Self { grace_ms: 5000 }
+    }
+}
+
+impl Reattach {
+    pub fn grace(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.grace_ms)
+    }
+}
+
+/// Desktop notifications about items, see [`crate::failures`]
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Notifications {
+    /// Tell the user when an item fails to handle a click or its menu
+    /// being opened
+    pub failed_calls: bool,
+    /// Tell the user when a click on an item of a VM whose items are only
+    /// displayed is not forwarded, see [`Limits::allow_interaction`]
+    pub read_only: bool,
+}
+
+/// Who may call methods of the exported items
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Access {
+    /// Reject calls from connections other than StatusNotifierHosts
+    /// registered with the watcher, so that other programs cannot send
+    /// events to the VM, see [`crate::hosts`]
+    pub hosts_only: bool,
+}
+
+/// Identifying the VM of agents connecting to a socket, see [`crate::peer`].
+/// If nothing is set, agents are served as if they were not VMs.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Peers {
+    /// VM names by the user ID of the agent, as a string
+    pub uid: HashMap<String, String>,
+    /// VM names by the vsock context ID of the agent, as a string
+    pub cid: HashMap<String, String>,
+    /// A command printing the name of the VM of the agent, given its
+    /// credentials in `SNI_PEER_PID`, `SNI_PEER_UID` and `SNI_PEER_GID`, or
+    /// its context ID in `SNI_PEER_CID`.  Used for agents not found in `uid`
+    /// or `cid`.
+    pub command: Vec<String>,
+}
+
+/// Saving the items of each VM, so that a restarted daemon shows them at
+/// once, see [`crate::state`]
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct State {
+    pub enabled: bool,
+    /// Where the items are saved, one file per VM.  Defaults to
+    /// `sni-daemon` in `XDG_RUNTIME_DIR`.
+    pub directory: Option<PathBuf>,
+}
+
+/// Keeping the order of the items of each VM across reboots, see
+/// [`crate::order`]
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Order {
+    pub enabled: bool,
+    /// Where the order is saved, one file per VM.  Defaults to
+    /// `sni-daemon/order` in `XDG_STATE_HOME`, or in `~/.local/state`.
+    pub directory: Option<PathBuf>,
+}
+
+/// Keeping the icons of each VM on disk, so that they need not be sent
+/// again, see [`crate::icon_cache`]
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct IconCache {
+    pub enabled: bool,
+    /// Where the icons are kept, one directory per VM.  Defaults to
+    /// `sni-daemon/icons` in `XDG_CACHE_HOME`, or in `~/.cache`.
+    pub directory: Option<PathBuf>,
+    /// Most bytes of icons kept for each VM.  The least recently used are
+    /// removed beyond that.
+    pub max_bytes: u64,
+}
+
+impl Default for IconCache {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: None,
+            max_bytes: 16 << 20,
+        }
+    }
+}
+
+/// Sandboxing the daemon once it is set up, see [`crate::sandbox`]
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Sandbox {
+    /// Whether to apply the sandbox.  The daemon refuses to start if it was
+    /// built without the `sandbox` feature.
+    pub enabled: bool,
+}
+
+/// How the daemon runs.  This is read once, when the daemon starts.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Runtime {
+    /// Threads answering D-Bus calls and running timers.  0 runs all of the
+    /// daemon on the thread reading events from the VM.
+    pub worker_threads: usize,
+    /// Most agents served at once by a daemon accepting connections, see
+    /// [`crate::activation`].  Agents connecting beyond that are refused.
+    pub max_connections: usize,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self {
+            worker_threads: 0,
+            max_connections: 32,
+        }
+    }
+}
+
+/// Logging settings
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Log {
+    /// The most verbose level that is logged
+    pub level: crate::logging::Level,
+    /// Log VM-provided strings that are safe to display, instead of only
+    /// their lengths and hashes.  For debugging only.
+    pub raw_strings: bool,
+}
+
+/// Consulting qrexec policy when a VM connects
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Policy {
+    /// Whether to consult qrexec policy at all
+    pub enabled: bool,
+    /// The qrexec service to query.  Features are queried as arguments of
+    /// this service, e.g. `qubes.StatusNotifierItem+menus`.
+    pub service: String,
+    /// The policy evaluation socket of qrexec-policy-daemon
+    pub socket: PathBuf,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            service: "qubes.StatusNotifierItem".to_owned(),
+            socket: "/etc/qubes-rpc/policy.EvalSimple".into(),
+        }
+    }
+}
+
+/// Looking up the Qubes label of a VM when it connects, see
+/// [`crate::label`]
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Labels {
+    /// Whether to look up labels at all
+    pub enabled: bool,
+    /// The Admin API socket of qubesd, only found in dom0
+    pub socket: PathBuf,
+}
+
+impl Default for Labels {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: "/var/run/qubesd.sock".into(),
+        }
+    }
+}
+
+/// Allow and deny lists for VM-provided application IDs.
+///
+/// Each pattern is either an exact application ID or a prefix followed by
+/// `*`.  If `allow` is non-empty, only matching IDs are permitted.  `deny`
+/// takes precedence over `allow`.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Filter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+fn matches(pattern: &str, app_id: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => app_id.starts_with(prefix),
+        None => pattern == app_id,
+    }
+}
+
+impl Filter {
+    pub fn permits(&self, app_id: &str) -> bool {
+        if self.deny.iter().any(|p| matches(p, app_id)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|p| matches(p, app_id))
+    }
+}
+
+pub(super) fn path() -> PathBuf {
+    std::env::var_os("SNI_DAEMON_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| DEFAULT_PATH.into())
+}
+
+pub(super) fn load() -> Result<Config, Box<dyn Error>> {
+    let path = path();
+    let data = match std::fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(format!("cannot read {}: {}", path.display(), e).into()),
+    };
+    let config: Config =
+        toml::from_str(&data).map_err(|e| format!("invalid config {}: {}", path.display(), e))?;
+    config
+        .validate()
+        .map_err(|e| format!("invalid config {}: {}", path.display(), e))?;
+    Ok(config)
+}
@@ -0,0 +1,143 @@
+//! Asking the user before clicks are forwarded to sensitive VMs
+//!
+//! If [`crate::config::Limits::confirm_interaction`] is set for a VM, a click
+//! on one of its items or a request for its context menu is only forwarded
+//! once the user allowed it, much like the `ask` action of qrexec policy.
+//! The daemon shows a desktop notification with an action allowing it.  The
+//! click is dropped if the notification is dismissed, if it was not answered
+//! within [`TIMEOUT`], or if no notification server is running.  While a
+//! click of an item waits for an answer, further ones are dropped.  Only
+//! answers from the connection the notification was sent to count, so that
+//! other programs cannot allow clicks.
+
+use dbus::channel::Sender as _;
+use dbus::nonblock::{Proxy, SyncConnection as Connection};
+use dbus::strings::BusName;
+use dbus::Message;
+use futures_util::StreamExt as _;
+use sni_icon::{names, IconServerEvent, ServerEvent};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::context::Context;
+
+/// How long the user has to allow a click
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// The key of the action allowing the click
+const ALLOW: &str = "allow";
+
+/// Ask the user, with `summary`, whether to forward `event` to item `id` of
+/// the daemon of `context`, and forward it if allowed
+pub(super) fn ask(
+    c: Arc<Connection>,
+    context: Context,
+    id: u64,
+    summary: String,
+    event: ServerEvent,
+) {
+    if !context.confirming.lock().unwrap().insert(id) {
+        return log!(Info, id = id, "Dropping a click while another one waits");
+    }
+    tokio::spawn(async move {
+        let allowed = prompt(&c, &summary).await.unwrap_or_else(|e| {
+            log!(
+                Warning,
+                id = id,
+                "Cannot ask whether to forward a click: {}",
+                e
+            );
+            false
+        });
+        context.confirming.lock().unwrap().remove(&id);
+        if !allowed {
+            return log!(Info, id = id, "Click on item {} not allowed", id);
+        }
+        // The item may be gone by now
+        if context.items.lock().unwrap().contains_key(&id) {
+            context.output.send(IconServerEvent { id, event })
+        }
+    });
+}
+
+/// Show a notification with `summary` and an action allowing the click,
+/// returning whether the user picked it in time
+async fn prompt(c: &Connection, summary: &str) -> Result<bool, Box<dyn Error>> {
+    let bus = Proxy::new(
+        names::name_dbus(),
+        names::path_dbus(),
+        Duration::from_secs(5),
+        c,
+    );
+    let (server,): (String,) = bus
+        .method_call(
+            names::interface_dbus(),
+            names::get_name_owner(),
+            (&*names::name_notifications(),),
+        )
+        .await?;
+    let server = BusName::new(server)?;
+    // Added first, so that no answer is missed
+    let (msg_match, mut signals) = c.add_match(names::notifications_rule()).await?.msg_stream();
+    let notifications = Proxy::new(
+        server.clone(),
+        names::path_notifications(),
+        Duration::from_secs(5),
+        c,
+    );
+    let notified = notifications
+        .method_call(
+            names::interface_notifications(),
+            names::notify(),
+            (
+                "sni-daemon",
+                0u32,
+                "",
+                summary,
+                "The application only learns of the click if it is allowed.",
+                vec![ALLOW, "Allow"],
+                dbus::arg::PropMap::new(),
+                TIMEOUT.as_millis() as i32,
+            ),
+        )
+        .await;
+    let answer = async {
+        let (notification,): (u32,) = notified?;
+        let answer = tokio::time::timeout(TIMEOUT, async {
+            while let Some(signal) = signals.next().await {
+                if signal.sender().is_none_or(|sender| *sender != *server) {
+                    continue;
+                }
+                let member = signal.member();
+                if member == Some(names::action_invoked()) {
+                    if let Ok((id, action)) = signal.read2::<u32, &str>() {
+                        if id == notification {
+                            return action == ALLOW;
+                        }
+                    }
+                } else if member == Some(names::notification_closed())
+                    && signal.read1::<u32>().is_ok_and(|id| id == notification)
+                {
+                    return false;
+                }
+            }
+            false
+        })
+        .await;
+        if answer.is_err() {
+            let mut msg = Message::method_call(
+                &server,
+                &names::path_notifications(),
+                &names::interface_notifications(),
+                &names::close_notification(),
+            )
+            .append1(notification);
+            msg.set_no_reply(true);
+            let _ = c.send(msg);
+        }
+        Ok::<_, dbus::Error>(answer.unwrap_or(false))
+    }
+    .await;
+    c.remove_match(msg_match.token()).await?;
+    Ok(answer?)
+}
@@ -0,0 +1,110 @@
+//! What was agreed with the agent when it connected
+//!
+//! Agents and daemons of different versions are often mixed up, e.g. when
+//! a template was updated but dom0 was not.  The version of the daemon, the
+//! transport, and what each side asked for and got are logged once the
+//! [`crate::handshake`] is done, as structured journal fields, and listed
+//! by the `Connection` method of the control interface, so that a support
+//! request tells at once whether the components match.  The agent sends
+//! only its protocol version, and both speak the older of theirs.
+
+use dbus::arg::{PropMap, Variant};
+use sni_icon::transport::Kind;
+use sni_icon::{Integrity, ProtocolLimits, PROTOCOL_VERSION};
+
+/// The version of this daemon
+pub(super) const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug)]
+pub(super) struct Connection {
+    pub transport: Kind,
+    /// The version of the protocol both sides speak
+    pub version: u32,
+    /// The limits the agent asked for
+    pub peer_limits: ProtocolLimits,
+    /// The limits in effect, the stricter of both sides
+    pub limits: ProtocolLimits,
+    /// The tag the agent asked for
+    pub peer_integrity: Integrity,
+    /// The tag in effect
+    pub integrity: Integrity,
+}
+
+/// Log that the daemon is starting, and how it talks to the agent
+pub(super) fn starting(transport: Kind) {
+    log!(
+        Info,
+        fields = &[
+            ("SNI_ICON_VERSION", VERSION),
+            ("SNI_ICON_PROTOCOL", &PROTOCOL_VERSION.to_string()),
+            ("SNI_ICON_TRANSPORT", transport.as_str()),
+        ],
+        "sni-daemon {} starting, protocol version {}, transport {}",
+        VERSION,
+        PROTOCOL_VERSION,
+        transport.as_str(),
+    );
+}
+
+/// Log `connection`, once the handshake is done
+pub(super) fn established(connection: &Connection) {
+    log!(
+        Info,
+        fields = &[
+            ("SNI_ICON_PROTOCOL", &connection.version.to_string()),
+            ("SNI_ICON_TRANSPORT", connection.transport.as_str()),
+            ("SNI_ICON_LIMITS", &format!("{:?}", connection.limits)),
+            ("SNI_ICON_PEER_LIMITS", &format!("{:?}", connection.peer_limits)),
+            ("SNI_ICON_INTEGRITY", connection.integrity.as_str()),
+            ("SNI_ICON_PEER_INTEGRITY", connection.peer_integrity.as_str()),
+        ],
+        "Agent connected over {} with protocol version {}: limits {:?} (agent asked for {:?}), integrity tag {} (agent asked for {})",
+        connection.transport.as_str(),
+        connection.version,
+        connection.limits,
+        connection.peer_limits,
+        connection.integrity.as_str(),
+        connection.peer_integrity.as_str(),
+    );
+}
+
+fn describe_limits(details: &mut PropMap, prefix: &str, limits: &ProtocolLimits) {
+    for (key, value) in [
+        ("MaxMessageSize", limits.max_message_size),
+        ("MaxIconSize", limits.max_icon_size),
+        ("MaxFrames", limits.max_frames),
+        ("MaxMenus", limits.max_menus),
+    ] {
+        details.insert(format!("{}{}", prefix, key), Variant(Box::new(value)));
+    }
+}
+
+/// The details of `connection`, as listed by the control interface.  Only
+/// `Version` and `ProtocolVersion` are known before the handshake is done,
+/// when the latter is the newest version the daemon speaks rather than the
+/// one both sides speak.
+pub(super) fn details(connection: Option<&Connection>) -> PropMap {
+    let mut details = PropMap::new();
+    details.insert("Version".to_owned(), Variant(Box::new(VERSION.to_owned())));
+    details.insert(
+        "ProtocolVersion".to_owned(),
+        Variant(Box::new(PROTOCOL_VERSION)),
+    );
+    let Some(connection) = connection else {
+        return details;
+    };
+    for (key, value) in [
+        ("Transport", connection.transport.as_str()),
+        ("Integrity", connection.integrity.as_str()),
+        ("PeerIntegrity", connection.peer_integrity.as_str()),
+    ] {
+        details.insert(key.to_owned(), Variant(Box::new(value.to_owned())));
+    }
+    details.insert(
+        "ProtocolVersion".to_owned(),
+        Variant(Box::new(connection.version)),
+    );
+    describe_limits(&mut details, "", &connection.limits);
+    describe_limits(&mut details, "Peer", &connection.peer_limits);
+    details
+}
@@ -0,0 +1,36 @@
+//! The state of one daemon, shared by the handlers of its items
+//!
+//! Nothing a daemon serves is kept in globals, so that several daemons can
+//! run in one process.  What is process-wide by nature, such as the panic
+//! hook and the settings of logging, stays global.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::hosts::Tracker;
+use crate::item::Items;
+use crate::output::Output;
+use crate::stats::Stats;
+
+#[derive(Clone)]
+pub(super) struct Context {
+    pub items: Items,
+    pub output: Output,
+    pub stats: Stats,
+    pub hosts: Tracker,
+    /// The items with a click waiting for the user, see [`crate::confirm`]
+    pub confirming: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        let output = Output::default();
+        Self {
+            items: Items::default(),
+            hosts: Tracker::new(output.clone()),
+            output,
+            stats: Stats::default(),
+            confirming: Arc::default(),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! The daemon's control interface, exported on the session bus
+
+use crate::config::{self, Config};
+use crate::connection::Connection;
+use crate::item::Items;
+use dbus::arg::PropMap;
+use dbus_crossroads::{Crossroads, IfaceToken};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+pub(super) struct Control {
+    pub config: Arc<Mutex<Config>>,
+    pub items: Items,
+    /// See [`crate::apply_filter`]
+    pub suppressed: Arc<Mutex<HashSet<u64>>>,
+    /// What was agreed with the agent, once the handshake is done
+    pub connection: Arc<Mutex<Option<Connection>>>,
+}
+
+impl Control {
+    fn reload(&mut self) -> Result<(), dbus::MethodErr> {
+        let new_config = config::load().map_err(|e| {
+            log!(Error, "Not reloading configuration: {}", e);
+            dbus::MethodErr::failed(&e)
+        })?;
+        log!(
+            Info,
+            "Configuration reloaded from {}",
+            config::path().display()
+        );
+        crate::redact::set_raw_strings(new_config.log.raw_strings);
+        crate::logging::set_max_level(new_config.log.level);
+        crate::apply_filter(&new_config.filter, &self.items, &self.suppressed);
+        *self.config.lock().unwrap() = new_config;
+        Ok(())
+    }
+
+    /// Log messages up to `level` until the configuration is reloaded
+    fn set_log_level(&self, level: &str) -> Result<(), dbus::MethodErr> {
+        let level = crate::logging::Level::from_name(level).ok_or_else(|| {
+            dbus::MethodErr::invalid_arg(&format!("unknown log level {:?}", level))
+        })?;
+        crate::logging::set_max_level(level);
+        log!(
+            Error,
+            "Log level set to {} over the control interface",
+            level.name()
+        );
+        Ok(())
+    }
+
+    /// The details of each item, by ID, so that it can be told why one
+    /// looks stale
+    fn items(&self) -> HashMap<u64, PropMap> {
+        let items = self.items.lock().unwrap();
+        items.iter().map(|(&id, ni)| (id, ni.details())).collect()
+    }
+}
+
+pub(super) fn register_control(cr: &mut Crossroads) -> IfaceToken<Control> {
+    cr.register(sni_icon::names::interface_sni_icon_control(), |b| {
+        b.method(
+            sni_icon::names::reload(),
+            (),
+            (),
+            |_, control: &mut Control, ()| control.reload(),
+        );
+        b.method(
+            sni_icon::names::items(),
+            (),
+            ("items",),
+            |_, control: &mut Control, ()| Ok((control.items(),)),
+        );
+        b.method(
+            sni_icon::names::set_log_level(),
+            ("level",),
+            (),
+            |_, control: &mut Control, (level,): (String,)| control.set_log_level(&level),
+        );
+        b.method(
+            sni_icon::names::connection(),
+            (),
+            ("details",),
+            |_, control: &mut Control, ()| {
+                let connection = control.connection.lock().unwrap();
+                Ok((crate::connection::details(connection.as_ref()),))
+            },
+        );
+    })
+}
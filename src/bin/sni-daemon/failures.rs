@@ -0,0 +1,86 @@
+//! Telling the user about items that fail to handle clicks
+//!
+//! When a call the daemon forwarded to an item fails or times out, the agent
+//! reports it ([`sni_icon::ClientEvent::CallFailed`]).  Such calls are
+//! counted ([`crate::stats`]) and logged, and if
+//! [`crate::config::Notifications::failed_calls`] is set, a failed click or
+//! menu also shows a desktop notification, so that the user knows why
+//! nothing happened.  An application that keeps failing is reported at most
+//! once every [`INTERVAL`].
+//!
+//! Clicks on the items of a VM whose items are only displayed are not
+//! forwarded at all, which [`notify_user`] tells the user about as well, see
+//! [`crate::config::Notifications::read_only`].
+
+use dbus::channel::Sender as _;
+use dbus::nonblock::SyncConnection as Connection;
+use dbus::Message;
+use sni_icon::{names, Call};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::item::NotifierIcon;
+
+/// How long notifications about the same application are held back
+pub(super) const INTERVAL: Duration = Duration::from_secs(60);
+/// How long a notification is shown, in milliseconds
+const EXPIRE_TIMEOUT: i32 = 10_000;
+
+/// When the user was last told about each application
+#[derive(Default)]
+pub(super) struct Failures {
+    notified: HashMap<String, Instant>,
+}
+
+impl Failures {
+    /// Note that `call` to `ni`, an item of `vm`, failed, and tell the user
+    /// if `notify`
+    pub fn report(
+        &mut self,
+        c: &Connection,
+        vm: Option<&str>,
+        ni: &NotifierIcon,
+        call: Call,
+        timed_out: bool,
+        notify: bool,
+    ) {
+        if !notify || !matches!(call, Call::Activate | Call::ContextMenu) {
+            return;
+        }
+        let now = Instant::now();
+        self.notified
+            .retain(|_, notified| now.duration_since(*notified) < INTERVAL);
+        if self.notified.contains_key(ni.vm_app_id()) {
+            return;
+        }
+        self.notified.insert(ni.vm_app_id().to_owned(), now);
+        let app = ni.shown_title();
+        let what = if timed_out {
+            "did not respond"
+        } else {
+            "failed to respond"
+        };
+        let summary = match vm {
+            Some(vm) => format!("{}'s {} {}", vm, app, what),
+            None => format!("{} {}", app, what),
+        };
+        notify_user(c, &summary, "");
+    }
+}
+
+/// Show a desktop notification with `summary` and `body`
+pub(super) fn notify_user(c: &Connection, summary: &str, body: &str) {
+    let mut msg = Message::method_call(
+        &names::name_notifications(),
+        &names::path_notifications(),
+        &names::interface_notifications(),
+        &names::notify(),
+    )
+    .append3("sni-daemon", 0u32, "")
+    .append3(summary, body, Vec::<&str>::new())
+    .append2(dbus::arg::PropMap::new(), EXPIRE_TIMEOUT);
+    msg.set_no_reply(true);
+    if c.send(msg).is_err() {
+        log!(Warning, "Cannot send notification");
+    }
+}
@@ -0,0 +1,304 @@
+//! The StatusNotifierHosts on the bus, which are the only connections
+//! allowed to call methods of items if `access.hosts_only` is set
+//!
+//! Hosts own a name such as `org.kde.StatusNotifierHost-1234`, which they
+//! register with the watcher.  Any connection may own such a name, so only
+//! the owners of names that registered are hosts.  The watcher announces
+//! that a host registered without telling which, and hosts register right
+//! after taking their name, so the owner of the name that appeared last,
+//! within [`REGISTRATION`], is taken to be that host.  The watcher does not
+//! tell when the last host goes away either, so hosts are forgotten when
+//! they lose their name, from `NameOwnerChanged`.  The owners of names
+//! present before the daemon started cannot be told apart, and are all
+//! hosts if the watcher says that a host is registered.
+//!
+//! The agent is told whether any host is present
+//! ([`sni_icon::ServerEvent::Hosts`]), so that it does not forward icons
+//! nobody would see.  It is only told that the last host went away once
+//! none came back for [`GRACE`], so that a panel that keeps crashing and
+//! restarting does not make it send icons again and again.
+
+use dbus::nonblock::{MsgMatch, Proxy, SyncConnection as Connection};
+use dbus::Message;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sni_icon::client::watcher::StatusNotifierWatcher as _;
+use sni_icon::names;
+
+use crate::output::Output;
+
+/// How long no host must be present before the agent is told so
+const GRACE: Duration = Duration::from_secs(10);
+/// How long after taking its name a host may register with the watcher
+const REGISTRATION: Duration = Duration::from_secs(5);
+
+fn is_host_name(name: &str) -> bool {
+    [
+        "org.kde.StatusNotifierHost-",
+        "org.freedesktop.StatusNotifierHost-",
+    ]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+}
+
+/// The owners of host names
+#[derive(Debug, Default)]
+struct Hosts {
+    /// The unique names of the hosts that registered with the watcher, by
+    /// the host names they own
+    registered: HashMap<String, String>,
+    /// The host names that did not register yet, with their owners and
+    /// when they appeared, oldest first
+    pending: Vec<(String, String, Instant)>,
+}
+
+impl Hosts {
+    /// `name` is now owned by `owner`, or by nobody if `owner` is empty
+    fn set_owner(&mut self, name: &str, owner: &str, now: Instant) {
+        self.registered.remove(name);
+        self.pending.retain(|(pending, _, _)| pending != name);
+        if !owner.is_empty() {
+            self.pending.push((name.to_owned(), owner.to_owned(), now));
+        }
+    }
+
+    /// The watcher announced that a host registered
+    fn registered(&mut self, now: Instant) {
+        self.pending
+            .retain(|&(_, _, appeared)| now.duration_since(appeared) <= REGISTRATION);
+        if let Some((name, owner, _)) = self.pending.pop() {
+            self.registered.insert(name, owner);
+        }
+    }
+
+    fn is_present(&self) -> bool {
+        !self.registered.is_empty()
+    }
+
+    fn may_call(&self, sender: &str) -> bool {
+        self.registered.values().any(|owner| owner == sender)
+    }
+}
+
+/// The hosts as a daemon sees them, and what its agent was told of them
+#[derive(Clone)]
+pub(super) struct Tracker(Arc<State>);
+
+struct State {
+    /// The hosts, once tracked
+    hosts: Mutex<Option<Hosts>>,
+    /// What the agent was told last.  [`None`] before the handshake.
+    announced: Mutex<Option<bool>>,
+    /// Whether only hosts may call methods of items
+    restricted: AtomicBool,
+    output: Output,
+}
+
+impl Tracker {
+    /// Hosts of a daemon telling its agent about them through `output`
+    pub fn new(output: Output) -> Self {
+        Self(Arc::new(State {
+            hosts: Mutex::new(None),
+            announced: Mutex::new(None),
+            restricted: AtomicBool::new(false),
+            output,
+        }))
+    }
+
+    fn is_present(&self) -> bool {
+        self.0
+            .hosts
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(Hosts::is_present)
+    }
+
+    /// Apply `change` to the hosts, and tell the agent if that changed
+    /// whether any is present
+    fn update(&self, change: impl FnOnce(&mut Hosts)) {
+        {
+            let mut hosts = self.0.hosts.lock().unwrap();
+            let Some(hosts) = &mut *hosts else { return };
+            change(hosts);
+        }
+        if self.is_present() {
+            self.tell(true)
+        } else {
+            let tracker = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(GRACE).await;
+                if !tracker.is_present() {
+                    tracker.tell(false)
+                }
+            });
+        }
+    }
+
+    /// Tell the agent whether any host is present, if that changed since it
+    /// was told last
+    fn tell(&self, present: bool) {
+        let mut announced = self.0.announced.lock().unwrap();
+        // Before the handshake, the agent is told by [`Tracker::announce`]
+        if announced.is_some_and(|announced| announced != present) {
+            *announced = Some(present);
+            self.send(present)
+        }
+    }
+
+    fn send(&self, present: bool) {
+        log!(Info, "Host present: {}", present);
+        self.0.output.send(sni_icon::IconServerEvent {
+            id: 0,
+            event: sni_icon::ServerEvent::Hosts { present },
+        })
+    }
+
+    /// Tell the agent whether any host is present, once the handshake is
+    /// done
+    pub fn announce(&self) {
+        let mut announced = self.0.announced.lock().unwrap();
+        let present = self.is_present();
+        *announced = Some(present);
+        self.send(present)
+    }
+
+    /// Track the hosts from now on, until the returned matches are dropped.
+    /// If `restrict` is set, only they may call methods of items.
+    pub async fn track(
+        &self,
+        c: &Connection,
+        restrict: bool,
+    ) -> Result<(MsgMatch, MsgMatch), Box<dyn Error>> {
+        *self.0.hosts.lock().unwrap() = Some(Hosts::default());
+        self.0.restricted.store(restrict, Ordering::Relaxed);
+        let tracker = self.clone();
+        let owners = c.add_match(names::name_owner_changed_rule()).await?.cb(
+            move |_, (name, _, owner): (String, String, String)| {
+                if is_host_name(&name) {
+                    tracker.update(|hosts| hosts.set_owner(&name, &owner, Instant::now()))
+                }
+                true
+            },
+        );
+        let tracker = self.clone();
+        let registrations = c
+            .add_match(names::status_notifier_host_registered_rule())
+            .await?
+            .cb(move |_, ()| {
+                tracker.update(|hosts| hosts.registered(Instant::now()));
+                true
+            });
+        // Hosts that were there before the matches were added
+        let bus = Proxy::new(
+            names::name_dbus(),
+            names::path_dbus(),
+            Duration::from_secs(1),
+            c,
+        );
+        let watcher = Proxy::new(
+            names::name_status_notifier_watcher(),
+            names::path_status_notifier_watcher(),
+            Duration::from_secs(1),
+            c,
+        );
+        let registered = watcher
+            .is_status_notifier_host_registered()
+            .await
+            .unwrap_or(false);
+        let (bus_names,): (Vec<String>,) = bus
+            .method_call(names::interface_dbus(), names::list_names(), ())
+            .await?;
+        for name in bus_names.into_iter().filter(|name| is_host_name(name)) {
+            let owner: Result<(String,), _> = bus
+                .method_call(names::interface_dbus(), names::get_name_owner(), (&*name,))
+                .await;
+            if let Ok((owner,)) = owner {
+                self.update(|hosts| {
+                    hosts.set_owner(&name, &owner, Instant::now());
+                    if registered {
+                        hosts.registered(Instant::now())
+                    }
+                })
+            }
+        }
+        Ok((owners, registrations))
+    }
+
+    /// Whether the sender of `msg` may call methods of items
+    pub fn may_call(&self, msg: &Message) -> bool {
+        if !self.0.restricted.load(Ordering::Relaxed) {
+            return true;
+        }
+        match &*self.0.hosts.lock().unwrap() {
+            // Not tracked yet
+            None => false,
+            Some(hosts) => msg.sender().is_some_and(|sender| hosts.may_call(&sender)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "org.kde.StatusNotifierHost-1234";
+    const FAKE: &str = "org.kde.StatusNotifierHost-6666";
+
+    #[test]
+    fn registered_hosts_may_call() {
+        let now = Instant::now();
+        let mut hosts = Hosts::default();
+        hosts.set_owner(HOST, ":1.10", now);
+        assert!(!hosts.may_call(":1.10"));
+        hosts.registered(now + Duration::from_millis(10));
+        assert!(hosts.is_present());
+        assert!(hosts.may_call(":1.10"));
+        hosts.set_owner(HOST, "", now + Duration::from_secs(60));
+        assert!(!hosts.is_present());
+        assert!(!hosts.may_call(":1.10"));
+    }
+
+    #[test]
+    fn self_named_hosts_are_refused() {
+        let now = Instant::now();
+        let mut hosts = Hosts::default();
+        hosts.set_owner(HOST, ":1.10", now);
+        hosts.registered(now);
+        // Takes a host name but does not register
+        hosts.set_owner(FAKE, ":1.66", now + Duration::from_secs(1));
+        assert!(!hosts.may_call(":1.66"));
+        // Nor does a later registration of another host count for it
+        hosts.registered(now + REGISTRATION + Duration::from_secs(2));
+        assert!(!hosts.may_call(":1.66"));
+        assert!(hosts.may_call(":1.10"));
+    }
+
+    #[test]
+    fn the_last_name_to_appear_registered() {
+        let now = Instant::now();
+        let mut hosts = Hosts::default();
+        hosts.set_owner(FAKE, ":1.66", now);
+        hosts.set_owner(HOST, ":1.10", now + Duration::from_millis(10));
+        hosts.registered(now + Duration::from_millis(20));
+        assert!(hosts.may_call(":1.10"));
+        assert!(!hosts.may_call(":1.66"));
+    }
+
+    #[test]
+    fn hosts_losing_their_name_are_forgotten() {
+        let now = Instant::now();
+        let mut hosts = Hosts::default();
+        hosts.set_owner(HOST, ":1.10", now);
+        hosts.registered(now);
+        // Another connection takes the name over
+        hosts.set_owner(HOST, ":1.66", now + Duration::from_secs(1));
+        assert!(!hosts.may_call(":1.10"));
+        assert!(!hosts.may_call(":1.66"));
+        assert!(!hosts.is_present());
+    }
+}
@@ -0,0 +1,260 @@
+//! The icons of a VM, kept on disk so that they need not be sent again
+//!
+//! Icons the VM sends are saved by their hash ([`sni_icon::icon_hash`]),
+//! before the border is drawn, one file per icon in a directory per VM.
+//! After the handshake the agent is told which icons are kept
+//! ([`ServerEvent::CachedIcons`]), and sends only the hash of those and of
+//! icons it sent since ([`ClientEvent::CachedIcon`]).  Icons come from the
+//! VM and the files may have been changed, so an icon is checked against its
+//! hash and the limits of the VM whenever it is loaded.  One that is missing
+//! or fails is asked for again ([`ServerEvent::CacheMiss`]).  Once the icons
+//! take more than [`crate::config::IconCache::max_bytes`], the least recently
+//! used are removed.
+//!
+//! [`ServerEvent::CachedIcons`]: sni_icon::ServerEvent::CachedIcons
+//! [`ClientEvent::CachedIcon`]: sni_icon::ClientEvent::CachedIcon
+//! [`ServerEvent::CacheMiss`]: sni_icon::ServerEvent::CacheMiss
+
+use bincode::Options as _;
+use sni_icon::{IconData, IconHash};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config;
+
+/// Most icons listed in [`sni_icon::ServerEvent::CachedIcons`], so that it
+/// fits into the smallest message size limit
+const MAX_ANNOUNCED: usize = 1024;
+/// The extension of the files icons are saved to
+const EXTENSION: &str = "icon";
+
+/// An icon that is kept
+struct Entry {
+    /// The size of its file
+    size: u64,
+    /// When it was last sent or used
+    used: SystemTime,
+}
+
+/// The icons kept for a VM
+pub(super) struct IconCache {
+    /// Where the icons are kept, if anywhere
+    directory: Option<PathBuf>,
+    entries: HashMap<IconHash, Entry>,
+    /// The size of all entries
+    size: u64,
+    max_bytes: u64,
+}
+
+impl IconCache {
+    /// The icons kept for `vm`, where `config` says
+    pub fn new(config: &config::IconCache, vm: Option<&str>) -> Self {
+        let mut cache = Self {
+            directory: config.enabled.then(|| directory(config, vm)).flatten(),
+            entries: HashMap::new(),
+            size: 0,
+            max_bytes: config.max_bytes,
+        };
+        if let Some(directory) = &cache.directory {
+            cache.entries = scan(directory);
+            cache.size = cache.entries.values().map(|entry| entry.size).sum();
+            cache.evict();
+        }
+        cache
+    }
+
+    /// Whether icons are kept at all
+    pub fn enabled(&self) -> bool {
+        self.directory.is_some()
+    }
+
+    /// The directory icons are kept in, if they are kept at all
+    #[cfg(feature = "sandbox")]
+    pub fn directory(&self) -> Option<&Path> {
+        self.directory.as_deref()
+    }
+
+    /// The hashes of the icons kept, most recently used first, as many as
+    /// are announced to the agent
+    pub fn hashes(&self) -> Vec<IconHash> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_unstable_by_key(|(_, entry)| std::cmp::Reverse(entry.used));
+        entries
+            .into_iter()
+            .take(MAX_ANNOUNCED)
+            .map(|(&hash, _)| hash)
+            .collect()
+    }
+
+    /// The frames of the icon with `hash`, if it is kept and still valid
+    /// for a VM with `limits`
+    pub fn get(&mut self, hash: &IconHash, limits: &config::Limits) -> Option<Vec<IconData>> {
+        let directory = self.directory.as_ref()?;
+        self.entries.get(hash)?;
+        let path = path(directory, hash);
+        match load(&path, limits).filter(|frames| sni_icon::icon_hash(frames) == *hash) {
+            Some(frames) => {
+                let now = SystemTime::now();
+                if let Ok(file) = std::fs::File::options().write(true).open(&path) {
+                    let _: Result<_, _> = file.set_modified(now);
+                }
+                if let Some(entry) = self.entries.get_mut(hash) {
+                    entry.used = now;
+                }
+                Some(frames)
+            }
+            None => {
+                log!(Warning, "Removing invalid cached icon {}", path.display());
+                self.remove(hash);
+                None
+            }
+        }
+    }
+
+    /// Keep the icon with `frames`, which passed [`crate::check_icon`]
+    pub fn insert(&mut self, frames: &[IconData]) {
+        let Some(directory) = &self.directory else {
+            return;
+        };
+        if frames.is_empty() {
+            return;
+        }
+        let hash = sni_icon::icon_hash(frames);
+        let now = SystemTime::now();
+        if let Some(entry) = self.entries.get_mut(&hash) {
+            entry.used = now;
+            return;
+        }
+        let path = path(directory, &hash);
+        let data = sni_icon::encoding()
+            .serialize(frames)
+            .expect("frames can always be serialized");
+        if let Err(e) = crate::state::write(&path, &data) {
+            return log!(Warning, "Cannot save icon to {}: {}", path.display(), e);
+        }
+        let size = data.len() as u64;
+        self.entries.insert(hash, Entry { size, used: now });
+        self.size += size;
+        self.evict();
+    }
+
+    /// Forget the icon with `hash` and remove its file
+    fn remove(&mut self, hash: &IconHash) {
+        let Some(entry) = self.entries.remove(hash) else {
+            return;
+        };
+        self.size -= entry.size;
+        let Some(directory) = &self.directory else {
+            return;
+        };
+        let path = path(directory, hash);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log!(Warning, "Cannot remove {}: {}", path.display(), e)
+            }
+            _ => {}
+        }
+    }
+
+    /// Remove the least recently used icons until the rest fit
+    fn evict(&mut self) {
+        if self.size <= self.max_bytes {
+            return;
+        }
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(&hash, entry)| (entry.used, hash))
+            .collect();
+        entries.sort_unstable();
+        for (_, hash) in entries {
+            if self.size <= self.max_bytes {
+                break;
+            }
+            self.remove(&hash);
+        }
+    }
+}
+
+/// Where the icons of `vm` are kept, if anywhere
+fn directory(config: &config::IconCache, vm: Option<&str>) -> Option<PathBuf> {
+    let Some(directory) = config.directory.clone().or_else(|| {
+        let cache = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(cache.join("sni-daemon").join("icons"))
+    }) else {
+        log!(Warning, "Not keeping icons: no cache directory");
+        return None;
+    };
+    let name = match vm {
+        None => "local",
+        Some(vm) if crate::state::is_plain(vm) => vm,
+        Some(vm) => {
+            log!(Warning, "Not keeping icons of VM {:?}", vm);
+            return None;
+        }
+    };
+    Some(directory.join(name))
+}
+
+/// The file the icon with `hash` is saved to
+fn path(directory: &Path, hash: &IconHash) -> PathBuf {
+    let name: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+    directory.join(name).with_extension(EXTENSION)
+}
+
+/// The hash an icon file is named by, if it is named like one
+fn hash_of(path: &Path) -> Option<IconHash> {
+    if path.extension()? != EXTENSION {
+        return None;
+    }
+    let name = path.file_stem()?.to_str()?;
+    if name.len() != 64 || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&name[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// The icons saved in `directory`, without loading them
+fn scan(directory: &Path) -> HashMap<IconHash, Entry> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            log!(Warning, "Cannot read {}: {}", directory.display(), e);
+            return HashMap::new();
+        }
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|file| {
+            let hash = hash_of(&file.path())?;
+            let metadata = file.metadata().ok().filter(|m| m.is_file())?;
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            let size = metadata.len();
+            Some((hash, Entry { size, used }))
+        })
+        .collect()
+}
+
+/// The frames saved at `path`, if they are valid for a VM with `limits`
+fn load(path: &Path, limits: &config::Limits) -> Option<Vec<IconData>> {
+    // The frames, their sizes and lengths, and the length of the list
+    let max_size = limits.max_icon_bytes as u64 + 16 * u64::from(limits.max_frames) + 8;
+    let data = std::fs::read(path).ok()?;
+    if data.len() as u64 > max_size {
+        return None;
+    }
+    let frames: Vec<IconData> = sni_icon::encoding()
+        .with_limit(max_size)
+        .deserialize(&data)
+        .ok()?;
+    crate::check_icon(&frames, limits).ok()?;
+    Some(frames)
+}
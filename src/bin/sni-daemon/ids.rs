@@ -0,0 +1,51 @@
+//! IDs the agent gave to the items it created
+//!
+//! The agent never gives an ID to two items, so that events of a destroyed
+//! item cannot be taken for those of a new one.  IDs need not increase: a
+//! restarted agent gives items that register again their old IDs, in the
+//! order they register, whether or not the daemon restored them (see
+//! [`crate::state`]).
+
+use std::collections::HashSet;
+
+#[derive(Debug, Default)]
+pub(super) struct Ids {
+    created: HashSet<u64>,
+}
+
+impl Ids {
+    /// Whether no item was created with `id` on this connection
+    pub fn is_new(&self, id: u64) -> bool {
+        !self.created.contains(&id)
+    }
+
+    /// Remember that an item was created with `id`
+    pub fn created(&mut self, id: u64) {
+        self.created.insert(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_ids_are_accepted_in_any_order() {
+        // An agent restarted with IDs 1 and 2 saved, and state disabled, so
+        // that nothing was restored: the items register again in reverse
+        // order, and a new one gets an ID above the saved ones
+        let mut ids = Ids::default();
+        for id in [2, 1, 3] {
+            assert!(ids.is_new(id), "{}", id);
+            ids.created(id);
+        }
+    }
+
+    #[test]
+    fn ids_are_not_reused() {
+        let mut ids = Ids::default();
+        ids.created(2);
+        assert!(!ids.is_new(2));
+        assert!(ids.is_new(1));
+    }
+}
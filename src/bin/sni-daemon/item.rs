@@ -1,50 +1,131 @@
+use dbus::arg::{PropMap, RefArg, Variant};
 use dbus::channel::{MatchingReceiver as _, Sender as _};
 use dbus::message::SignalArgs as _;
 use dbus::nonblock::SyncConnection as Connection;
-use dbus::strings::{ErrorName, Path};
-use dbus_crossroads::Crossroads;
+use dbus::strings::{BusName, Path};
+use dbus::Message;
+use dbus_crossroads::{Crossroads, IfaceToken};
 use futures_util::future::{AbortHandle, Abortable};
-use sni_icon::{server, IconServerEvent};
-use std::io::Write as _;
+use sni_icon::request::Pending;
+use sni_icon::{
+    server, Capabilities, IconServerEvent, IconType, Reply, Request, RequestId, SafeText,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use bincode::Options as _;
+use std::time::{Duration, Instant};
 
-use sni_icon::{names::path_status_notifier_item as path, IconData, ServerEvent};
+use sni_icon::{names, IconData, ServerEvent};
 
-fn send_or_panic<T: serde::Serialize>(s: T) {
-    let options = bincode::DefaultOptions::new()
-        .with_fixint_encoding()
-        .with_native_endian()
-        .reject_trailing_bytes();
-    let mut out = std::io::stdout().lock();
-    let v = options.serialize(&s).expect("Cannot encode data");
-    eprintln!("Sending {} bytes", v.len());
-    out.write_all(&((v.len() as u32).to_le_bytes())[..])
-        .expect("cannot write to stdout");
-    out.write_all(&v[..]).expect("cannot write to stdout");
-    out.flush().expect("Cannot flush stdout");
+use crate::activity::Activity;
+use crate::chunks::Chunks;
+use crate::config::{ItemSettings, Missing};
+use crate::context::Context;
+use crate::menu::Menu;
+use crate::output::Output;
+use crate::scroll;
+use crate::state::Item;
+use crate::status;
+
+/// The items of a daemon, by ID
+pub(super) type Items = Arc<Mutex<HashMap<u64, NotifierIcon>>>;
+
+/// A method call that is answered when the VM replies to a request
+pub(super) enum Waiting {
+    AboutToShow(Message),
 }
 
 pub(super) struct NotifierIcon {
     id: u64,
+    /// The ID the interfaces of the item look it up by, which changes along
+    /// with `id` when the item is reattached, see [`crate::linger`]
+    key: Arc<AtomicU64>,
+    /// The object path of the item, see [`names::path_sni_icon_item`]
+    path: Path<'static>,
     connection: Arc<Connection>,
+    /// The state of the daemon of the item
+    context: Context,
     category: String,
     app_id: String,
+    vm_app_id: String,
 
     tooltip: Option<sni_icon::Tooltip>,
     title: Option<String>,
     status: Option<String>,
+    /// Descriptions of the icon and the attention icon for screen readers,
+    /// see [`sni_icon::ClientEvent::AccessibleDesc`]
+    accessible_desc: Option<SafeText>,
+    attention_accessible_desc: Option<SafeText>,
 
     icon: Option<Vec<IconData>>,
     attention_icon: Option<Vec<IconData>>,
     overlay_icon: Option<Vec<IconData>>,
-    is_menu: bool,
+    /// The icon with the overlay icon or badges drawn onto it, if any are to
+    /// be drawn, see [`crate::config::Icons`]
+    composited_icon: Option<Vec<IconData>>,
+    /// The number of items the VM exports, for the count badge
+    count: usize,
+    /// Where hosts should show the item, see [`crate::order`]
+    ordering_index: u32,
+    /// Whether the item was restored from a previous run, and has not been
+    /// taken over by an item of the agent yet, see [`crate::state`]
+    restored: bool,
+    /// Whether the agent destroyed the item, which is kept for a while in
+    /// case its application comes back, see [`crate::linger`]
+    lingering: bool,
+    /// Whether the VM said that the item does not answer calls
+    unresponsive: bool,
+    /// As created by the agent, less a menu if menus are not allowed
+    capabilities: Capabilities,
+    /// Whether the menu object is to be exported, which follows
+    /// `capabilities`, see [`Exports`]
+    exports_menu: Arc<AtomicBool>,
+    menu: Option<Menu>,
+    /// Incremented whenever the menu changes
+    menu_revision: u32,
+    /// Method calls waiting for the VM to answer a request
+    pending: Pending<Waiting>,
+    settings: Arc<ItemSettings>,
+    /// The answer to `GetAll`, computed when first needed after a change
+    properties: Option<Arc<PropMap>>,
+    /// Icon frames being received in chunks
+    chunks: Chunks,
+    /// Partial steps scrolled over the item
+    scroll: scroll::Accumulator,
+    /// Status changes not shown yet
+    hysteresis: status::Hysteresis,
+    /// What the VM sent for the item, see [`crate::activity`]
+    activity: Activity,
+    /// Signals held back until the update being applied is complete, see
+    /// [`NotifierIcon::begin_update`]
+    held: Option<Vec<Message>>,
+    /// When the user was last told that the item is only displayed, see
+    /// [`NotifierIcon::accepts_input`]
+    read_only_notified: Option<Instant>,
 
     abort_handle: AbortHandle,
+    socket: RawFd,
 }
 
 impl Drop for NotifierIcon {
     fn drop(&mut self) {
+        for waiting in self.pending.cancel_item(self.id) {
+            let Waiting::AboutToShow(msg) = waiting;
+            let e = dbus::MethodErr::failed("Item was destroyed");
+            let _ = self.connection.send(e.to_message(&msg));
+        }
+        // Tell hosts to hide the item even before the bus notices that the
+        // connection is gone
+        let _ = self.connection.send(
+            (server::item::StatusNotifierItemNewStatus {
+                status: sni_icon::spec::Status::Passive.as_str().to_owned(),
+            })
+            .to_emit_message(&self.path),
+        );
+        AsRef::<dbus::channel::Channel>::as_ref(&*self.connection).flush();
+        crate::panic::unregister(self.socket);
         self.abort_handle.abort()
     }
 }
@@ -53,128 +134,827 @@ impl NotifierIcon {
     pub fn new(
         id: u64,
         app_id: String,
+        vm_app_id: String,
         category: String,
-        cr: Arc<Mutex<Crossroads>>,
-        is_menu: bool,
+        context: &Context,
+        capabilities: Capabilities,
+        settings: Arc<ItemSettings>,
     ) -> Self {
-        eprintln!("Creating new notifier icon");
+        log!(Debug, id = id, "Creating new notifier icon");
         let (resource, connection) =
-            dbus_tokio::connection::new_session_sync().expect("Cannot connect to session bus");
+            sni_icon::session::connect().expect("Cannot connect to session bus");
         let (abort_handle, abort_registration) = AbortHandle::new_pair();
-        tokio::task::spawn_local(Abortable::new(resource, abort_registration));
+        tokio::spawn(Abortable::new(resource, abort_registration));
+        let socket = crate::panic::register(&connection);
+        let path = names::path_sni_icon_item(settings.vm.as_deref(), id);
+        let key = Arc::new(AtomicU64::new(id));
+        let exports_menu = Arc::new(AtomicBool::new(
+            capabilities.contains(Capabilities::HAS_MENU),
+        ));
+        let icon = NotifierIconWrapper {
+            context: context.clone(),
+            key: key.clone(),
+            path: path.clone(),
+            exports_menu: exports_menu.clone(),
+        };
+        let cr = Arc::new(Mutex::new(Exports::new(&icon)));
+        #[cfg(feature = "testing")]
+        let weak = Arc::downgrade(&connection);
         connection.start_receive(
             dbus::message::MatchRule::new_method_call(),
             Box::new(move |msg, conn| {
-                super::ID.with(|id_| id_.set(id));
-                cr.lock().unwrap().handle_message(msg, conn).unwrap();
+                #[cfg(feature = "testing")]
+                if let Some(delay) = sni_icon::fault::reply_delay() {
+                    let (weak, icon, cr) = (weak.clone(), icon.clone(), cr.clone());
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        if let Some(conn) = weak.upgrade() {
+                            dispatch(&icon, &cr, msg, &conn)
+                        }
+                    });
+                    return true;
+                }
+                dispatch(&icon, &cr, msg, conn);
                 true
             }),
         );
         Self {
             id,
+            key,
+            path,
             app_id,
+            vm_app_id,
             category,
 
             connection,
+            context: context.clone(),
             tooltip: None,
             title: None,
             status: None,
+            accessible_desc: None,
+            attention_accessible_desc: None,
             icon: None,
             attention_icon: None,
             overlay_icon: None,
-            is_menu,
+            composited_icon: None,
+            count: 0,
+            ordering_index: 0,
+            restored: false,
+            lingering: false,
+            unresponsive: false,
+            capabilities,
+            exports_menu,
+            menu: None,
+            menu_revision: 0,
+            pending: Pending::new(),
+            settings,
+            properties: None,
+            chunks: Chunks::default(),
+            scroll: Default::default(),
+            hysteresis: Default::default(),
+            activity: Activity::default(),
+            held: None,
+            read_only_notified: None,
             abort_handle,
+            socket,
+        }
+    }
+    /// Hold back signals until [`NotifierIcon::end_update`], so that hosts
+    /// only see the item once all changes of an update are applied
+    pub fn begin_update(&mut self) {
+        self.held.get_or_insert_with(Vec::new);
+    }
+    /// Send the signals held back since [`NotifierIcon::begin_update`]
+    pub fn end_update(&mut self) {
+        for msg in self.held.take().into_iter().flatten() {
+            self.connection.send(msg).unwrap();
+        }
+    }
+    /// Send the signal `msg`, or hold it back during an update.  A held back
+    /// signal replaces an earlier one of the same name, as hosts fetch the
+    /// property again anyway.
+    fn emit(&mut self, msg: Message) {
+        match &mut self.held {
+            Some(held) => {
+                held.retain(|earlier| {
+                    (earlier.path(), earlier.member()) != (msg.path(), msg.member())
+                });
+                held.push(msg)
+            }
+            None => {
+                self.connection.send(msg).unwrap();
+            }
         }
     }
     pub fn set_title(&mut self, title: Option<String>) {
+        self.properties = None;
         self.title = title;
-        self.connection
-            .send((server::item::StatusNotifierItemNewTitle {}).to_emit_message(&path()))
-            .unwrap();
+        self.emit((server::item::StatusNotifierItemNewTitle {}).to_emit_message(&self.path));
+    }
+    /// The title shown: the one of the item, or else the default one
+    pub fn shown_title(&self) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            self.settings
+                .defaults
+                .title
+                .clone()
+                .unwrap_or_else(|| self.vm_app_id.clone())
+        })
+    }
+    /// Set the description of icon `typ` for screen readers
+    pub fn set_accessible_desc(&mut self, typ: IconType, desc: Option<SafeText>) {
+        self.properties = None;
+        let msg = match typ {
+            IconType::Normal => {
+                self.accessible_desc = desc;
+                (server::item::StatusNotifierItemNewIcon {}).to_emit_message(&self.path)
+            }
+            IconType::Attention => {
+                self.attention_accessible_desc = desc;
+                (server::item::StatusNotifierItemNewAttentionIcon {}).to_emit_message(&self.path)
+            }
+            _ => panic!("guest sent bad icon type"),
+        };
+        self.emit(msg);
+    }
+    pub fn set_menu(&mut self, menu: Option<Menu>) {
+        self.properties = None;
+        let update = match (&self.menu, &menu) {
+            (Some(old), Some(new)) => old.diff(new),
+            _ => None,
+        };
+        self.menu = menu;
+        let msg = match update {
+            // Only properties changed, so hosts need not fetch the layout
+            Some(update) if update.updated_props.is_empty() => return,
+            Some(update) => update.to_emit_message(&names::path_menu()),
+            None => {
+                self.menu_revision = self.menu_revision.wrapping_add(1);
+                (server::menu::DbusmenuLayoutUpdated {
+                    revision: self.menu_revision,
+                    parent: 0,
+                })
+                .to_emit_message(&names::path_menu())
+            }
+        };
+        self.emit(msg);
+    }
+    /// Update checkmarks and radio buttons after entry `id` was clicked
+    pub fn menu_entry_clicked(&mut self, id: i32) {
+        if let Some(menu) = &mut self.menu {
+            let changed = menu.toggle(id);
+            if !changed.is_empty() {
+                self.connection
+                    .send(
+                        menu.properties_updated(&changed)
+                            .to_emit_message(&names::path_menu()),
+                    )
+                    .unwrap();
+            }
+        }
+    }
+    /// Send `body` to the VM, keeping `waiting` until the VM answers or
+    /// `timeout` passes.  The caller must call
+    /// [`NotifierIcon::expire_requests`] once the timeout has passed.
+    pub fn request(&mut self, body: Request, waiting: Waiting, timeout: Duration) {
+        let request = self.pending.insert(self.id, waiting, Some(timeout));
+        self.context.output.send(IconServerEvent {
+            id: self.id,
+            event: ServerEvent::Request { request, body },
+        });
+    }
+    /// Answer the method call waiting for `request`
+    pub fn reply(&mut self, request: RequestId, body: Reply) {
+        let msg = match (self.pending.remove(request), body) {
+            (Some(Waiting::AboutToShow(msg)), Reply::AboutToShow { need_update }) => {
+                msg.method_return().append1(need_update)
+            }
+            (None, _) => {
+                log!(
+                    Debug,
+                    id = self.id,
+                    "Reply to request {} arrived too late",
+                    request
+                );
+                return;
+            }
+        };
+        let _ = self.connection.send(msg);
+    }
+    /// Answer the method calls whose requests the VM did not answer in time
+    pub fn expire_requests(&mut self) {
+        for (request, waiting) in self.pending.expired() {
+            log!(Debug, id = self.id, "Request {} timed out", request);
+            let msg = match waiting {
+                Waiting::AboutToShow(msg) => msg.method_return().append1(false),
+            };
+            let _ = self.connection.send(msg);
+        }
+    }
+    pub fn chunks(&mut self) -> &mut Chunks {
+        &mut self.chunks
     }
-    pub fn bus_path(&self) -> String {
+    pub fn activity(&mut self) -> &mut Activity {
+        &mut self.activity
+    }
+    /// What the control interface lists about the item
+    pub fn details(&self) -> PropMap {
+        let mut details = PropMap::new();
+        let status = self.status.clone();
+        let status = status.unwrap_or_else(|| self.settings.defaults.status.clone());
+        for (key, value) in [
+            ("AppId", self.vm_app_id.clone()),
+            ("Title", self.shown_title()),
+            ("Status", status),
+        ] {
+            details.insert(key.to_owned(), Variant(Box::new(value)));
+        }
+        let responsive = !self.unresponsive;
+        details.insert("Responsive".to_owned(), Variant(Box::new(responsive)));
+        self.activity.describe(&mut details);
+        details
+    }
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    /// The current menu and its revision
+    pub fn menu(&self) -> Option<(u32, &Menu)> {
+        self.menu.as_ref().map(|menu| (self.menu_revision, menu))
+    }
+    /// Request `name` for the connection of this item, failing if another
+    /// connection already has it
+    pub async fn request_name(&self, name: BusName<'static>) -> Result<(), Box<dyn Error>> {
+        use dbus::nonblock::stdintf::org_freedesktop_dbus::RequestNameReply;
+        match self
+            .connection
+            .request_name(name.clone(), false, false, true)
+            .await?
+        {
+            RequestNameReply::PrimaryOwner => Ok(()),
+            reply => Err(format!("Cannot own {}: {:?}", name, reply).into()),
+        }
+    }
+    /// The unique name of the connection of this item
+    pub fn bus_name(&self) -> String {
         self.connection.unique_name().to_string()
     }
-    pub fn set_tooltip(&mut self, tooltip: Option<sni_icon::Tooltip>) {
+    pub fn path(&self) -> &Path<'static> {
+        &self.path
+    }
+    /// The VM the item belongs to, if the daemon was started by qrexec
+    pub fn vm(&self) -> Option<&str> {
+        self.settings.vm.as_deref()
+    }
+    /// The application ID as sent by the VM, before prefixing or hashing
+    pub fn vm_app_id(&self) -> &str {
+        &self.vm_app_id
+    }
+    /// The item as saved, see [`crate::state`]
+    pub fn saved(&self) -> Item {
+        Item {
+            id: self.id,
+            app_id: self.app_id.clone(),
+            vm_app_id: self.vm_app_id.clone(),
+            category: self.category.clone(),
+            capabilities: self.capabilities,
+            title: self.title.clone(),
+            status: self.status.clone(),
+        }
+    }
+    /// Make this a placeholder for `saved`, until [`NotifierIcon::take_over`]
+    pub fn restore(&mut self, saved: Item) {
+        self.restored = true;
+        self.set_title(saved.title);
+        self.set_status(saved.status);
+    }
+    /// Set where hosts should show the item, before it is registered
+    pub fn set_ordering_index(&mut self, index: u32) {
+        self.ordering_index = index;
+    }
+    pub fn is_restored(&self) -> bool {
+        self.restored
+    }
+    /// Keep the item, greyed out, after the agent destroyed it, until it is
+    /// reattached or removed
+    pub fn linger(&mut self) {
+        for waiting in self.pending.cancel_item(self.id) {
+            let Waiting::AboutToShow(msg) = waiting;
+            let e = dbus::MethodErr::failed("Item was destroyed");
+            let _ = self.connection.send(e.to_message(&msg));
+        }
+        self.chunks = Chunks::default();
+        self.lingering = true;
+        self.properties = None;
+        self.composite();
+        self.emit((server::item::StatusNotifierItemNewIcon {}).to_emit_message(&self.path));
+    }
+    pub fn is_lingering(&self) -> bool {
+        self.lingering
+    }
+    /// Grey the icon out while the item does not answer calls
+    pub fn set_responsive(&mut self, responsive: bool) {
+        let unresponsive = !responsive;
+        if self.unresponsive == unresponsive {
+            return;
+        }
+        self.properties = None;
+        self.unresponsive = unresponsive;
+        self.composite();
+        self.emit((server::item::StatusNotifierItemNewIcon {}).to_emit_message(&self.path));
+    }
+    /// Become item `id` of the agent, created with `category` and
+    /// `capabilities`, after lingering.  The menu is removed, as its entries
+    /// were those of the application that went away.
+    pub fn reattach(&mut self, id: u64, category: String, capabilities: Capabilities) {
+        self.id = id;
+        self.key.store(id, Ordering::Relaxed);
+        self.lingering = false;
+        self.unresponsive = false;
+        self.take_over(category, capabilities);
+        self.composite();
+        self.emit((server::item::StatusNotifierItemNewIcon {}).to_emit_message(&self.path));
+        self.set_menu(None);
+    }
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+    /// Whether input from panels may be forwarded to the VM, which it may
+    /// not if the item is only displayed, see
+    /// [`crate::config::Limits::allow_interaction`].  Then the user is told
+    /// why nothing happened if `notify` and the configuration say so, at most
+    /// once every [`crate::failures::INTERVAL`].
+    pub fn accepts_input(&mut self, notify: bool) -> bool {
+        if !self.settings.read_only {
+            return true;
+        }
+        log!(
+            Debug,
+            id = self.id,
+            "Not forwarding input to a read-only VM"
+        );
+        let notified = self
+            .read_only_notified
+            .is_some_and(|notified| notified.elapsed() < crate::failures::INTERVAL);
+        if notify && self.settings.notify_read_only && !notified {
+            self.read_only_notified = Some(Instant::now());
+            let app = self.shown_title();
+            let summary = match &self.settings.vm {
+                Some(vm) => format!("{}'s {} is display-only", vm, app),
+                None => format!("{} is display-only", app),
+            };
+            let body = "Clicks on it are not passed on to the application.";
+            crate::failures::notify_user(&self.connection, &summary, body);
+        }
+        false
+    }
+    /// Forward `event`, a click, to the VM, once the user allowed it if
+    /// the VM's clicks must be confirmed, see [`crate::confirm`]
+    fn click(&mut self, event: ServerEvent) {
+        if !self.settings.confirm_interaction {
+            return self
+                .context
+                .output
+                .send(IconServerEvent { id: self.id, event });
+        }
+        let app = self.shown_title();
+        let summary = match &self.settings.vm {
+            Some(vm) => format!("Pass a click on {}'s {} on to it?", vm, app),
+            None => format!("Pass a click on {} on to it?", app),
+        };
+        crate::confirm::ask(
+            self.connection.clone(),
+            self.context.clone(),
+            self.id,
+            summary,
+            event,
+        );
+    }
+    /// Fail a method call that needs `capability`, if the item lacks it
+    fn require(&self, capability: Capabilities) -> Result<(), dbus::MethodErr> {
+        if self.capabilities.contains(capability) {
+            Ok(())
+        } else {
+            Err(dbus::MethodErr::failed("Not supported by the item"))
+        }
+    }
+    /// Become the item the agent created with `category` and `capabilities`
+    pub fn take_over(&mut self, category: String, capabilities: Capabilities) {
+        self.properties = None;
+        self.restored = false;
+        self.category = category;
+        self.capabilities = capabilities;
+        self.exports_menu.store(
+            capabilities.contains(Capabilities::HAS_MENU),
+            Ordering::Relaxed,
+        );
+    }
+    pub fn set_tooltip(&mut self, mut tooltip: Option<sni_icon::Tooltip>) {
+        if let Some(tooltip) = &mut tooltip {
+            crate::tooltip::limit(&self.settings.tooltips, tooltip);
+        }
+        self.properties = None;
         self.tooltip = tooltip;
-        self.connection
-            .send((server::item::StatusNotifierItemNewToolTip {}).to_emit_message(&path()))
-            .unwrap();
+        self.emit((server::item::StatusNotifierItemNewToolTip {}).to_emit_message(&self.path));
+    }
+    /// Set the status to `status` as sent by the VM, now or once the status
+    /// has been shown long enough, see [`crate::status`].  Returns when
+    /// [`NotifierIcon::show_held_status`] must be called, if it must.
+    pub fn offer_status(&mut self, status: Option<String>) -> Option<Duration> {
+        match self.hysteresis.offer(&self.settings.status, status.clone()) {
+            status::Change::Show => {
+                self.set_status(status);
+                None
+            }
+            status::Change::Held(delay) => delay,
+        }
+    }
+    /// Show the status held back by [`NotifierIcon::offer_status`], if it is
+    /// due.  Returns when to call this again, if another one is held back.
+    pub fn show_held_status(&mut self) -> Option<Duration> {
+        let (status, again) = self.hysteresis.due(&self.settings.status);
+        match status {
+            Some(status) if status != self.status => self.set_status(status),
+            _ => {}
+        }
+        again
     }
     pub fn set_status(&mut self, status: Option<String>) {
+        self.properties = None;
+        let badge = self.needs_badge();
         self.status = status.clone();
-        self.connection
-            .send(
-                (server::item::StatusNotifierItemNewStatus {
-                    status: status.unwrap_or_else(|| "normal".to_owned()),
-                })
-                .to_emit_message(&path()),
-            )
-            .unwrap();
+        self.update_badge(badge);
+        self.emit(
+            (server::item::StatusNotifierItemNewStatus {
+                status: status.unwrap_or_else(|| self.settings.defaults.status.clone()),
+            })
+            .to_emit_message(&self.path),
+        );
     }
     pub fn set_icon(&mut self, icon: Option<Vec<IconData>>) {
+        self.properties = None;
         self.icon = icon;
-        self.connection
-            .send((server::item::StatusNotifierItemNewIcon {}).to_emit_message(&path()))
-            .unwrap();
+        self.composite();
+        self.emit((server::item::StatusNotifierItemNewIcon {}).to_emit_message(&self.path));
     }
     pub fn set_attention_icon(&mut self, attention_icon: Option<Vec<IconData>>) {
+        self.properties = None;
+        let badge = self.needs_badge();
         self.attention_icon = attention_icon;
-        self.connection
-            .send((server::item::StatusNotifierItemNewAttentionIcon {}).to_emit_message(&path()))
-            .unwrap();
+        self.update_badge(badge);
+        self.emit(
+            (server::item::StatusNotifierItemNewAttentionIcon {}).to_emit_message(&self.path),
+        );
     }
     pub fn set_overlay_icon(&mut self, overlay_icon: Option<Vec<IconData>>) {
+        self.properties = None;
         self.overlay_icon = overlay_icon;
-        self.connection
-            .send((server::item::StatusNotifierItemNewOverlayIcon {}).to_emit_message(&path()))
-            .unwrap();
+        if self.settings.icons.composite_overlay {
+            self.composite();
+            self.emit((server::item::StatusNotifierItemNewIcon {}).to_emit_message(&self.path));
+            return;
+        }
+        self.emit((server::item::StatusNotifierItemNewOverlayIcon {}).to_emit_message(&self.path));
+    }
+    /// Whether the attention badge is to be drawn onto the icon: the item
+    /// needs attention, but has no attention icon that would show it
+    fn needs_badge(&self) -> bool {
+        let status = self
+            .status
+            .as_ref()
+            .unwrap_or(&self.settings.defaults.status);
+        self.settings.icons.attention_badge
+            && status == sni_icon::spec::Status::NeedsAttention.as_str()
+            && self.attention_icon.as_ref().is_none_or(Vec::is_empty)
+    }
+    /// Redraw the icon if whether it has a badge changed from `had_badge`
+    fn update_badge(&mut self, had_badge: bool) {
+        if self.needs_badge() != had_badge {
+            self.composite();
+            self.emit((server::item::StatusNotifierItemNewIcon {}).to_emit_message(&self.path));
+        }
+    }
+    /// Set the number of items the VM exports, redrawing the count badge if
+    /// it changed
+    pub fn set_count(&mut self, count: usize) {
+        if self.count == count {
+            return;
+        }
+        self.count = count;
+        if self.settings.icons.count_badge {
+            self.properties = None;
+            self.composite();
+            self.emit((server::item::StatusNotifierItemNewIcon {}).to_emit_message(&self.path));
+        }
+    }
+    /// Update the composited icon after anything drawn onto it changed
+    fn composite(&mut self) {
+        let icons = &self.settings.icons;
+        let overlay = self
+            .overlay_icon
+            .as_ref()
+            .filter(|_| icons.composite_overlay);
+        let badge = self.needs_badge();
+        let count = icons.count_badge && self.count > 0;
+        let faded = self.lingering || self.unresponsive;
+        let inset = self.settings.border.drawn_width();
+        self.composited_icon = match &self.icon {
+            Some(icon) if overlay.is_some() || badge || count || faded => {
+                let mut icon = icon.clone();
+                if let Some(overlay) = overlay {
+                    sni_icon::image::overlay(&mut icon, overlay, inset)
+                }
+                if badge {
+                    sni_icon::image::attention_badge(&mut icon, self.settings.label_color, inset)
+                }
+                if count {
+                    sni_icon::image::count_badge(
+                        &mut icon,
+                        self.count,
+                        self.settings.label_color,
+                        inset,
+                    )
+                }
+                if faded {
+                    sni_icon::image::fade(&mut icon, icons.fade, &self.settings.border)
+                }
+                Some(icon)
+            }
+            _ => None,
+        };
     }
 }
 
-pub(super) struct NotifierIconWrapper;
+/// An item as seen by the interfaces it exports, which look it up among
+/// the items of its daemon whenever they are called
+#[derive(Clone)]
+pub(super) struct NotifierIconWrapper {
+    context: Context,
+    /// The ID of the item, see [`NotifierIcon::reattach`]
+    key: Arc<AtomicU64>,
+    path: Path<'static>,
+    /// Whether the menu object is to be exported, see [`Exports`]
+    exports_menu: Arc<AtomicBool>,
+}
 
-fn call_with_icon<T, U: FnOnce(&mut NotifierIcon) -> Result<T, dbus::MethodErr>>(
-    cb: U,
-) -> Result<T, dbus::MethodErr> {
-    crate::WRAPPER.with(|items| {
-        let mut items = items.lock().unwrap();
-        match crate::ID.with(|id| items.get_mut(&id.get())) {
-            None => {
-                let err = unsafe {
-                    // SAFETY: the preconditions are held
-                    ErrorName::from_slice_unchecked("org.freedesktop.DBus.Error.ServiceUnknown\0")
-                };
-                Err((err, "Icon does not exist").into())
-            }
+impl NotifierIconWrapper {
+    /// Call `cb` with the item, if it still exists
+    pub fn with_icon<T>(
+        &self,
+        cb: impl FnOnce(&mut NotifierIcon) -> Result<T, dbus::MethodErr>,
+    ) -> Result<T, dbus::MethodErr> {
+        match self.context.items.lock().unwrap().get_mut(&self.key()) {
+            None => Err((names::error_service_unknown(), "Icon does not exist").into()),
             Some(icon) => cb(icon),
         }
-    })
+    }
+
+    /// The ID of the item
+    pub fn key(&self) -> u64 {
+        self.key.load(Ordering::Relaxed)
+    }
+
+    /// The state of the daemon of the item
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+}
+
+/// The objects of an item.  Both interfaces are registered once, but the
+/// dbusmenu object is only exported while the item has a menu, which may
+/// change when another item takes the item over ([`NotifierIcon::take_over`]).
+/// Calls are handled with this locked, and then lock the items, so taking
+/// over only sets [`NotifierIconWrapper::exports_menu`], which the next call
+/// applies.
+struct Exports {
+    cr: Crossroads,
+    menu_token: IfaceToken<NotifierIconWrapper>,
+    /// Whether the dbusmenu object is in `cr`
+    menu: bool,
+}
+
+impl Exports {
+    fn new(icon: &NotifierIconWrapper) -> Self {
+        let mut cr = Crossroads::new();
+        let item_token =
+            server::item::register_status_notifier_item::<NotifierIconWrapper>(&mut cr);
+        let menu_token = server::menu::register_dbusmenu::<NotifierIconWrapper>(&mut cr);
+        cr.insert(icon.path.clone(), &[item_token], icon.clone());
+        let mut exports = Self {
+            cr,
+            menu_token,
+            menu: false,
+        };
+        exports.update(icon);
+        exports
+    }
+
+    /// Export or remove the dbusmenu object, as `icon` says
+    fn update(&mut self, icon: &NotifierIconWrapper) {
+        let menu = icon.exports_menu.load(Ordering::Relaxed);
+        if menu == self.menu {
+            return;
+        }
+        if menu {
+            self.cr
+                .insert(names::path_menu(), &[self.menu_token], icon.clone());
+        } else {
+            self.cr.remove::<NotifierIconWrapper>(&names::path_menu());
+        }
+        self.menu = menu;
+    }
+}
+
+/// Handle `msg`, a method call to `icon`
+fn dispatch(icon: &NotifierIconWrapper, cr: &Mutex<Exports>, msg: Message, conn: &Connection) {
+    let id = icon.key();
+    if !icon.context.hosts.may_call(&msg) {
+        log!(
+            Debug,
+            id = id,
+            "Rejecting call from a connection that is not a host"
+        );
+        let e = dbus::MethodErr::from((
+            names::error_access_denied(),
+            "Only StatusNotifierHosts may call items",
+        ));
+        let _ = conn.send(e.to_message(&msg));
+        return;
+    }
+    if is_get_all(&icon.path, &msg) {
+        get_all(icon, msg, conn);
+        return;
+    }
+    if crate::menu::is_about_to_show(&msg) {
+        crate::menu::about_to_show(icon, msg, conn);
+        return;
+    }
+    let mut exports = cr.lock().unwrap();
+    exports.update(icon);
+    if let Err(e) = sni_icon::session::dispatch(&mut exports.cr, msg, conn) {
+        log!(Warning, id = id, "Item {}: {}", id, e);
+    }
+}
+
+/// Whether `msg` is a call to `GetAll` for the properties of the item at
+/// `path`.  These are answered by [`get_all`], not by crossroads, as hosts
+/// call it after every change and computing the answer copies every pixmap.
+fn is_get_all(path: &Path<'static>, msg: &Message) -> bool {
+    msg.msg_type() == dbus::MessageType::MethodCall
+        && msg.path().as_ref() == Some(path)
+        && msg.interface() == Some(names::interface_properties())
+        && msg.member() == Some(names::get_all())
+        && msg
+            .read1::<&str>()
+            .is_ok_and(|interface| interface == &*names::interface_status_notifier_item())
+}
+
+/// Answer `msg`, a call to `GetAll`, from the cached properties of the
+/// item, computing them first if needed
+fn get_all(item: &NotifierIconWrapper, msg: Message, conn: &Connection) {
+    let properties = item
+        .with_icon(|icon| Ok(icon.properties.clone()))
+        .and_then(|cached| {
+            if let Some(properties) = cached {
+                return Ok(properties);
+            }
+            let properties = Arc::new(all_properties(item));
+            item.with_icon(|icon| {
+                icon.properties = Some(properties.clone());
+                Ok(properties)
+            })
+        });
+    let _ = conn.send(match properties {
+        Ok(properties) => msg.method_return().append1(&*properties),
+        Err(e) => e.to_message(&msg),
+    });
+}
+
+/// All properties of `icon`, leaving out those whose getter fails as
+/// crossroads does
+fn all_properties(icon: &NotifierIconWrapper) -> PropMap {
+    use server::item::StatusNotifierItem as _;
+    fn insert<T: RefArg + 'static>(
+        props: &mut PropMap,
+        name: &str,
+        value: Result<T, dbus::MethodErr>,
+    ) {
+        if let Ok(value) = value {
+            props.insert(name.to_owned(), Variant(Box::new(value)));
+        }
+    }
+    let mut props = PropMap::new();
+    insert(&mut props, "Category", icon.category());
+    insert(&mut props, "Id", icon.id());
+    insert(&mut props, "Title", icon.title());
+    insert(&mut props, "Status", icon.status());
+    insert(&mut props, "WindowId", icon.window_id());
+    insert(&mut props, "IconThemePath", icon.icon_theme_path());
+    insert(&mut props, "Menu", icon.menu());
+    insert(&mut props, "ItemIsMenu", icon.item_is_menu());
+    insert(&mut props, "IconName", icon.icon_name());
+    insert(&mut props, "IconPixmap", icon.icon_pixmap());
+    insert(&mut props, "OverlayIconName", icon.overlay_icon_name());
+    insert(&mut props, "OverlayIconPixmap", icon.overlay_icon_pixmap());
+    insert(&mut props, "AttentionIconName", icon.attention_icon_name());
+    insert(
+        &mut props,
+        "AttentionIconPixmap",
+        icon.attention_icon_pixmap(),
+    );
+    insert(
+        &mut props,
+        "AttentionMovieName",
+        icon.attention_movie_name(),
+    );
+    insert(&mut props, "ToolTip", icon.tool_tip());
+    insert(
+        &mut props,
+        "IconAccessibleDesc",
+        icon.icon_accessible_desc(),
+    );
+    insert(
+        &mut props,
+        "AttentionAccessibleDesc",
+        icon.attention_accessible_desc(),
+    );
+    insert(
+        &mut props,
+        "XAyatanaOrderingIndex",
+        icon.xayatana_ordering_index(),
+    );
+    props
+}
+
+/// The line naming `vm` at the end of every tooltip description, so that
+/// hosts showing only the description still tell which VM an item belongs
+/// to.  Descriptions may contain markup, so the name is escaped.
+fn vm_footer(vm: &str) -> String {
+    let mut footer = "From VM: ".to_owned();
+    for c in SafeText::new(vm).chars() {
+        match c {
+            '&' => footer.push_str("&amp;"),
+            '<' => footer.push_str("&lt;"),
+            '>' => footer.push_str("&gt;"),
+            '"' => footer.push_str("&quot;"),
+            '\'' => footer.push_str("&apos;"),
+            c => footer.push(c),
+        }
+    }
+    footer
+}
+
+/// The answer for property `name`, which the item lacks, as `missing` says
+fn lacking<T>(name: &str, missing: Missing, empty: T) -> Result<T, dbus::MethodErr> {
+    match missing {
+        Missing::Error => Err(dbus::MethodErr::no_property(&name)),
+        Missing::Empty => Ok(empty),
+    }
+}
+
+/// `frames` as D-Bus pixmaps
+fn pixmaps(frames: &[IconData]) -> Vec<(i32, i32, Vec<u8>)> {
+    frames
+        .iter()
+        .map(|f| (f.width as i32, f.height as i32, f.data.clone()))
+        .collect()
+}
+
+fn send_scroll(output: &Output, id: u64, delta: i32, orientation: scroll::Orientation) {
+    output.send(IconServerEvent {
+        id,
+        event: ServerEvent::Scroll {
+            delta,
+            orientation: orientation.as_str().to_owned(),
+        },
+    });
 }
 
 impl server::item::StatusNotifierItem for NotifierIconWrapper {
     fn context_menu(&mut self, x: i32, y: i32) -> Result<(), dbus::MethodErr> {
-        eprintln!("Got context menu event: {x}x{y}");
-        call_with_icon(|icon| {
-            send_or_panic(IconServerEvent {
-                id: icon.id,
-                event: ServerEvent::ContextMenu { x, y },
-            });
+        log!(Debug, "Got context menu event: {x}x{y}");
+        self.with_icon(|icon| {
+            if icon.accepts_input(true) {
+                icon.click(ServerEvent::ContextMenu { x, y });
+            }
             Ok(())
         })
     }
     fn activate(&mut self, x: i32, y: i32) -> Result<(), dbus::MethodErr> {
-        call_with_icon(|icon| {
-            send_or_panic(IconServerEvent {
-                id: icon.id,
-                event: ServerEvent::Activate { x, y },
-            });
+        self.with_icon(|icon| {
+            icon.require(Capabilities::SUPPORTS_ACTIVATION)?;
+            if icon.accepts_input(true) {
+                icon.click(ServerEvent::Activate { x, y });
+            }
             Ok(())
         })
     }
     fn secondary_activate(&mut self, x: i32, y: i32) -> Result<(), dbus::MethodErr> {
-        call_with_icon(|icon| {
-            send_or_panic(IconServerEvent {
+        self.with_icon(|icon| {
+            icon.require(Capabilities::SUPPORTS_SECONDARY_ACTIVATE)?;
+            if !icon.accepts_input(true) {
+                return Ok(());
+            }
+            icon.context.output.send(IconServerEvent {
                 id: icon.id,
                 event: ServerEvent::SecondaryActivate { x, y },
             });
@@ -182,112 +962,186 @@ impl server::item::StatusNotifierItem for NotifierIconWrapper {
         })
     }
     fn scroll(&mut self, delta: i32, orientation: String) -> Result<(), dbus::MethodErr> {
-        call_with_icon(|icon| {
-            send_or_panic(IconServerEvent {
-                id: icon.id,
-                event: ServerEvent::Scroll { delta, orientation },
-            });
+        let orientation = scroll::Orientation::parse(&orientation)
+            .ok_or_else(|| dbus::MethodErr::invalid_arg("orientation"))?;
+        self.with_icon(|icon| {
+            if !icon.accepts_input(false) {
+                return Ok(());
+            }
+            let settings = &icon.settings.scroll;
+            let delta = icon.scroll.add(settings, delta, orientation);
+            let window = settings.coalesce_window();
+            if delta == 0 {
+                return Ok(());
+            } else if window.is_zero() {
+                send_scroll(&icon.context.output, icon.id, delta, orientation);
+            } else if icon.scroll.queue(orientation, delta) {
+                let (id, context) = (icon.id, self.context.clone());
+                tokio::spawn(async move {
+                    tokio::time::sleep(window).await;
+                    if let Some(icon) = context.items.lock().unwrap().get_mut(&id) {
+                        match icon.scroll.take(&icon.settings.scroll, orientation) {
+                            0 => {}
+                            delta => send_scroll(&context.output, id, delta, orientation),
+                        }
+                    }
+                });
+            }
             Ok(())
         })
     }
     fn category(&self) -> Result<String, dbus::MethodErr> {
-        call_with_icon(|icon| Ok(icon.category.clone()))
+        self.with_icon(|icon| Ok(icon.settings.categories.apply(&icon.category).to_owned()))
     }
     fn id(&self) -> Result<String, dbus::MethodErr> {
-        call_with_icon(|icon| Ok(icon.app_id.clone()))
+        self.with_icon(|icon| Ok(icon.app_id.clone()))
     }
     fn title(&self) -> Result<String, dbus::MethodErr> {
-        call_with_icon(|icon| {
-            icon.title
-                .clone()
-                .ok_or_else(|| dbus::MethodErr::no_property("Title"))
-        })
+        self.with_icon(|icon| Ok(icon.shown_title()))
     }
     fn status(&self) -> Result<String, dbus::MethodErr> {
-        call_with_icon(|icon| {
-            icon.status
+        self.with_icon(|icon| {
+            Ok(icon
+                .status
                 .clone()
-                .ok_or_else(|| dbus::MethodErr::no_property("status"))
+                .unwrap_or_else(|| icon.settings.defaults.status.clone()))
         })
     }
     fn window_id(&self) -> Result<i32, dbus::MethodErr> {
         Ok(0)
     }
     fn icon_theme_path(&self) -> Result<String, dbus::MethodErr> {
-        Err(dbus::MethodErr::no_property("icon_theme_path"))
+        self.with_icon(|icon| Ok(icon.settings.defaults.icon_theme_path.clone()))
     }
     fn menu(&self) -> Result<Path<'static>, dbus::MethodErr> {
-        eprintln!("menu() called!");
-        call_with_icon(|_| Err(dbus::MethodErr::no_property("menu")))
+        log!(Debug, "menu() called!");
+        self.with_icon(|icon| match icon.menu {
+            Some(_) => Ok(names::path_menu()),
+            None => lacking(
+                "Menu",
+                icon.settings.properties.menu,
+                Path::from("/").into_static(),
+            ),
+        })
     }
     fn item_is_menu(&self) -> Result<bool, dbus::MethodErr> {
-        call_with_icon(|icon| Ok(icon.is_menu))
+        self.with_icon(|icon| Ok(icon.capabilities.contains(Capabilities::IS_MENU)))
     }
     fn icon_name(&self) -> Result<String, dbus::MethodErr> {
-        Err(dbus::MethodErr::no_property("IconName"))
+        self.with_icon(|icon| {
+            lacking(
+                "IconName",
+                icon.settings.properties.icon_names,
+                String::new(),
+            )
+        })
     }
     fn icon_pixmap(&self) -> Result<Vec<(i32, i32, Vec<u8>)>, dbus::MethodErr> {
-        call_with_icon(|icon| {
-            Ok(icon
-                .icon
-                .as_deref()
-                .unwrap_or(&[])
-                .iter()
-                .map(|f| (f.width as i32, f.height as i32, f.data.clone()))
-                .collect())
+        self.with_icon(|icon| {
+            Ok(pixmaps(
+                icon.composited_icon
+                    .as_deref()
+                    .or(icon.icon.as_deref())
+                    .unwrap_or(&[]),
+            ))
         })
     }
     fn overlay_icon_name(&self) -> Result<String, dbus::MethodErr> {
-        Err(dbus::MethodErr::no_property("OverlayIconName"))
+        self.with_icon(|icon| {
+            lacking(
+                "OverlayIconName",
+                icon.settings.properties.icon_names,
+                String::new(),
+            )
+        })
     }
     fn overlay_icon_pixmap(&self) -> Result<Vec<(i32, i32, Vec<u8>)>, dbus::MethodErr> {
-        call_with_icon(|overlay_icon| {
-            Ok(overlay_icon
-                .overlay_icon
-                .as_deref()
-                .unwrap_or(&[])
-                .iter()
-                .map(|f| (f.width as i32, f.height as i32, f.data.clone()))
-                .collect())
+        self.with_icon(|overlay_icon| {
+            let missing = overlay_icon.settings.properties.overlay_icon_pixmap;
+            if overlay_icon.settings.icons.composite_overlay {
+                // Drawn onto the icon instead
+                return lacking("OverlayIconPixmap", missing, vec![]);
+            }
+            match overlay_icon.overlay_icon.as_deref() {
+                Some(frames) if !frames.is_empty() => Ok(pixmaps(frames)),
+                _ => lacking("OverlayIconPixmap", missing, vec![]),
+            }
         })
     }
     fn attention_icon_name(&self) -> Result<String, dbus::MethodErr> {
-        Err(dbus::MethodErr::no_property("AttentionIconName"))
+        self.with_icon(|icon| {
+            lacking(
+                "AttentionIconName",
+                icon.settings.properties.icon_names,
+                String::new(),
+            )
+        })
     }
     fn attention_icon_pixmap(&self) -> Result<Vec<(i32, i32, Vec<u8>)>, dbus::MethodErr> {
-        call_with_icon(|attention_icon| {
-            Ok(attention_icon
-                .attention_icon
-                .as_deref()
-                .unwrap_or(&[])
-                .iter()
-                .map(|f| (f.width as i32, f.height as i32, f.data.clone()))
-                .collect())
+        self.with_icon(|attention_icon| {
+            let missing = attention_icon.settings.properties.attention_icon_pixmap;
+            match attention_icon.attention_icon.as_deref() {
+                Some(frames) if !frames.is_empty() => Ok(pixmaps(frames)),
+                _ => lacking("AttentionIconPixmap", missing, vec![]),
+            }
         })
     }
     fn attention_movie_name(&self) -> Result<String, dbus::MethodErr> {
-        Err(dbus::MethodErr::no_property("AttentionMovieName"))
+        self.with_icon(|icon| {
+            lacking(
+                "AttentionMovieName",
+                icon.settings.properties.icon_names,
+                String::new(),
+            )
+        })
     }
 
     fn tool_tip(
         &self,
     ) -> Result<(String, Vec<(i32, i32, Vec<u8>)>, String, String), dbus::MethodErr> {
-        call_with_icon(|tooltip| {
-            let tooltip = tooltip
-                .tooltip
-                .as_ref()
-                .ok_or_else(|| dbus::MethodErr::no_property("ToolTip"))?;
-            let icon_data = tooltip
-                .icon_data
-                .iter()
-                .map(|f| (f.width as i32, f.height as i32, f.data.clone()))
-                .collect();
+        self.with_icon(|icon| {
+            let footer = icon.settings.vm.as_deref().map(vm_footer);
+            let tooltip = match &icon.tooltip {
+                Some(tooltip) => tooltip,
+                None => {
+                    return Ok((
+                        String::new(),
+                        vec![],
+                        String::new(),
+                        footer.unwrap_or_default(),
+                    ))
+                }
+            };
+            let icon_data = pixmaps(&tooltip.icon_data);
+            let mut description =
+                crate::tooltip::wrap(&icon.settings.tooltips, &tooltip.description);
+            if let Some(footer) = footer {
+                if !description.is_empty() {
+                    description.push_str("<br/>");
+                }
+                description.push_str(&footer);
+            }
             Ok((
                 String::new(),
                 icon_data,
-                tooltip.title.clone(),
-                tooltip.description.clone(),
+                tooltip.title.clone().into(),
+                description,
             ))
         })
     }
+    fn icon_accessible_desc(&self) -> Result<String, dbus::MethodErr> {
+        self.with_icon(|icon| match &icon.accessible_desc {
+            Some(desc) => Ok(desc.to_string()),
+            None => Ok(icon.shown_title()),
+        })
+    }
+    fn attention_accessible_desc(&self) -> Result<String, dbus::MethodErr> {
+        self.with_icon(|icon| match &icon.attention_accessible_desc {
+            Some(desc) => Ok(desc.to_string()),
+            None => Ok(icon.shown_title()),
+        })
+    }
+    fn xayatana_ordering_index(&self) -> Result<u32, dbus::MethodErr> {
+        self.with_icon(|icon| Ok(icon.ordering_index))
+    }
 }
@@ -0,0 +1,133 @@
+//! Looking up the color of the Qubes label of a VM
+//!
+//! The label is a property of the VM in qubesd, which answers the Admin API
+//! on a socket in dom0: `admin.vm.property.Get+label` names the label of the
+//! VM, and `admin.label.Get` gives its color.  Daemons not running in dom0
+//! cannot reach the socket, and fall back to the color configured for the
+//! VM, see [`crate::config::Labels`].
+
+use crate::config::Labels;
+use std::error::Error;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+/// Call `method` of qubesd with `arg` on `dest`, and return its answer
+async fn call(
+    labels: &Labels,
+    method: &str,
+    dest: &str,
+    arg: &str,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut socket = tokio::net::UnixStream::connect(&labels.socket)
+        .await
+        .map_err(|e| format!("cannot connect to {}: {}", labels.socket.display(), e))?;
+    // The source, method, destination and argument, each followed by a NUL,
+    // then the payload, which is empty
+    let request = format!("dom0\0{}\0{}\0{}\0", method, dest, arg);
+    socket.write_all(request.as_bytes()).await?;
+    socket.shutdown().await?;
+    let mut response = Vec::new();
+    socket.take(4096).read_to_end(&mut response).await?;
+    answer(&response).map(<[u8]>::to_vec).ok_or_else(|| {
+        format!(
+            "{} failed: {:?}",
+            method,
+            String::from_utf8_lossy(&response)
+        )
+        .into()
+    })
+}
+
+/// The answer in a `response` of qubesd, which starts with `0\0` on success
+/// and `2\0` followed by the exception otherwise
+fn answer(response: &[u8]) -> Option<&[u8]> {
+    response.strip_prefix(b"0\0")
+}
+
+/// The name of the label in the answer of `admin.vm.property.Get`, which is
+/// e.g. `default=False type=label red`
+fn label_name(answer: &[u8]) -> Option<&str> {
+    let answer = std::str::from_utf8(answer).ok()?;
+    let mut words = answer.split(' ');
+    words.next().filter(|word| word.starts_with("default="))?;
+    words.next().filter(|&word| word == "type=label")?;
+    let name = words.next()?;
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    (words.next().is_none() && !name.is_empty() && name.chars().all(valid)).then_some(name)
+}
+
+/// The color in the answer of `admin.label.Get`, which is e.g. `0xcc0000`
+fn label_color(answer: &[u8]) -> Option<[u8; 3]> {
+    let hex = std::str::from_utf8(answer).ok()?.strip_prefix("0x")?;
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let [_, color @ ..] = u32::from_str_radix(hex, 16).ok()?.to_be_bytes();
+    Some(color)
+}
+
+/// The color of the label of `vm`, as red, green and blue
+pub(super) async fn color(labels: &Labels, vm: &str) -> Result<[u8; 3], Box<dyn Error>> {
+    let answer = call(labels, "admin.vm.property.Get", vm, "label").await?;
+    let name = label_name(&answer).ok_or_else(|| {
+        format!(
+            "bad label of VM {:?}: {:?}",
+            vm,
+            String::from_utf8_lossy(&answer)
+        )
+    })?;
+    let answer = call(labels, "admin.label.Get", "dom0", name).await?;
+    label_color(&answer).ok_or_else(|| {
+        format!(
+            "bad color of label {:?}: {:?}",
+            name,
+            String::from_utf8_lossy(&answer)
+        )
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_parsed() {
+        assert_eq!(
+            answer(b"0\0default=False type=label red"),
+            Some(&b"default=False type=label red"[..])
+        );
+        assert_eq!(answer(b"2\0QubesVMNotFoundError\0\0No such domain\0"), None);
+        assert_eq!(answer(b""), None);
+    }
+
+    #[test]
+    fn label_names_are_parsed() {
+        assert_eq!(label_name(b"default=False type=label red"), Some("red"));
+        assert_eq!(label_name(b"default=True type=label gray"), Some("gray"));
+        for invalid in [
+            &b"default=False type=str red"[..],
+            b"default=False type=label",
+            b"default=False type=label ",
+            b"default=False type=label red green",
+            b"default=False type=label ../red",
+            b"type=label red",
+        ] {
+            assert_eq!(label_name(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn label_colors_are_parsed() {
+        assert_eq!(label_color(b"0xcc0000"), Some([0xcc, 0, 0]));
+        assert_eq!(label_color(b"0x73d216"), Some([0x73, 0xd2, 0x16]));
+        for invalid in [
+            &b"cc0000"[..],
+            b"0xcc00",
+            b"0x1cc0000",
+            b"0xgg0000",
+            b"0x+c0000",
+        ] {
+            assert_eq!(label_color(invalid), None, "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Items whose application went away, kept for a while
+//!
+//! When an application restarts, the agent destroys its item and creates a
+//! new one moments later, which would make panels remove the icon and add
+//! it again, often elsewhere.  Instead, an item the agent destroys is kept,
+//! greyed out, for [`crate::config::Reattach::grace_ms`].  If the agent
+//! creates an item of the same application meanwhile, the new item takes
+//! the old one over, as items restored from a previous run are taken over
+//! (see [`crate::state`]).  Otherwise the old item is removed then.
+//! Lingering items are not saved, and count towards the limit of items of
+//! the VM.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::item::{Items, NotifierIcon};
+
+/// The lingering item of the application `vm_app_id` that a new item of it
+/// is to take over, the one that went away last if there are several
+pub(super) fn matching(items: &HashMap<u64, NotifierIcon>, vm_app_id: &str) -> Option<u64> {
+    items
+        .iter()
+        .filter(|(_, ni)| ni.is_lingering() && ni.vm_app_id() == vm_app_id)
+        .map(|(&id, _)| id)
+        .max()
+}
+
+/// Remove item `id` once `grace` has passed, unless it was taken over
+pub(super) fn expire(id: u64, grace: Duration, items: Items, store: crate::state::Store) {
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        let mut items = items.lock().unwrap();
+        if !items.get(&id).is_some_and(NotifierIcon::is_lingering) {
+            return;
+        }
+        log!(Info, id = id, "Removing item {} that did not come back", id);
+        items.remove(&id);
+        crate::update_counts(&mut items);
+        store.save(&items);
+    });
+}
@@ -0,0 +1,186 @@
+//! Logging to the systemd journal, or to stderr
+//!
+//! If stderr is connected to the journal (as indicated by `JOURNAL_STREAM`),
+//! messages are sent using the native journal protocol with the structured
+//! fields `QUBES_VM`, `SNI_ICON_ID` and `EVENT`, and any others given, so
+//! that e.g. `journalctl -u sni-daemon SNI_ICON_ID=5` works.  Otherwise,
+//! they are written to stderr.
+
+use std::fmt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+/// Message priorities, with the same values as syslog(3)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub(super) enum Level {
+    Error = 3,
+    Warning = 4,
+    #[default]
+    Info = 6,
+    Debug = 7,
+}
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static VM: OnceLock<String> = OnceLock::new();
+static JOURNAL: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+
+/// Set the VM whose name is sent as `QUBES_VM` with every message
+pub(super) fn set_vm(vm: &str) {
+    let _ = VM.set(vm.to_owned());
+}
+
+impl Level {
+    /// The levels, from the least to the most verbose
+    const ALL: [Level; 4] = [Level::Error, Level::Warning, Level::Info, Level::Debug];
+
+    /// The level named `name`, as in the configuration
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|level| level.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+pub(super) fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed)
+}
+
+/// Make messages more verbose each time SIGUSR2 is received, going back to
+/// only errors after debug messages.  The level set in the configuration
+/// applies again when it is reloaded.
+pub(super) fn cycle_on_sigusr2() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        while sigusr2.recv().await.is_some() {
+            let current = MAX_LEVEL.load(Ordering::Relaxed);
+            let next = Level::ALL
+                .into_iter()
+                .find(|&level| level as u8 > current)
+                .unwrap_or(Level::Error);
+            set_max_level(next);
+            // Logged at the lowest level, so that it is seen whatever the level
+            write(
+                Level::Error,
+                None,
+                None,
+                &[],
+                format_args!("Log level set to {} by SIGUSR2", next.name()),
+            );
+        }
+    });
+    Ok(())
+}
+
+fn journal() -> Option<&'static UnixDatagram> {
+    JOURNAL
+        .get_or_init(|| {
+            std::env::var_os("JOURNAL_STREAM")?;
+            let socket = UnixDatagram::unbound().ok()?;
+            socket.connect(JOURNAL_SOCKET).ok()?;
+            Some(socket)
+        })
+        .as_ref()
+}
+
+fn append_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        // Binary-safe serialization: NL, length as little-endian u64, value
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+pub(super) fn write(
+    level: Level,
+    id: Option<u64>,
+    event: Option<&str>,
+    fields: &[(&str, &str)],
+    args: fmt::Arguments<'_>,
+) {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    let message = args.to_string();
+    if let Some(journal) = journal() {
+        let mut buf = Vec::with_capacity(message.len() + 128);
+        append_field(&mut buf, "PRIORITY", &(level as u8).to_string());
+        append_field(&mut buf, "SYSLOG_IDENTIFIER", "sni-daemon");
+        append_field(&mut buf, "MESSAGE", &message);
+        if let Some(vm) = VM.get() {
+            append_field(&mut buf, "QUBES_VM", vm);
+        }
+        if let Some(id) = id {
+            append_field(&mut buf, "SNI_ICON_ID", &id.to_string());
+        }
+        if let Some(event) = event {
+            append_field(&mut buf, "EVENT", event);
+        }
+        for (key, value) in fields {
+            append_field(&mut buf, key, value);
+        }
+        if journal.send(&buf).is_ok() {
+            return;
+        }
+    }
+    eprintln!("{}", message);
+}
+
+/// Log a message.  The level is a [`Level`] variant, optionally followed by
+/// `id = ...` and `event = ...` for the corresponding structured fields, or
+/// by `fields = ...`, a slice of other fields as pairs of strings.
+macro_rules! log {
+    ($level:ident, id = $id:expr, event = $event:expr, $($arg:tt)+) => {
+        crate::logging::write(
+            crate::logging::Level::$level,
+            Some($id),
+            Some($event),
+            &[],
+            format_args!($($arg)+),
+        )
+    };
+    ($level:ident, id = $id:expr, $($arg:tt)+) => {
+        crate::logging::write(
+            crate::logging::Level::$level,
+            Some($id),
+            None,
+            &[],
+            format_args!($($arg)+),
+        )
+    };
+    ($level:ident, fields = $fields:expr, $($arg:tt)+) => {
+        crate::logging::write(
+            crate::logging::Level::$level,
+            None,
+            None,
+            $fields,
+            format_args!($($arg)+),
+        )
+    };
+    ($level:ident, $($arg:tt)+) => {
+        crate::logging::write(
+            crate::logging::Level::$level,
+            None,
+            None,
+            &[],
+            format_args!($($arg)+),
+        )
+    };
+}
@@ -0,0 +1,455 @@
+//! Export of menus forwarded from the VM, using the dbusmenu protocol
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::channel::Sender as _;
+use dbus::nonblock::SyncConnection;
+use dbus::Message;
+use server::menu::DbusmenuItemsPropertiesUpdated;
+use sni_icon::menu::{ToggleState, ToggleType};
+use sni_icon::{server, Event, IconServerEvent, MenuItem, Request, ServerEvent};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::item::{NotifierIconWrapper, Waiting};
+
+/// How long the VM has to answer AboutToShow before the daemon answers
+/// `false` on its behalf
+const ABOUT_TO_SHOW_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// A menu as received from the VM, after validation
+pub(super) struct Menu {
+    root: MenuItem,
+    /// PNG-encoded icons of the entries, by entry ID
+    icons: HashMap<i32, Vec<u8>>,
+}
+
+type Layout = (i32, PropMap, Vec<Variant<Box<dyn RefArg + 'static>>>);
+
+impl Menu {
+    /// Validate `root` against the limits in [`sni_icon::menu`], and the
+    /// icons of all of its entries.  Icons that are not well-formed pixmaps
+    /// of at most `max_icon_size` pixels in either dimension are discarded,
+    /// and the others are encoded as PNG.
+    pub fn new(mut root: MenuItem, max_icon_size: u32) -> Self {
+        fn walk(item: &mut MenuItem, max_icon_size: u32, icons: &mut HashMap<i32, Vec<u8>>) {
+            if let Some(icon) = item.icon.take() {
+                match encode_png(&icon, max_icon_size) {
+                    Some(png) => {
+                        icons.insert(item.id, png);
+                    }
+                    None => log!(
+                        Warning,
+                        "Discarded invalid {}x{} icon of menu entry {}",
+                        icon.width,
+                        icon.height,
+                        item.id
+                    ),
+                }
+            }
+            for child in &mut item.children {
+                walk(child, max_icon_size, icons)
+            }
+        }
+        if root.limit() {
+            log!(Warning, "Menu exceeds limits, truncating it");
+        }
+        let mut icons = HashMap::new();
+        walk(&mut root, max_icon_size, &mut icons);
+        Self { root, icons }
+    }
+
+    fn find(&self, id: i32) -> Option<&MenuItem> {
+        fn find(item: &MenuItem, id: i32) -> Option<&MenuItem> {
+            if item.id == id {
+                return Some(item);
+            }
+            item.children.iter().find_map(|child| find(child, id))
+        }
+        find(&self.root, id)
+    }
+
+    /// The changes from `self` to `new`, if both have the same layout and
+    /// only properties of entries differ
+    pub fn diff(&self, new: &Menu) -> Option<DbusmenuItemsPropertiesUpdated> {
+        fn walk(
+            old_menu: &Menu,
+            new_menu: &Menu,
+            old: &MenuItem,
+            new: &MenuItem,
+            update: &mut DbusmenuItemsPropertiesUpdated,
+        ) -> Option<()> {
+            if old.id != new.id || old.children.len() != new.children.len() {
+                return None;
+            }
+            let unchanged = old.separator == new.separator
+                && old.label == new.label
+                && old.enabled == new.enabled
+                && old.visible == new.visible
+                && old.toggle_type == new.toggle_type
+                && old.toggle_state == new.toggle_state
+                && old.shortcuts == new.shortcuts
+                && old_menu.icons.get(&old.id) == new_menu.icons.get(&new.id);
+            if !unchanged {
+                let props = new_menu.props(new, &[]);
+                let removed: Vec<String> = old_menu
+                    .props(old, &[])
+                    .into_keys()
+                    .filter(|name| !props.contains_key(name))
+                    .collect();
+                if !removed.is_empty() {
+                    update.removed_props.push((new.id, removed));
+                }
+                update.updated_props.push((new.id, props));
+            }
+            for (old_child, new_child) in old.children.iter().zip(&new.children) {
+                walk(old_menu, new_menu, old_child, new_child, update)?;
+            }
+            Some(())
+        }
+        let mut update = DbusmenuItemsPropertiesUpdated {
+            updated_props: vec![],
+            removed_props: vec![],
+        };
+        walk(self, new, &self.root, &new.root, &mut update)?;
+        Some(update)
+    }
+
+    /// Toggle the checkmark or radio button of entry `id`, as the VM is
+    /// expected to do when the entry is clicked.  The VM's next update of
+    /// the menu overrides this.  Returns the IDs of all changed entries.
+    pub fn toggle(&mut self, id: i32) -> Vec<i32> {
+        fn parent_of(item: &mut MenuItem, id: i32) -> Option<&mut MenuItem> {
+            if item.children.iter().any(|child| child.id == id) {
+                return Some(item);
+            }
+            item.children
+                .iter_mut()
+                .find_map(|child| parent_of(child, id))
+        }
+        let mut changed = vec![];
+        let siblings = match parent_of(&mut self.root, id) {
+            Some(parent) => &mut parent.children,
+            None => return changed,
+        };
+        let toggle_type = siblings
+            .iter()
+            .find(|child| child.id == id)
+            .and_then(|child| child.toggle_type);
+        for entry in siblings.iter_mut() {
+            let state = match toggle_type {
+                Some(ToggleType::Checkmark) if entry.id == id => match entry.toggle_state {
+                    ToggleState::On => ToggleState::Off,
+                    _ => ToggleState::On,
+                },
+                Some(ToggleType::Radio) if entry.toggle_type == Some(ToggleType::Radio) => {
+                    if entry.id == id {
+                        ToggleState::On
+                    } else {
+                        ToggleState::Off
+                    }
+                }
+                _ => continue,
+            };
+            if entry.toggle_state != state {
+                entry.toggle_state = state;
+                changed.push(entry.id);
+            }
+        }
+        changed
+    }
+
+    /// The `ItemsPropertiesUpdated` signal for the entries in `ids`
+    pub fn properties_updated(&self, ids: &[i32]) -> DbusmenuItemsPropertiesUpdated {
+        DbusmenuItemsPropertiesUpdated {
+            updated_props: ids
+                .iter()
+                .filter_map(|&id| Some((id, self.props(self.find(id)?, &[]))))
+                .collect(),
+            removed_props: vec![],
+        }
+    }
+
+    /// The properties of `item` named in `names`, or all of them if `names`
+    /// is empty.  Properties with their default values are omitted.
+    fn props(&self, item: &MenuItem, names: &[String]) -> PropMap {
+        let mut props = PropMap::new();
+        let mut insert = |name: &str, value: Box<dyn RefArg>| {
+            if names.is_empty() || names.iter().any(|n| n == name) {
+                props.insert(name.to_owned(), Variant(value));
+            }
+        };
+        if item.separator {
+            insert("type", Box::new("separator".to_owned()));
+        }
+        if !item.label.is_empty() {
+            insert("label", Box::new(String::from(item.label.clone())));
+        }
+        if !item.enabled {
+            insert("enabled", Box::new(false));
+        }
+        if !item.visible {
+            insert("visible", Box::new(false));
+        }
+        if let Some(toggle_type) = item.toggle_type {
+            insert(
+                "toggle-type",
+                Box::new(toggle_type.to_dbusmenu().to_owned()),
+            );
+            insert("toggle-state", Box::new(item.toggle_state.to_dbusmenu()));
+        }
+        if !item.shortcuts.is_empty() {
+            let shortcuts: Vec<Vec<String>> =
+                item.shortcuts.iter().map(|s| s.tokens().to_vec()).collect();
+            insert("shortcut", Box::new(shortcuts));
+        }
+        if let Some(png) = self.icons.get(&item.id) {
+            insert("icon-data", Box::new(png.clone()));
+        }
+        if !item.children.is_empty() {
+            insert("children-display", Box::new("submenu".to_owned()));
+        }
+        props
+    }
+
+    /// The layout of `item` and its descendants, `depth` levels deep.  A
+    /// negative depth means all descendants.
+    fn layout(&self, item: &MenuItem, depth: i32, names: &[String]) -> Layout {
+        let children = if depth == 0 {
+            vec![]
+        } else {
+            item.children
+                .iter()
+                .map(|child| {
+                    let layout = self.layout(child, depth.saturating_sub(1).max(-1), names);
+                    Variant(Box::new(layout) as Box<dyn RefArg>)
+                })
+                .collect()
+        };
+        (item.id, self.props(item, names), children)
+    }
+}
+
+/// Encode `icon`, an ARGB32 pixmap, as PNG
+fn encode_png(icon: &sni_icon::IconData, max_icon_size: u32) -> Option<Vec<u8>> {
+    let (width, height) = (icon.width, icon.height);
+    if width == 0 || height == 0 || width > max_icon_size || height > max_icon_size {
+        return None;
+    }
+    if icon.data.len() != width as usize * height as usize * 4 {
+        return None;
+    }
+    let rgba: Vec<u8> = icon
+        .data
+        .chunks_exact(4)
+        .flat_map(|p| [p[1], p[2], p[3], p[0]])
+        .collect();
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().ok()?;
+    writer.write_image_data(&rgba).ok()?;
+    writer.finish().ok()?;
+    Some(png)
+}
+
+/// Whether `msg` is a call to AboutToShow.  These are answered by
+/// [`about_to_show`], not by crossroads, as the answer comes from the VM.
+pub(super) fn is_about_to_show(msg: &Message) -> bool {
+    msg.msg_type() == dbus::MessageType::MethodCall
+        && msg.interface() == Some(sni_icon::names::interface_com_canonical_dbusmenu())
+        && msg.member() == Some(sni_icon::names::about_to_show())
+}
+
+/// Forward `msg`, a call to AboutToShow, to the VM.  The reply is sent when
+/// the VM answers, or after [`ABOUT_TO_SHOW_TIMEOUT`] if it does not.
+pub(super) fn about_to_show(item: &NotifierIconWrapper, msg: Message, conn: &SyncConnection) {
+    let checked = msg
+        .read1::<i32>()
+        .map_err(dbus::MethodErr::from)
+        .and_then(|entry| {
+            item.with_menu(|icon_id, _, menu| {
+                menu.find(entry).ok_or_else(|| invalid_id(entry))?;
+                Ok((icon_id, entry))
+            })
+        });
+    let (icon_id, entry) = match checked {
+        Ok(checked) => checked,
+        Err(e) => {
+            let _ = conn.send(e.to_message(&msg));
+            return;
+        }
+    };
+    let accepted = item.with_icon(|icon| Ok(icon.accepts_input(false)));
+    if !accepted.expect("icon checked above") {
+        // Nothing changes without the VM
+        let _ = conn.send(msg.method_return().append1(false));
+        return;
+    }
+    item.with_icon(|icon| {
+        icon.request(
+            Request::AboutToShow { id: entry },
+            Waiting::AboutToShow(msg),
+            ABOUT_TO_SHOW_TIMEOUT,
+        );
+        Ok(())
+    })
+    .expect("icon checked above");
+    let items = item.context().items.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(ABOUT_TO_SHOW_TIMEOUT).await;
+        if let Some(icon) = items.lock().unwrap().get_mut(&icon_id) {
+            icon.expire_requests()
+        }
+    });
+}
+
+fn invalid_id(id: i32) -> dbus::MethodErr {
+    dbus::MethodErr::invalid_arg(&format!("no menu entry with ID {}", id))
+}
+
+impl NotifierIconWrapper {
+    /// Call `cb` with the ID of the item, and the revision and entries of
+    /// its menu
+    fn with_menu<T>(
+        &self,
+        cb: impl FnOnce(u64, u32, &Menu) -> Result<T, dbus::MethodErr>,
+    ) -> Result<T, dbus::MethodErr> {
+        self.with_icon(|icon| {
+            let id = icon.id();
+            match icon.menu() {
+                Some((revision, menu)) => cb(id, revision, menu),
+                None => Err(dbus::MethodErr::failed("Item has no menu")),
+            }
+        })
+    }
+}
+
+impl server::menu::Dbusmenu for NotifierIconWrapper {
+    fn get_layout(
+        &mut self,
+        parent_id: i32,
+        recursion_depth: i32,
+        property_names: Vec<String>,
+    ) -> Result<(u32, Layout), dbus::MethodErr> {
+        self.with_menu(|_, revision, menu| {
+            let parent = menu.find(parent_id).ok_or_else(|| invalid_id(parent_id))?;
+            Ok((
+                revision,
+                menu.layout(parent, recursion_depth, &property_names),
+            ))
+        })
+    }
+    fn get_group_properties(
+        &mut self,
+        ids: Vec<i32>,
+        property_names: Vec<String>,
+    ) -> Result<Vec<(i32, PropMap)>, dbus::MethodErr> {
+        self.with_menu(|_, _, menu| {
+            Ok(ids
+                .into_iter()
+                .filter_map(|id| Some((id, menu.props(menu.find(id)?, &property_names))))
+                .collect())
+        })
+    }
+    fn get_property(
+        &mut self,
+        id: i32,
+        name: String,
+    ) -> Result<Variant<Box<dyn RefArg + 'static>>, dbus::MethodErr> {
+        self.with_menu(|_, _, menu| {
+            let item = menu.find(id).ok_or_else(|| invalid_id(id))?;
+            menu.props(item, std::slice::from_ref(&name))
+                .remove(&name)
+                .ok_or_else(|| dbus::MethodErr::no_property(&name))
+        })
+    }
+    fn event(
+        &mut self,
+        id: i32,
+        event_id: String,
+        _data: Variant<Box<dyn RefArg + 'static>>,
+        timestamp: u32,
+    ) -> Result<(), dbus::MethodErr> {
+        self.with_menu(|_, _, menu| menu.find(id).map(drop).ok_or_else(|| invalid_id(id)))?;
+        let timestamp = if sni_icon::deterministic() {
+            0
+        } else {
+            timestamp
+        };
+        match Event::from_dbus(&event_id) {
+            Some(Event::Clicked) => self.with_icon(|icon| {
+                if !icon.accepts_input(true) {
+                    return Ok(());
+                }
+                self.context().output.send(IconServerEvent {
+                    id: icon.id(),
+                    event: ServerEvent::MenuClicked { id, timestamp },
+                });
+                icon.menu_entry_clicked(id);
+                Ok(())
+            })?,
+            Some(event @ (Event::Opened | Event::Closed)) => self.with_icon(|icon| {
+                if !icon.accepts_input(false) {
+                    return Ok(());
+                }
+                self.context().output.send(IconServerEvent {
+                    id: icon.id(),
+                    event: ServerEvent::MenuState {
+                        id,
+                        event,
+                        timestamp,
+                    },
+                });
+                Ok(())
+            })?,
+            // Hovering: panels send these as the pointer moves, which is
+            // too often to be worth waking up the VM for
+            _ => {}
+        }
+        Ok(())
+    }
+    fn event_group(
+        &mut self,
+        events: Vec<(i32, String, Variant<Box<dyn RefArg + 'static>>, u32)>,
+    ) -> Result<Vec<i32>, dbus::MethodErr> {
+        let mut id_errors = vec![];
+        for (id, event_id, data, timestamp) in events {
+            if self.event(id, event_id, data, timestamp).is_err() {
+                id_errors.push(id);
+            }
+        }
+        Ok(id_errors)
+    }
+    fn about_to_show(&mut self, id: i32) -> Result<bool, dbus::MethodErr> {
+        // Not reached: these calls are intercepted and forwarded to the VM
+        self.with_menu(|_, _, menu| {
+            menu.find(id).ok_or_else(|| invalid_id(id))?;
+            Ok(false)
+        })
+    }
+    fn about_to_show_group(
+        &mut self,
+        ids: Vec<i32>,
+    ) -> Result<(Vec<i32>, Vec<i32>), dbus::MethodErr> {
+        self.with_menu(|_, _, menu| {
+            let id_errors = ids
+                .into_iter()
+                .filter(|&id| menu.find(id).is_none())
+                .collect();
+            Ok((vec![], id_errors))
+        })
+    }
+    fn version(&self) -> Result<u32, dbus::MethodErr> {
+        Ok(3)
+    }
+    fn text_direction(&self) -> Result<String, dbus::MethodErr> {
+        Ok("ltr".to_owned())
+    }
+    fn status(&self) -> Result<String, dbus::MethodErr> {
+        Ok("normal".to_owned())
+    }
+    fn icon_theme_path(&self) -> Result<Vec<String>, dbus::MethodErr> {
+        Ok(vec![])
+    }
+}
@@ -0,0 +1,113 @@
+//! The order of the items of a VM, kept across reboots
+//!
+//! Each application of a VM is given an index when it first creates an
+//! item, which its items export as `XAyatanaOrderingIndex`.  Hosts that
+//! support it then show items in the order their applications were first
+//! seen, instead of the order in which they happened to start.  Indices
+//! start at 1, as 0 means that an item has no index.  They are saved in one
+//! file per VM, in a directory that survives reboots, and a VM gets at most
+//! [`MAX_APPS`] of them.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Most applications of a VM given an index
+const MAX_APPS: usize = 256;
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    /// Indices by the application ID sent by the VM
+    index: BTreeMap<String, u32>,
+}
+
+/// The indices of the applications of a VM
+pub(super) struct Order {
+    /// Where the indices are saved, if anywhere
+    path: Option<PathBuf>,
+    file: File,
+}
+
+impl Order {
+    /// The indices of the applications of `vm`, as saved where `config`
+    /// says
+    pub fn new(config: &crate::config::Order, vm: Option<&str>) -> Self {
+        let path = config.enabled.then(|| path(config, vm)).flatten();
+        let file = match &path {
+            None => File::default(),
+            Some(path) => load(path),
+        };
+        Self { path, file }
+    }
+
+    /// The directory indices are saved to, if they are saved at all
+    #[cfg(feature = "sandbox")]
+    pub fn directory(&self) -> Option<&std::path::Path> {
+        self.path.as_deref().and_then(std::path::Path::parent)
+    }
+
+    /// The index of the application `vm_app_id`, giving it the next one if
+    /// it has none yet, or 0 if the order is not kept or the VM has too
+    /// many applications
+    pub fn index(&mut self, vm_app_id: &str) -> u32 {
+        let Some(path) = &self.path else {
+            return 0;
+        };
+        if let Some(&index) = self.file.index.get(vm_app_id) {
+            return index;
+        }
+        if self.file.index.len() >= MAX_APPS {
+            return 0;
+        }
+        let index = self.file.index.values().max().map_or(1, |max| max + 1);
+        self.file.index.insert(vm_app_id.to_owned(), index);
+        let data = toml::to_string(&self.file).expect("indices can always be serialized");
+        if let Err(e) = crate::state::write(path, data.as_bytes()) {
+            log!(Warning, "Cannot save order to {}: {}", path.display(), e)
+        }
+        index
+    }
+}
+
+/// Where the indices of `vm` are saved, if anywhere
+fn path(config: &crate::config::Order, vm: Option<&str>) -> Option<PathBuf> {
+    let Some(directory) = config.directory.clone().or_else(|| {
+        let state = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })?;
+        Some(state.join("sni-daemon").join("order"))
+    }) else {
+        log!(
+            Warning,
+            "Not keeping the order of items: no state directory"
+        );
+        return None;
+    };
+    let name = match vm {
+        None => "local",
+        Some(vm) if crate::state::is_plain(vm) => vm,
+        Some(vm) => {
+            log!(Warning, "Not keeping the order of items of VM {:?}", vm);
+            return None;
+        }
+    };
+    Some(directory.join(format!("{}.toml", name)))
+}
+
+/// The indices saved at `path`
+fn load(path: &std::path::Path) -> File {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return File::default(),
+        Err(e) => {
+            log!(Warning, "Cannot read {}: {}", path.display(), e);
+            return File::default();
+        }
+    };
+    toml::from_str(&data).unwrap_or_else(|e| {
+        log!(Warning, "Ignoring invalid {}: {}", path.display(), e);
+        File::default()
+    })
+}
@@ -0,0 +1,47 @@
+//! Sending messages to the agent
+//!
+//! Each message is written to stdout after its length, and followed by the
+//! tag negotiated with the agent, see [`sni_icon::integrity`].  The
+//! [`sni_icon::Hello`] itself is sent before the tag is negotiated, and
+//! carries none.
+
+use sni_icon::Integrity;
+use std::io::Write as _;
+use std::sync::{Arc, Mutex};
+
+/// The stdout of a daemon, shared by everything sending to its agent
+#[derive(Clone, Debug, Default)]
+pub(super) struct Output {
+    integrity: Arc<Mutex<Integrity>>,
+}
+
+impl Output {
+    /// Tag the messages sent from now on with `integrity`
+    pub fn set_integrity(&self, integrity: Integrity) {
+        *self.integrity.lock().unwrap() = integrity
+    }
+
+    /// Send `s`, panicking if the agent is gone
+    pub fn send<T: sni_icon::Message>(&self, s: T) {
+        self.write(sni_icon::encode(&s).expect("Cannot encode data"))
+    }
+
+    /// Send the message `v`, which is already encoded
+    pub fn write(&self, v: Vec<u8>) {
+        let integrity = *self.integrity.lock().unwrap();
+        let mut out = std::io::stdout().lock();
+        #[cfg(feature = "testing")]
+        let messages = sni_icon::fault::message(v);
+        #[cfg(not(feature = "testing"))]
+        let messages = [v];
+        for v in messages {
+            log!(Debug, "Sending {} bytes", v.len());
+            out.write_all(&((v.len() as u32).to_le_bytes())[..])
+                .expect("cannot write to stdout");
+            out.write_all(&v[..]).expect("cannot write to stdout");
+            out.write_all(&integrity.tag(&v))
+                .expect("cannot write to stdout");
+        }
+        out.flush().expect("Cannot flush stdout");
+    }
+}
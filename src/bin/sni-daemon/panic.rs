@@ -0,0 +1,49 @@
+//! Cleanup after a panic
+//!
+//! A panic may happen while the icon registry is locked, or in a task that
+//! tokio would otherwise just log, leaving the exported icons in dom0's
+//! panel until the process goes away.  The panic hook therefore disconnects
+//! every bus connection of the daemon directly at the socket level and then
+//! aborts the process.
+
+use dbus::channel::Channel;
+use dbus::nonblock::SyncConnection;
+use std::collections::HashSet;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::sync::{LazyLock, Mutex};
+
+/// File descriptors of all bus connections owned by the daemon, on any
+/// thread
+static SOCKETS: LazyLock<Mutex<HashSet<RawFd>>> = LazyLock::new(Default::default);
+
+/// Register a bus connection to be disconnected on panic.  The returned file
+/// descriptor must be passed to [`unregister`] before the connection is
+/// dropped.
+pub(super) fn register(connection: &SyncConnection) -> RawFd {
+    let fd = AsRef::<Channel>::as_ref(connection).watch().fd;
+    SOCKETS.lock().unwrap().insert(fd);
+    fd
+}
+
+pub(super) fn unregister(fd: RawFd) {
+    SOCKETS.lock().unwrap().remove(&fd);
+}
+
+pub(super) fn install_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        // The panic may have happened while the set was locked
+        if let Ok(sockets) = SOCKETS.try_lock() {
+            for &fd in &*sockets {
+                // SAFETY: registered file descriptors are unregistered before
+                // the connection owning them is closed.
+                let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+                // The bus daemon now drops the connection, releasing its
+                // names and unregistering its items.
+                let _ = socket2::SockRef::from(&fd).shutdown(std::net::Shutdown::Both);
+            }
+        }
+        std::process::abort()
+    }));
+}
@@ -0,0 +1,119 @@
+//! Identifying the VM at the other end of a connection accepted on a socket
+//!
+//! When agents connect to a socket, the daemon is not started by qrexec for
+//! each VM, so `QREXEC_REMOTE_DOMAIN` does not tell which VM connected.
+//! Instead, the [`Peer`] is mapped to a VM by a [`Resolver`], configured in
+//! the `peers` section.  The VM is then used
+//! for policy, limits, labeling and logging, as if the daemon had been
+//! started by qrexec for it.
+
+use std::collections::HashMap;
+use std::process::Command;
+
+use sni_icon::transport::Peer;
+
+use crate::config::Peers;
+
+/// Finds the VM a peer belongs to
+pub(super) trait Resolver: Send + Sync {
+    /// The name of the VM of `peer`, or [`None`] if it is not one
+    fn resolve(&self, peer: &Peer) -> Result<Option<String>, String>;
+}
+
+/// VMs by the user ID of a local peer
+struct ByUid(HashMap<String, String>);
+
+impl Resolver for ByUid {
+    fn resolve(&self, peer: &Peer) -> Result<Option<String>, String> {
+        Ok(match peer {
+            Peer::Process(cred) => self.0.get(&cred.uid.to_string()).cloned(),
+            _ => None,
+        })
+    }
+}
+
+/// VMs by the context ID of a vsock peer
+struct ByCid(HashMap<String, String>);
+
+impl Resolver for ByCid {
+    fn resolve(&self, peer: &Peer) -> Result<Option<String>, String> {
+        Ok(match peer {
+            Peer::Vsock { cid } => self.0.get(&cid.to_string()).cloned(),
+            _ => None,
+        })
+    }
+}
+
+/// A command printing the VM of the peer, given what is known about it in
+/// the environment variables `SNI_PEER_PID`, `SNI_PEER_UID` and
+/// `SNI_PEER_GID` for local peers, or `SNI_PEER_CID` for vsock peers.
+/// Empty output means that the peer is not a VM.
+struct ByCommand(Vec<String>);
+
+impl Resolver for ByCommand {
+    fn resolve(&self, peer: &Peer) -> Result<Option<String>, String> {
+        let (program, args) = self.0.split_first().expect("checked in resolver()");
+        let mut command = Command::new(program);
+        command.args(args);
+        match *peer {
+            Peer::Process(cred) => {
+                command
+                    .env("SNI_PEER_UID", cred.uid.to_string())
+                    .env("SNI_PEER_GID", cred.gid.to_string());
+                if let Some(pid) = cred.pid {
+                    command.env("SNI_PEER_PID", pid.to_string());
+                }
+            }
+            Peer::Vsock { cid } => {
+                command.env("SNI_PEER_CID", cid.to_string());
+            }
+            Peer::Unknown => return Ok(None),
+        }
+        let output = command
+            .output()
+            .map_err(|e| format!("cannot run {:?}: {}", program, e))?;
+        if !output.status.success() {
+            return Err(format!("{:?} failed: {}", program, output.status));
+        }
+        let vm = String::from_utf8(output.stdout)
+            .map_err(|_| format!("{:?} printed a VM name that is not UTF-8", program))?;
+        let vm = vm.trim();
+        Ok((!vm.is_empty()).then(|| vm.to_owned()))
+    }
+}
+
+/// Try each resolver in turn, until one finds a VM
+struct First(Vec<Box<dyn Resolver>>);
+
+impl Resolver for First {
+    fn resolve(&self, peer: &Peer) -> Result<Option<String>, String> {
+        for resolver in &self.0 {
+            if let Some(vm) = resolver.resolve(peer)? {
+                return Ok(Some(vm));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The resolver configured by `peers`: by user ID or context ID, then by
+/// command.
+/// [`None`] if neither is configured, in which case peers are not
+/// identified at all.
+pub(super) fn resolver(peers: &Peers) -> Option<Box<dyn Resolver>> {
+    let mut resolvers: Vec<Box<dyn Resolver>> = vec![];
+    if !peers.uid.is_empty() {
+        resolvers.push(Box::new(ByUid(peers.uid.clone())))
+    }
+    if !peers.cid.is_empty() {
+        resolvers.push(Box::new(ByCid(peers.cid.clone())))
+    }
+    if !peers.command.is_empty() {
+        resolvers.push(Box::new(ByCommand(peers.command.clone())))
+    }
+    match resolvers.len() {
+        0 => None,
+        1 => resolvers.pop(),
+        _ => Some(Box::new(First(resolvers))),
+    }
+}
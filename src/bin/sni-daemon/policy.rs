@@ -0,0 +1,52 @@
+//! Querying qrexec policy for what a VM may do
+//!
+//! Each feature is a separate argument of the configured service, so an
+//! administrator can write e.g.
+//!
+//! ```text
+//! qubes.StatusNotifierItem  +         @tag:tray  @adminvm  allow
+//! qubes.StatusNotifierItem  +menus    untrusted  @adminvm  deny
+//! ```
+//!
+//! The arguments are `menus`, `tooltips` and `interaction`, see
+//! [`Limits`].
+
+use crate::config::{Limits, Policy};
+use std::error::Error;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+/// Ask qrexec-policy-daemon whether `vm` may call `service` in dom0.
+async fn allowed(policy: &Policy, vm: &str, service: &str) -> Result<bool, Box<dyn Error>> {
+    let mut socket = tokio::net::UnixStream::connect(&policy.socket)
+        .await
+        .map_err(|e| format!("cannot connect to {}: {}", policy.socket.display(), e))?;
+    // Socket-based qrexec services get a "service+argument source\0" header,
+    // followed by the request: source, intended target, and queried service.
+    let request = format!("policy.EvalSimple+ dom0\0{}\0@adminvm\0{}", vm, service);
+    socket.write_all(request.as_bytes()).await?;
+    socket.shutdown().await?;
+    let mut response = Vec::new();
+    socket.take(4096).read_to_end(&mut response).await?;
+    match response.strip_suffix(b"\n").unwrap_or(&response[..]) {
+        b"result=allow" => Ok(true),
+        b"result=deny" => Ok(false),
+        other => Err(format!("bad response from policy daemon: {:?}", other).into()),
+    }
+}
+
+/// Restrict `limits` according to qrexec policy.  Returns `false` if `vm`
+/// may not export icons at all.
+pub(super) async fn apply(
+    policy: &Policy,
+    vm: &str,
+    limits: &mut Limits,
+) -> Result<bool, Box<dyn Error>> {
+    if !allowed(policy, vm, &format!("{}+", policy.service)).await? {
+        return Ok(false);
+    }
+    limits.allow_menus &= allowed(policy, vm, &format!("{}+menus", policy.service)).await?;
+    limits.allow_tooltips &= allowed(policy, vm, &format!("{}+tooltips", policy.service)).await?;
+    limits.allow_interaction &=
+        allowed(policy, vm, &format!("{}+interaction", policy.service)).await?;
+    Ok(true)
+}
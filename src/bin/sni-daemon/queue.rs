@@ -0,0 +1,395 @@
+//! The queue of events read from the VM and waiting to be handled
+//!
+//! Events are read as they arrive, but handling them waits for the throttle
+//! and for D-Bus, so the queue holds at most
+//! [`crate::config::Limits::max_queued_events`].  When it is full, an event
+//! setting the same property of an item as a queued one replaces it, as
+//! only the latest value matters.  Any other event waits for room, which
+//! stops reading from the VM until the daemon has caught up.
+//!
+//! Events changing properties of the same item that are queued one after
+//! the other are taken together ([`Queue::pop_related`]), so that hosts see
+//! all of them applied at once.
+
+use sni_icon::{ClientEvent, IconClientEvent, IconType};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// What an event sets, for events that only set one property of an item
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Property {
+    Title,
+    Status,
+    Icon(IconType),
+    Tooltip,
+    Menu,
+    AccessibleDesc(IconType),
+    Responsive,
+}
+
+impl Property {
+    fn of(event: &ClientEvent) -> Option<Self> {
+        Some(match *event {
+            ClientEvent::Title(_) => Self::Title,
+            ClientEvent::Status(_) => Self::Status,
+            ClientEvent::Icon { typ, .. }
+            | ClientEvent::CachedIcon { typ, .. }
+            | ClientEvent::RemoveIcon(typ) => Self::Icon(typ),
+            ClientEvent::Tooltip { .. } | ClientEvent::RemoveTooltip => Self::Tooltip,
+            ClientEvent::Menu(_) | ClientEvent::RemoveMenu => Self::Menu,
+            ClientEvent::AccessibleDesc { typ, .. } => Self::AccessibleDesc(typ),
+            ClientEvent::Responsive(_) => Self::Responsive,
+            _ => return None,
+        })
+    }
+}
+
+/// Whether `event` only changes properties of its item
+pub(super) fn changes_properties(event: &ClientEvent) -> bool {
+    Property::of(event).is_some()
+        || matches!(
+            event,
+            ClientEvent::IconChunk { .. } | ClientEvent::Update(_)
+        )
+}
+
+/// An event waiting to be handled
+pub(super) struct Queued {
+    pub event: IconClientEvent,
+    /// Whether the event replaced a queued one.  The icon chunks received
+    /// before an icon that replaced another belong to the replaced one.
+    pub replaced: bool,
+    /// The size of the event as received, and of those it replaced
+    pub size: usize,
+}
+
+struct State {
+    events: VecDeque<Queued>,
+    capacity: usize,
+    closed: bool,
+}
+
+/// A queue with one side reading events and the other handling them
+pub(super) struct Queue {
+    state: Mutex<State>,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl Queue {
+    /// A queue of at most `capacity` events, but at least one
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                events: VecDeque::new(),
+                capacity: capacity.max(1),
+                closed: false,
+            }),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    /// Add `event`, received as `size` bytes, waiting for room if it
+    /// replaces no queued event.  Returns whether it replaced one.
+    pub async fn push(&self, event: IconClientEvent, size: usize) -> bool {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.events.len() < state.capacity {
+                    state.events.push_back(Queued {
+                        event,
+                        replaced: false,
+                        size,
+                    });
+                    self.readable.notify_one();
+                    return false;
+                }
+                if let Some(replaced) = state.coalesce(&event) {
+                    state.events.push_back(Queued {
+                        event,
+                        replaced: true,
+                        size: size + replaced,
+                    });
+                    return true;
+                }
+            }
+            self.writable.notified().await;
+        }
+    }
+
+    /// Take the next event, without waiting, if it only changes properties
+    /// of item `id`
+    pub fn pop_related(&self, id: u64) -> Option<Queued> {
+        let mut state = self.state.lock().unwrap();
+        let next = &state.events.front()?.event;
+        if next.id != id || !changes_properties(&next.event) {
+            return None;
+        }
+        self.writable.notify_one();
+        state.events.pop_front()
+    }
+
+    /// Tell the handling side that no more events will be added
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_one();
+    }
+
+    /// Take the next event, or [`None`] once the queue is empty and closed
+    pub async fn pop(&self) -> Option<Queued> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(event) = state.events.pop_front() {
+                    self.writable.notify_one();
+                    return Some(event);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+}
+
+impl State {
+    /// Remove the queued events that `event` makes pointless, returning
+    /// their size if there were any.  Those are the latest event of the same
+    /// item setting the same property, if only events setting other
+    /// properties follow it, and for icons, the chunks it would have taken.
+    fn coalesce(&mut self, event: &IconClientEvent) -> Option<usize> {
+        let property = Property::of(&event.event)?;
+        let mut same_item = self
+            .events
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, queued)| queued.event.id == event.id);
+        let index = loop {
+            let (index, queued) = same_item.next()?;
+            match Property::of(&queued.event.event) {
+                Some(queued) if queued == property => break index,
+                Some(_) => {}
+                // Creation, destruction, chunks and replies
+                None => return None,
+            }
+        };
+        let mut size = self.events.remove(index)?.size;
+        if let Property::Icon(typ) = property {
+            // The chunks the replaced icon would have taken
+            let mut index = index;
+            while index > 0 {
+                index -= 1;
+                let queued = &self.events[index].event;
+                if queued.id != event.id {
+                    continue;
+                }
+                match queued.event {
+                    ClientEvent::IconChunk { typ: t, .. } if t == typ => {
+                        size += self.events.remove(index).map_or(0, |chunk| chunk.size);
+                    }
+                    ClientEvent::IconChunk { .. } => {}
+                    _ => break,
+                }
+            }
+        }
+        Some(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: u64, event: ClientEvent) -> IconClientEvent {
+        IconClientEvent {
+            id,
+            timestamp: 0,
+            event,
+        }
+    }
+
+    fn title(id: u64, title: &str) -> IconClientEvent {
+        event(id, ClientEvent::Title(Some(title.to_owned())))
+    }
+
+    fn icon(id: u64, typ: IconType) -> IconClientEvent {
+        event(id, ClientEvent::Icon { typ, data: vec![] })
+    }
+
+    fn chunk(id: u64, typ: IconType) -> IconClientEvent {
+        event(
+            id,
+            ClientEvent::IconChunk {
+                typ,
+                width: 1,
+                height: 1,
+                offset: 0,
+                data: vec![0; 4],
+            },
+        )
+    }
+
+    /// A full queue holding `events`, each received as 10 bytes
+    fn state(events: Vec<IconClientEvent>) -> State {
+        State {
+            capacity: events.len(),
+            events: events
+                .into_iter()
+                .map(|event| Queued {
+                    event,
+                    replaced: false,
+                    size: 10,
+                })
+                .collect(),
+            closed: false,
+        }
+    }
+
+    /// Icon 1 following its chunks, and chunks of other icons
+    fn state_of_icons() -> State {
+        state(vec![
+            chunk(1, IconType::Normal),
+            chunk(1, IconType::Attention),
+            chunk(2, IconType::Normal),
+            chunk(1, IconType::Normal),
+            icon(1, IconType::Normal),
+        ])
+    }
+
+    /// Events do not implement `PartialEq`, so they are compared as printed
+    fn debug(event: &IconClientEvent) -> String {
+        format!("{:?}", event)
+    }
+
+    fn events(state: &State) -> Vec<String> {
+        state
+            .events
+            .iter()
+            .map(|queued| debug(&queued.event))
+            .collect()
+    }
+
+    #[test]
+    fn the_same_property_is_replaced() {
+        let mut state = state(vec![title(1, "Old"), title(2, "Other"), title(1, "Older")]);
+        assert_eq!(state.coalesce(&title(1, "New")), Some(10));
+        assert_eq!(
+            events(&state),
+            [debug(&title(1, "Old")), debug(&title(2, "Other"))]
+        );
+        assert_eq!(state.coalesce(&title(1, "New")), Some(10));
+        assert_eq!(events(&state), [debug(&title(2, "Other"))]);
+        assert_eq!(state.coalesce(&title(3, "New")), None);
+    }
+
+    #[test]
+    fn other_properties_are_kept() {
+        let status = || event(1, ClientEvent::Status(Some("Active".to_owned())));
+        let mut state = state(vec![title(1, "Old"), status()]);
+        assert_eq!(state.coalesce(&title(1, "New")), Some(10));
+        assert_eq!(events(&state), [debug(&status())]);
+        let mut state = state_of_icons();
+        assert_eq!(state.coalesce(&icon(1, IconType::Overlay)), None);
+    }
+
+    #[test]
+    fn other_events_are_not_skipped() {
+        for other in [
+            ClientEvent::Destroy,
+            ClientEvent::Create {
+                category: "ApplicationStatus".to_owned(),
+                app_id: "org.example.App".to_owned(),
+                is_menu: false,
+                capabilities: Default::default(),
+            },
+        ] {
+            let mut state = state(vec![title(1, "Old"), event(1, other)]);
+            assert_eq!(state.coalesce(&title(1, "New")), None);
+            assert_eq!(state.events.len(), 2);
+        }
+        let mut state = state(vec![title(1, "Old"), chunk(1, IconType::Normal)]);
+        assert_eq!(state.coalesce(&title(1, "New")), None);
+    }
+
+    #[test]
+    fn icons_are_replaced_with_their_chunks() {
+        let mut state = state_of_icons();
+        assert_eq!(state.coalesce(&icon(1, IconType::Normal)), Some(30));
+        assert_eq!(
+            events(&state),
+            [
+                debug(&chunk(1, IconType::Attention)),
+                debug(&chunk(2, IconType::Normal))
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_of_earlier_icons_are_kept() {
+        let mut state = state(vec![
+            chunk(1, IconType::Normal),
+            icon(1, IconType::Normal),
+            chunk(1, IconType::Normal),
+            icon(1, IconType::Normal),
+        ]);
+        assert_eq!(state.coalesce(&icon(1, IconType::Normal)), Some(20));
+        assert_eq!(
+            events(&state),
+            [
+                debug(&chunk(1, IconType::Normal)),
+                debug(&icon(1, IconType::Normal))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn related_events_are_taken_together() {
+        let queue = Queue::new(8);
+        for event in [
+            title(1, "Title"),
+            event(1, ClientEvent::Status(Some("Active".to_owned()))),
+            title(2, "Other"),
+            title(1, "Again"),
+            event(1, ClientEvent::Destroy),
+        ] {
+            queue.push(event, 10).await;
+        }
+        let first = queue.pop().await.unwrap();
+        assert_eq!(debug(&first.event), debug(&title(1, "Title")));
+        let related = queue.pop_related(1).unwrap();
+        assert!(matches!(related.event.event, ClientEvent::Status(_)));
+        // Another item comes first
+        assert!(queue.pop_related(1).is_none());
+        assert_eq!(
+            debug(&queue.pop().await.unwrap().event),
+            debug(&title(2, "Other"))
+        );
+        assert_eq!(
+            debug(&queue.pop_related(1).unwrap().event),
+            debug(&title(1, "Again"))
+        );
+        // Destruction does not only change properties
+        assert!(queue.pop_related(1).is_none());
+        queue.close();
+        assert!(queue.pop().await.is_some());
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn full_queues_replace_events() {
+        let queue = Queue::new(2);
+        queue.push(title(1, "Old"), 10).await;
+        queue.push(title(2, "Other"), 10).await;
+        queue.push(title(1, "New"), 5).await;
+        let first = queue.pop().await.unwrap();
+        assert_eq!(debug(&first.event), debug(&title(2, "Other")));
+        let second = queue.pop().await.unwrap();
+        assert_eq!(debug(&second.event), debug(&title(1, "New")));
+        assert!(second.replaced);
+        assert_eq!(second.size, 15);
+    }
+}
@@ -0,0 +1,186 @@
+//! Logging of VM-controlled data
+//!
+//! Strings from the VM are hostile: logging them verbatim would let a VM
+//! write escape sequences to a dom0 terminal or confuse anyone reading the
+//! logs.  By default only their length and a hash prefix are logged.  With
+//! `raw_strings` enabled in the `[log]` section of the configuration, strings
+//! that are [`SafelyDisplayable`] are logged as well.
+
+use qubes_utils::SafelyDisplayable;
+use sha2::{Digest as _, Sha256};
+use sni_icon::{ClientEvent, IconClientEvent};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RAW_STRINGS: AtomicBool = AtomicBool::new(false);
+
+pub(super) fn set_raw_strings(raw: bool) {
+    RAW_STRINGS.store(raw, Ordering::Relaxed)
+}
+
+/// The name of the variant of `event`, for the `EVENT` journal field
+pub(super) fn event_name(event: &ClientEvent) -> &'static str {
+    match event {
+        ClientEvent::Create { .. } => "Create",
+        ClientEvent::Title(_) => "Title",
+        ClientEvent::Status(_) => "Status",
+        ClientEvent::Icon { .. } => "Icon",
+        ClientEvent::RemoveIcon(_) => "RemoveIcon",
+        ClientEvent::IconChunk { .. } => "IconChunk",
+        ClientEvent::Destroy => "Destroy",
+        ClientEvent::Tooltip { .. } => "Tooltip",
+        ClientEvent::RemoveTooltip => "RemoveTooltip",
+        ClientEvent::Menu(_) => "Menu",
+        ClientEvent::RemoveMenu => "RemoveMenu",
+        ClientEvent::Reply { .. } => "Reply",
+        ClientEvent::AccessibleDesc { .. } => "AccessibleDesc",
+        ClientEvent::Update(_) => "Update",
+        ClientEvent::CachedIcon { .. } => "CachedIcon",
+        ClientEvent::Responsive(_) => "Responsive",
+        ClientEvent::CallFailed { .. } => "CallFailed",
+        _ => "Unknown",
+    }
+}
+
+/// A VM-controlled string, formatted safely
+pub(super) struct Redacted<'a>(pub &'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if RAW_STRINGS.load(Ordering::Relaxed) {
+            if let Ok(s) = SafelyDisplayable::try_from(self.0) {
+                return write!(f, "{:?}", &*s);
+            }
+        }
+        let hash = Sha256::digest(self.0.as_bytes());
+        write!(
+            f,
+            "<{} bytes, sha256 {:02x}{:02x}{:02x}{:02x}>",
+            self.0.len(),
+            hash[0],
+            hash[1],
+            hash[2],
+            hash[3]
+        )
+    }
+}
+
+/// An event from the VM, formatted without pixel data and with all strings
+/// redacted
+pub(super) struct RedactedEvent<'a>(pub &'a IconClientEvent);
+
+impl fmt::Display for RedactedEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn optional(s: &Option<String>) -> Option<Redacted<'_>> {
+            s.as_deref().map(Redacted)
+        }
+        fn changed(s: &Option<Option<String>>) -> String {
+            match s {
+                None => "unchanged".to_owned(),
+                Some(s) => optional(s).map_or("None".to_owned(), |s| s.to_string()),
+            }
+        }
+        write!(f, "item {}: ", self.0.id)?;
+        match &self.0.event {
+            ClientEvent::Create {
+                category,
+                app_id,
+                capabilities,
+                ..
+            } => write!(
+                f,
+                "Create {{ category: {}, app_id: {}, capabilities: {:#x} }}",
+                Redacted(category),
+                Redacted(app_id),
+                capabilities.0
+            ),
+            ClientEvent::Title(title) => match optional(title) {
+                Some(title) => write!(f, "Title({})", title),
+                None => f.write_str("Title(None)"),
+            },
+            ClientEvent::Status(status) => match optional(status) {
+                Some(status) => write!(f, "Status({})", status),
+                None => f.write_str("Status(None)"),
+            },
+            ClientEvent::Icon { typ, data } => {
+                write!(f, "Icon {{ typ: {:?}, frames: {} }}", typ, data.len())
+            }
+            ClientEvent::RemoveIcon(typ) => write!(f, "RemoveIcon({:?})", typ),
+            ClientEvent::IconChunk {
+                typ,
+                width,
+                height,
+                offset,
+                data,
+            } => write!(
+                f,
+                "IconChunk {{ typ: {:?}, width: {}, height: {}, offset: {}, length: {} }}",
+                typ,
+                width,
+                height,
+                offset,
+                data.len()
+            ),
+            ClientEvent::Destroy => f.write_str("Destroy"),
+            ClientEvent::Tooltip {
+                icon_data,
+                title,
+                description,
+            } => write!(
+                f,
+                "Tooltip {{ frames: {}, title: {}, description: {} }}",
+                icon_data.len(),
+                Redacted(title),
+                Redacted(description)
+            ),
+            ClientEvent::RemoveTooltip => f.write_str("RemoveTooltip"),
+            ClientEvent::Menu(root) => {
+                fn count(item: &sni_icon::MenuItem) -> usize {
+                    1 + item.children.iter().map(count).sum::<usize>()
+                }
+                write!(f, "Menu {{ entries: {} }}", count(root))
+            }
+            ClientEvent::RemoveMenu => f.write_str("RemoveMenu"),
+            ClientEvent::Reply { request, body } => {
+                write!(f, "Reply {{ request: {}, body: {:?} }}", request, body)
+            }
+            ClientEvent::AccessibleDesc { typ, desc } => match desc {
+                Some(desc) => write!(
+                    f,
+                    "AccessibleDesc {{ typ: {:?}, desc: {} }}",
+                    typ,
+                    Redacted(desc)
+                ),
+                None => write!(f, "AccessibleDesc {{ typ: {:?}, desc: None }}", typ),
+            },
+            ClientEvent::Update(update) => write!(
+                f,
+                "Update {{ title: {}, status: {}, tooltip: {}, icons: {:?} }}",
+                changed(&update.title),
+                changed(&update.status),
+                match &update.tooltip {
+                    None => "unchanged",
+                    Some(None) => "None",
+                    Some(Some(_)) => "set",
+                },
+                update
+                    .icons
+                    .iter()
+                    .map(|(typ, data)| (typ, data.len()))
+                    .collect::<Vec<_>>()
+            ),
+            ClientEvent::CachedIcon { typ, hash } => write!(
+                f,
+                "CachedIcon {{ typ: {:?}, hash: {:02x}{:02x}{:02x}{:02x} }}",
+                typ, hash[0], hash[1], hash[2], hash[3]
+            ),
+            ClientEvent::Responsive(responsive) => write!(f, "Responsive({})", responsive),
+            ClientEvent::CallFailed { call, timed_out } => write!(
+                f,
+                "CallFailed {{ call: {:?}, timed_out: {} }}",
+                call, timed_out
+            ),
+            _ => f.write_str("Unknown"),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Ownership of all bus state of the daemon
+
+use crate::item::Items;
+use dbus::channel::{Channel, MatchingReceiver as _, Sender as _, Token};
+use dbus::nonblock::SyncConnection;
+use dbus::strings::BusName;
+use dbus::Message;
+use sni_icon::names;
+use std::sync::{Arc, PoisonError};
+
+/// The daemon's icons, together with the bus state that exists to serve
+/// them.  Dropping this destroys every icon, along with the paths each
+/// serves on its own connection, and releases the daemon's well-known name,
+/// so that nothing outlives the daemon even if the process does.
+pub(super) struct Registry {
+    pub icons: Items,
+    connection: Arc<SyncConnection>,
+    control_token: Token,
+    name: Option<BusName<'static>>,
+}
+
+impl Registry {
+    pub fn new(
+        icons: Items,
+        connection: Arc<SyncConnection>,
+        control_token: Token,
+        name: Option<BusName<'static>>,
+    ) -> Self {
+        Self {
+            icons,
+            connection,
+            control_token,
+            name,
+        }
+    }
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        // Dropping an icon emits its final signals and closes its connection
+        let icons = std::mem::take(&mut *self.icons.lock().unwrap_or_else(PoisonError::into_inner));
+        log!(Info, "Tearing down {} icons", icons.len());
+        drop(icons);
+        // This drops the control crossroads instance
+        self.connection.stop_receive(self.control_token);
+        if let Some(name) = self.name.take() {
+            let msg = Message::method_call(
+                &names::name_dbus(),
+                &names::path_dbus(),
+                &names::interface_dbus(),
+                &names::release_name(),
+            )
+            .append1(&*name);
+            if self.connection.send(msg).is_err() {
+                log!(Warning, "Cannot release bus name");
+            }
+        }
+        AsRef::<Channel>::as_ref(&*self.connection).flush();
+    }
+}
@@ -0,0 +1,233 @@
+//! Sandboxing the daemon once it is set up
+//!
+//! By the time the daemon starts reading events from the VM, it is connected
+//! to the session bus and to the agent, and only needs to read its
+//! configuration (when reloaded) and to save items to its state directory.
+//! Should parsing the input of the VM ever be compromised, the sandbox keeps
+//! the attacker from doing much else:
+//!
+//! - a Landlock ruleset denies all file system access except reading the
+//!   directory of the configuration file and writing the state directory,
+//!   and all execution of programs;
+//! - a seccomp filter (see [`sni_icon::seccomp`]) makes system calls that
+//!   the daemon never needs fail with `EPERM`, in particular starting new
+//!   processes, tracing others and changing namespaces or mounts.
+//!
+//! Landlock is only used if the kernel supports it.  The sandbox must be
+//! applied before tokio starts any threads, as Landlock only restricts the
+//! calling thread and threads started later.
+
+use sni_icon::seccomp;
+use std::error::Error;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::Path;
+
+/// `struct landlock_ruleset_attr`, as of Landlock ABI 3
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr`
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+/// The last right of Landlock ABI 1
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// Rights needed to save items, see [`crate::state`]
+const STATE_ACCESS: u64 = ACCESS_FS_READ_FILE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_REFER
+    | ACCESS_FS_TRUNCATE;
+
+/// Apply the sandbox to the whole process.  `config` is the path of the
+/// configuration file, and `state` the directories the daemon saves to,
+/// which are created if need be.
+pub(super) fn apply(config: &Path, state: &[&Path]) -> Result<(), Box<dyn Error>> {
+    seccomp::no_new_privs().map_err(|e| format!("cannot set no_new_privs: {}", e))?;
+    let mut rules = vec![];
+    if let Some(directory) = config.parent() {
+        rules.push((directory, ACCESS_FS_READ_FILE));
+    }
+    for &state in state {
+        use std::os::unix::fs::DirBuilderExt as _;
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(state)
+            .map_err(|e| format!("cannot create {}: {}", state.display(), e))?;
+        rules.push((state, STATE_ACCESS));
+    }
+    match landlock(&rules)? {
+        Some(abi) => log!(Info, "Applied Landlock ruleset (ABI {})", abi),
+        None => log!(
+            Warning,
+            "Landlock is not supported, not restricting file access"
+        ),
+    }
+    seccomp::install(seccomp::Mode::Deny, DENIED)
+        .map_err(|e| format!("cannot install seccomp filter: {}", e))?;
+    log!(Info, "Applied seccomp filter");
+    Ok(())
+}
+
+/// Restrict file access to `rules`, returning the Landlock ABI version used,
+/// or [`None`] if the kernel does not support Landlock
+fn landlock(rules: &[(&Path, u64)]) -> Result<Option<i64>, Box<dyn Error>> {
+    // SAFETY: this only queries the ABI version
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Ok(None);
+    }
+    let handled = match abi {
+        1 => (ACCESS_FS_MAKE_SYM << 1) - 1,
+        2 => (ACCESS_FS_REFER << 1) - 1,
+        _ => (ACCESS_FS_TRUNCATE << 1) - 1,
+    };
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: `attr` is valid for reads of its size
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of_val(&attr),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(format!(
+            "cannot create Landlock ruleset: {}",
+            io::Error::last_os_error()
+        )
+        .into());
+    }
+    // SAFETY: the kernel returned a new file descriptor
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as _) };
+    for &(path, access) in rules {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: `c_path` is a valid C string
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            log!(
+                Warning,
+                "Cannot open {}, denying access to it: {}",
+                path.display(),
+                io::Error::last_os_error()
+            );
+            continue;
+        }
+        // SAFETY: open() returned a new file descriptor
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let rule = PathBeneathAttr {
+            allowed_access: access & handled & !ACCESS_FS_EXECUTE,
+            parent_fd: fd.as_raw_fd(),
+        };
+        // SAFETY: `rule` is valid for reads of its size
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule,
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(format!(
+                "cannot allow access to {}: {}",
+                path.display(),
+                io::Error::last_os_error()
+            )
+            .into());
+        }
+    }
+    // SAFETY: the ruleset is a valid file descriptor
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+        return Err(format!(
+            "cannot apply Landlock ruleset: {}",
+            io::Error::last_os_error()
+        )
+        .into());
+    }
+    Ok(Some(abi))
+}
+
+/// System calls that fail with `EPERM`
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_fork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_open_tree,
+    libc::SYS_move_mount,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_mount_setattr,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_syslog,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_personality,
+    libc::SYS_fanotify_init,
+];
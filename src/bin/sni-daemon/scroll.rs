@@ -0,0 +1,240 @@
+//! Normalization of scroll deltas from the panel
+//!
+//! The StatusNotifierItem specification does not say what the delta of a
+//! `Scroll` call is measured in.  Some panels send ±120 per step of the
+//! wheel, as Qt does, others ±1.  The daemon divides deltas by the step
+//! configured for the panel in use (see [`crate::config::Scroll`]), so that
+//! VMs always receive whole steps.  Partial steps, as sent by touchpads, are
+//! added up until they make a whole one, or forgotten after a while without
+//! scrolling.
+//!
+//! Touchpads also call `Scroll` dozens of times per second.  Whole steps are
+//! therefore queued for a short window, and the steps of all calls in that
+//! window are forwarded as a single event per orientation.
+
+use std::time::Instant;
+
+use crate::config;
+
+/// The orientations of the `Scroll` method
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Orientation {
+    Vertical,
+    Horizontal,
+}
+
+impl Orientation {
+    /// Parse the orientation of a `Scroll` call, ignoring case
+    pub fn parse(orientation: &str) -> Option<Self> {
+        if orientation.eq_ignore_ascii_case("vertical") {
+            Some(Self::Vertical)
+        } else if orientation.eq_ignore_ascii_case("horizontal") {
+            Some(Self::Horizontal)
+        } else {
+            None
+        }
+    }
+
+    /// The name forwarded to the VM
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Vertical => "vertical",
+            Self::Horizontal => "horizontal",
+        }
+    }
+}
+
+/// The partial and queued steps of one orientation
+#[derive(Debug, Default, Clone, Copy)]
+struct Partial {
+    delta: i64,
+    last: Option<Instant>,
+    /// Whole steps waiting to be forwarded, if a window is open
+    queued: Option<i64>,
+}
+
+/// Deltas scrolled over an item that were not forwarded yet
+#[derive(Debug, Default)]
+pub(super) struct Accumulator {
+    vertical: Partial,
+    horizontal: Partial,
+}
+
+impl Accumulator {
+    fn partial(&mut self, orientation: Orientation) -> &mut Partial {
+        match orientation {
+            Orientation::Vertical => &mut self.vertical,
+            Orientation::Horizontal => &mut self.horizontal,
+        }
+    }
+
+    /// The number of whole steps to forward after scrolling by `delta` in
+    /// `orientation`, which is 0 if nothing is to be forwarded
+    pub fn add(&mut self, settings: &config::Scroll, delta: i32, orientation: Orientation) -> i32 {
+        self.add_at(settings, delta, orientation, Instant::now())
+    }
+
+    /// [`add`](Self::add), scrolling at `now`
+    fn add_at(
+        &mut self,
+        settings: &config::Scroll,
+        delta: i32,
+        orientation: Orientation,
+        now: Instant,
+    ) -> i32 {
+        let partial = self.partial(orientation);
+        if partial
+            .last
+            .is_some_and(|last| now - last > settings.partial_timeout())
+        {
+            partial.delta = 0;
+        }
+        partial.last = Some(now);
+        let step = i64::from(settings.step.max(1));
+        let total = partial.delta + i64::from(delta);
+        let steps = total / step;
+        partial.delta = total - steps * step;
+        clamp(settings, steps)
+    }
+
+    /// Queue `steps` to be forwarded at the end of the window.  Returns
+    /// whether this opened the window, in which case the caller must
+    /// [`take`](Self::take) the steps when it ends.
+    pub fn queue(&mut self, orientation: Orientation, steps: i32) -> bool {
+        let partial = self.partial(orientation);
+        let opened = partial.queued.is_none();
+        *partial.queued.get_or_insert(0) += i64::from(steps);
+        opened
+    }
+
+    /// Close the window of `orientation`, returning the steps queued in it
+    pub fn take(&mut self, settings: &config::Scroll, orientation: Orientation) -> i32 {
+        self.partial(orientation)
+            .queued
+            .take()
+            .map_or(0, |steps| clamp(settings, steps))
+    }
+}
+
+/// Limit `steps` to [`config::Scroll::max_steps`]
+fn clamp(settings: &config::Scroll, steps: i64) -> i32 {
+    let max = match settings.max_steps {
+        0 => i64::from(i32::MAX),
+        max => i64::from(max),
+    };
+    steps.clamp(-max, max) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn settings(step: u32, max_steps: u32) -> config::Scroll {
+        config::Scroll {
+            step,
+            max_steps,
+            ..Default::default()
+        }
+    }
+
+    /// The steps forwarded for each of `deltas`, scrolled 10 ms apart
+    fn steps(settings: &config::Scroll, deltas: &[i32]) -> Vec<i32> {
+        let mut accumulator = Accumulator::default();
+        let start = Instant::now();
+        (0..)
+            .zip(deltas)
+            .map(|(i, &delta)| {
+                let now = start + Duration::from_millis(10 * i);
+                accumulator.add_at(settings, delta, Orientation::Vertical, now)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deltas_are_whole_steps() {
+        for (step, max_steps, deltas, expected) in [
+            // Qt panels
+            (120, 0, &[120, -120, 240, -360][..], &[1, -1, 2, -3][..]),
+            // Panels sending steps
+            (1, 0, &[1, -1, 3], &[1, -1, 3]),
+            // Touchpads on Qt panels
+            (120, 0, &[50, 50, 50, 50, 40], &[0, 0, 1, 0, 1]),
+            (120, 0, &[-50, -50, -50, 20, -110], &[0, 0, -1, 0, -1]),
+            // Partial steps in either direction cancel out
+            (120, 0, &[60, -60, 60, 60], &[0, 0, 0, 1]),
+            // A step of 0 is taken as 1
+            (0, 0, &[2], &[2]),
+            (1, 3, &[2, 5, -10], &[2, 3, -3]),
+            (120, 2, &[1200, -1200], &[2, -2]),
+            (1, 0, &[i32::MAX, i32::MIN], &[i32::MAX, i32::MIN + 1]),
+        ] {
+            assert_eq!(
+                steps(&settings(step, max_steps), deltas),
+                expected,
+                "step {}, max_steps {}, deltas {:?}",
+                step,
+                max_steps,
+                deltas
+            );
+        }
+    }
+
+    #[test]
+    fn partial_steps_are_forgotten() {
+        let settings = settings(120, 0);
+        let mut accumulator = Accumulator::default();
+        let start = Instant::now();
+        let late = start + settings.partial_timeout() + Duration::from_millis(1);
+        assert_eq!(
+            accumulator.add_at(&settings, 100, Orientation::Vertical, start),
+            0
+        );
+        assert_eq!(
+            accumulator.add_at(&settings, 100, Orientation::Vertical, late),
+            0
+        );
+        let soon = late + Duration::from_millis(10);
+        assert_eq!(
+            accumulator.add_at(&settings, 20, Orientation::Vertical, soon),
+            1
+        );
+    }
+
+    #[test]
+    fn orientations_are_separate() {
+        let settings = settings(120, 0);
+        let mut accumulator = Accumulator::default();
+        let now = Instant::now();
+        accumulator.add_at(&settings, 100, Orientation::Vertical, now);
+        assert_eq!(
+            accumulator.add_at(&settings, 100, Orientation::Horizontal, now),
+            0
+        );
+        assert_eq!(
+            accumulator.add_at(&settings, 20, Orientation::Vertical, now),
+            1
+        );
+    }
+
+    #[test]
+    fn windows_add_up_steps() {
+        let settings = settings(1, 5);
+        let mut accumulator = Accumulator::default();
+        assert!(accumulator.queue(Orientation::Vertical, 2));
+        assert!(!accumulator.queue(Orientation::Vertical, 1));
+        assert!(accumulator.queue(Orientation::Horizontal, -1));
+        assert!(!accumulator.queue(Orientation::Vertical, 1));
+        assert_eq!(accumulator.take(&settings, Orientation::Vertical), 4);
+        assert_eq!(accumulator.take(&settings, Orientation::Horizontal), -1);
+        // Closed windows are empty
+        assert_eq!(accumulator.take(&settings, Orientation::Vertical), 0);
+        // Steps added up are limited too
+        assert!(accumulator.queue(Orientation::Vertical, 5));
+        assert!(!accumulator.queue(Orientation::Vertical, 5));
+        assert_eq!(accumulator.take(&settings, Orientation::Vertical), 5);
+        assert!(accumulator.queue(Orientation::Vertical, -4));
+        assert!(!accumulator.queue(Orientation::Vertical, 6));
+        assert_eq!(accumulator.take(&settings, Orientation::Vertical), 2);
+    }
+}
@@ -0,0 +1,154 @@
+//! The items of a VM, saved so that a daemon started after a crash or an
+//! upgrade can show them at once
+//!
+//! Only what is needed to export an item is saved, never icons.  Restored
+//! items are placeholders until the agent creates an item with the same ID
+//! and application ID, which then takes the placeholder over.  Placeholders
+//! that are not taken over are removed after [`RESTORE_TIMEOUT`].
+
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::os::unix::fs::{DirBuilderExt as _, OpenOptionsExt as _};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::item::NotifierIcon;
+
+/// How long restored items wait for the agent to take them over
+pub(super) const RESTORE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An item, as saved
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct Item {
+    pub id: u64,
+    pub app_id: String,
+    pub vm_app_id: String,
+    pub category: String,
+    pub capabilities: sni_icon::Capabilities,
+    pub title: Option<String>,
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    item: Vec<Item>,
+}
+
+/// Where the items of a VM are saved, if anywhere
+#[derive(Debug, Clone)]
+pub(super) struct Store(Option<PathBuf>);
+
+/// Whether `vm` can be used as a file name as it is
+pub(super) fn is_plain(vm: &str) -> bool {
+    !vm.is_empty()
+        && !vm.starts_with('.')
+        && vm
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+impl Store {
+    /// The store for the items of `vm`, as configured by `config`
+    pub fn new(config: &crate::config::State, vm: Option<&str>) -> Self {
+        if !config.enabled {
+            return Self(None);
+        }
+        let Some(directory) = config.directory.clone().or_else(|| {
+            std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("sni-daemon"))
+        }) else {
+            log!(Warning, "Not saving items: no state directory");
+            return Self(None);
+        };
+        let name = match vm {
+            None => "local",
+            Some(vm) if is_plain(vm) => vm,
+            Some(vm) => {
+                log!(Warning, "Not saving items of VM {:?}", vm);
+                return Self(None);
+            }
+        };
+        Self(Some(directory.join(format!("{}.toml", name))))
+    }
+
+    /// The directory items are saved to, if they are saved at all
+    #[cfg(feature = "sandbox")]
+    pub fn directory(&self) -> Option<&std::path::Path> {
+        self.0.as_deref().and_then(std::path::Path::parent)
+    }
+
+    /// The items saved by the previous daemon for the VM
+    pub fn load(&self) -> Vec<Item> {
+        let Some(path) = &self.0 else {
+            return vec![];
+        };
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return vec![],
+            Err(e) => {
+                log!(Warning, "Cannot read {}: {}", path.display(), e);
+                return vec![];
+            }
+        };
+        match toml::from_str::<File>(&data) {
+            Ok(file) => file.item,
+            Err(e) => {
+                log!(Warning, "Ignoring invalid {}: {}", path.display(), e);
+                vec![]
+            }
+        }
+    }
+
+    /// Save `items`, replacing what was saved before
+    pub fn save(&self, items: &HashMap<u64, NotifierIcon>) {
+        let Some(path) = &self.0 else {
+            return;
+        };
+        let mut file = File {
+            item: items
+                .values()
+                .filter(|item| !item.is_lingering())
+                .map(NotifierIcon::saved)
+                .collect(),
+        };
+        file.item.sort_unstable_by_key(|item| item.id);
+        let data = toml::to_string(&file).expect("items can always be serialized");
+        if let Err(e) = write(path, data.as_bytes()) {
+            log!(Warning, "Cannot save items to {}: {}", path.display(), e)
+        }
+    }
+
+    /// Forget the saved items, as the agent is gone along with them
+    pub fn clear(&self) {
+        let Some(path) = &self.0 else {
+            return;
+        };
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log!(Warning, "Cannot remove {}: {}", path.display(), e)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Replace the contents of `path` with `data`, atomically
+pub(super) fn write(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(directory) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(directory)?;
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".new");
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary)?
+        .write_all(data)?;
+    std::fs::rename(&temporary, path)
+}
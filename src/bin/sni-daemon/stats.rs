@@ -0,0 +1,143 @@
+//! Performance statistics, logged once a minute
+//!
+//! Latency is measured from the [`sni_icon::IconClientEvent::timestamp`] set
+//! by the agent until the event has been handled, by which time its D-Bus
+//! signals have been sent.  The clocks of the VM and dom0 are only as close
+//! as time synchronization keeps them, so events that appear to come from
+//! the future, and events without a timestamp, are not measured.  The
+//! summary gives the median, the 95th percentile and the maximum, and how
+//! many events took less than each of [`BUCKETS`], so that reports of items
+//! lagging can be told apart from a slow host.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the summary is logged
+const INTERVAL: Duration = Duration::from_secs(60);
+/// Most latencies kept per interval.  Later events are not measured.
+const MAX_SAMPLES: usize = 1 << 16;
+/// Upper bounds of the latency histogram, in microseconds.  The last
+/// bucket holds all longer latencies.
+const BUCKETS: [u64; 5] = [1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+#[derive(Default)]
+struct Counters {
+    events: u64,
+    bytes: u64,
+    /// Updates that were not applied
+    dropped: u64,
+    /// Queued updates replaced by later ones, see [`crate::queue`]
+    coalesced: u64,
+    /// Calls to items that failed, and those of them that timed out
+    failed_calls: u64,
+    timed_out_calls: u64,
+    /// In microseconds
+    latencies: Vec<u64>,
+}
+
+/// The statistics of a daemon
+#[derive(Clone, Default)]
+pub(super) struct Stats(Arc<Mutex<Counters>>);
+
+impl Stats {
+    fn counters(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.0.lock().unwrap()
+    }
+
+    /// Count a message of `bytes` bytes from the agent
+    pub fn received(&self, bytes: usize) {
+        let mut counters = self.counters();
+        counters.events += 1;
+        counters.bytes += bytes as u64;
+    }
+
+    /// Measure the latency of an event sent at `timestamp`, now that it has
+    /// been handled
+    pub fn handled(&self, timestamp: u64) {
+        let now = sni_icon::timestamp();
+        if timestamp == 0 || timestamp > now {
+            return;
+        }
+        let mut counters = self.counters();
+        if counters.latencies.len() < MAX_SAMPLES {
+            counters.latencies.push(now - timestamp)
+        }
+    }
+
+    /// Count an update that was not applied
+    pub fn dropped(&self) {
+        self.counters().dropped += 1
+    }
+
+    /// Count a queued update that was replaced by a later one
+    pub fn coalesced(&self) {
+        self.counters().coalesced += 1
+    }
+
+    /// Count a call to an item that failed, or did not answer if `timed_out`
+    pub fn call_failed(&self, timed_out: bool) {
+        let mut counters = self.counters();
+        counters.failed_calls += 1;
+        counters.timed_out_calls += u64::from(timed_out);
+    }
+
+    /// Log a summary every [`INTERVAL`] in which anything happened, forever
+    pub async fn run(self) {
+        let mut interval =
+            tokio::time::interval_at(tokio::time::Instant::now() + INTERVAL, INTERVAL);
+        let mut since = Instant::now();
+        loop {
+            interval.tick().await;
+            let Counters {
+                events,
+                bytes,
+                dropped,
+                coalesced,
+                failed_calls,
+                timed_out_calls,
+                mut latencies,
+            } = std::mem::take(&mut *self.counters());
+            let seconds = since.elapsed().as_secs_f64();
+            since = Instant::now();
+            if events == 0 && dropped == 0 {
+                continue;
+            }
+            latencies.sort_unstable();
+            log!(
+                Info,
+                "{:.1} events/s, {:.0} bytes/s, latency {}, {} dropped updates, \
+                 {} coalesced updates, {} failed calls ({} timed out)",
+                events as f64 / seconds,
+                bytes as f64 / seconds,
+                summary(&latencies),
+                dropped,
+                coalesced,
+                failed_calls,
+                timed_out_calls
+            );
+        }
+    }
+}
+
+/// The percentiles and histogram of `latencies`, which must be sorted
+fn summary(latencies: &[u64]) -> String {
+    let Some(&max) = latencies.last() else {
+        return "unknown".to_owned();
+    };
+    let percentile = |p: usize| Duration::from_micros(latencies[(latencies.len() - 1) * p / 100]);
+    let mut summary = format!(
+        "median {:?}, 95th percentile {:?}, max {:?} (",
+        percentile(50),
+        percentile(95),
+        Duration::from_micros(max)
+    );
+    let mut below = 0;
+    for bound in BUCKETS {
+        let count = latencies.partition_point(|&latency| latency < bound);
+        summary += &format!("<{:?}: {}, ", Duration::from_micros(bound), count - below);
+        below = count;
+    }
+    let last = Duration::from_micros(BUCKETS[BUCKETS.len() - 1]);
+    summary += &format!(">={:?}: {})", last, latencies.len() - below);
+    summary
+}
@@ -0,0 +1,225 @@
+//! Hysteresis of the status of items
+//!
+//! Some applications switch between `Active` and `NeedsAttention` many
+//! times a second, which makes panels flicker.  Once the status of an item
+//! has changed, later changes are held back until it has been shown for
+//! [`config::Status::hold_ms`], and only the latest of them is shown then.
+//! A change to `NeedsAttention` is held back for at most
+//! [`config::Status::attention_hold_ms`], and is shown even if the status
+//! changed again meanwhile, so that attention is never lost or delayed for
+//! long.  The later status then follows once it has been shown for
+//! [`config::Status::hold_ms`].
+
+use sni_icon::spec::Status;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// The status asking for attention
+const NEEDS_ATTENTION: &str = Status::NeedsAttention.as_str();
+
+/// The status changes of an item that were not shown yet
+#[derive(Debug, Default)]
+pub(super) struct Hysteresis {
+    /// When the status shown last changed
+    changed: Option<Instant>,
+    /// The latest status, if it is held back
+    pending: Option<Option<String>>,
+    /// Whether `NeedsAttention` was held back.  It is shown even if the
+    /// status changed again since.
+    attention: bool,
+    /// When the held back status is shown
+    deadline: Option<Instant>,
+}
+
+/// What to do with a status change, see [`Hysteresis::offer`]
+pub(super) enum Change {
+    /// Show it now
+    Show,
+    /// It is held back.  If a delay is given, the caller must call
+    /// [`Hysteresis::due`] after it.
+    Held(Option<Duration>),
+}
+
+impl Hysteresis {
+    /// Decide when `status` is to be shown
+    pub fn offer(&mut self, settings: &config::Status, status: Option<String>) -> Change {
+        self.offer_at(settings, status, Instant::now())
+    }
+
+    /// [`offer`](Self::offer) at `now`
+    fn offer_at(
+        &mut self,
+        settings: &config::Status,
+        status: Option<String>,
+        now: Instant,
+    ) -> Change {
+        let hold = settings.hold();
+        let changed = match self.changed {
+            Some(changed) if self.pending.is_some() || now - changed < hold => changed,
+            _ => {
+                self.changed = Some(now);
+                return Change::Show;
+            }
+        };
+        let mut deadline = changed + hold;
+        if status.as_deref() == Some(NEEDS_ATTENTION) {
+            self.attention = true;
+            deadline = deadline.min(now + settings.attention_hold());
+        }
+        self.pending = Some(status);
+        match self.deadline {
+            Some(earlier) if earlier <= deadline => Change::Held(None),
+            _ => {
+                self.deadline = Some(deadline);
+                Change::Held(Some(deadline.saturating_duration_since(now)))
+            }
+        }
+    }
+
+    /// The status held back, if it is to be shown by now, and when to call
+    /// this again, if another status is still held back
+    pub fn due(&mut self, settings: &config::Status) -> (Option<Option<String>>, Option<Duration>) {
+        self.due_at(settings, Instant::now())
+    }
+
+    /// [`due`](Self::due) at `now`
+    fn due_at(
+        &mut self,
+        settings: &config::Status,
+        now: Instant,
+    ) -> (Option<Option<String>>, Option<Duration>) {
+        if self.deadline.is_none_or(|deadline| deadline > now) {
+            return (None, None);
+        }
+        self.deadline = None;
+        let Some(status) = self.pending.take() else {
+            return (None, None);
+        };
+        self.changed = Some(now);
+        if std::mem::take(&mut self.attention) && status.as_deref() != Some(NEEDS_ATTENTION) {
+            self.pending = Some(status);
+            self.deadline = Some(now + settings.hold());
+            return (
+                Some(Some(NEEDS_ATTENTION.to_owned())),
+                Some(settings.hold()),
+            );
+        }
+        (Some(status), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: config::Status = config::Status {
+        hold_ms: 500,
+        attention_hold_ms: 200,
+    };
+
+    /// Offers and checks of what is due, at milliseconds after the start
+    struct Clock {
+        start: Instant,
+        hysteresis: Hysteresis,
+    }
+
+    impl Clock {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                hysteresis: Hysteresis::default(),
+            }
+        }
+
+        fn offer(&mut self, ms: u64, status: &str) -> Change {
+            let now = self.start + Duration::from_millis(ms);
+            self.hysteresis
+                .offer_at(&SETTINGS, Some(status.to_owned()), now)
+        }
+
+        fn due(&mut self, ms: u64) -> (Option<Option<String>>, Option<Duration>) {
+            let now = self.start + Duration::from_millis(ms);
+            self.hysteresis.due_at(&SETTINGS, now)
+        }
+    }
+
+    fn shown(status: &str) -> Option<Option<String>> {
+        Some(Some(status.to_owned()))
+    }
+
+    fn held(change: Change) -> Option<Option<u128>> {
+        match change {
+            Change::Show => None,
+            Change::Held(delay) => Some(delay.map(|delay| delay.as_millis())),
+        }
+    }
+
+    #[test]
+    fn changes_are_held_back() {
+        let mut clock = Clock::new();
+        assert!(matches!(clock.offer(0, "Active"), Change::Show));
+        assert_eq!(held(clock.offer(100, "Passive")), Some(Some(400)));
+        assert_eq!(held(clock.offer(200, "Active")), Some(None));
+        assert_eq!(clock.due(499), (None, None));
+        assert_eq!(clock.due(500), (shown("Active"), None));
+        // Nothing is held back anymore
+        assert_eq!(clock.due(2000), (None, None));
+        assert!(matches!(clock.offer(2000, "Passive"), Change::Show));
+    }
+
+    #[test]
+    fn attention_is_shown_early() {
+        let mut clock = Clock::new();
+        assert!(matches!(clock.offer(0, "Active"), Change::Show));
+        assert_eq!(held(clock.offer(10, NEEDS_ATTENTION)), Some(Some(200)));
+        assert_eq!(clock.due(209), (None, None));
+        assert_eq!(clock.due(210), (shown(NEEDS_ATTENTION), None));
+    }
+
+    #[test]
+    fn flapping_shows_attention_then_the_latest_status() {
+        let mut clock = Clock::new();
+        assert!(matches!(clock.offer(0, "Active"), Change::Show));
+        for (ms, status) in [
+            (10, NEEDS_ATTENTION),
+            (20, "Active"),
+            (30, NEEDS_ATTENTION),
+            (40, "Active"),
+        ] {
+            let change = held(clock.offer(ms, status));
+            // Only the first change asks to be called back
+            assert_eq!(change, Some((ms == 10).then_some(200)), "at {}", ms);
+        }
+        assert_eq!(
+            clock.due(210),
+            (shown(NEEDS_ATTENTION), Some(Duration::from_millis(500)))
+        );
+        assert_eq!(clock.due(709), (None, None));
+        assert_eq!(clock.due(710), (shown("Active"), None));
+    }
+
+    #[test]
+    fn attention_held_longer_than_the_hold_is_not_delayed() {
+        let mut clock = Clock::new();
+        assert!(matches!(clock.offer(0, "Active"), Change::Show));
+        assert_eq!(held(clock.offer(400, "Passive")), Some(Some(100)));
+        // The hold ends before the attention would be shown
+        assert_eq!(held(clock.offer(450, NEEDS_ATTENTION)), Some(None));
+        assert_eq!(clock.due(500), (shown(NEEDS_ATTENTION), None));
+    }
+
+    #[test]
+    fn no_hold_shows_every_change() {
+        let settings = config::Status {
+            hold_ms: 0,
+            attention_hold_ms: 0,
+        };
+        let mut hysteresis = Hysteresis::default();
+        let now = Instant::now();
+        for status in ["Active", NEEDS_ATTENTION, "Active"] {
+            let change = hysteresis.offer_at(&settings, Some(status.to_owned()), now);
+            assert!(matches!(change, Change::Show));
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Rate limiting of events from the VM
+
+use std::time::{Duration, Instant};
+
+/// A token bucket holding at most one second's worth of events
+pub(super) struct Throttle {
+    rate: u32,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    /// Allow `rate` events per second, or any number if `rate` is 0
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            tokens: rate.into(),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Wait until another event may be processed
+    pub async fn wait(&mut self) {
+        if self.rate == 0 {
+            return;
+        }
+        let rate = f64::from(self.rate);
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.refilled).as_secs_f64() * rate).min(rate);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            let delay = Duration::from_secs_f64((1.0 - self.tokens) / rate);
+            log!(Warning, "Update rate exceeded, pausing for {:?}", delay);
+            tokio::time::sleep(delay).await;
+            self.tokens = 1.0;
+            self.refilled = Instant::now();
+        }
+        self.tokens -= 1.0;
+    }
+}
@@ -0,0 +1,119 @@
+//! Limits on the text of tooltips
+//!
+//! Some panels let the user select and copy the text of tooltips, so a VM
+//! must not be able to put more in them than fits on screen.  The title and
+//! the description are truncated when the VM sends them, to
+//! [`config::Tooltips::max_title_chars`] and
+//! [`config::Tooltips::max_description_len`], and the description is
+//! broken into at most [`config::Tooltips::max_lines`] lines of at most
+//! [`config::Tooltips::max_line_chars`] characters when hosts read it.
+//! Markup and escape sequences are already stripped by [`SafeText`].
+
+use sni_icon::{SafeText, Tooltip};
+
+use crate::config;
+
+/// Truncate the title and the description of `tooltip`
+pub(super) fn limit(settings: &config::Tooltips, tooltip: &mut Tooltip) {
+    if let Some((end, _)) = tooltip.title.char_indices().nth(settings.max_title_chars) {
+        tooltip.title.truncate(end);
+    }
+    tooltip.description.truncate(settings.max_description_len);
+}
+
+/// `description` broken into lines, at spaces where possible, and joined
+/// with `<br/>`.  Text left out for lack of lines is replaced with `…`,
+/// which takes the place of the last character if the line is full.
+pub(super) fn wrap(settings: &config::Tooltips, description: &SafeText) -> String {
+    let max_chars = settings.max_line_chars.max(1);
+    let mut lines: Vec<String> = Vec::new();
+    let mut rest = description.trim();
+    while !rest.is_empty() {
+        if lines.len() == settings.max_lines {
+            if let Some(last) = lines.last_mut() {
+                if let Some((end, _)) = last.char_indices().nth(max_chars - 1) {
+                    last.truncate(end);
+                }
+                last.push('…');
+            }
+            break;
+        }
+        let mut end = rest
+            .char_indices()
+            .nth(max_chars)
+            .map_or(rest.len(), |(i, _)| i);
+        if end < rest.len() && !rest[end..].starts_with(' ') {
+            end = rest[..end].rfind(' ').filter(|&i| i > 0).unwrap_or(end);
+        }
+        lines.push(rest[..end].trim_end().to_owned());
+        rest = rest[end..].trim_start();
+    }
+    lines.join("<br/>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapped(max_line_chars: usize, max_lines: usize, description: &str) -> String {
+        let settings = config::Tooltips {
+            max_line_chars,
+            max_lines,
+            ..Default::default()
+        };
+        wrap(&settings, &SafeText::new(description))
+    }
+
+    #[test]
+    fn lines_are_broken_at_spaces() {
+        for (max_line_chars, description, expected) in [
+            (80, "Short enough", "Short enough"),
+            (80, "  Trimmed  ", "Trimmed"),
+            (10, "The quick brown fox", "The quick<br/>brown fox"),
+            (10, "Spaced     out    words", "Spaced<br/>out<br/>words"),
+            (5, "abcde fgh", "abcde<br/>fgh"),
+            (80, "", ""),
+        ] {
+            assert_eq!(
+                wrapped(max_line_chars, 10, description),
+                expected,
+                "{:?}",
+                description
+            );
+        }
+    }
+
+    #[test]
+    fn long_words_are_broken() {
+        assert_eq!(wrapped(5, 10, "abcdefghijkl"), "abcde<br/>fghij<br/>kl");
+        assert_eq!(wrapped(5, 10, "ab abcdefgh"), "ab<br/>abcde<br/>fgh");
+        // A line is never empty
+        assert_eq!(wrapped(0, 10, "abc"), "a<br/>b<br/>c");
+    }
+
+    #[test]
+    fn characters_are_counted() {
+        assert_eq!(wrapped(5, 10, "ééééé ééééé"), "ééééé<br/>ééééé");
+        assert_eq!(
+            wrapped(3, 10, "日本語のテキスト"),
+            "日本語<br/>のテキ<br/>スト"
+        );
+        assert_eq!(wrapped(2, 10, "𠮷𠮷𠮷"), "𠮷𠮷<br/>𠮷");
+    }
+
+    #[test]
+    fn text_beyond_the_last_line_is_left_out() {
+        assert_eq!(wrapped(5, 2, "aaaa bbbb cccc"), "aaaa<br/>bbbb…");
+        // The ellipsis does not make the line longer
+        assert_eq!(wrapped(5, 2, "abcdefghijklmno"), "abcde<br/>fghi…");
+        assert_eq!(wrapped(3, 1, "日本語のテキスト"), "日本…");
+        assert_eq!(wrapped(1, 1, "ab"), "…");
+        // Nothing is left out
+        assert_eq!(wrapped(5, 2, "abcdefghij"), "abcde<br/>fghij");
+    }
+
+    #[test]
+    fn no_lines_leave_out_everything() {
+        assert_eq!(wrapped(80, 0, "Anything"), "");
+    }
+}
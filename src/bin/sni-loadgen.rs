@@ -0,0 +1,499 @@
+//! Load generator for `sni-daemon`
+//!
+//! Speaks the agent side of the protocol, creating items and updating their
+//! icons and menus at fixed rates, so that the limits and rate limits of the
+//! daemon can be tuned with data.  The daemon is started as a child with a
+//! socket as its stdin and stdout, or reached at the Unix socket it was
+//! activated on.
+//!
+//! Latency is measured by destroying one item each second and waiting for
+//! its [`ServerEvent::DestroyAck`], which the daemon only sends once it has
+//! handled everything sent before.  The item is then created again.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::ffi::OsString;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use bincode::Options as _;
+use futures_util::StreamExt as _;
+use sni_icon::menu::MenuItem;
+use sni_icon::{
+    encoding, icon_events, read_message, read_message_tagged, write_message, write_message_tagged,
+    Capabilities, ClientEvent, Hello, IconClientEvent, IconData, IconServerEvent, IconType,
+    Integrity, ProtocolLimits, SafeText, ServerEvent, MIN_MESSAGE_SIZE, PROTOCOL_VERSION,
+};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::time::{Interval, MissedTickBehavior};
+
+const USAGE: &str = "usage: sni-loadgen [--icons N] [--rate UPDATES_PER_SECOND] \
+[--sizes SIZE,...] [--menus MENUS_PER_SECOND] [--menu-entries N] [--duration SECONDS] \
+[--integrity TAG] [--socket PATH | [--] DAEMON [ARGUMENTS...]]";
+
+/// How often an item is destroyed to measure latency
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for the daemon to destroy all items at the end
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the daemon is
+#[derive(Debug)]
+enum Target {
+    /// The Unix socket the daemon was activated on
+    Socket(PathBuf),
+    /// The command starting the daemon
+    Command(Vec<OsString>),
+}
+
+#[derive(Debug)]
+struct Options {
+    /// Number of items
+    icons: u64,
+    /// Icon updates per second, over all items
+    rate: u32,
+    /// Width and height of each frame of an icon
+    sizes: Vec<u32>,
+    /// Menu updates per second, over all items
+    menu_rate: u32,
+    /// Number of entries of each menu
+    menu_entries: i32,
+    duration: Duration,
+    integrity: Integrity,
+    target: Target,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            icons: 16,
+            rate: 100,
+            sizes: vec![22],
+            menu_rate: 0,
+            menu_entries: 16,
+            duration: Duration::from_secs(10),
+            integrity: Integrity::None,
+            target: Target::Command(vec!["sni-daemon".into()]),
+        }
+    }
+}
+
+/// The value of option `name`, which is the next argument
+fn value<T: FromStr>(args: &mut impl Iterator<Item = OsString>, name: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    let value = args
+        .next()
+        .ok_or_else(|| format!("{} requires a value", name))?;
+    let value = value
+        .to_str()
+        .ok_or_else(|| format!("invalid value for {}", name))?;
+    value
+        .parse()
+        .map_err(|e| format!("invalid value {:?} for {}: {}", value, name, e))
+}
+
+impl Options {
+    /// Parse `args`, not including the program name
+    fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self, String> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--icons") => options.icons = value(&mut args, "--icons")?,
+                Some("--rate") => options.rate = value(&mut args, "--rate")?,
+                Some("--sizes") => {
+                    let sizes: String = value(&mut args, "--sizes")?;
+                    options.sizes = sizes
+                        .split(',')
+                        .map(|size| match size.parse() {
+                            Ok(size) if size > 0 => Ok(size),
+                            _ => Err(format!("invalid icon size {:?}", size)),
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                Some("--menus") => options.menu_rate = value(&mut args, "--menus")?,
+                Some("--menu-entries") => {
+                    options.menu_entries = value(&mut args, "--menu-entries")?
+                }
+                Some("--duration") => {
+                    let seconds: f64 = value(&mut args, "--duration")?;
+                    options.duration = Duration::try_from_secs_f64(seconds)
+                        .map_err(|e| format!("invalid duration: {}", e))?;
+                }
+                Some("--integrity") => options.integrity = value(&mut args, "--integrity")?,
+                Some("--socket") => {
+                    options.target = Target::Socket(value(&mut args, "--socket")?);
+                }
+                Some("--help") => return Err(USAGE.to_owned()),
+                Some("--") => {
+                    options.target = Target::Command(args.collect());
+                    break;
+                }
+                Some(arg) if arg.starts_with('-') => {
+                    return Err(format!("unknown argument {:?}\n{}", arg, USAGE))
+                }
+                _ => {
+                    options.target = Target::Command(std::iter::once(arg).chain(args).collect());
+                    break;
+                }
+            }
+        }
+        if options.icons == 0 {
+            return Err("--icons must be at least 1".to_owned());
+        }
+        if let Target::Command(command) = &options.target {
+            if command.is_empty() {
+                return Err(format!("no daemon to start\n{}", USAGE));
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Connect to the daemon at `target`, starting it if need be
+fn connect(target: &Target) -> Result<(UnixStream, Option<std::process::Child>), Box<dyn Error>> {
+    let (stream, child) = match target {
+        Target::Socket(path) => (std::os::unix::net::UnixStream::connect(path)?, None),
+        Target::Command(command) => {
+            let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+            let theirs = OwnedFd::from(theirs);
+            let child = Command::new(&command[0])
+                .args(&command[1..])
+                .stdin(Stdio::from(theirs.try_clone()?))
+                .stdout(Stdio::from(theirs))
+                .spawn()
+                .map_err(|e| format!("cannot start {:?}: {}", command[0], e))?;
+            (ours, Some(child))
+        }
+    };
+    stream.set_nonblocking(true)?;
+    Ok((UnixStream::from_std(stream)?, child))
+}
+
+/// What was sent to the daemon, and how long it took to answer
+#[derive(Debug, Default)]
+struct Stats {
+    messages: u64,
+    bytes: u64,
+    icons: u64,
+    menus: u64,
+    /// From each [`ClientEvent::Destroy`] to its [`ServerEvent::DestroyAck`]
+    latencies: Vec<Duration>,
+}
+
+/// The sending side of the connection to the daemon
+struct Sender {
+    writer: OwnedWriteHalf,
+    limits: ProtocolLimits,
+    integrity: Integrity,
+    next_id: u64,
+    /// Increased with each update, so that no two icons or menus are the same
+    generation: u32,
+    stats: Stats,
+}
+
+impl Sender {
+    async fn send(&mut self, id: u64, event: ClientEvent) -> Result<(), Box<dyn Error>> {
+        let message = sni_icon::encode(&IconClientEvent::new(id, event))?;
+        if message.len() > self.limits.max_message_size as usize {
+            return Err(format!("message of {} bytes is too large", message.len()).into());
+        }
+        write_message_tagged(&mut self.writer, &message, self.integrity).await?;
+        self.stats.messages += 1;
+        self.stats.bytes += message.len() as u64;
+        Ok(())
+    }
+
+    /// Create an item, returning its ID
+    async fn create(&mut self, options: &Options) -> Result<u64, Box<dyn Error>> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let mut capabilities = Capabilities::SUPPORTS_ACTIVATION;
+        if options.menu_rate > 0 {
+            capabilities |= Capabilities::HAS_MENU;
+        }
+        let event = ClientEvent::Create {
+            category: "ApplicationStatus".to_owned(),
+            app_id: format!("org.qubes_os.LoadGen.Item{}", id),
+            is_menu: false,
+            capabilities,
+        };
+        self.send(id, event).await?;
+        self.send(
+            id,
+            ClientEvent::Title(Some(format!("Load test item {}", id))),
+        )
+        .await?;
+        self.update_icon(id, options).await?;
+        if options.menu_rate > 0 {
+            self.update_menu(id, options).await?;
+        }
+        Ok(id)
+    }
+
+    async fn update_icon(&mut self, id: u64, options: &Options) -> Result<(), Box<dyn Error>> {
+        self.generation = self.generation.wrapping_add(1);
+        let pixel = self.generation.to_be_bytes();
+        let frames = options.sizes.iter().map(|&size| IconData {
+            width: size,
+            height: size,
+            data: pixel.repeat((size * size) as usize),
+        });
+        for event in icon_events(IconType::Normal, frames, &self.limits) {
+            self.send(id, event).await?
+        }
+        self.stats.icons += 1;
+        Ok(())
+    }
+
+    async fn update_menu(&mut self, id: u64, options: &Options) -> Result<(), Box<dyn Error>> {
+        self.generation = self.generation.wrapping_add(1);
+        let entry = |id, label: &str| MenuItem {
+            id,
+            separator: false,
+            label: SafeText::new(label),
+            enabled: true,
+            visible: true,
+            toggle_type: None,
+            toggle_state: Default::default(),
+            shortcuts: vec![],
+            icon: None,
+            children: vec![],
+        };
+        let mut root = entry(0, "");
+        root.children = (1..=options.menu_entries)
+            .map(|id| entry(id, &format!("Entry {} ({})", id, self.generation)))
+            .collect();
+        root.limit();
+        self.send(id, ClientEvent::Menu(root)).await?;
+        self.stats.menus += 1;
+        Ok(())
+    }
+}
+
+/// Exchange [`Hello`]s with the daemon, returning the limits and tag both
+/// keep to
+async fn handshake(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+    integrity: Integrity,
+) -> Result<(ProtocolLimits, Integrity), Box<dyn Error>> {
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        limits: ProtocolLimits::default(),
+        integrity,
+    };
+    write_message(writer, &encoding().serialize(&hello)?).await?;
+    let buffer = read_message(reader, MIN_MESSAGE_SIZE).await?;
+    let hello = Hello::decode(&buffer)?;
+    Ok((
+        ProtocolLimits::default().min(hello.limits),
+        integrity.negotiate(hello.integrity),
+    ))
+}
+
+/// Forward the IDs of items the daemon has destroyed to `acks`, until the
+/// daemon goes away
+async fn read_acks(
+    mut reader: OwnedReadHalf,
+    limits: ProtocolLimits,
+    integrity: Integrity,
+    acks: futures_channel::mpsc::UnboundedSender<u64>,
+) {
+    loop {
+        let buffer =
+            match read_message_tagged(&mut reader, limits.max_message_size, integrity).await {
+                Ok(buffer) => buffer,
+                Err(e) if e.is_closed() => return,
+                Err(e) => return eprintln!("Error reading from daemon: {}", e),
+            };
+        match sni_icon::decode::<IconServerEvent>(&buffer) {
+            Ok(IconServerEvent {
+                id,
+                event: ServerEvent::DestroyAck,
+            }) => {
+                if acks.unbounded_send(id).is_err() {
+                    return;
+                }
+            }
+            Ok(event) => eprintln!("Ignoring {:?}", event),
+            Err(e) => return eprintln!("Malformed message from daemon: {}", e),
+        }
+    }
+}
+
+/// An interval ticking `rate` times per second, or [`None`] if `rate` is 0
+fn ticker(rate: u32) -> Option<Interval> {
+    (rate > 0).then(|| {
+        let mut interval = tokio::time::interval(Duration::from_secs(1) / rate);
+        // Updates delayed by a slow daemon are dropped, not sent in a burst
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        interval
+    })
+}
+
+/// The next tick of `interval`, or never if there is none
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// The `percent`th percentile of `sorted`
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+fn report(options: &Options, stats: &Stats, elapsed: Duration, drain: Option<Duration>) {
+    let seconds = elapsed.as_secs_f64();
+    let mib = stats.bytes as f64 / f64::from(1 << 20);
+    println!("Ran for {:.1}s against {:?}", seconds, options.target);
+    println!(
+        "Sent {} messages ({:.0}/s), {:.1} MiB ({:.1} MiB/s)",
+        stats.messages,
+        stats.messages as f64 / seconds,
+        mib,
+        mib / seconds
+    );
+    println!(
+        "Icon updates: {} ({:.1}/s, asked for {}/s)",
+        stats.icons,
+        stats.icons as f64 / seconds,
+        options.rate
+    );
+    println!(
+        "Menu updates: {} ({:.1}/s, asked for {}/s)",
+        stats.menus,
+        stats.menus as f64 / seconds,
+        options.menu_rate
+    );
+    let mut latencies = stats.latencies.clone();
+    latencies.sort_unstable();
+    if latencies.is_empty() {
+        println!("Destroy latency: no probes answered");
+    } else {
+        println!(
+            "Destroy latency over {} probes: min {:?}, median {:?}, 99th percentile {:?}, max {:?}",
+            latencies.len(),
+            latencies[0],
+            percentile(&latencies, 50),
+            percentile(&latencies, 99),
+            latencies[latencies.len() - 1]
+        );
+    }
+    match drain {
+        Some(drain) => println!("Destroying all {} items took {:?}", options.icons, drain),
+        None => println!(
+            "Destroying all {} items took more than {:?}",
+            options.icons, DRAIN_TIMEOUT
+        ),
+    }
+}
+
+async fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    let (stream, child) = connect(&options.target)?;
+    let (mut reader, mut writer) = stream.into_split();
+    let (limits, integrity) = handshake(&mut reader, &mut writer, options.integrity).await?;
+    eprintln!(
+        "Negotiated limits {:?}, integrity tag {:?}",
+        limits, integrity
+    );
+    let (acks, mut destroyed) = futures_channel::mpsc::unbounded();
+    tokio::spawn(read_acks(reader, limits, integrity, acks));
+    let mut sender = Sender {
+        writer,
+        limits,
+        integrity,
+        next_id: 0,
+        generation: 0,
+        stats: Stats::default(),
+    };
+    let start = Instant::now();
+    let mut items = vec![];
+    for _ in 0..options.icons {
+        items.push(sender.create(&options).await?);
+    }
+    let mut updates = ticker(options.rate);
+    let mut menus = ticker(options.menu_rate);
+    let mut probes = tokio::time::interval(PROBE_INTERVAL);
+    probes.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // The item being destroyed to measure latency, if any
+    let mut probe: Option<(u64, Instant)> = None;
+    let (mut next_icon, mut next_menu) = (0, 0);
+    let deadline = tokio::time::sleep(options.duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            () = &mut deadline => break,
+            () = tick(&mut updates) => if !items.is_empty() {
+                next_icon = (next_icon + 1) % items.len();
+                sender.update_icon(items[next_icon], &options).await?;
+            },
+            () = tick(&mut menus) => if !items.is_empty() {
+                next_menu = (next_menu + 1) % items.len();
+                sender.update_menu(items[next_menu], &options).await?;
+            },
+            _ = probes.tick() => if probe.is_none() && !items.is_empty() {
+                let id = items.remove(0);
+                sender.send(id, ClientEvent::Destroy).await?;
+                probe = Some((id, Instant::now()));
+            },
+            ack = destroyed.next() => match (ack, probe) {
+                (None, _) => return Err("daemon went away".into()),
+                (Some(ack), Some((id, sent))) if ack == id => {
+                    sender.stats.latencies.push(sent.elapsed());
+                    probe = None;
+                    items.push(sender.create(&options).await?);
+                }
+                (Some(ack), _) => eprintln!("Unexpected DestroyAck for item {}", ack),
+            },
+        }
+    }
+    let elapsed = start.elapsed();
+    // Destroy everything, waiting for the daemon to catch up
+    let drain_start = Instant::now();
+    let mut pending: HashSet<u64> = probe.map(|(id, _)| id).into_iter().collect();
+    for id in items {
+        sender.send(id, ClientEvent::Destroy).await?;
+        pending.insert(id);
+    }
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while !pending.is_empty() {
+            match destroyed.next().await {
+                Some(id) => {
+                    pending.remove(&id);
+                }
+                None => return Err("daemon went away"),
+            }
+        }
+        Ok(())
+    })
+    .await;
+    let drain = match drained {
+        Ok(result) => Some(result.map(|()| drain_start.elapsed())?),
+        Err(_) => None,
+    };
+    report(&options, &sender.stats, elapsed, drain);
+    drop(sender);
+    if let Some(mut child) = child {
+        let status = child.wait()?;
+        if !status.success() {
+            eprintln!("Daemon exited: {}", status);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let options = Options::parse(std::env::args_os().skip(1))?;
+    run(options).await
+}
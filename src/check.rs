@@ -0,0 +1,112 @@
+//! The `--check` self-test of both binaries, for packaging and health probes
+//!
+//! Each check prints a line to stderr.  The exit status is that of the first
+//! check that failed, following `sysexits.h`:
+//!
+//! - 0 if all checks passed;
+//! - 69 (`EX_UNAVAILABLE`) if the session bus cannot be used;
+//! - 70 (`EX_SOFTWARE`) if D-Bus string validation was compiled out;
+//! - 75 (`EX_TEMPFAIL`) if a bus name is owned by another connection, for
+//!   instance because another instance is running;
+//! - 78 (`EX_CONFIG`) if the configuration is invalid.
+
+use crate::error::ValidationError;
+use crate::names;
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::strings::BusName;
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// The command-line flag selecting the self-test
+pub const FLAG: &str = "--check";
+
+/// The ways a check can fail
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Failure {
+    Unavailable = 69,
+    Software = 70,
+    TempFail = 75,
+    Config = 78,
+}
+
+/// The results of the checks so far
+#[derive(Debug, Default)]
+pub struct Check {
+    failure: Option<Failure>,
+}
+
+impl Check {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record and print the result of the check `what`
+    pub fn report<T>(&mut self, what: &str, result: Result<T, (Failure, String)>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                eprintln!("ok: {}", what);
+                Some(value)
+            }
+            Err((failure, message)) => {
+                eprintln!("FAILED: {}: {}", what, message);
+                self.failure.get_or_insert(failure);
+                None
+            }
+        }
+    }
+
+    /// The exit status to report the results with
+    pub fn exit_code(&self) -> ExitCode {
+        match self.failure {
+            None => ExitCode::SUCCESS,
+            Some(failure) => ExitCode::from(failure as u8),
+        }
+    }
+}
+
+/// Whether the D-Bus strings received from the VM are validated, which the
+/// `no-string-validation` feature of the `dbus` crate would turn off
+pub fn string_validation() -> Result<(), (Failure, String)> {
+    match BusName::new("not a bus name") {
+        Err(_) => Ok(()),
+        Ok(_) => Err((
+            Failure::Software,
+            "built with D-Bus string validation disabled".to_owned(),
+        )),
+    }
+}
+
+/// Refuse to start if D-Bus string validation is off.  Both binaries rely on
+/// it to reject malformed bus names, object paths and interface names from
+/// the VM before they reach libdbus, which aborts the process on them.
+pub fn require_string_validation() -> Result<(), ValidationError> {
+    string_validation().map_err(|_| ValidationError::StringValidationDisabled)
+}
+
+/// Whether `name` has no owner on `c`.  The name is not requested, as the
+/// binaries request it with flags that would take it from an owner that
+/// allows replacement, and releasing it would not give it back.
+pub async fn name_available(
+    c: &SyncConnection,
+    name: BusName<'static>,
+) -> Result<(), (Failure, String)> {
+    let bus = Proxy::new(
+        names::name_dbus(),
+        names::path_dbus(),
+        Duration::from_secs(5),
+        c,
+    );
+    let (owned,): (bool,) = bus
+        .method_call(names::interface_dbus(), names::name_has_owner(), (&*name,))
+        .await
+        .map_err(|e| (Failure::Unavailable, e.to_string()))?;
+    if owned {
+        Err((
+            Failure::TempFail,
+            format!("{} is owned by another connection", name),
+        ))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! Command-line arguments, which are the same for both binaries
+
+/// The parsed command line
+#[derive(Debug, Default)]
+pub struct Args {
+    /// Run the self-test of [`crate::check`] instead of starting
+    pub check: bool,
+    /// See [`crate::session`]
+    pub session_bus_address: Option<String>,
+    /// The tag this side asks for after each message, see
+    /// [`crate::integrity`]
+    pub integrity: crate::Integrity,
+    /// The vsock address the daemon listens on and the agent connects to,
+    /// see [`crate::transport::Vsock`]
+    #[cfg(feature = "vsock")]
+    pub vsock: Option<crate::transport::VsockAddress>,
+    /// Restrict the system calls of the agent once it is set up, see
+    /// [`crate::seccomp`].  The daemon is sandboxed by its configuration
+    /// instead.
+    #[cfg(feature = "sandbox")]
+    pub seccomp: bool,
+    /// How long the agent waits for items to answer.  The daemon does not
+    /// take it.
+    pub timeout: Option<std::time::Duration>,
+}
+
+impl Args {
+    /// Parse `args`, not including the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == crate::check::FLAG {
+                parsed.check = true;
+            } else if arg == "--session-bus-address" {
+                let address = args
+                    .next()
+                    .ok_or("--session-bus-address requires an address")?;
+                parsed.session_bus_address = Some(address);
+            } else if let Some(address) = arg.strip_prefix("--session-bus-address=") {
+                parsed.session_bus_address = Some(address.to_owned());
+            } else if arg == "--integrity" {
+                let integrity = args.next().ok_or("--integrity requires a tag")?;
+                parsed.integrity = integrity.parse()?;
+            } else if let Some(integrity) = arg.strip_prefix("--integrity=") {
+                parsed.integrity = integrity.parse()?;
+            } else if arg == "--vsock" {
+                let address = args.next().ok_or("--vsock requires an address")?;
+                #[cfg(feature = "vsock")]
+                {
+                    parsed.vsock = Some(address.parse()?);
+                }
+                #[cfg(not(feature = "vsock"))]
+                return Err(format!(
+                    "cannot use vsock address {:?}: built without vsock support",
+                    address
+                ));
+            } else if arg == "--timeout" {
+                let timeout = args.next().ok_or("--timeout requires milliseconds")?;
+                parsed.timeout = Some(parse_timeout(&timeout)?);
+            } else if let Some(timeout) = arg.strip_prefix("--timeout=") {
+                parsed.timeout = Some(parse_timeout(timeout)?);
+            } else if arg == "--seccomp" {
+                #[cfg(feature = "sandbox")]
+                {
+                    parsed.seccomp = true;
+                }
+                #[cfg(not(feature = "sandbox"))]
+                return Err("cannot use --seccomp: built without sandbox support".to_owned());
+            } else {
+                return Err(format!("unknown argument {:?}", arg));
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Parse a timeout given in milliseconds, which must not be 0
+fn parse_timeout(milliseconds: &str) -> Result<std::time::Duration, String> {
+    match milliseconds.parse() {
+        Ok(0) | Err(_) => Err(format!("invalid timeout {:?}", milliseconds)),
+        Ok(milliseconds) => Ok(std::time::Duration::from_millis(milliseconds)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|&arg| arg.to_owned()))
+    }
+
+    #[test]
+    fn no_arguments() {
+        let args = parse(&[]).unwrap();
+        assert!(!args.check);
+        assert_eq!(args.session_bus_address, None);
+        assert_eq!(args.integrity, crate::Integrity::None);
+        assert_eq!(args.timeout, None);
+    }
+
+    #[test]
+    fn arguments_are_parsed() {
+        let args = parse(&[
+            "--check",
+            "--session-bus-address",
+            "unix:path=/run/bus",
+            "--integrity=crc32",
+            "--timeout",
+            "250",
+        ])
+        .unwrap();
+        assert!(args.check);
+        assert_eq!(
+            args.session_bus_address.as_deref(),
+            Some("unix:path=/run/bus")
+        );
+        assert_eq!(args.integrity, crate::Integrity::Crc32);
+        assert_eq!(args.timeout, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn values_may_follow_an_equals_sign() {
+        let args = parse(&["--session-bus-address=unix:abstract=x", "--timeout=1"]).unwrap();
+        assert_eq!(args.session_bus_address.as_deref(), Some("unix:abstract=x"));
+        assert_eq!(args.timeout, Some(Duration::from_millis(1)));
+        assert_eq!(
+            parse(&["--integrity", "none"]).unwrap().integrity,
+            crate::Integrity::None
+        );
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        for args in [
+            &["--frobnicate"][..],
+            &["check"],
+            &["--session-bus-address"],
+            &["--integrity"],
+            &["--integrity=sha1"],
+            &["--timeout=0"],
+            &["--timeout", "-5"],
+            &["--timeout=soon"],
+            &["--timeout"],
+            &["--vsock"],
+        ] {
+            assert!(parse(args).is_err(), "{:?} accepted", args);
+        }
+    }
+
+    #[cfg(not(feature = "sandbox"))]
+    #[test]
+    fn seccomp_requires_the_sandbox() {
+        assert!(parse(&["--seccomp"]).is_err());
+    }
+
+    #[cfg(feature = "sandbox")]
+    #[test]
+    fn seccomp_is_parsed() {
+        assert!(parse(&["--seccomp"]).unwrap().seccomp);
+    }
+}
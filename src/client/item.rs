@@ -26,6 +26,9 @@ pub trait StatusNotifierItem {
     fn attention_movie_name(&self) -> nonblock::MethodReply<String>;
     fn tool_tip(&self)
         -> nonblock::MethodReply<(String, Vec<(i32, i32, Vec<u8>)>, String, String)>;
+    fn icon_accessible_desc(&self) -> nonblock::MethodReply<String>;
+    fn attention_accessible_desc(&self) -> nonblock::MethodReply<String>;
+    fn xayatana_ordering_index(&self) -> nonblock::MethodReply<u32>;
 }
 
 #[derive(Debug)]
@@ -288,4 +291,28 @@ impl<'a, T: nonblock::NonblockReply, C: ::std::ops::Deref<Target = T>> StatusNot
             "ToolTip",
         )
     }
+
+    fn icon_accessible_desc(&self) -> nonblock::MethodReply<String> {
+        <Self as nonblock::stdintf::org_freedesktop_dbus::Properties>::get(
+            &self,
+            "org.kde.StatusNotifierItem",
+            "IconAccessibleDesc",
+        )
+    }
+
+    fn attention_accessible_desc(&self) -> nonblock::MethodReply<String> {
+        <Self as nonblock::stdintf::org_freedesktop_dbus::Properties>::get(
+            &self,
+            "org.kde.StatusNotifierItem",
+            "AttentionAccessibleDesc",
+        )
+    }
+
+    fn xayatana_ordering_index(&self) -> nonblock::MethodReply<u32> {
+        <Self as nonblock::stdintf::org_freedesktop_dbus::Properties>::get(
+            &self,
+            "org.kde.StatusNotifierItem",
+            "XAyatanaOrderingIndex",
+        )
+    }
 }
@@ -8,6 +8,7 @@ pub trait StatusNotifierWatcher {
     fn register_status_notifier_item(&self, service: &str) -> nonblock::MethodReply<()>;
     fn register_status_notifier_host(&self, service: &str) -> nonblock::MethodReply<()>;
     fn registered_status_notifier_items(&self) -> nonblock::MethodReply<Vec<String>>;
+    fn registered_status_notifier_hosts(&self) -> nonblock::MethodReply<Vec<String>>;
     fn is_status_notifier_host_registered(&self) -> nonblock::MethodReply<bool>;
     fn protocol_version(&self) -> nonblock::MethodReply<i32>;
 }
@@ -119,6 +120,14 @@ impl<'a, T: nonblock::NonblockReply, C: ::std::ops::Deref<Target = T>> StatusNot
         )
     }
 
+    fn registered_status_notifier_hosts(&self) -> nonblock::MethodReply<Vec<String>> {
+        <Self as nonblock::stdintf::org_freedesktop_dbus::Properties>::get(
+            &self,
+            "org.kde.StatusNotifierWatcher",
+            "RegisteredStatusNotifierHosts",
+        )
+    }
+
     fn is_status_notifier_host_registered(&self) -> nonblock::MethodReply<bool> {
         <Self as nonblock::stdintf::org_freedesktop_dbus::Properties>::get(
             &self,
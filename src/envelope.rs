@@ -0,0 +1,224 @@
+//! Envelopes around events
+//!
+//! Every message after the [`crate::Hello`]s is an envelope: the length of
+//! the event as a little-endian `u32`, the event encoded with
+//! [`crate::encoding`], and then any number of sections, each made of its
+//! kind and its length as little-endian `u32`s and that many bytes.
+//! Sections carry the optional fields of a message.  A receiver skips those
+//! of kinds it does not know, so fields can be added as new kinds of
+//! sections without changing [`crate::PROTOCOL_VERSION`].
+
+use crate::error::ProtocolError;
+
+/// The kind of the section holding the time an event was sent, see
+/// [`crate::IconClientEvent::timestamp`]: a little-endian `u64`
+pub const TIMESTAMP: u32 = 1;
+
+/// The kind of the section holding the capabilities of an item, see
+/// [`crate::ClientEvent::Create`]: a little-endian `u32`.  Items created
+/// without it are given [`crate::Capabilities::legacy`].
+pub const CAPABILITIES: u32 = 2;
+
+/// The sections of an envelope, in the order they are sent
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Sections(Vec<(u32, Vec<u8>)>);
+
+impl Sections {
+    /// Add a section of `kind` holding `data`
+    pub fn insert(&mut self, kind: u32, data: Vec<u8>) {
+        self.0.push((kind, data))
+    }
+
+    /// The data of the first section of `kind`
+    pub fn get(&self, kind: u32) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, data)| &data[..])
+    }
+}
+
+/// A message that ends with an event, which may be one the receiver does not
+/// know, see [`decode`]
+pub trait Message: serde::Serialize + serde::de::DeserializeOwned {
+    /// Whether the event was decoded as `Unknown`
+    fn is_unknown(&self) -> bool;
+
+    /// The optional fields of the message, sent as sections
+    fn sections(&self) -> Sections {
+        Sections::default()
+    }
+
+    /// Set the optional fields of the message from the sections it came with
+    fn set_sections(&mut self, _sections: &Sections) {}
+}
+
+/// Encode `message` in an envelope
+pub fn encode<T: Message>(message: &T) -> Result<Vec<u8>, ProtocolError> {
+    use bincode::Options as _;
+    let event = crate::encoding()
+        .serialize(message)
+        .map_err(ProtocolError::Encode)?;
+    let mut buffer = Vec::with_capacity(4 + event.len());
+    buffer.extend_from_slice(&(event.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&event);
+    for (kind, data) in &message.sections().0 {
+        buffer.extend_from_slice(&kind.to_le_bytes());
+        buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(data);
+    }
+    Ok(buffer)
+}
+
+/// Decode the envelope in `buffer`.  An unknown event is decoded as
+/// `Unknown` without its fields, which are skipped; any other event must
+/// use up all of its length.
+pub fn decode<T: Message>(buffer: &[u8]) -> Result<T, ProtocolError> {
+    use bincode::Options as _;
+    let malformed =
+        |what: String| ProtocolError::Decode(Box::new(bincode::ErrorKind::Custom(what)));
+    let (mut event, mut rest) =
+        split(buffer).ok_or_else(|| malformed("truncated event".to_owned()))?;
+    // A damaged length must not be allocated before it is found to be
+    // longer than the event
+    let mut message: T = crate::encoding()
+        .with_limit(event.len() as u64)
+        .allow_trailing_bytes()
+        .deserialize_from(&mut event)
+        .map_err(ProtocolError::Decode)?;
+    if !event.is_empty() && !message.is_unknown() {
+        return Err(malformed(format!("{} trailing bytes", event.len())));
+    }
+    let mut sections = Sections::default();
+    while !rest.is_empty() {
+        let kind = rest
+            .get(..4)
+            .ok_or_else(|| malformed("truncated section".to_owned()))?;
+        let (data, tail) =
+            split(&rest[4..]).ok_or_else(|| malformed("truncated section".to_owned()))?;
+        sections.insert(
+            u32::from_le_bytes(kind.try_into().unwrap()),
+            data.to_owned(),
+        );
+        rest = tail;
+    }
+    message.set_sections(&sections);
+    Ok(message)
+}
+
+/// Split off the part of `buffer` whose length it starts with
+fn split(buffer: &[u8]) -> Option<(&[u8], &[u8])> {
+    let length = u32::from_le_bytes(buffer.get(..4)?.try_into().unwrap()) as usize;
+    let rest = &buffer[4..];
+    (length <= rest.len()).then(|| rest.split_at(length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientEvent, IconClientEvent, IconServerEvent, ServerEvent};
+
+    fn title(timestamp: u64) -> IconClientEvent {
+        IconClientEvent {
+            id: 7,
+            timestamp,
+            event: ClientEvent::Title(Some("Title".to_owned())),
+        }
+    }
+
+    /// The encoding of `event`, without its envelope
+    fn encode_event(event: &IconClientEvent) -> Vec<u8> {
+        use bincode::Options as _;
+        crate::encoding().serialize(event).unwrap()
+    }
+
+    /// An envelope holding `event`, then `sections`
+    fn envelope(event: &[u8], sections: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut buffer = (event.len() as u32).to_le_bytes().to_vec();
+        buffer.extend_from_slice(event);
+        for (kind, data) in sections {
+            buffer.extend_from_slice(&kind.to_le_bytes());
+            buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(data);
+        }
+        buffer
+    }
+
+    /// The encoding of an event of item 7 with the variant `index`, which no
+    /// version knows, followed by `payload`
+    fn unknown_event(index: u32, payload: &[u8]) -> Vec<u8> {
+        let mut event = 7u64.to_ne_bytes().to_vec();
+        event.extend_from_slice(&index.to_ne_bytes());
+        event.extend_from_slice(payload);
+        event
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        for timestamp in [0, 1, u64::MAX] {
+            let decoded: IconClientEvent = decode(&encode(&title(timestamp)).unwrap()).unwrap();
+            assert_eq!(decoded.timestamp, timestamp);
+            assert!(matches!(decoded.event, ClientEvent::Title(Some(t)) if t == "Title"));
+        }
+        // A timestamp of 0 is not sent
+        assert_eq!(
+            encode(&title(0)).unwrap(),
+            envelope(&encode_event(&title(0)), &[])
+        );
+    }
+
+    #[test]
+    fn unknown_events_are_skipped() {
+        let buffer = envelope(&unknown_event(9999, &[1, 2, 3, 4, 5]), &[]);
+        let decoded: IconClientEvent = decode(&buffer).unwrap();
+        assert_eq!(decoded.id, 7);
+        assert!(matches!(decoded.event, ClientEvent::Unknown));
+        let decoded: IconServerEvent = decode(&buffer).unwrap();
+        assert_eq!(decoded.id, 7);
+        assert!(matches!(decoded.event, ServerEvent::Unknown));
+    }
+
+    #[test]
+    fn unknown_events_keep_their_sections() {
+        let timestamp = 42u64.to_le_bytes();
+        let buffer = envelope(&unknown_event(9999, &[1, 2, 3]), &[(TIMESTAMP, &timestamp)]);
+        let decoded: IconClientEvent = decode(&buffer).unwrap();
+        assert!(matches!(decoded.event, ClientEvent::Unknown));
+        assert_eq!(decoded.timestamp, 42);
+    }
+
+    #[test]
+    fn unknown_sections_are_ignored() {
+        let event = encode_event(&title(0));
+        let timestamp = 42u64.to_le_bytes();
+        let buffer = envelope(
+            &event,
+            &[(9999, &[1, 2, 3]), (TIMESTAMP, &timestamp), (10000, &[])],
+        );
+        let decoded: IconClientEvent = decode(&buffer).unwrap();
+        assert_eq!(decoded.timestamp, 42);
+        assert!(matches!(decoded.event, ClientEvent::Title(Some(t)) if t == "Title"));
+    }
+
+    #[test]
+    fn truncated_envelopes_are_rejected() {
+        let timestamp = 42u64.to_le_bytes();
+        let buffer = envelope(&encode_event(&title(0)), &[(TIMESTAMP, &timestamp)]);
+        // Every prefix that does not end between two sections
+        let event_end = buffer.len() - 16;
+        for len in (0..buffer.len()).filter(|&len| len != event_end) {
+            assert!(
+                decode::<IconClientEvent>(&buffer[..len]).is_err(),
+                "envelope truncated to {} bytes accepted",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_of_known_events_are_rejected() {
+        let mut event = encode_event(&title(0));
+        event.push(0);
+        assert!(decode::<IconClientEvent>(&envelope(&event, &[])).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! Errors of the library
+//!
+//! [`Error`] is what the functions of this crate that can fail for more than
+//! one reason return.  It says which layer failed, so that callers can tell
+//! a peer that went away ([`TransportError::Closed`]) from one that broke the
+//! protocol, which should not be retried, or from a missing session bus.
+
+use std::fmt;
+use std::io;
+
+/// An error of any layer
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Error {
+    Transport(TransportError),
+    Protocol(ProtocolError),
+    Bus(BusError),
+    Validation(ValidationError),
+}
+
+/// Reading or writing the stream to the other side failed
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum TransportError {
+    /// The other side closed the stream
+    Closed,
+    Io(io::Error),
+}
+
+/// The other side sent something it should not have, or a message could
+/// not be encoded
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A message was longer than the negotiated limit
+    MessageTooLarge {
+        size: usize,
+        max: u32,
+    },
+    /// A message did not match its tag, see [`crate::integrity`]
+    Integrity {
+        size: u32,
+    },
+    /// The other side only speaks versions of the protocol older than
+    /// `expected`, see [`crate::MIN_PROTOCOL_VERSION`]
+    Version {
+        peer: u32,
+        expected: u32,
+    },
+    /// An icon frame has no pixels, or not as much data as its size
+    /// requires
+    InvalidFrame {
+        width: u32,
+        height: u32,
+        length: usize,
+    },
+    /// An icon frame is wider or higher than the negotiated limit
+    FrameTooLarge {
+        width: u32,
+        height: u32,
+        max: u32,
+    },
+    /// An icon has more frames than the negotiated limit
+    TooManyFrames {
+        count: usize,
+        max: u32,
+    },
+    Encode(bincode::Error),
+    Decode(bincode::Error),
+}
+
+/// Talking to the session bus failed
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum BusError {
+    /// The bus could not be connected to
+    Connect(dbus::Error),
+}
+
+/// Something to be used on the bus is not valid
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum ValidationError {
+    /// D-Bus strings are not validated, see
+    /// [`crate::check::require_string_validation`]
+    StringValidationDisabled,
+    /// `name` is not a valid bus name
+    BusName { name: String, reason: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(e) => e.fmt(f),
+            Self::Protocol(e) => e.fmt(f),
+            Self::Bus(e) => e.fmt(f),
+            Self::Validation(e) => e.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => f.write_str("connection closed by the other side"),
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MessageTooLarge { size, max } => {
+                write!(f, "message of {} bytes exceeds the limit of {}", size, max)
+            }
+            Self::Integrity { size } => {
+                write!(f, "message of {} bytes fails its integrity check", size)
+            }
+            Self::Version { peer, expected } => write!(
+                f,
+                "peer speaks protocol version {}, expected {} or later",
+                peer, expected
+            ),
+            Self::InvalidFrame {
+                width,
+                height,
+                length,
+            } => write!(
+                f,
+                "icon frame of {}x{} pixels has {} bytes of data",
+                width, height, length
+            ),
+            Self::FrameTooLarge { width, height, max } => write!(
+                f,
+                "icon frame of {}x{} pixels exceeds the limit of {}",
+                width, height, max
+            ),
+            Self::TooManyFrames { count, max } => {
+                write!(f, "icon of {} frames exceeds the limit of {}", count, max)
+            }
+            Self::Encode(e) => write!(f, "cannot encode message: {}", e),
+            Self::Decode(e) => write!(f, "malformed message: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect(e) => write!(f, "cannot connect to the session bus: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StringValidationDisabled => f.write_str(
+                "D-Bus string validation is disabled, so names from the VM would not be \
+                 checked. Rebuild without the no-string-validation feature of the dbus crate.",
+            ),
+            Self::BusName { name, reason } => {
+                write!(f, "invalid bus name {:?}: {}", name, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transport(e) => e.source(),
+            Self::Protocol(e) => e.source(),
+            Self::Bus(e) => e.source(),
+            Self::Validation(e) => e.source(),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Closed => None,
+            Self::Io(e) => Some(e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Encode(e) | Self::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for BusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connect(e) => Some(e),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Closed,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Transport(e.into())
+    }
+}
+
+impl From<TransportError> for Error {
+    fn from(e: TransportError) -> Self {
+        Self::Transport(e)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        Self::Protocol(e)
+    }
+}
+
+impl From<BusError> for Error {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
+
+impl From<ValidationError> for Error {
+    fn from(e: ValidationError) -> Self {
+        Self::Validation(e)
+    }
+}
+
+impl Error {
+    /// Whether the other side closed the stream, which ends a connection
+    /// normally
+    pub fn is_closed(&self) -> bool {
+        matches!(self, Self::Transport(TransportError::Closed))
+    }
+}
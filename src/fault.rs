@@ -0,0 +1,230 @@
+//! Failure injection, for testing only
+//!
+//! Faults are configured with the `SNI_ICON_FAULTS` environment variable, a
+//! comma-separated list of `key=value` settings:
+//!
+//! - `seed`: seed of the random number generator, so that runs can be
+//!   repeated exactly.  Defaults to 1.
+//! - `drop`, `duplicate`, `truncate`, `corrupt`: the probability of doing
+//!   that to a message, between 0 and 1.  Default to 0.
+//! - `delay`: the probability of delaying a D-Bus reply, between 0 and 1.
+//!   Defaults to 0.
+//! - `delay_ms`: how long D-Bus replies are delayed.  Defaults to 100.
+//!
+//! If the variable is not set, no faults are injected.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// The name of the environment variable configuring faults
+pub const VARIABLE: &str = "SNI_ICON_FAULTS";
+
+struct Config {
+    drop: f64,
+    duplicate: f64,
+    truncate: f64,
+    corrupt: f64,
+    delay: f64,
+    delay_time: Duration,
+}
+
+struct Faults {
+    config: Config,
+    /// State of a xorshift64 generator, never 0
+    state: u64,
+}
+
+impl Faults {
+    fn parse(settings: &str) -> Result<Self, String> {
+        let mut config = Config {
+            drop: 0.0,
+            duplicate: 0.0,
+            truncate: 0.0,
+            corrupt: 0.0,
+            delay: 0.0,
+            delay_time: Duration::from_millis(100),
+        };
+        let mut seed = 1;
+        for setting in settings.split(',').filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("setting {:?} has no value", setting))?;
+            let invalid = |_| format!("invalid value {:?} for {}", value, key);
+            let probability = || match value.parse::<f64>() {
+                Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+                _ => Err(format!("{} must be between 0 and 1", key)),
+            };
+            match key {
+                "seed" => seed = value.parse().map_err(invalid)?,
+                "drop" => config.drop = probability()?,
+                "duplicate" => config.duplicate = probability()?,
+                "truncate" => config.truncate = probability()?,
+                "corrupt" => config.corrupt = probability()?,
+                "delay" => config.delay = probability()?,
+                "delay_ms" => {
+                    config.delay_time = Duration::from_millis(value.parse().map_err(invalid)?)
+                }
+                _ => return Err(format!("unknown setting {:?}", key)),
+            }
+        }
+        Ok(Self {
+            config,
+            state: seed | 1,
+        })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// `true` with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn message(&mut self, mut message: Vec<u8>) -> Vec<Vec<u8>> {
+        if self.chance(self.config.drop) {
+            return vec![];
+        }
+        if !message.is_empty() && self.chance(self.config.truncate) {
+            message.truncate(self.below(message.len()));
+        }
+        if !message.is_empty() && self.chance(self.config.corrupt) {
+            let i = self.below(message.len());
+            message[i] ^= 1 << self.below(8);
+        }
+        if self.chance(self.config.duplicate) {
+            vec![message.clone(), message]
+        } else {
+            vec![message]
+        }
+    }
+}
+
+fn faults() -> Option<&'static Mutex<Faults>> {
+    static FAULTS: OnceLock<Option<Mutex<Faults>>> = OnceLock::new();
+    FAULTS
+        .get_or_init(|| {
+            let settings = std::env::var(VARIABLE).ok()?;
+            match Faults::parse(&settings) {
+                Ok(faults) => Some(Mutex::new(faults)),
+                Err(e) => panic!("invalid {}: {}", VARIABLE, e),
+            }
+        })
+        .as_ref()
+}
+
+/// Apply faults to `message`, an encoded message about to be sent without
+/// its length prefix.  Returns the messages to send instead: none if it is
+/// dropped, and two if it is duplicated.
+pub fn message(message: Vec<u8>) -> Vec<Vec<u8>> {
+    match faults() {
+        None => vec![message],
+        Some(faults) => faults.lock().unwrap().message(message),
+    }
+}
+
+/// How long to delay the reply to a D-Bus method call, if at all
+pub fn reply_delay() -> Option<Duration> {
+    let mut faults = faults()?.lock().unwrap();
+    let delay = faults.config.delay;
+    faults.chance(delay).then_some(faults.config.delay_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientEvent, IconClientEvent};
+
+    const SETTINGS: &str = "seed=42,drop=0.1,duplicate=0.1,truncate=0.2,corrupt=0.2";
+
+    fn messages() -> impl Iterator<Item = Vec<u8>> {
+        (1..=500).map(|id| {
+            let event = IconClientEvent {
+                id,
+                timestamp: 0,
+                event: ClientEvent::Title(Some(format!("Title {}", id))),
+            };
+            crate::encode(&event).unwrap()
+        })
+    }
+
+    fn run(settings: &str) -> Vec<Vec<Vec<u8>>> {
+        let mut faults = Faults::parse(settings).unwrap();
+        messages().map(|message| faults.message(message)).collect()
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        let faults = Faults::parse("seed=7,drop=0.5,delay=1,delay_ms=20").unwrap();
+        assert_eq!(faults.config.drop, 0.5);
+        assert_eq!(faults.config.delay, 1.0);
+        assert_eq!(faults.config.delay_time, Duration::from_millis(20));
+        assert_eq!(faults.state, 7);
+        // The generator must never be in state 0
+        assert_eq!(Faults::parse("seed=0").unwrap().state, 1);
+        for invalid in [
+            "drop",
+            "drop=2",
+            "corrupt=-1",
+            "seed=x",
+            "delay_ms=1.5",
+            "other=1",
+        ] {
+            assert!(Faults::parse(invalid).is_err(), "{:?} accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn no_faults_by_default() {
+        let original: Vec<_> = messages().map(|message| vec![message]).collect();
+        assert_eq!(run(""), original);
+    }
+
+    #[test]
+    fn faults_are_reproducible() {
+        assert_eq!(run(SETTINGS), run(SETTINGS));
+        assert_ne!(run(SETTINGS), run(&SETTINGS.replace("seed=42", "seed=44")));
+    }
+
+    #[test]
+    fn every_fault_happens() {
+        let faulted = run(SETTINGS);
+        let original: Vec<_> = messages().collect();
+        let dropped = faulted.iter().filter(|sent| sent.is_empty()).count();
+        let duplicated = faulted.iter().filter(|sent| sent.len() == 2).count();
+        let (mut truncated, mut corrupted) = (0, 0);
+        for (sent, original) in faulted.iter().zip(&original) {
+            if let Some(message) = sent.first() {
+                if message.len() < original.len() {
+                    truncated += 1;
+                } else if message != original {
+                    corrupted += 1;
+                }
+            }
+        }
+        for (fault, count) in [
+            ("dropped", dropped),
+            ("duplicated", duplicated),
+            ("truncated", truncated),
+            ("corrupted", corrupted),
+        ] {
+            assert!(count > 10, "only {} messages {}", count, fault);
+        }
+    }
+
+    #[test]
+    fn receivers_survive_faults() {
+        for message in run(SETTINGS).into_iter().flatten() {
+            // Damaged messages must be errors, not panics
+            let _ = crate::decode::<IconClientEvent>(&message);
+        }
+    }
+}
@@ -0,0 +1,700 @@
+//! Drawing onto icons: the border that marks them as coming from a VM, and
+//! icons and badges drawn onto them, for hosts that ignore some of the
+//! icons of an item
+//!
+//! Frames are ARGB32 in network byte order, with alpha not premultiplied.
+//! They come from VMs, so none of these functions trust their sizes: frames
+//! without as much data as their size requires are left alone, or removed
+//! by [`border`].  Nothing is drawn over the border.
+
+use crate::IconData;
+
+/// The border drawn around every icon frame, which marks icons as coming
+/// from a VM, see [`border`]
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Border {
+    /// Width in pixels
+    pub width: u32,
+    pub style: BorderStyle,
+    pub placement: BorderPlacement,
+    /// Draw the first letter of the name of the VM onto the badge of
+    /// [`BorderStyle::Corner`]
+    pub letter: bool,
+}
+
+impl Default for Border {
+    fn default() -> Self {
+        Self {
+            width: 2,
+            style: BorderStyle::Solid,
+            placement: BorderPlacement::Inside,
+            letter: false,
+        }
+    }
+}
+
+impl Border {
+    /// The widest border that may be configured
+    pub const MAX_WIDTH: u32 = 32;
+
+    /// The width of the border as drawn, 0 if none is
+    pub fn drawn_width(&self) -> u32 {
+        match self.style {
+            BorderStyle::Solid | BorderStyle::Dashed => self.width,
+            BorderStyle::Corner | BorderStyle::None => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BorderStyle {
+    Solid,
+    /// Dashes three times as long as the border is wide
+    Dashed,
+    /// A badge in the color of the label of the VM in the bottom-left
+    /// corner instead of a border, which is easier on the eye at small
+    /// sizes
+    Corner,
+    /// No border, for trusted VMs
+    None,
+}
+
+/// How icons of items that are gone or do not answer are drawn, see [`fade`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fade {
+    /// Grey and half transparent
+    #[default]
+    Grey,
+    /// Half transparent, in their colors
+    Dim,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BorderPlacement {
+    /// Over the outermost pixels of the frame
+    Inside,
+    /// Around the frame, which grows by twice the width of the border
+    Outside,
+}
+
+/// Whether `frame` has as much data as its size requires
+fn valid(frame: &IconData) -> bool {
+    (u64::from(frame.width) * u64::from(frame.height)).checked_mul(4)
+        == Some(frame.data.len() as u64)
+}
+
+/// The pixel of `frame` at `x`, `y`, which must be a valid frame
+fn pixel(frame: &mut IconData, x: u32, y: u32) -> &mut [u8] {
+    let offset = (y as usize * frame.width as usize + x as usize) * 4;
+    &mut frame.data[offset..offset + 4]
+}
+
+/// The frame of `frames` to scale to `width`, preferring the smallest that
+/// is at least that wide
+fn best_frame(frames: &[IconData], width: u32) -> Option<&IconData> {
+    let frames = frames
+        .iter()
+        .filter(|f| valid(f) && f.width > 0 && f.height > 0);
+    frames
+        .clone()
+        .filter(|f| f.width >= width)
+        .min_by_key(|f| f.width)
+        .or_else(|| frames.max_by_key(|f| f.width))
+}
+
+/// Draw `border` around each frame of `frames`, in red, or its corner badge
+/// in `color` (RGB), with the first letter of `vm` if it is to have one.
+/// Frames without as much data as their size requires are removed, as the
+/// border could not be drawn onto them, and so are frames too large to grow
+/// by the border.
+pub fn border(frames: &mut Vec<IconData>, border: &Border, color: [u8; 3], vm: Option<&str>) {
+    const RED: [u8; 4] = [255, 255, 0, 0];
+    let width = border.drawn_width();
+    let grows = border.placement == BorderPlacement::Outside && width > 0;
+    frames.retain(|frame| {
+        valid(frame)
+            && (!grows
+                || (frame.width.checked_add(2 * width).is_some()
+                    && frame.height.checked_add(2 * width).is_some()))
+    });
+    if border.style == BorderStyle::Corner {
+        let letter = vm
+            .and_then(|vm| vm.chars().next())
+            .filter(|_| border.letter)
+            .and_then(glyph);
+        for frame in frames.iter_mut() {
+            corner_badge(frame, color, letter)
+        }
+        return;
+    }
+    if width == 0 {
+        return;
+    }
+    let dash = 3 * width;
+    for frame in frames.iter_mut() {
+        if grows {
+            grow(frame, width);
+        }
+        let (w, h) = (frame.width, frame.height);
+        for y in 0..h {
+            // Rows of the top and bottom edges are drawn in full, others
+            // only at the left and right edges.  Dashes run along the edge.
+            let edge = y < width || y >= h.saturating_sub(width);
+            let columns = if edge { 0..w } else { 0..width.min(w) };
+            let right = if edge {
+                0..0
+            } else {
+                w.saturating_sub(width).max(width)..w
+            };
+            for x in columns.chain(right) {
+                let along = if edge { x } else { y };
+                if border.style == BorderStyle::Dashed && along / dash % 2 == 1 {
+                    continue;
+                }
+                pixel(frame, x, y).copy_from_slice(&RED);
+            }
+        }
+    }
+}
+
+/// Draw a square of `color` (RGB) onto the bottom-left corner of `frame`,
+/// with `glyph` on it in white if the square is large enough
+fn corner_badge(frame: &mut IconData, color: [u8; 3], glyph: Option<[u8; 5]>) {
+    const WHITE: [u8; 4] = [255; 4];
+    let background = [255, color[0], color[1], color[2]];
+    let side = frame.width.min(frame.height);
+    let size = (side / 3).max(4).min(side);
+    // In pixels of the font, with a margin of one around the glyph, which
+    // is centered
+    let scale = size / 7;
+    let offset = (size - 7 * scale) / 2;
+    let top = frame.height - size;
+    for y in 0..size {
+        for x in 0..size {
+            let lit = match glyph.filter(|_| scale > 0) {
+                Some(glyph) => {
+                    let column = x.checked_sub(offset).map_or(0, |x| x / scale);
+                    let row = y.checked_sub(offset).map_or(0, |y| y / scale);
+                    (1..=3).contains(&column)
+                        && (1..=5).contains(&row)
+                        && glyph[row as usize - 1] & (0b100 >> (column - 1)) != 0
+                }
+                None => false,
+            };
+            let color = if lit { WHITE } else { background };
+            pixel(frame, x, top + y).copy_from_slice(&color);
+        }
+    }
+}
+
+/// Add `by` transparent pixels on each side of `frame`, which must be a
+/// valid frame that does not get too large
+fn grow(frame: &mut IconData, by: u32) {
+    let (width, height) = (frame.width + 2 * by, frame.height + 2 * by);
+    let mut data = vec![0; width as usize * height as usize * 4];
+    let row = frame.width as usize * 4;
+    // Frames of width 0 have no data
+    for (y, src) in frame.data.chunks_exact(row.max(1)).enumerate() {
+        let dst = ((y + by as usize) * width as usize + by as usize) * 4;
+        data[dst..dst + row].copy_from_slice(src);
+    }
+    *frame = IconData {
+        width,
+        height,
+        data,
+    };
+}
+
+/// Blend the pixel `src` over `dst`
+fn blend(dst: &mut [u8], src: &[u8]) {
+    let src_alpha = u32::from(src[0]);
+    let dst_alpha = u32::from(dst[0]) * (255 - src_alpha) / 255;
+    let alpha = src_alpha + dst_alpha;
+    if alpha == 0 {
+        return;
+    }
+    dst[0] = alpha as u8;
+    for i in 1..4 {
+        let color = u32::from(src[i]) * src_alpha + u32::from(dst[i]) * dst_alpha;
+        dst[i] = (color / alpha) as u8;
+    }
+}
+
+/// Draw each frame of `base` as `fade` says, to show that its item is gone
+/// or does not answer, leaving `border` drawn onto it as it is
+pub fn fade(base: &mut [IconData], fade: Fade, border: &Border) {
+    let inset = border.drawn_width();
+    for frame in base.iter_mut().filter(|f| valid(f)) {
+        let (width, height) = (frame.width, frame.height);
+        let side = width.min(height);
+        let corner = match border.style {
+            BorderStyle::Corner => (side / 3).max(4).min(side),
+            _ => 0,
+        };
+        for y in inset..height.saturating_sub(inset) {
+            for x in inset..width.saturating_sub(inset) {
+                if x < corner && y >= height - corner {
+                    continue;
+                }
+                let p = pixel(frame, x, y);
+                p[0] /= 2;
+                if fade == Fade::Grey {
+                    let luma =
+                        (u32::from(p[1]) * 77 + u32::from(p[2]) * 150 + u32::from(p[3]) * 29) >> 8;
+                    p[1..].fill(luma as u8);
+                }
+            }
+        }
+    }
+}
+
+/// Draw `overlay` onto `base`, scaled to its bottom-right quadrant and
+/// clear of a border of `inset` pixels.  Both must be valid frames, and
+/// `overlay` must not be empty.
+fn draw(base: &mut IconData, overlay: &IconData, inset: u32) {
+    let (left, top) = (base.width / 2, base.height / 2);
+    let right = base.width.saturating_sub(inset);
+    let bottom = base.height.saturating_sub(inset);
+    let (width, height) = (u64::from(base.width - left), u64::from(base.height - top));
+    for y in top..bottom {
+        let src_y = u64::from(y - top) * u64::from(overlay.height) / height;
+        for x in left..right {
+            let src_x = u64::from(x - left) * u64::from(overlay.width) / width;
+            let src = ((src_y * u64::from(overlay.width) + src_x) * 4) as usize;
+            blend(pixel(base, x, y), &overlay.data[src..src + 4]);
+        }
+    }
+}
+
+/// Draw a frame of `overlay` onto the bottom-right quadrant of each frame
+/// of `base`, inside its border of `inset` pixels
+pub fn overlay(base: &mut [IconData], overlay: &[IconData], inset: u32) {
+    for frame in base.iter_mut().filter(|f| valid(f)) {
+        if let Some(overlay) = best_frame(overlay, frame.width / 2) {
+            draw(frame, overlay, inset)
+        }
+    }
+}
+
+/// Draw an exclamation mark on a disc of `color` (RGB) onto the top-right
+/// quadrant of each frame of `base`, inside its border of `inset` pixels
+pub fn attention_badge(base: &mut [IconData], color: [u8; 3], inset: u32) {
+    const WHITE: [u8; 4] = [255; 4];
+    let disc = [255, color[0], color[1], color[2]];
+    for frame in base.iter_mut().filter(|f| valid(f)) {
+        let size = frame.width.min(frame.height) / 2;
+        if size < 4 {
+            continue;
+        }
+        // All in units of a twentieth of the size of the badge
+        let unit = size as f32 / 20.0;
+        let (center_x, center_y) = (frame.width as f32 - 10.0 * unit, 10.0 * unit);
+        let right = frame.width.saturating_sub(inset);
+        for y in inset..size {
+            for x in frame.width - size..right {
+                let dx = (x as f32 + 0.5 - center_x) / unit;
+                let dy = (y as f32 + 0.5 - center_y) / unit;
+                let color = if dx * dx + dy * dy > 100.0 {
+                    continue;
+                } else if dx.abs() <= 1.5
+                    && ((-7.0..=2.5).contains(&dy) || (4.5..=7.0).contains(&dy))
+                {
+                    WHITE
+                } else {
+                    disc
+                };
+                pixel(frame, x, y).copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+/// Digits and `+` in a font of 3×5 pixels, one row per byte, the leftmost
+/// pixel in bit 2
+const GLYPHS: [[u8; 5]; 11] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b000, 0b010, 0b111, 0b010, 0b000],
+];
+const PLUS: usize = 10;
+
+/// Letters in the font of [`GLYPHS`]
+const LETTERS: [[u8; 5]; 26] = [
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b110, 0b100, 0b111],
+    [0b111, 0b100, 0b110, 0b100, 0b100],
+    [0b011, 0b100, 0b101, 0b101, 0b011],
+    [0b101, 0b101, 0b111, 0b101, 0b101],
+    [0b111, 0b010, 0b010, 0b010, 0b111],
+    [0b001, 0b001, 0b001, 0b101, 0b010],
+    [0b101, 0b101, 0b110, 0b101, 0b101],
+    [0b100, 0b100, 0b100, 0b100, 0b111],
+    [0b101, 0b111, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b101, 0b101, 0b101],
+    [0b010, 0b101, 0b101, 0b101, 0b010],
+    [0b110, 0b101, 0b110, 0b100, 0b100],
+    [0b010, 0b101, 0b101, 0b110, 0b011],
+    [0b110, 0b101, 0b110, 0b101, 0b101],
+    [0b011, 0b100, 0b010, 0b001, 0b110],
+    [0b111, 0b010, 0b010, 0b010, 0b010],
+    [0b101, 0b101, 0b101, 0b101, 0b111],
+    [0b101, 0b101, 0b101, 0b101, 0b010],
+    [0b101, 0b101, 0b111, 0b111, 0b101],
+    [0b101, 0b101, 0b010, 0b101, 0b101],
+    [0b101, 0b101, 0b010, 0b010, 0b010],
+    [0b111, 0b001, 0b010, 0b100, 0b111],
+];
+
+/// The glyph of `c`, if it is an ASCII letter or digit.  Letters are drawn
+/// in upper case.
+fn glyph(c: char) -> Option<[u8; 5]> {
+    match c.to_ascii_uppercase() {
+        c @ '0'..='9' => Some(GLYPHS[c as usize - '0' as usize]),
+        c @ 'A'..='Z' => Some(LETTERS[c as usize - 'A' as usize]),
+        _ => None,
+    }
+}
+
+/// The glyphs `count` is written with: up to two digits, or `9+`
+fn count_glyphs(count: usize) -> Vec<usize> {
+    match count {
+        0..=9 => vec![count],
+        10..=99 => vec![count / 10, count % 10],
+        _ => vec![9, PLUS],
+    }
+}
+
+/// Draw `count` in white on a box of `color` (RGB) onto the top-left
+/// quadrant of each frame of `base`, inside its border of `inset` pixels
+pub fn count_badge(base: &mut [IconData], count: usize, color: [u8; 3], inset: u32) {
+    const WHITE: [u8; 4] = [255; 4];
+    let background = [255, color[0], color[1], color[2]];
+    let glyphs = count_glyphs(count);
+    // In pixels of the font, with a margin of one around the glyphs, which
+    // are one apart
+    let (columns, rows) = (glyphs.len() as u32 * 4 + 1, 7);
+    for frame in base.iter_mut().filter(|f| valid(f)) {
+        let size = frame.width.min(frame.height) / 2;
+        let scale = size.saturating_sub(inset) / columns.max(rows);
+        if scale == 0 {
+            continue;
+        }
+        let (left, top) = (inset, inset);
+        for y in 0..rows * scale {
+            let row = y / scale;
+            for x in 0..columns * scale {
+                let column = x / scale;
+                // The glyph this column is in, if not in a margin
+                let glyph = (column % 4 != 0).then_some(column as usize / 4);
+                let lit = match (glyph, row) {
+                    (Some(glyph), 1..=5) => {
+                        GLYPHS[glyphs[glyph]][row as usize - 1] & (0b100 >> (column % 4 - 1)) != 0
+                    }
+                    _ => false,
+                };
+                let color = if lit { WHITE } else { background };
+                pixel(frame, left + x, top + y).copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 4] = [255, 255, 0, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    /// Neither red nor the color of any badge
+    const GREY: [u8; 4] = [255, 128, 128, 128];
+
+    fn frame(width: u32, height: u32) -> IconData {
+        IconData {
+            width,
+            height,
+            data: GREY.repeat(width as usize * height as usize),
+        }
+    }
+
+    fn at(frame: &IconData, x: u32, y: u32) -> [u8; 4] {
+        let offset = ((y * frame.width + x) * 4) as usize;
+        frame.data[offset..offset + 4].try_into().unwrap()
+    }
+
+    fn borders() -> impl Iterator<Item = Border> {
+        let styles = [
+            BorderStyle::Solid,
+            BorderStyle::Dashed,
+            BorderStyle::Corner,
+            BorderStyle::None,
+        ];
+        let placements = [BorderPlacement::Inside, BorderPlacement::Outside];
+        (0..=4).flat_map(move |width| {
+            styles.into_iter().flat_map(move |style| {
+                placements.into_iter().flat_map(move |placement| {
+                    [false, true].map(|letter| Border {
+                        width,
+                        style,
+                        placement,
+                        letter,
+                    })
+                })
+            })
+        })
+    }
+
+    #[test]
+    fn border_handles_all_small_sizes() {
+        for border in borders() {
+            for (width, height) in (0..12).flat_map(|w| (0..12).map(move |h| (w, h))) {
+                let mut frames = vec![frame(width, height)];
+                super::border(&mut frames, &border, BLUE, Some("work"));
+                assert_eq!(frames.len(), 1);
+                assert!(valid(&frames[0]), "{:?} {}x{}", border, width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn solid_border_covers_edges_only() {
+        let border = Border::default();
+        let mut frames = vec![frame(10, 8)];
+        super::border(&mut frames, &border, BLUE, None);
+        let frame = &frames[0];
+        for y in 0..8 {
+            for x in 0..10 {
+                let edge = !(2..8).contains(&x) || !(2..6).contains(&y);
+                assert_eq!(
+                    at(frame, x, y),
+                    if edge { RED } else { GREY },
+                    "{}, {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fade_leaves_border_alone() {
+        const BLUE_PIXEL: [u8; 4] = [255, 0, 0, 255];
+        for style in [BorderStyle::Solid, BorderStyle::Corner] {
+            let border = Border {
+                style,
+                ..Border::default()
+            };
+            let mut frames = vec![frame(9, 9)];
+            super::border(&mut frames, &border, BLUE, None);
+            let drawn = frames[0].clone();
+            fade(&mut frames, Fade::Grey, &border);
+            for y in 0..9 {
+                for x in 0..9 {
+                    let before = at(&drawn, x, y);
+                    let after = at(&frames[0], x, y);
+                    if before == RED || before == BLUE_PIXEL {
+                        assert_eq!(after, before, "{:?} {}, {}", style, x, y);
+                    } else {
+                        assert_eq!(after, [127, 128, 128, 128], "{:?} {}, {}", style, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dim_keeps_colors() {
+        const ORANGE: [u8; 4] = [255, 255, 128, 0];
+        let mut frames = vec![IconData {
+            width: 1,
+            height: 1,
+            data: ORANGE.to_vec(),
+        }];
+        let border = Border {
+            style: BorderStyle::None,
+            ..Border::default()
+        };
+        fade(&mut frames, Fade::Dim, &border);
+        assert_eq!(frames[0].data, [127, 255, 128, 0]);
+        fade(&mut frames, Fade::Grey, &border);
+        assert_eq!(frames[0].data, [63, 151, 151, 151]);
+    }
+
+    #[test]
+    fn outside_border_keeps_all_pixels() {
+        let border = Border {
+            placement: BorderPlacement::Outside,
+            width: 3,
+            ..Border::default()
+        };
+        let mut frames = vec![frame(5, 4)];
+        super::border(&mut frames, &border, BLUE, None);
+        let frame = &frames[0];
+        assert_eq!((frame.width, frame.height), (11, 10));
+        for y in 0..10 {
+            for x in 0..11 {
+                let edge = !(3..8).contains(&x) || !(3..7).contains(&y);
+                assert_eq!(
+                    at(frame, x, y),
+                    if edge { RED } else { GREY },
+                    "{}, {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dashed_border_has_gaps() {
+        let border = Border {
+            style: BorderStyle::Dashed,
+            width: 1,
+            ..Border::default()
+        };
+        let mut frames = vec![frame(12, 12)];
+        super::border(&mut frames, &border, BLUE, None);
+        let top: Vec<_> = (0..12).map(|x| at(&frames[0], x, 0) == RED).collect();
+        assert_eq!(top, [true, true, true, false, false, false].repeat(2));
+    }
+
+    #[test]
+    fn border_removes_invalid_frames() {
+        for border in borders() {
+            let mut frames = vec![
+                IconData {
+                    width: 4,
+                    height: 4,
+                    data: vec![0; 63],
+                },
+                IconData {
+                    width: u32::MAX,
+                    height: u32::MAX,
+                    data: vec![],
+                },
+                IconData {
+                    width: u32::MAX,
+                    height: 0,
+                    data: vec![],
+                },
+                frame(4, 4),
+            ];
+            super::border(&mut frames, &border, BLUE, None);
+            let grows = border.placement == BorderPlacement::Outside && border.drawn_width() > 0;
+            assert_eq!(frames.len(), if grows { 1 } else { 2 }, "{:?}", border);
+            assert!(frames.iter().all(valid));
+        }
+    }
+
+    #[test]
+    fn corner_badge_has_letter() {
+        let border = Border {
+            style: BorderStyle::Corner,
+            letter: true,
+            ..Border::default()
+        };
+        let mut frames = vec![frame(21, 21)];
+        super::border(&mut frames, &border, BLUE, Some("work"));
+        let frame = &frames[0];
+        // The badge is 7 pixels, so the glyph is not scaled
+        assert_eq!(at(frame, 0, 20), [255, 0, 0, 255]);
+        assert_eq!(at(frame, 7, 20), GREY);
+        assert_eq!(at(frame, 0, 13), GREY);
+        // The first row of W is 101
+        assert_eq!(at(frame, 1, 15), [255; 4]);
+        assert_eq!(at(frame, 2, 15), [255, 0, 0, 255]);
+        assert_eq!(at(frame, 3, 15), [255; 4]);
+    }
+
+    #[test]
+    fn badges_handle_all_small_sizes() {
+        let overlays = [frame(1, 1), frame(3, 7), frame(0, 5), frame(16, 16)];
+        for inset in 0..=4 {
+            for (width, height) in (0..20).flat_map(|w| (0..20).map(move |h| (w, h))) {
+                let mut frames = vec![frame(width, height)];
+                overlay(&mut frames, &overlays, inset);
+                attention_badge(&mut frames, BLUE, inset);
+                for count in [0, 7, 42, 1000] {
+                    count_badge(&mut frames, count, BLUE, inset);
+                }
+                assert!(valid(&frames[0]));
+            }
+        }
+    }
+
+    #[test]
+    fn badges_ignore_invalid_frames() {
+        let invalid = IconData {
+            width: 16,
+            height: 16,
+            data: vec![0; 5],
+        };
+        let mut frames = vec![invalid.clone()];
+        overlay(&mut frames, &[frame(8, 8)], 2);
+        overlay(&mut frames, std::slice::from_ref(&invalid), 2);
+        attention_badge(&mut frames, BLUE, 2);
+        count_badge(&mut frames, 3, BLUE, 2);
+        assert_eq!(frames[0].data, invalid.data);
+        let mut frames = vec![frame(16, 16)];
+        overlay(&mut frames, &[invalid], 2);
+        assert_eq!(frames[0].data, frame(16, 16).data);
+    }
+
+    #[test]
+    fn badges_stay_inside_border() {
+        let border = Border::default();
+        let mut frames = vec![frame(32, 32)];
+        super::border(&mut frames, &border, BLUE, None);
+        let bordered = frames[0].clone();
+        let opaque = IconData {
+            width: 4,
+            height: 4,
+            data: [255, 0, 255, 0].repeat(16),
+        };
+        overlay(&mut frames, &[opaque], 2);
+        attention_badge(&mut frames, BLUE, 2);
+        count_badge(&mut frames, 99, BLUE, 2);
+        for y in 0..32 {
+            for x in 0..32 {
+                if !(2..30).contains(&x) || !(2..30).contains(&y) {
+                    assert_eq!(at(&frames[0], x, y), at(&bordered, x, y), "{}, {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blend_respects_alpha() {
+        let mut dst = [255, 10, 20, 30];
+        blend(&mut dst, &[0, 200, 200, 200]);
+        assert_eq!(dst, [255, 10, 20, 30]);
+        blend(&mut dst, &[255, 1, 2, 3]);
+        assert_eq!(dst, [255, 1, 2, 3]);
+        let mut dst = [0; 4];
+        blend(&mut dst, &[0, 9, 9, 9]);
+        assert_eq!(dst, [0; 4]);
+    }
+
+    #[test]
+    fn glyphs_of_characters() {
+        assert_eq!(glyph('a'), glyph('A'));
+        assert_eq!(glyph('0'), Some(GLYPHS[0]));
+        assert_eq!(glyph('z'), Some(LETTERS[25]));
+        assert_eq!(glyph('-'), None);
+        assert_eq!(glyph('é'), None);
+    }
+}
@@ -0,0 +1,161 @@
+//! Integrity tags on messages
+//!
+//! vchan neither corrupts nor truncates data, but the sockets used to test
+//! without it (see [`crate::transport`]) are not always as careful.  A side
+//! can ask for a tag after each message in its [`crate::Hello`], so that a
+//! damaged message is reported as such by [`crate::read_message_tagged`],
+//! instead of as an error decoding it.  The tag covers the length prefix as
+//! well as the message.  [`crate::Hello`]s themselves are never tagged.
+
+use std::str::FromStr;
+
+/// The tag after each message
+#[non_exhaustive]
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+pub enum Integrity {
+    /// No tag
+    #[default]
+    None,
+    /// The CRC-32 of the message, as a little-endian `u32`
+    Crc32,
+}
+
+impl FromStr for Integrity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "crc32" => Ok(Self::Crc32),
+            _ => Err(format!("unknown integrity tag {:?}", s)),
+        }
+    }
+}
+
+impl Integrity {
+    /// The tag used when one side asks for `self` and the other for `other`:
+    /// the stronger of the two
+    pub fn negotiate(self, other: Self) -> Self {
+        self.max(other)
+    }
+
+    /// The name of the tag, as [`Integrity::from_str`] takes it
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Crc32 => "crc32",
+        }
+    }
+
+    /// The size of the tag, in bytes
+    pub fn size(self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Crc32 => 4,
+        }
+    }
+
+    /// The tag of `message`
+    pub fn tag(self, message: &[u8]) -> Vec<u8> {
+        match self {
+            Self::None => vec![],
+            Self::Crc32 => {
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.update(&(message.len() as u32).to_le_bytes());
+                hasher.update(message);
+                hasher.finalize().to_le_bytes().to_vec()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{ProtocolError, TransportError};
+    use crate::{read_message_tagged, write_message_tagged, Error};
+
+    async fn framed(message: &[u8], integrity: Integrity) -> Vec<u8> {
+        let mut framed = vec![];
+        write_message_tagged(&mut framed, message, integrity)
+            .await
+            .unwrap();
+        framed
+    }
+
+    #[test]
+    fn negotiate_picks_the_stronger_tag() {
+        use Integrity::*;
+        for (ours, theirs, expected) in [
+            (None, None, None),
+            (None, Crc32, Crc32),
+            (Crc32, None, Crc32),
+            (Crc32, Crc32, Crc32),
+        ] {
+            assert_eq!(ours.negotiate(theirs), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn tagged_messages_round_trip() {
+        for integrity in [Integrity::None, Integrity::Crc32] {
+            let framed = framed(b"hello", integrity).await;
+            assert_eq!(framed.len(), 4 + 5 + integrity.size());
+            let message = read_message_tagged(&mut &framed[..], 64, integrity).await;
+            assert_eq!(message.unwrap(), b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupted_bytes_fail_the_check() {
+        let framed = framed(b"hello", Integrity::Crc32).await;
+        // Every byte after the length prefix, including those of the tag
+        for position in 4..framed.len() {
+            let mut corrupted = framed.clone();
+            corrupted[position] ^= 0x20;
+            let result = read_message_tagged(&mut &corrupted[..], 64, Integrity::Crc32).await;
+            assert!(
+                matches!(
+                    result,
+                    Err(Error::Protocol(ProtocolError::Integrity { size: 5 }))
+                ),
+                "corruption at {} not detected",
+                position
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_tags_are_rejected() {
+        let framed = framed(b"hello", Integrity::Crc32).await;
+        for len in framed.len() - Integrity::Crc32.size()..framed.len() {
+            let result = read_message_tagged(&mut &framed[..len], 64, Integrity::Crc32).await;
+            assert!(matches!(
+                result,
+                Err(Error::Transport(TransportError::Closed))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn untagged_messages_are_not_accepted_as_tagged() {
+        let mut framed = framed(b"hello", Integrity::None).await;
+        framed.extend_from_slice(&framed.clone());
+        let result = read_message_tagged(&mut &framed[..], 64, Integrity::Crc32).await;
+        assert!(matches!(
+            result,
+            Err(Error::Protocol(ProtocolError::Integrity { .. }))
+        ));
+    }
+}
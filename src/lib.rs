@@ -1,8 +1,223 @@
+pub mod check;
+pub mod cli;
 pub mod client;
+pub mod envelope;
+pub mod error;
+#[cfg(feature = "testing")]
+pub mod fault;
+pub mod image;
+pub mod integrity;
+pub mod menu;
 pub mod names;
+pub mod publisher;
+pub mod request;
+mod safe_text;
+#[cfg(feature = "sandbox")]
+pub mod seccomp;
 pub mod server;
+pub mod session;
+pub mod signal;
+pub mod spec;
+pub mod stream;
+pub mod transport;
 
-#[derive(Debug, serde::Deserialize, serde::Serialize, Copy, Clone, Eq, PartialEq)]
+pub use envelope::{decode, encode, Message};
+pub use error::Error;
+pub use integrity::Integrity;
+pub use menu::MenuItem;
+pub use request::RequestId;
+pub use safe_text::SafeText;
+
+/// Largest message either side sends or accepts, not counting the length
+/// prefix.  The limit negotiated in the handshake may be lower.  Icon frames
+/// that do not fit are sent as [`ClientEvent::IconChunk`]s.
+pub const MAX_MESSAGE_SIZE: u32 = 1 << 24;
+/// Smallest message size limit that can be negotiated
+pub const MIN_MESSAGE_SIZE: u32 = 1 << 16;
+/// Version of the protocol, sent in [`Hello`]
+///
+/// New events are added as variants just before [`ClientEvent::Unknown`]
+/// or [`ServerEvent::Unknown`], which are never sent: a side that does not
+/// know them yet decodes them as `Unknown`, so adding events does not change
+/// the version.  Neither
+/// does adding optional fields, see [`envelope`].
+pub const PROTOCOL_VERSION: u32 = 8;
+/// Oldest version of the protocol still spoken.  Both sides speak the older
+/// of their versions, see [`Hello::decode`].  Versions before 8 did not send
+/// events in [`envelope`]s.
+pub const MIN_PROTOCOL_VERSION: u32 = 8;
+
+/// The bincode options messages are encoded with, on both sides: fixed-size
+/// integers in native byte order, as both run on the same machine
+pub fn encoding() -> impl bincode::Options {
+    use bincode::Options as _;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_native_endian()
+        .reject_trailing_bytes()
+}
+
+/// Read one message from the other side: its length as a little-endian
+/// `u32`, then that many bytes.  A length above `max_size` is a
+/// [`ProtocolError::MessageTooLarge`](error::ProtocolError::MessageTooLarge),
+/// and the end of the stream [`TransportError::Closed`](error::TransportError::Closed).
+pub async fn read_message<R>(reader: &mut R, max_size: u32) -> Result<Vec<u8>, Error>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    read_message_tagged(reader, max_size, Integrity::None).await
+}
+
+/// Read one message as [`read_message`] does, followed by its tag, which
+/// must match.  A message with a wrong tag is a
+/// [`ProtocolError::Integrity`](error::ProtocolError::Integrity).
+pub async fn read_message_tagged<R>(
+    reader: &mut R,
+    max_size: u32,
+    integrity: Integrity,
+) -> Result<Vec<u8>, Error>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use error::ProtocolError;
+    use tokio::io::AsyncReadExt as _;
+    let size = reader.read_u32_le().await?;
+    if size > max_size {
+        return Err(ProtocolError::MessageTooLarge {
+            size: size as usize,
+            max: max_size,
+        }
+        .into());
+    }
+    let mut buffer = vec![0; size as _];
+    reader.read_exact(&mut buffer[..]).await?;
+    let mut tag = vec![0; integrity.size()];
+    reader.read_exact(&mut tag[..]).await?;
+    if tag != integrity.tag(&buffer) {
+        return Err(ProtocolError::Integrity { size }.into());
+    }
+    Ok(buffer)
+}
+
+/// Write `message` to the other side, framed as [`read_message`] expects
+pub async fn write_message<W>(writer: &mut W, message: &[u8]) -> Result<(), Error>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    write_message_tagged(writer, message, Integrity::None).await
+}
+
+/// Write `message` to the other side, framed as [`read_message_tagged`]
+/// expects
+pub async fn write_message_tagged<W>(
+    writer: &mut W,
+    message: &[u8],
+    integrity: Integrity,
+) -> Result<(), Error>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt as _;
+    writer.write_u32_le(message.len() as u32).await?;
+    writer.write_all(message).await?;
+    writer.write_all(&integrity.tag(message)).await?;
+    Ok(writer.flush().await?)
+}
+
+/// Whether deterministic mode is enabled, by setting the environment
+/// variable `SNI_ICON_DETERMINISTIC` to `1`.  In deterministic mode, the same
+/// sequence of D-Bus events produces the same protocol stream and the same
+/// D-Bus messages, apart from the order of dictionary entries, so that
+/// recorded runs can be compared with each other:
+///
+/// - the agent adds items one at a time, in the order they were registered,
+///   so that their IDs and events do not depend on the timing of replies;
+/// - the daemon exports each item under a well-known name derived from its
+///   VM and ID, instead of the unique name of its connection;
+/// - the daemon sends 0 instead of the timestamps of menu events;
+/// - events from the VM carry 0 instead of the time they were sent.
+pub fn deterministic() -> bool {
+    static DETERMINISTIC: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *DETERMINISTIC
+        .get_or_init(|| std::env::var_os("SNI_ICON_DETERMINISTIC").is_some_and(|v| v == "1"))
+}
+
+/// The first message sent by each side, before any events.  Both sides
+/// enforce the [`ProtocolLimits::min`] of their own limits and those of the
+/// other side.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Hello {
+    pub version: u32,
+    pub limits: ProtocolLimits,
+    /// The tag this side asks for after each later message.  Both sides use
+    /// the [`Integrity::negotiate`]d tag.  Publishers never use tags.
+    pub integrity: Integrity,
+}
+
+impl Hello {
+    /// Decode the Hello of the other side, with its version replaced by the
+    /// one both sides speak: the older of [`PROTOCOL_VERSION`] and its own.
+    /// That version must be at least [`MIN_PROTOCOL_VERSION`].  Fields that
+    /// later versions add to the Hello are ignored.
+    pub fn decode(buffer: &[u8]) -> Result<Self, error::ProtocolError> {
+        use bincode::Options as _;
+        let hello: Self = encoding()
+            .allow_trailing_bytes()
+            .deserialize(buffer)
+            .map_err(error::ProtocolError::Decode)?;
+        let version = hello.version.min(PROTOCOL_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(error::ProtocolError::Version {
+                peer: hello.version,
+                expected: MIN_PROTOCOL_VERSION,
+            });
+        }
+        Ok(Self { version, ..hello })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ProtocolLimits {
+    /// Largest message that may be sent, not counting the length prefix
+    pub max_message_size: u32,
+    /// Maximum width and height of an icon frame, in pixels
+    pub max_icon_size: u32,
+    /// Maximum number of frames of a single icon
+    pub max_frames: u32,
+    /// Maximum number of items that have a menu at the same time
+    pub max_menus: u32,
+}
+
+impl Default for ProtocolLimits {
+    /// The most that the protocol allows
+    fn default() -> Self {
+        Self {
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_icon_size: u32::MAX,
+            max_frames: u32::MAX,
+            max_menus: u32::MAX,
+        }
+    }
+}
+
+impl ProtocolLimits {
+    /// The stricter of each limit of `self` and `other`.  The message size
+    /// is kept between [`MIN_MESSAGE_SIZE`] and [`MAX_MESSAGE_SIZE`].
+    pub fn min(self, other: Self) -> Self {
+        Self {
+            max_message_size: self
+                .max_message_size
+                .min(other.max_message_size)
+                .clamp(MIN_MESSAGE_SIZE, MAX_MESSAGE_SIZE),
+            max_icon_size: self.max_icon_size.min(other.max_icon_size),
+            max_frames: self.max_frames.min(other.max_frames),
+            max_menus: self.max_menus.min(other.max_menus),
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Copy, Clone, Eq, PartialEq, Hash)]
 #[repr(u8)]
 pub enum IconType {
     Normal = 1,
@@ -12,21 +227,123 @@ pub enum IconType {
     Title = 16,
 }
 
+/// The standard events of a dbusmenu entry, see [`ServerEvent::MenuState`]
+#[non_exhaustive]
 #[derive(Debug, serde::Deserialize, serde::Serialize, Copy, Clone, Eq, PartialEq)]
 #[repr(u8)]
 pub enum Event {
     Clicked,
     Hovered,
+    /// A submenu was opened, so its entries are about to be seen
     Opened,
+    /// A submenu was closed
     Closed,
 }
 
+impl Event {
+    /// The event with the dbusmenu event ID `id`, if it is a standard one
+    pub fn from_dbus(id: &str) -> Option<Self> {
+        match id {
+            "clicked" => Some(Self::Clicked),
+            "hovered" => Some(Self::Hovered),
+            "opened" => Some(Self::Opened),
+            "closed" => Some(Self::Closed),
+            _ => None,
+        }
+    }
+
+    /// The dbusmenu event ID of the event
+    pub fn as_dbus(self) -> &'static str {
+        match self {
+            Self::Clicked => "clicked",
+            Self::Hovered => "hovered",
+            Self::Opened => "opened",
+            Self::Closed => "closed",
+        }
+    }
+}
+
+/// The calls to an item that the daemon asks for, see
+/// [`ClientEvent::CallFailed`]
+#[non_exhaustive]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Copy, Clone, Eq, PartialEq)]
+pub enum Call {
+    Activate,
+    SecondaryActivate,
+    ContextMenu,
+    Scroll,
+}
+
+/// What an item has and supports, as known when it is created, so that the
+/// daemon can export it accordingly
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// The item has a menu.  Menus of items without it are not shown.
+    pub const HAS_MENU: Self = Self(1 << 0);
+    /// The item has a tooltip
+    pub const HAS_TOOLTIP: Self = Self(1 << 1);
+    /// The item does something when activated.  Without it, the daemon
+    /// fails `Activate` calls, so that the panel shows the menu instead.
+    pub const SUPPORTS_ACTIVATION: Self = Self(1 << 2);
+    /// The item does something on `SecondaryActivate`
+    pub const SUPPORTS_SECONDARY_ACTIVATE: Self = Self(1 << 3);
+    /// The `ItemIsMenu` property of the item
+    pub const IS_MENU: Self = Self(1 << 4);
+
+    /// Whether all capabilities of `other` are in `self`
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// What an item created by an agent that does not send
+    /// [`envelope::CAPABILITIES`] is taken to have: everything, as before
+    /// capabilities were sent
+    pub fn legacy(is_menu: bool) -> Self {
+        let all = Self::HAS_MENU
+            | Self::HAS_TOOLTIP
+            | Self::SUPPORTS_ACTIVATION
+            | Self::SUPPORTS_SECONDARY_ACTIVATE;
+        if is_menu {
+            all | Self::IS_MENU
+        } else {
+            all
+        }
+    }
+
+    /// `self` without the capabilities of `other`
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0
+    }
+}
+
+#[non_exhaustive]
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub enum ClientEvent {
     Create {
         category: String,
         app_id: String,
+        /// The `ItemIsMenu` property of the item, for daemons that do not
+        /// know [`envelope::CAPABILITIES`]
         is_menu: bool,
+        /// Sent as a [`envelope::CAPABILITIES`] section
+        #[serde(skip)]
+        capabilities: Capabilities,
     },
 
     Title(Option<String>),
@@ -38,47 +355,588 @@ pub enum ClientEvent {
 
     RemoveIcon(IconType),
 
+    /// Part of a frame of icon `typ` that is too large to send in a single
+    /// message, starting at byte `offset` of its data.  A chunk at offset 0
+    /// starts a new frame.  The frames are added to those of the next
+    /// [`ClientEvent::Icon`] of the same type, which is sent once all chunks
+    /// have been.
+    IconChunk {
+        typ: IconType,
+        width: u32,
+        height: u32,
+        offset: u32,
+        data: Vec<u8>,
+    },
+
     Destroy,
 
     Tooltip {
         icon_data: Vec<IconData>,
-        title: String,
-        description: String,
+        title: SafeText,
+        description: SafeText,
     },
 
     RemoveTooltip,
+
+    /// The complete menu of the item, starting at its root
+    Menu(MenuItem),
+
+    RemoveMenu,
+
+    /// The answer to the [`ServerEvent::Request`] with the same `request`
+    Reply {
+        request: RequestId,
+        body: Reply,
+    },
+
+    /// The description of icon `typ`, [`IconType::Normal`] or
+    /// [`IconType::Attention`], for screen readers, as Ayatana items have
+    /// them.  Without one, the title is used.
+    AccessibleDesc {
+        typ: IconType,
+        desc: Option<SafeText>,
+    },
+
+    /// Several properties that changed at once, such as when an item is
+    /// created.  Hosts are only told once all of them are applied.
+    Update(Update),
+
+    /// Icon `typ` has the frames of the icon with hash `hash`, see
+    /// [`icon_hash`], which the daemon keeps on disk: one it listed in
+    /// [`ServerEvent::CachedIcons`], or one sent to it since.  If it does
+    /// not have the icon after all, it answers with
+    /// [`ServerEvent::CacheMiss`].
+    CachedIcon {
+        typ: IconType,
+        hash: IconHash,
+    },
+
+    /// Whether the item answers calls.  The VM sends `false` once a call to
+    /// the item timed out, and `true` once it answers again.  Items are
+    /// assumed to answer until then.
+    Responsive(bool),
+
+    /// A call the daemon asked for, such as with [`ServerEvent::Activate`],
+    /// failed: the item answered with an error, or did not answer in time
+    /// if `timed_out`
+    CallFailed {
+        call: Call,
+        timed_out: bool,
+    },
+
+    /// An event added in a later version, which is ignored.  Never sent.
+    #[serde(other)]
+    Unknown,
 }
 
+#[non_exhaustive]
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub enum ServerEvent {
-    Activate { x: i32, y: i32 },
-    ContextMenu { x: i32, y: i32 },
-    SecondaryActivate { x: i32, y: i32 },
-    Scroll { delta: i32, orientation: String },
+    Activate {
+        x: i32,
+        y: i32,
+    },
+    ContextMenu {
+        x: i32,
+        y: i32,
+    },
+    SecondaryActivate {
+        x: i32,
+        y: i32,
+    },
+    Scroll {
+        delta: i32,
+        orientation: String,
+    },
+    MenuClicked {
+        id: i32,
+        timestamp: u32,
+    },
+    /// Submenu `id` was opened or closed.  Clicks are sent as
+    /// [`ServerEvent::MenuClicked`], and hovering is not forwarded.
+    MenuState {
+        id: i32,
+        event: Event,
+        timestamp: u32,
+    },
+    /// A request that the VM answers with a [`ClientEvent::Reply`]
+    /// carrying the same `request`
+    Request {
+        request: RequestId,
+        body: Request,
+    },
+    /// The item was torn down after a [`ClientEvent::Destroy`].  No events
+    /// for it follow.
+    DestroyAck,
+    /// The daemon restored the item from a previous run, without its icons.
+    /// If the item still exists, all of its properties are to be sent
+    /// again; otherwise, the daemon removes it after a while.
+    Resync,
+    /// The daemon discarded an event of the item for breaking the protocol,
+    /// e.g. icon frames that fail [`IconData::check`]
+    Rejected {
+        reason: String,
+    },
+    /// Whether any StatusNotifierHost is on the bus in dom0.  Sent after
+    /// the handshake and whenever it changes, though the last host going
+    /// away is only sent once none came back for a while, so that a
+    /// restarting panel changes nothing.  It is not about any item, so
+    /// the ID is 0.  While no host is present, the VM may leave out icons,
+    /// which nobody would see, and send them once one is.
+    Hosts {
+        present: bool,
+    },
+    /// The icons the daemon keeps on disk, most recently used first.  Sent
+    /// after the handshake if the daemon keeps icons, with the ID 0.  The VM
+    /// may then send [`ClientEvent::CachedIcon`] instead of these icons and
+    /// of those it sends in full later.
+    CachedIcons {
+        hashes: Vec<IconHash>,
+    },
+    /// The daemon does not have the icon of a [`ClientEvent::CachedIcon`],
+    /// which the VM is to send in full
+    CacheMiss {
+        typ: IconType,
+        hash: IconHash,
+    },
+    /// An event added in a later version, which is ignored.  Never sent.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Requests from the daemon that need an answer from the VM
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub enum Request {
+    /// Submenu `id` is about to be shown
+    AboutToShow { id: i32 },
+}
+
+/// Answers to [`Request`]s, with a variant of the same name for each
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub enum Reply {
+    AboutToShow { need_update: bool },
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct IconClientEvent {
     pub id: u64,
+    /// When the event was sent, see [`timestamp`], or 0 if unknown.  Only
+    /// used to measure latency, so it is not trusted in any way.  Sent as a
+    /// [`envelope::TIMESTAMP`] section, if not 0.
+    #[serde(skip)]
+    pub timestamp: u64,
+    /// Last, so that an unknown event can be skipped, see [`decode`]
     pub event: ClientEvent,
 }
 
+impl Message for IconClientEvent {
+    fn is_unknown(&self) -> bool {
+        matches!(self.event, ClientEvent::Unknown)
+    }
+
+    fn sections(&self) -> envelope::Sections {
+        let mut sections = envelope::Sections::default();
+        if self.timestamp != 0 {
+            sections.insert(envelope::TIMESTAMP, self.timestamp.to_le_bytes().to_vec());
+        }
+        if let ClientEvent::Create { capabilities, .. } = &self.event {
+            sections.insert(
+                envelope::CAPABILITIES,
+                capabilities.0.to_le_bytes().to_vec(),
+            );
+        }
+        sections
+    }
+
+    fn set_sections(&mut self, sections: &envelope::Sections) {
+        self.timestamp = sections
+            .get(envelope::TIMESTAMP)
+            .and_then(|data| data.try_into().ok())
+            .map_or(0, u64::from_le_bytes);
+        if let ClientEvent::Create {
+            is_menu,
+            capabilities,
+            ..
+        } = &mut self.event
+        {
+            *capabilities = sections
+                .get(envelope::CAPABILITIES)
+                .and_then(|data| data.try_into().ok())
+                .map_or(Capabilities::legacy(*is_menu), |data| {
+                    Capabilities(u32::from_le_bytes(data))
+                });
+        }
+    }
+}
+
+impl IconClientEvent {
+    /// `event` for item `id`, sent now
+    pub fn new(id: u64, event: ClientEvent) -> Self {
+        Self {
+            id,
+            timestamp: timestamp(),
+            event,
+        }
+    }
+}
+
+/// The current time in microseconds since the Unix epoch, or 0 in
+/// [`deterministic`] mode
+pub fn timestamp() -> u64 {
+    if deterministic() {
+        return 0;
+    }
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct IconServerEvent {
     pub id: u64,
+    /// Last, so that an unknown event can be skipped, see [`decode`]
     pub event: ServerEvent,
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+impl Message for IconServerEvent {
+    fn is_unknown(&self) -> bool {
+        matches!(self.event, ServerEvent::Unknown)
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct IconData {
     pub width: u32,
     pub height: u32,
     pub data: Vec<u8>,
 }
 
+impl IconData {
+    /// Check that the frame has pixels, no more than `limits` allow, and as
+    /// much data as its size requires
+    pub fn check(&self, limits: &ProtocolLimits) -> Result<(), error::ProtocolError> {
+        let (width, height) = (self.width, self.height);
+        let length = self.data.len();
+        if width == 0
+            || height == 0
+            || (u64::from(width) * u64::from(height)).checked_mul(4) != Some(length as u64)
+        {
+            return Err(error::ProtocolError::InvalidFrame {
+                width,
+                height,
+                length,
+            });
+        }
+        if width > limits.max_icon_size || height > limits.max_icon_size {
+            return Err(error::ProtocolError::FrameTooLarge {
+                width,
+                height,
+                max: limits.max_icon_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Check all of `frames`, and that there are no more than `limits` allow, see
+/// [`IconData::check`]
+pub fn check_frames(
+    frames: &[IconData],
+    limits: &ProtocolLimits,
+) -> Result<(), error::ProtocolError> {
+    if frames.len() > limits.max_frames as usize {
+        return Err(error::ProtocolError::TooManyFrames {
+            count: frames.len(),
+            max: limits.max_frames,
+        });
+    }
+    frames.iter().try_for_each(|frame| frame.check(limits))
+}
+
+/// The SHA-256 hash an icon is known by, see [`ClientEvent::CachedIcon`]
+pub type IconHash = [u8; 32];
+
+/// The hash of the icon with `frames`.  Their order does not matter, as the
+/// daemon puts frames sent in chunks after the others.
+pub fn icon_hash(frames: &[IconData]) -> IconHash {
+    use sha2::Digest as _;
+    let mut frames: Vec<&IconData> = frames.iter().collect();
+    frames.sort_unstable_by(|a, b| (a.width, a.height, &a.data).cmp(&(b.width, b.height, &b.data)));
+    let mut hash = sha2::Sha256::new();
+    for frame in frames {
+        hash.update(frame.width.to_le_bytes());
+        hash.update(frame.height.to_le_bytes());
+        hash.update((frame.data.len() as u64).to_le_bytes());
+        hash.update(&frame.data);
+    }
+    hash.finalize().into()
+}
+
+/// The events setting the frames of icon `typ` to `frames`, leaving out those
+/// that fail [`IconData::check`] or are beyond `limits`.  Frames that do not
+/// fit into a single message together with the others are sent in chunks
+/// first.
+pub fn icon_events(
+    typ: IconType,
+    frames: impl IntoIterator<Item = IconData>,
+    limits: &ProtocolLimits,
+) -> Vec<ClientEvent> {
+    // Room for everything in a message except frame data, including the
+    // envelope
+    const OVERHEAD: usize = 128;
+    let budget = limits.max_message_size as usize - OVERHEAD;
+    let mut size = 0;
+    let mut events = vec![];
+    let mut data = vec![];
+    let frames = frames
+        .into_iter()
+        .filter(|frame| frame.check(limits).is_ok())
+        .take(limits.max_frames as usize);
+    for frame in frames {
+        // Width, height, and the length of the data
+        let frame_size = 16 + frame.data.len();
+        if size + frame_size <= budget {
+            size += frame_size;
+            data.push(frame);
+            continue;
+        }
+        for (i, chunk) in frame.data.chunks(budget).enumerate() {
+            events.push(ClientEvent::IconChunk {
+                typ,
+                width: frame.width,
+                height: frame.height,
+                offset: (i * budget) as u32,
+                data: chunk.to_owned(),
+            })
+        }
+    }
+    events.push(ClientEvent::Icon { typ, data });
+    events
+}
+
+/// The properties in a [`ClientEvent::Update`].  [`None`] means unchanged.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Update {
+    pub title: Option<Option<String>>,
+    pub status: Option<Option<String>>,
+    /// `Some(None)` if the tooltip was removed
+    pub tooltip: Option<Option<Tooltip>>,
+    /// The frames of the icons that changed.  Chunks of an icon, see
+    /// [`ClientEvent::IconChunk`], are sent before the update.
+    pub icons: Vec<(IconType, Vec<IconData>)>,
+}
+
+impl Update {
+    /// Whether nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.status.is_none()
+            && self.tooltip.is_none()
+            && self.icons.is_empty()
+    }
+
+    /// The events setting each of the properties that changed
+    pub fn into_events(self) -> Vec<ClientEvent> {
+        let mut events = vec![];
+        events.extend(self.status.map(ClientEvent::Status));
+        events.extend(self.title.map(ClientEvent::Title));
+        events.extend(self.tooltip.map(|tooltip| match tooltip {
+            None => ClientEvent::RemoveTooltip,
+            Some(Tooltip {
+                title,
+                description,
+                icon_data,
+            }) => ClientEvent::Tooltip {
+                icon_data,
+                title,
+                description,
+            },
+        }));
+        events.extend(
+            self.icons
+                .into_iter()
+                .map(|(typ, data)| ClientEvent::Icon { typ, data }),
+        );
+        events
+    }
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct Tooltip {
-    pub title: String,
-    pub description: String,
+    pub title: SafeText,
+    pub description: SafeText,
     pub icon_data: Vec<IconData>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The values both sides must agree on
+    #[test]
+    fn constants() {
+        assert_eq!(MAX_MESSAGE_SIZE, 16 << 20);
+        assert_eq!(MIN_MESSAGE_SIZE, 64 << 10);
+        assert_eq!(PROTOCOL_VERSION, 8);
+        assert_eq!(Capabilities::HAS_MENU.0, 1);
+        assert_eq!(Capabilities::HAS_TOOLTIP.0, 2);
+        assert_eq!(Capabilities::SUPPORTS_ACTIVATION.0, 4);
+        assert_eq!(Capabilities::SUPPORTS_SECONDARY_ACTIVATE.0, 8);
+        assert_eq!(Capabilities::IS_MENU.0, 16);
+    }
+
+    fn create(is_menu: bool, capabilities: Capabilities) -> IconClientEvent {
+        IconClientEvent {
+            id: 1,
+            timestamp: 0,
+            event: ClientEvent::Create {
+                category: "ApplicationStatus".to_owned(),
+                app_id: "org.example.App".to_owned(),
+                is_menu,
+                capabilities,
+            },
+        }
+    }
+
+    fn capabilities(event: &IconClientEvent) -> Capabilities {
+        match event.event {
+            ClientEvent::Create { capabilities, .. } => capabilities,
+            _ => panic!("not a Create"),
+        }
+    }
+
+    #[test]
+    fn capabilities_are_sent_in_a_section() {
+        let sent = Capabilities::HAS_MENU | Capabilities::SUPPORTS_ACTIVATION;
+        let buffer = encode(&create(false, sent)).unwrap();
+        let received: IconClientEvent = decode(&buffer).unwrap();
+        assert_eq!(capabilities(&received), sent);
+    }
+
+    /// Agents from before capabilities were sent only send `is_menu`
+    #[test]
+    fn capabilities_default_to_everything() {
+        for is_menu in [false, true] {
+            let buffer = encode(&create(is_menu, Capabilities::default())).unwrap();
+            let event_len = u32::from_le_bytes(buffer[..4].try_into().unwrap()) as usize;
+            let received: IconClientEvent = decode(&buffer[..4 + event_len]).unwrap();
+            assert_eq!(capabilities(&received), Capabilities::legacy(is_menu));
+            assert_eq!(
+                capabilities(&received).contains(Capabilities::IS_MENU),
+                is_menu
+            );
+        }
+    }
+
+    fn encoded_hello(version: u32) -> Vec<u8> {
+        use bincode::Options as _;
+        let hello = Hello {
+            version,
+            limits: ProtocolLimits::default(),
+            integrity: Integrity::None,
+        };
+        encoding().serialize(&hello).unwrap()
+    }
+
+    #[test]
+    fn versions_are_negotiated() {
+        let hello = Hello::decode(&encoded_hello(PROTOCOL_VERSION)).unwrap();
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+        // A later version may add fields to its Hello
+        let mut later = encoded_hello(PROTOCOL_VERSION + 1);
+        later.extend_from_slice(&[1, 2, 3, 4]);
+        let hello = Hello::decode(&later).unwrap();
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn versions_too_old_are_rejected() {
+        let error = Hello::decode(&encoded_hello(MIN_PROTOCOL_VERSION - 1)).unwrap_err();
+        assert!(matches!(
+            error,
+            error::ProtocolError::Version { peer, expected }
+                if peer == MIN_PROTOCOL_VERSION - 1 && expected == MIN_PROTOCOL_VERSION
+        ));
+    }
+
+    #[test]
+    fn limits_are_negotiated() {
+        let ours = ProtocolLimits {
+            max_message_size: 1,
+            max_icon_size: 64,
+            max_frames: 8,
+            max_menus: 4,
+        };
+        let theirs = ProtocolLimits {
+            max_message_size: u32::MAX,
+            max_icon_size: 32,
+            max_frames: 16,
+            max_menus: 4,
+        };
+        let expected = ProtocolLimits {
+            max_message_size: MIN_MESSAGE_SIZE,
+            max_icon_size: 32,
+            max_frames: 8,
+            max_menus: 4,
+        };
+        assert_eq!(ours.min(theirs), expected);
+        assert_eq!(theirs.min(ours), expected);
+        assert_eq!(
+            ProtocolLimits::default()
+                .min(ProtocolLimits::default())
+                .max_message_size,
+            MAX_MESSAGE_SIZE
+        );
+    }
+
+    fn frame(width: u32, height: u32, length: usize) -> IconData {
+        IconData {
+            width,
+            height,
+            data: vec![0; length],
+        }
+    }
+
+    #[test]
+    fn frames_are_checked() {
+        let limits = ProtocolLimits {
+            max_icon_size: 4,
+            max_frames: 2,
+            ..Default::default()
+        };
+        assert!(frame(4, 4, 64).check(&limits).is_ok());
+        assert!(frame(1, 3, 12).check(&limits).is_ok());
+        for (width, height, length) in [(0, 1, 0), (1, 0, 0), (2, 2, 15), (2, 2, 17)] {
+            assert!(matches!(
+                frame(width, height, length).check(&limits),
+                Err(error::ProtocolError::InvalidFrame { .. })
+            ));
+        }
+        assert!(matches!(
+            frame(5, 1, 20).check(&limits),
+            Err(error::ProtocolError::FrameTooLarge { max: 4, .. })
+        ));
+        // The length must not overflow
+        assert!(matches!(
+            frame(u32::MAX, u32::MAX, 0).check(&ProtocolLimits::default()),
+            Err(error::ProtocolError::InvalidFrame { .. })
+        ));
+        assert!(check_frames(&[], &limits).is_ok());
+        assert!(check_frames(&[frame(1, 1, 4), frame(2, 2, 16)], &limits).is_ok());
+        assert!(matches!(
+            check_frames(&[frame(1, 1, 4), frame(1, 1, 4), frame(1, 1, 4)], &limits),
+            Err(error::ProtocolError::TooManyFrames { count: 3, max: 2 })
+        ));
+        assert!(check_frames(&[frame(1, 1, 4), frame(1, 1, 3)], &limits).is_err());
+    }
+
+    #[test]
+    fn icon_hash_ignores_frame_order() {
+        let (a, b) = (frame(1, 1, 4), frame(2, 2, 16));
+        assert_eq!(
+            icon_hash(&[a.clone(), b.clone()]),
+            icon_hash(&[b.clone(), a.clone()])
+        );
+        assert_ne!(icon_hash(std::slice::from_ref(&a)), icon_hash(&[a, b]));
+    }
+}
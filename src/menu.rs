@@ -0,0 +1,246 @@
+//! Forwarded menus and the limits on their structure
+//!
+//! The limits are enforced by the agent before sending a menu, and again by
+//! the daemon after receiving one, so that a hostile VM cannot make the dom0
+//! panel render a pathological menu.  Deserialization itself refuses menus
+//! nested more deeply than [`MAX_DEPTH`], as it would otherwise recurse
+//! without bound.
+
+use crate::{IconData, SafeText};
+use core::cell::Cell;
+use core::fmt;
+
+/// Maximum nesting depth of submenus below the root
+pub const MAX_DEPTH: usize = 8;
+/// Maximum number of entries in a single (sub)menu
+pub const MAX_ENTRIES_PER_LEVEL: usize = 64;
+/// Maximum number of entries in a menu, not counting the root
+pub const MAX_ENTRIES: usize = 512;
+/// Maximum length of a label, in bytes
+pub const MAX_LABEL_LENGTH: usize = 256;
+/// Maximum number of shortcuts of a single entry
+pub const MAX_SHORTCUTS: usize = 4;
+
+/// Modifier keys that may appear in a [`Shortcut`]
+const MODIFIERS: &[&str] = &["Control", "Alt", "Shift", "Super"];
+/// Named keys that may appear in a [`Shortcut`], in addition to ASCII
+/// letters and digits and F1 to F12
+const NAMED_KEYS: &[&str] = &[
+    "BackSpace",
+    "Delete",
+    "Down",
+    "End",
+    "Escape",
+    "Home",
+    "Insert",
+    "Left",
+    "Page_Down",
+    "Page_Up",
+    "Return",
+    "Right",
+    "Tab",
+    "Up",
+    "comma",
+    "equal",
+    "minus",
+    "period",
+    "plus",
+    "slash",
+    "space",
+];
+
+/// An entry of a forwarded dbusmenu, together with its children
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct MenuItem {
+    /// The dbusmenu ID of the entry.  The root always has ID 0.
+    pub id: i32,
+    pub separator: bool,
+    /// The label, including the access key marked with `_`
+    pub label: SafeText,
+    pub enabled: bool,
+    pub visible: bool,
+    /// Whether the entry has a checkmark or radio button
+    pub toggle_type: Option<ToggleType>,
+    pub toggle_state: ToggleState,
+    /// Keyboard shortcuts, as displayed next to the label
+    pub shortcuts: Vec<Shortcut>,
+    /// The icon of the entry.  Named icons are resolved inside the VM, so
+    /// this is always a pixmap in the same format as [`IconData`] elsewhere.
+    pub icon: Option<IconData>,
+    #[serde(deserialize_with = "deserialize_children")]
+    pub children: Vec<MenuItem>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum ToggleType {
+    Checkmark,
+    Radio,
+}
+
+impl ToggleType {
+    /// The value of the dbusmenu `toggle-type` property
+    pub fn to_dbusmenu(self) -> &'static str {
+        match self {
+            Self::Checkmark => "checkmark",
+            Self::Radio => "radio",
+        }
+    }
+
+    pub fn from_dbusmenu(value: &str) -> Option<Self> {
+        match value {
+            "checkmark" => Some(Self::Checkmark),
+            "radio" => Some(Self::Radio),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum ToggleState {
+    #[default]
+    Off,
+    On,
+    Indeterminate,
+}
+
+impl ToggleState {
+    /// The value of the dbusmenu `toggle-state` property
+    pub fn to_dbusmenu(self) -> i32 {
+        match self {
+            Self::Off => 0,
+            Self::On => 1,
+            Self::Indeterminate => -1,
+        }
+    }
+
+    pub fn from_dbusmenu(value: i64) -> Self {
+        match value {
+            0 => Self::Off,
+            1 => Self::On,
+            _ => Self::Indeterminate,
+        }
+    }
+}
+
+/// A keyboard shortcut in the form of the dbusmenu `shortcut` property:
+/// zero or more modifiers followed by a single key.  Only whitelisted key
+/// names are representable, and deserialization rejects anything else.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize)]
+#[serde(transparent)]
+pub struct Shortcut(Vec<String>);
+
+impl Shortcut {
+    pub fn new<T: AsRef<str>>(tokens: &[T]) -> Option<Self> {
+        let (key, modifiers) = tokens.split_last()?;
+        if modifiers.len() > MODIFIERS.len()
+            || !modifiers.iter().all(|m| MODIFIERS.contains(&m.as_ref()))
+            || !is_key(key.as_ref())
+        {
+            return None;
+        }
+        Some(Self(tokens.iter().map(|t| t.as_ref().to_owned()).collect()))
+    }
+
+    pub fn tokens(&self) -> &[String] {
+        &self.0
+    }
+}
+
+fn is_key(key: &str) -> bool {
+    match key.as_bytes() {
+        [c] => c.is_ascii_alphanumeric(),
+        [b'F', b'1'..=b'9', ..] => matches!(key[1..].parse::<u8>(), Ok(1..=12)),
+        _ => NAMED_KEYS.contains(&key),
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Shortcut {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tokens = <Vec<String>>::deserialize(deserializer)?;
+        Self::new(&tokens).ok_or_else(|| serde::de::Error::custom("invalid keyboard shortcut"))
+    }
+}
+
+impl MenuItem {
+    /// Cut the menu down to the limits in this module: entries beyond
+    /// [`MAX_DEPTH`], [`MAX_ENTRIES_PER_LEVEL`], or [`MAX_ENTRIES`] are
+    /// removed, labels are truncated to [`MAX_LABEL_LENGTH`], and shortcuts
+    /// beyond [`MAX_SHORTCUTS`] are removed.  Returns `true` if anything was
+    /// changed.
+    pub fn limit(&mut self) -> bool {
+        fn walk(item: &mut MenuItem, depth: usize, budget: &mut usize) -> bool {
+            let mut changed = item.label.truncate(MAX_LABEL_LENGTH);
+            if item.shortcuts.len() > MAX_SHORTCUTS {
+                item.shortcuts.truncate(MAX_SHORTCUTS);
+                changed = true;
+            }
+            let allowed = if depth >= MAX_DEPTH {
+                0
+            } else {
+                MAX_ENTRIES_PER_LEVEL.min(*budget)
+            };
+            if item.children.len() > allowed {
+                item.children.truncate(allowed);
+                changed = true;
+            }
+            *budget -= item.children.len();
+            for child in &mut item.children {
+                changed |= walk(child, depth + 1, budget);
+            }
+            changed
+        }
+        let mut budget = MAX_ENTRIES;
+        walk(self, 0, &mut budget)
+    }
+}
+
+thread_local! {
+    /// Nesting depth of the menu being deserialized on this thread
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+fn deserialize_children<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<MenuItem>, D::Error> {
+    struct Children {
+        depth: usize,
+    }
+
+    impl<'de> serde::de::Visitor<'de> for Children {
+        type Value = Vec<MenuItem>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "at most {} menu entries", MAX_ENTRIES_PER_LEVEL)
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> Result<Self::Value, A::Error> {
+            // Refuse before recursing into any child
+            if self.depth >= MAX_DEPTH && seq.size_hint() != Some(0) {
+                return Err(serde::de::Error::custom("menu nested too deeply"));
+            }
+            if seq.size_hint().unwrap_or(0) > MAX_ENTRIES_PER_LEVEL {
+                return Err(serde::de::Error::invalid_length(
+                    seq.size_hint().unwrap_or(0),
+                    &self,
+                ));
+            }
+            let mut children = vec![];
+            while let Some(child) = seq.next_element()? {
+                if children.len() == MAX_ENTRIES_PER_LEVEL {
+                    return Err(serde::de::Error::invalid_length(children.len() + 1, &self));
+                }
+                children.push(child);
+            }
+            Ok(children)
+        }
+    }
+
+    let depth = DEPTH.with(Cell::get);
+    DEPTH.with(|d| d.set(depth + 1));
+    let children = deserializer.deserialize_seq(Children { depth });
+    DEPTH.with(|d| d.set(depth));
+    children
+}
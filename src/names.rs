@@ -1,7 +1,9 @@
 //! Functions to obtain various D-Bus names
 
+use crate::error::ValidationError;
+use dbus::message::MatchRule;
 use dbus::strings::BusName;
-use dbus::strings::{Interface, Member, Path};
+use dbus::strings::{ErrorName, Interface, Member, Path};
 pub fn interface_com_canonical_dbusmenu() -> Interface<'static> {
     // SAFETY: this is a valid NUL-terminated interface name
     unsafe { Interface::from_slice_unchecked("com.canonical.dbusmenu\0") }
@@ -12,16 +14,46 @@ pub fn name_owner_changed() -> Member<'static> {
     unsafe { Member::from_slice_unchecked("NameOwnerChanged\0") }
 }
 
+pub fn list_names() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("ListNames\0") }
+}
+
+pub fn get_name_owner() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("GetNameOwner\0") }
+}
+
+pub fn name_has_owner() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("NameHasOwner\0") }
+}
+
 pub fn get_layout() -> Member<'static> {
     // SAFETY: this is a valid NUL-terminated member name
     unsafe { Member::from_slice_unchecked("GetLayout\0") }
 }
 
+pub fn about_to_show() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("AboutToShow\0") }
+}
+
 pub fn interface_dbus() -> Interface<'static> {
     // SAFETY: this is a valid NUL-terminated interface name
     unsafe { Interface::from_slice_unchecked("org.freedesktop.DBus\0") }
 }
 
+pub fn interface_properties() -> Interface<'static> {
+    // SAFETY: this is a valid NUL-terminated interface name
+    unsafe { Interface::from_slice_unchecked("org.freedesktop.DBus.Properties\0") }
+}
+
+pub fn get_all() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("GetAll\0") }
+}
+
 pub fn path_dbus() -> Path<'static> {
     // SAFETY: this is a valid NUL-terminated path name
     unsafe { Path::from_slice_unchecked("/org/freedesktop/DBus\0") }
@@ -42,28 +74,270 @@ pub fn interface_status_notifier_watcher() -> Interface<'static> {
     unsafe { Interface::from_slice_unchecked("org.kde.StatusNotifierWatcher\0") }
 }
 
-pub fn layout_updated<'a, 'b: 'a, 'c: 'a>(
-    b: BusName<'b>,
-    p: Path<'c>,
-) -> dbus::message::MatchRule<'a> {
+pub fn layout_updated<'a, 'b: 'a, 'c: 'a>(b: BusName<'b>, p: Path<'c>) -> MatchRule<'a> {
     // SAFETY: this is a valid NUL-terminated member name
     let member = unsafe { Member::from_slice_unchecked("LayoutUpdated\0") };
-    dbus::message::MatchRule::new_signal(interface_com_canonical_dbusmenu(), member)
+    MatchRule::new_signal(interface_com_canonical_dbusmenu(), member)
         .with_strict_sender(b)
         .with_path(p)
 }
 
+/// All signals of the menu at `p`, sent by `b`.  `b` may be a well-known
+/// name, as the sender is not matched strictly.
+pub fn dbusmenu_signals<'a, 'b: 'a, 'c: 'a>(b: BusName<'b>, p: Path<'c>) -> MatchRule<'a> {
+    MatchRule::new()
+        .with_type(dbus::message::MessageType::Signal)
+        .with_sender(b)
+        .with_path(p)
+        .with_interface(interface_com_canonical_dbusmenu())
+}
+
+/// `NameOwnerChanged` signals from the bus
+pub fn name_owner_changed_rule() -> MatchRule<'static> {
+    MatchRule::new_signal(interface_dbus(), name_owner_changed())
+        .with_strict_sender(name_dbus())
+        .with_path(path_dbus())
+}
+
+pub fn status_notifier_host_registered() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("StatusNotifierHostRegistered\0") }
+}
+
+/// The signal of the watcher announcing that a host registered
+pub fn status_notifier_host_registered_rule() -> MatchRule<'static> {
+    MatchRule::new_signal(
+        interface_status_notifier_watcher(),
+        status_notifier_host_registered(),
+    )
+    .with_sender(name_status_notifier_watcher())
+    .with_path(path_status_notifier_watcher())
+}
+
 pub fn path_status_notifier_watcher() -> Path<'static> {
     // SAFETY: this is a valid NUL-terminated path name
     unsafe { Path::from_slice_unchecked("/StatusNotifierWatcher\0") }
 }
 
+pub fn registered_status_notifier_items() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("RegisteredStatusNotifierItems\0") }
+}
+
+pub fn registered_status_notifier_hosts() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("RegisteredStatusNotifierHosts\0") }
+}
+
+pub fn is_status_notifier_host_registered() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("IsStatusNotifierHostRegistered\0") }
+}
+
 pub fn register_status_notifier_item() -> Member<'static> {
     // SAFETY: this is a valid NUL-terminated member name
     unsafe { Member::from_slice_unchecked("RegisterStatusNotifierItem\0") }
 }
 
+pub fn name_notifications() -> BusName<'static> {
+    // SAFETY: this is a valid NUL-terminated bus name
+    unsafe { BusName::from_slice_unchecked("org.freedesktop.Notifications\0") }
+}
+
+pub fn path_notifications() -> Path<'static> {
+    // SAFETY: this is a valid NUL-terminated path name
+    unsafe { Path::from_slice_unchecked("/org/freedesktop/Notifications\0") }
+}
+
+pub fn interface_notifications() -> Interface<'static> {
+    // SAFETY: this is a valid NUL-terminated interface name
+    unsafe { Interface::from_slice_unchecked("org.freedesktop.Notifications\0") }
+}
+
+pub fn notify() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("Notify\0") }
+}
+
+pub fn close_notification() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("CloseNotification\0") }
+}
+
+pub fn action_invoked() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("ActionInvoked\0") }
+}
+
+pub fn notification_closed() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("NotificationClosed\0") }
+}
+
+/// The signals of the notification server
+pub fn notifications_rule() -> MatchRule<'static> {
+    MatchRule::new()
+        .with_type(dbus::MessageType::Signal)
+        .with_sender(name_notifications())
+        .with_path(path_notifications())
+        .with_interface(interface_notifications())
+}
+
+pub fn interface_status_notifier_item() -> Interface<'static> {
+    // SAFETY: this is a valid NUL-terminated interface name
+    unsafe { Interface::from_slice_unchecked("org.kde.StatusNotifierItem\0") }
+}
+
 pub fn path_status_notifier_item() -> Path<'static> {
     // SAFETY: this is a valid NUL-terminated path name
     unsafe { Path::from_slice_unchecked("/StatusNotifierItem\0") }
 }
+
+/// How the names of items call `vm`, or a daemon not started by qrexec if
+/// `vm` is [`None`].  VM names may contain characters that object paths and
+/// bus names may not, so the VM is named by a hash.
+fn vm_hash(vm: Option<&str>) -> String {
+    use sha2::Digest as _;
+    match vm {
+        None => "local".to_owned(),
+        Some(vm) => sha2::Sha256::digest(vm.as_bytes())[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    }
+}
+
+/// The object path of item `id` of the daemon serving `vm`, or of a daemon
+/// not started by qrexec if `vm` is [`None`].  It stays the same when the
+/// daemon restarts, so that hosts can keep the place of the item.
+pub fn path_sni_icon_item(vm: Option<&str>, id: u64) -> Path<'static> {
+    Path::new(format!("/StatusNotifierItem/{}/{}", vm_hash(vm), id)).expect("valid object path")
+}
+
+pub fn path_menu() -> Path<'static> {
+    // SAFETY: this is a valid NUL-terminated path name
+    unsafe { Path::from_slice_unchecked("/Menu\0") }
+}
+
+/// The value of the `Menu` property of items without a menu, used by some
+/// toolkits instead of `/`
+pub fn path_no_dbusmenu() -> Path<'static> {
+    // SAFETY: this is a valid NUL-terminated path name
+    unsafe { Path::from_slice_unchecked("/NO_DBUSMENU\0") }
+}
+
+pub fn path_root() -> Path<'static> {
+    // SAFETY: this is a valid NUL-terminated path name
+    unsafe { Path::from_slice_unchecked("/\0") }
+}
+
+pub fn interface_sni_icon_control() -> Interface<'static> {
+    // SAFETY: this is a valid NUL-terminated interface name
+    unsafe { Interface::from_slice_unchecked("org.qubes_os.SniIcon.Control\0") }
+}
+
+pub fn path_sni_icon_control() -> Path<'static> {
+    // SAFETY: this is a valid NUL-terminated path name
+    unsafe { Path::from_slice_unchecked("/org/qubes_os/SniIcon/Control\0") }
+}
+
+/// The well-known name requested by the daemon serving `vm`, or by a
+/// daemon not started by qrexec if `vm` is [`None`].
+pub fn name_sni_icon_daemon(vm: Option<&str>) -> Result<BusName<'static>, ValidationError> {
+    match vm {
+        // SAFETY: this is a valid NUL-terminated bus name
+        None => Ok(unsafe { BusName::from_slice_unchecked("org.qubes_os.SniIcon.Daemon\0") }),
+        Some(vm) => {
+            let name = format!("org.qubes_os.SniIcon.Daemon.{}", vm);
+            BusName::new(name.clone()).map_err(|reason| ValidationError::BusName { name, reason })
+        }
+    }
+}
+
+pub fn reload() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("Reload\0") }
+}
+
+pub fn items() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("Items\0") }
+}
+
+pub fn set_log_level() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("SetLogLevel\0") }
+}
+
+pub fn connection() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("Connection\0") }
+}
+
+/// The well-known name of item `id` of the daemon serving `vm` in
+/// deterministic mode, see [`crate::deterministic`].  The daemons of all
+/// VMs share the session bus, so the name includes the VM, as the path does.
+pub fn name_sni_icon_item(vm: Option<&str>, id: u64) -> BusName<'static> {
+    BusName::new(format!(
+        "org.qubes_os.SniIcon.Item.Vm_{}.Id{}",
+        vm_hash(vm),
+        id
+    ))
+    .expect("valid bus name")
+}
+
+pub fn release_name() -> Member<'static> {
+    // SAFETY: this is a valid NUL-terminated member name
+    unsafe { Member::from_slice_unchecked("ReleaseName\0") }
+}
+
+pub fn error_service_unknown() -> ErrorName<'static> {
+    // SAFETY: this is a valid NUL-terminated error name
+    unsafe { ErrorName::from_slice_unchecked("org.freedesktop.DBus.Error.ServiceUnknown\0") }
+}
+
+pub fn error_unknown_method() -> ErrorName<'static> {
+    // SAFETY: this is a valid NUL-terminated error name
+    unsafe { ErrorName::from_slice_unchecked("org.freedesktop.DBus.Error.UnknownMethod\0") }
+}
+
+pub fn error_access_denied() -> ErrorName<'static> {
+    // SAFETY: this is a valid NUL-terminated error name
+    unsafe { ErrorName::from_slice_unchecked("org.freedesktop.DBus.Error.AccessDenied\0") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The names of items must not change between releases, as hosts key
+    /// the places of items on them
+    #[test]
+    fn item_names_are_stable() {
+        let mut names = String::new();
+        for vm in [None, Some("work"), Some("personal"), Some("sys-whonix")] {
+            for id in [1, 42] {
+                names += &format!(
+                    "{:?} {} {} {}\n",
+                    vm,
+                    id,
+                    name_sni_icon_item(vm, id),
+                    path_sni_icon_item(vm, id)
+                );
+            }
+        }
+        assert_eq!(names, include_str!("fixtures/item-names.txt"));
+    }
+
+    #[test]
+    fn item_names_differ_between_vms() {
+        assert_ne!(
+            name_sni_icon_item(Some("work"), 1),
+            name_sni_icon_item(Some("personal"), 1)
+        );
+        assert_ne!(
+            name_sni_icon_item(None, 1),
+            name_sni_icon_item(Some("local"), 1)
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Publishing icons from a Rust program in the VM, without D-Bus
+//!
+//! The agent listens on a Unix socket, at [`socket_path`].  A publisher
+//! speaks the same protocol over it as the agent does with the daemon: a
+//! [`Hello`] from each side, then [`IconClientEvent`]s from the publisher and
+//! [`IconServerEvent`]s from the agent.  Item IDs are chosen by the publisher
+//! and only mean something on its connection; the agent forwards each item
+//! under an ID of its own.  Closing the connection destroys all its items.
+
+use crate::error::ProtocolError;
+use crate::{
+    encoding, icon_events, read_message, write_message, Capabilities, ClientEvent, Error, Hello,
+    IconClientEvent, IconData, IconServerEvent, IconType, Integrity, ProtocolLimits,
+    MIN_MESSAGE_SIZE, PROTOCOL_VERSION,
+};
+use bincode::Options as _;
+use std::path::PathBuf;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+/// The environment variable overriding the path of the socket
+pub const SOCKET_VARIABLE: &str = "SNI_ICON_PUBLISHER_SOCKET";
+
+/// The path of the socket the agent listens on: that given by
+/// `SNI_ICON_PUBLISHER_SOCKET`, or else `sni-icon-publisher` in
+/// `XDG_RUNTIME_DIR`.  [`None`] if neither is set.
+pub fn socket_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os(SOCKET_VARIABLE) {
+        return Some(path.into());
+    }
+    std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("sni-icon-publisher"))
+}
+
+/// Exchange [`Hello`]s with the other side, returning the limits both keep to
+pub async fn handshake(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+    limits: ProtocolLimits,
+) -> Result<ProtocolLimits, Error> {
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        limits,
+        integrity: Integrity::None,
+    };
+    let message = encoding()
+        .serialize(&hello)
+        .map_err(ProtocolError::Encode)?;
+    write_message(writer, &message).await?;
+    let buffer = read_message(reader, MIN_MESSAGE_SIZE).await?;
+    let hello = Hello::decode(&buffer)?;
+    Ok(limits.min(hello.limits))
+}
+
+/// Sends the events of the items of a publisher
+#[derive(Debug)]
+pub struct Publisher {
+    writer: OwnedWriteHalf,
+    limits: ProtocolLimits,
+    next_id: u64,
+}
+
+/// Receives the events for the items of a [`Publisher`]
+#[derive(Debug)]
+pub struct Events {
+    reader: OwnedReadHalf,
+    limits: ProtocolLimits,
+}
+
+impl Publisher {
+    /// Connect to the agent listening at `path`
+    pub async fn connect(path: impl AsRef<std::path::Path>) -> Result<(Self, Events), Error> {
+        Self::new(UnixStream::connect(path).await?).await
+    }
+
+    /// Publish over `stream`, which is connected to the agent
+    pub async fn new(stream: UnixStream) -> Result<(Self, Events), Error> {
+        let (mut reader, mut writer) = stream.into_split();
+        let limits = handshake(&mut reader, &mut writer, ProtocolLimits::default()).await?;
+        let publisher = Self {
+            writer,
+            limits,
+            next_id: 0,
+        };
+        Ok((publisher, Events { reader, limits }))
+    }
+
+    /// The limits negotiated with the agent
+    pub fn limits(&self) -> ProtocolLimits {
+        self.limits
+    }
+
+    /// Send `event`.  Frames of icons should be sent with
+    /// [`Publisher::set_icon`], which keeps to the limits.
+    pub async fn send(&mut self, event: IconClientEvent) -> Result<(), Error> {
+        let message = crate::encode(&event)?;
+        if message.len() > self.limits.max_message_size as usize {
+            return Err(ProtocolError::MessageTooLarge {
+                size: message.len(),
+                max: self.limits.max_message_size,
+            }
+            .into());
+        }
+        write_message(&mut self.writer, &message).await
+    }
+
+    /// Create an item with `capabilities`, returning its ID
+    pub async fn create(
+        &mut self,
+        app_id: &str,
+        category: &str,
+        capabilities: Capabilities,
+    ) -> Result<u64, Error> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(IconClientEvent::new(
+            id,
+            ClientEvent::Create {
+                category: category.to_owned(),
+                app_id: app_id.to_owned(),
+                is_menu: capabilities.contains(Capabilities::IS_MENU),
+                capabilities,
+            },
+        ))
+        .await?;
+        Ok(id)
+    }
+
+    pub async fn set_title(&mut self, id: u64, title: Option<String>) -> Result<(), Error> {
+        let event = ClientEvent::Title(title);
+        self.send(IconClientEvent::new(id, event)).await
+    }
+
+    pub async fn set_status(&mut self, id: u64, status: Option<String>) -> Result<(), Error> {
+        let event = ClientEvent::Status(status);
+        self.send(IconClientEvent::new(id, event)).await
+    }
+
+    /// Set the frames of icon `typ` of item `id`, see [`icon_events`]
+    pub async fn set_icon(
+        &mut self,
+        id: u64,
+        typ: IconType,
+        frames: Vec<IconData>,
+    ) -> Result<(), Error> {
+        for event in icon_events(typ, frames, &self.limits) {
+            self.send(IconClientEvent::new(id, event)).await?
+        }
+        Ok(())
+    }
+
+    pub async fn destroy(&mut self, id: u64) -> Result<(), Error> {
+        let event = ClientEvent::Destroy;
+        self.send(IconClientEvent::new(id, event)).await
+    }
+}
+
+impl Events {
+    /// The next event for one of the items.  [`Error::is_closed`] means
+    /// that the agent has gone away.
+    pub async fn next(&mut self) -> Result<IconServerEvent, Error> {
+        let buffer = read_message(&mut self.reader, self.limits.max_message_size).await?;
+        Ok(crate::decode(&buffer)?)
+    }
+}
@@ -0,0 +1,104 @@
+//! Correlation of requests and replies
+//!
+//! Requests are sent with a [`RequestId`], and the reply carries the same
+//! ID.  Each side keeps the requests it has not finished with in a
+//! [`Pending`] table: the sender to match replies and to time out requests
+//! that are not answered, the receiver to cancel work for items that are
+//! destroyed in the meantime.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct RequestId(pub u64);
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+struct Entry<T> {
+    item: u64,
+    deadline: Option<Instant>,
+    value: T,
+}
+
+/// Requests that are waiting for a reply or for work to finish, each
+/// belonging to an item
+pub struct Pending<T> {
+    last: u64,
+    requests: HashMap<RequestId, Entry<T>>,
+}
+
+impl<T> Default for Pending<T> {
+    fn default() -> Self {
+        Self {
+            last: 0,
+            requests: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Pending<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a new request concerning `item`, returning its ID.  If `timeout`
+    /// is not [`None`], the request is returned by [`Pending::expired`]
+    /// once it has passed.
+    pub fn insert(&mut self, item: u64, value: T, timeout: Option<Duration>) -> RequestId {
+        self.last += 1;
+        let id = RequestId(self.last);
+        self.track(id, item, value, timeout);
+        id
+    }
+
+    /// Add a request that was received from the other side, and so already
+    /// has an ID.  A previous request with the same ID is replaced.
+    pub fn track(&mut self, id: RequestId, item: u64, value: T, timeout: Option<Duration>) {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.requests.insert(
+            id,
+            Entry {
+                item,
+                deadline,
+                value,
+            },
+        );
+    }
+
+    /// Remove the request `id`, because it was answered.  Returns [`None`]
+    /// if there is no such request, for instance because it timed out or was
+    /// cancelled.
+    pub fn remove(&mut self, id: RequestId) -> Option<T> {
+        self.requests.remove(&id).map(|entry| entry.value)
+    }
+
+    /// Remove all requests whose timeout has passed
+    pub fn expired(&mut self) -> Vec<(RequestId, T)> {
+        let now = Instant::now();
+        let ids: Vec<RequestId> = self
+            .requests
+            .iter()
+            .filter(|(_, entry)| entry.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.remove(id)?)))
+            .collect()
+    }
+
+    /// Remove all requests concerning `item`, because it was destroyed
+    pub fn cancel_item(&mut self, item: u64) -> Vec<T> {
+        let ids: Vec<RequestId> = self
+            .requests
+            .iter()
+            .filter(|(_, entry)| entry.item == item)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter().filter_map(|id| self.remove(id)).collect()
+    }
+}
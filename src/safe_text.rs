@@ -0,0 +1,144 @@
+//! The [`SafeText`] type
+
+use core::fmt::{self, Display};
+use core::ops::Deref;
+use qubes_utils::SafelyDisplayable;
+
+/// Text from a VM that has been sanitized for display in dom0: markup and
+/// terminal escape sequences are stripped, and code points that are not
+/// [`SafelyDisplayable`] are removed.  Escape sequences would otherwise
+/// leave their parameters behind as text, which could end up in a terminal
+/// when copied from a tooltip.
+///
+/// The only way to obtain a `SafeText` is to sanitize a string, and
+/// deserialization sanitizes as well.  Protocol types therefore cannot hold
+/// text that has not been sanitized, no matter what the other side sent.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(transparent)]
+pub struct SafeText(String);
+
+/// Where [`SafeText::new`] is in a terminal escape sequence
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// After an ESC
+    Start,
+    /// In a control sequence, which ends with a byte from `@` to `~`
+    Control,
+    /// In an operating system command or another string, which ends with
+    /// BEL or ESC `\`
+    String,
+}
+
+impl SafeText {
+    pub fn new(text: &str) -> Self {
+        let mut data = String::with_capacity(text.len());
+        let mut in_tag = false;
+        let mut escape = Escape::None;
+        let mut buf = [0; 4];
+        for c in text.chars() {
+            let previous = escape;
+            escape = match (escape, c) {
+                (Escape::None, '\u{1b}') => Escape::Start,
+                (Escape::None, '\u{9b}') | (Escape::Start, '[') => Escape::Control,
+                (Escape::None, '\u{90}' | '\u{9d}' | '\u{9e}' | '\u{9f}')
+                | (Escape::Start, 'P' | ']' | '^' | '_' | 'X') => Escape::String,
+                (Escape::None, _) => Escape::None,
+                // Sequences of ESC and a single character
+                (Escape::Start, _) => Escape::None,
+                (Escape::Control, '@'..='~') => Escape::None,
+                (Escape::Control, _) => Escape::Control,
+                (Escape::String, '\u{7}' | '\u{9c}') => Escape::None,
+                (Escape::String, '\u{1b}') => Escape::Start,
+                (Escape::String, _) => Escape::String,
+            };
+            // The last character of a sequence is part of it too
+            if !matches!((previous, escape), (Escape::None, Escape::None)) {
+                continue;
+            }
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                _ if in_tag => {}
+                c => {
+                    if SafelyDisplayable::try_from(&*c.encode_utf8(&mut buf)).is_ok() {
+                        data.push(c)
+                    }
+                }
+            }
+        }
+        Self(data)
+    }
+
+    /// Truncate to at most `max_len` bytes, at a character boundary.
+    /// Returns `true` if anything was removed.
+    pub fn truncate(&mut self, max_len: usize) -> bool {
+        if self.0.len() <= max_len {
+            return false;
+        }
+        let mut len = max_len;
+        while !self.0.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.0.truncate(len);
+        true
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SafeText {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        Ok(Self::new(&text))
+    }
+}
+
+impl Deref for SafeText {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for SafeText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<SafeText> for String {
+    fn from(value: SafeText) -> Self {
+        value.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_is_stripped() {
+        assert_eq!(
+            SafeText::new("<b>Battery</b> at 50%").as_str(),
+            "Battery at 50%"
+        );
+    }
+
+    #[test]
+    fn escape_sequences_are_stripped() {
+        for (text, safe) in [
+            ("\u{1b}[1;31mred\u{1b}[0m", "red"),
+            ("\u{9b}2Jclear", "clear"),
+            ("\u{1b}]0;title\u{7}text", "text"),
+            ("\u{1b}]52;c;cm0gLXJm\u{1b}\\paste", "paste"),
+            ("\u{1b}cReset", "Reset"),
+            // Unterminated
+            ("text\u{1b}[12", "text"),
+        ] {
+            assert_eq!(SafeText::new(text).as_str(), safe, "{:?}", text);
+        }
+    }
+}
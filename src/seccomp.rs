@@ -0,0 +1,157 @@
+//! seccomp filters, for sandboxing both binaries
+//!
+//! A filter either denies the system calls it lists, or allows only those.
+//! Denied system calls fail with `EPERM`, so that a missing entry shows up as
+//! an error instead of killing the process.  Either way, `clone` is only
+//! allowed if it starts a thread, and `clone3` fails with `ENOSYS`, as its
+//! flags cannot be checked, so that the C library falls back to `clone`.
+//! System calls of other architectures kill the process.
+
+use std::io;
+
+/// How the system calls listed in a filter are treated
+#[derive(Debug, Clone, Copy)]
+pub enum Mode {
+    /// Listed system calls are denied, others are allowed
+    Deny,
+    /// Only listed system calls are allowed
+    AllowOnly,
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Offsets into `struct seccomp_data`
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+/// The low half of the first argument, on little-endian machines
+const ARG0_OFFSET: u32 = 16;
+
+/// Where a jump of [`filter`] goes
+#[derive(Clone, Copy)]
+enum Target {
+    /// The next instruction
+    Next,
+    /// The returns at the end of the filter, in this order
+    Allow,
+    Deny,
+    NoSys,
+    Kill,
+}
+
+/// The filter treating `syscalls` according to `mode`
+fn filter(mode: Mode, syscalls: &[libc::c_long]) -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    use Target::*;
+    const LOAD: u32 = BPF_LD | BPF_W | BPF_ABS;
+    const JEQ: u32 = BPF_JMP | BPF_JEQ | BPF_K;
+    let (listed, unlisted) = match mode {
+        Mode::Deny => (Deny, Allow),
+        Mode::AllowOnly => (Allow, Deny),
+    };
+    let mut checks = vec![
+        (LOAD, ARCH_OFFSET, None),
+        (JEQ, AUDIT_ARCH, Some((Next, Kill))),
+        (LOAD, NR_OFFSET, None),
+    ];
+    // x32 system calls share the architecture of x86-64
+    #[cfg(target_arch = "x86_64")]
+    checks.push((
+        BPF_JMP | libc::BPF_JGE | BPF_K,
+        0x4000_0000,
+        Some((Deny, Next)),
+    ));
+    checks.extend(
+        syscalls
+            .iter()
+            .map(|&nr| (JEQ, nr as u32, Some((listed, Next)))),
+    );
+    checks.extend([
+        (JEQ, libc::SYS_clone3 as u32, Some((NoSys, Next))),
+        (JEQ, libc::SYS_clone as u32, Some((Next, unlisted))),
+        (LOAD, ARG0_OFFSET, None),
+        (
+            BPF_JMP | BPF_JSET | BPF_K,
+            libc::CLONE_THREAD as u32,
+            Some((Allow, Deny)),
+        ),
+    ]);
+    let end = checks.len();
+    let mut program: Vec<_> = checks
+        .into_iter()
+        .enumerate()
+        .map(|(i, (code, k, jumps))| {
+            let offset = |target| {
+                let index = match target {
+                    Next => i + 1,
+                    Allow => end,
+                    Deny => end + 1,
+                    NoSys => end + 2,
+                    Kill => end + 3,
+                };
+                u8::try_from(index - i - 1).expect("seccomp filter too long")
+            };
+            let (jt, jf) = jumps.map_or((0, 0), |(jt, jf)| (offset(jt), offset(jf)));
+            libc::sock_filter {
+                code: code as u16,
+                jt,
+                jf,
+                k,
+            }
+        })
+        .collect();
+    let ret = |k| libc::sock_filter {
+        code: (BPF_RET | BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    program.extend([
+        ret(libc::SECCOMP_RET_ALLOW),
+        ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        ret(libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+    ]);
+    program
+}
+
+/// Keep the process from gaining privileges, as needed before installing a
+/// filter or applying a Landlock ruleset
+pub fn no_new_privs() -> io::Result<()> {
+    // SAFETY: prctl() with these arguments has no memory-safety implications
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Install the filter treating `syscalls` according to `mode` on all
+/// threads of the process
+pub fn install(mode: Mode, syscalls: &[libc::c_long]) -> io::Result<()> {
+    no_new_privs()?;
+    let mut program = filter(mode, syscalls);
+    let prog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: `prog` points to a valid filter, which the kernel copies
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        // With TSYNC, the thread that could not be synchronized
+        1.. => Err(io::Error::other(format!(
+            "cannot install seccomp filter on thread {}",
+            result
+        ))),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -27,6 +27,9 @@ pub trait StatusNotifierItem {
     fn tool_tip(
         &self,
     ) -> Result<(String, Vec<(i32, i32, Vec<u8>)>, String, String), dbus::MethodErr>;
+    fn icon_accessible_desc(&self) -> Result<String, dbus::MethodErr>;
+    fn attention_accessible_desc(&self) -> Result<String, dbus::MethodErr>;
+    fn xayatana_ordering_index(&self) -> Result<u32, dbus::MethodErr>;
 }
 
 #[derive(Debug)]
@@ -203,5 +206,11 @@ where
         b.property::<(String, Vec<(i32, i32, Vec<u8>)>, String, String), _>("ToolTip")
             .get(|_, t| t.tool_tip())
             .annotate("org.qtproject.QtDBus.QtTypeName", "ToolTip");
+        b.property::<String, _>("IconAccessibleDesc")
+            .get(|_, t| t.icon_accessible_desc());
+        b.property::<String, _>("AttentionAccessibleDesc")
+            .get(|_, t| t.attention_accessible_desc());
+        b.property::<u32, _>("XAyatanaOrderingIndex")
+            .get(|_, t| t.xayatana_ordering_index());
     })
 }
@@ -8,6 +8,7 @@ pub trait StatusNotifierWatcher {
     fn register_status_notifier_item(&mut self, service: String) -> Result<(), dbus::MethodErr>;
     fn register_status_notifier_host(&mut self, service: String) -> Result<(), dbus::MethodErr>;
     fn registered_status_notifier_items(&self) -> Result<Vec<String>, dbus::MethodErr>;
+    fn registered_status_notifier_hosts(&self) -> Result<Vec<String>, dbus::MethodErr>;
     fn is_status_notifier_host_registered(&self) -> Result<bool, dbus::MethodErr>;
     fn protocol_version(&self) -> Result<i32, dbus::MethodErr>;
 }
@@ -118,6 +119,9 @@ where
         b.property::<Vec<String>, _>("RegisteredStatusNotifierItems")
             .get(|_, t| t.registered_status_notifier_items())
             .annotate("org.qtproject.QtDBus.QtTypeName.Out0", "QStringList");
+        b.property::<Vec<String>, _>("RegisteredStatusNotifierHosts")
+            .get(|_, t| t.registered_status_notifier_hosts())
+            .annotate("org.qtproject.QtDBus.QtTypeName.Out0", "QStringList");
         b.property::<bool, _>("IsStatusNotifierHostRegistered")
             .get(|_, t| t.is_status_notifier_host_registered());
         b.property::<i32, _>("ProtocolVersion")
@@ -0,0 +1,75 @@
+//! Connections to the session bus
+//!
+//! Both binaries connect to the bus given by `--session-bus-address`, or
+//! else by the `SNI_ICON_SESSION_BUS_ADDRESS` environment variable, or else
+//! to the usual session bus.  The override applies to every connection the
+//! program makes, without changing `DBUS_SESSION_BUS_ADDRESS` for the
+//! processes it talks to.
+
+use crate::error::BusError;
+use dbus::channel::{Channel, Sender};
+use dbus::nonblock::SyncConnection;
+use dbus::{Message, MessageType};
+use dbus_crossroads::Crossroads;
+use dbus_tokio::connection::IOResource;
+use std::sync::{Arc, OnceLock};
+
+/// The environment variable overriding the address of the session bus
+pub const ADDRESS_VARIABLE: &str = "SNI_ICON_SESSION_BUS_ADDRESS";
+
+static ADDRESS: OnceLock<Option<String>> = OnceLock::new();
+
+/// Connect to `address` instead of the session bus, as given on the command
+/// line; [`None`] leaves it to the environment.  Must be called before the
+/// first connection is made, and only once.
+pub fn set_address(address: Option<String>) {
+    ADDRESS
+        .set(address.or_else(|| std::env::var(ADDRESS_VARIABLE).ok()))
+        .expect("session bus address is set only once, before connecting")
+}
+
+fn address() -> Option<&'static str> {
+    ADDRESS
+        .get_or_init(|| std::env::var(ADDRESS_VARIABLE).ok())
+        .as_deref()
+}
+
+/// Connect to the session bus.  The returned resource must be spawned, and
+/// finishes when the connection is lost.
+pub fn connect() -> Result<(IOResource<SyncConnection>, Arc<SyncConnection>), BusError> {
+    let connect = || match address() {
+        None => dbus_tokio::connection::new_session_sync(),
+        Some(address) => {
+            let mut channel = Channel::open_private(address)?;
+            channel.register()?;
+            dbus_tokio::connection::from_channel(channel)
+        }
+    };
+    connect().map_err(BusError::Connect)
+}
+
+/// Handle `msg` with `cr`, replying on `conn`.  Crossroads answers calls to
+/// unknown objects and methods itself, but cannot handle messages that are
+/// not method calls with a path and a member.  Those are answered with an
+/// error if they expect a reply, and described in the returned error, for
+/// the caller to log before handling the next message.
+pub fn dispatch(cr: &mut Crossroads, msg: Message, conn: &impl Sender) -> Result<(), String> {
+    if msg.msg_type() == MessageType::MethodCall && msg.path().is_some() && msg.member().is_some() {
+        return cr
+            .handle_message(msg, conn)
+            .map_err(|()| "Crossroads rejected a method call".to_owned());
+    }
+    let description = format!(
+        "{:?} from {:?} to {:?} {:?}.{:?}",
+        msg.msg_type(),
+        msg.sender(),
+        msg.path(),
+        msg.interface(),
+        msg.member()
+    );
+    if msg.msg_type() == MessageType::MethodCall && !msg.get_no_reply() {
+        let e = dbus::MethodErr::from((crate::names::error_unknown_method(), "Malformed call"));
+        let _: Result<_, _> = conn.send(e.to_message(&msg));
+    }
+    Err(format!("Cannot handle {}", description))
+}
@@ -0,0 +1,14 @@
+//! The signals that stop the agent and the daemon
+
+/// Wait for SIGTERM or SIGINT, returning the name of the signal
+pub async fn terminated() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate()).expect("cannot handle SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("cannot handle SIGINT");
+    let sigterm = std::pin::pin!(sigterm.recv());
+    let sigint = std::pin::pin!(sigint.recv());
+    match futures_util::future::select(sigterm, sigint).await {
+        futures_util::future::Either::Left(_) => "SIGTERM",
+        futures_util::future::Either::Right(_) => "SIGINT",
+    }
+}
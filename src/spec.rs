@@ -0,0 +1,124 @@
+//! The values the StatusNotifierItem specification allows
+//!
+//! Items are created with a [`Category`] ([`crate::ClientEvent::Create`]) and
+//! have a [`Status`] ([`crate::ClientEvent::Status`]).  Both are sent as the
+//! strings of the specification, so that the agent can forward what
+//! applications set without knowing them all.  These types tell the values
+//! of the specification apart from the rest, for the components that check
+//! or produce them.
+
+/// The category of an item, which hosts may group or sort items by
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Category {
+    ApplicationStatus,
+    Communications,
+    SystemServices,
+    Hardware,
+}
+
+impl Category {
+    /// All categories of the specification
+    pub const ALL: [Self; 4] = [
+        Self::ApplicationStatus,
+        Self::Communications,
+        Self::SystemServices,
+        Self::Hardware,
+    ];
+
+    /// The category called `name`, if the specification has it
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|category| category.as_str() == name)
+    }
+
+    /// The name of the category in the specification
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::ApplicationStatus => "ApplicationStatus",
+            Self::Communications => "Communications",
+            Self::SystemServices => "SystemServices",
+            Self::Hardware => "Hardware",
+        }
+    }
+}
+
+/// The status of an item
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Status {
+    /// The item need not be shown
+    Passive,
+    Active,
+    /// The item asks for the attention of the user
+    NeedsAttention,
+}
+
+impl Status {
+    /// All statuses of the specification
+    pub const ALL: [Self; 3] = [Self::Passive, Self::Active, Self::NeedsAttention];
+
+    /// The status called `name`, if the specification has it
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|status| status.as_str() == name)
+    }
+
+    /// The name of the status in the specification
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Passive => "Passive",
+            Self::Active => "Active",
+            Self::NeedsAttention => "NeedsAttention",
+        }
+    }
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categories_are_those_of_the_specification() {
+        let names: Vec<_> = Category::ALL.iter().map(|c| c.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "ApplicationStatus",
+                "Communications",
+                "SystemServices",
+                "Hardware"
+            ]
+        );
+        for category in Category::ALL {
+            assert_eq!(Category::parse(category.as_str()), Some(category));
+            assert_eq!(category.to_string(), category.as_str());
+        }
+        for name in ["", "applicationstatus", "Hardware ", "Other"] {
+            assert_eq!(Category::parse(name), None);
+        }
+    }
+
+    #[test]
+    fn statuses_are_those_of_the_specification() {
+        let names: Vec<_> = Status::ALL.iter().map(|s| s.as_str()).collect();
+        assert_eq!(names, ["Passive", "Active", "NeedsAttention"]);
+        for status in Status::ALL {
+            assert_eq!(Status::parse(status.as_str()), Some(status));
+            assert_eq!(status.to_string(), status.as_str());
+        }
+        for name in ["", "active", "Needs Attention", "Hidden"] {
+            assert_eq!(Status::parse(name), None);
+        }
+    }
+}
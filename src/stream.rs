@@ -0,0 +1,95 @@
+//! The events of a connection as a [`Stream`] and a [`Sink`]
+//!
+//! Embedders that connected over a [`crate::transport::Transport`], or are
+//! given the stdin and stdout of qrexec, can read the events of the other
+//! side with [`events`] and send their own with [`sender`], and use the
+//! combinators of `futures-util` instead of a loop around
+//! [`crate::read_message_tagged`].  Both expect the [`crate::Hello`]s to
+//! have been exchanged already.  The daemon reads [`crate::IconClientEvent`]s
+//! and sends [`crate::IconServerEvent`]s, and the agent the other way round.
+
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::error::TransportError;
+use crate::{Error, Integrity, Message};
+
+/// The messages read from `reader`, each at most `max_size` bytes and
+/// followed by its tag.  The stream ends when the other side closes the
+/// connection, and after the first error.
+pub fn events<T, R>(
+    reader: R,
+    max_size: u32,
+    integrity: Integrity,
+) -> impl Stream<Item = Result<T, Error>>
+where
+    T: Message,
+    R: AsyncRead + Unpin,
+{
+    futures_util::stream::unfold(Some(reader), move |reader| async move {
+        let mut reader = reader?;
+        let message = match crate::read_message_tagged(&mut reader, max_size, integrity).await {
+            Ok(buffer) => crate::decode(&buffer).map_err(Error::from),
+            Err(Error::Transport(TransportError::Closed)) => return None,
+            Err(e) => Err(e),
+        };
+        let reader = message.is_ok().then_some(reader);
+        Some((message, reader))
+    })
+}
+
+/// A sink writing each message to `writer`, followed by its tag, and
+/// flushing it
+pub fn sender<T, W>(writer: W, integrity: Integrity) -> impl Sink<T, Error = Error>
+where
+    T: Message,
+    W: AsyncWrite + Unpin,
+{
+    futures_util::sink::unfold(writer, move |mut writer, message: T| async move {
+        let buffer = crate::encode(&message)?;
+        crate::write_message_tagged(&mut writer, &buffer, integrity).await?;
+        Ok(writer)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientEvent, IconClientEvent};
+    use futures_util::{SinkExt as _, StreamExt as _};
+
+    fn event(id: u64, title: &str) -> IconClientEvent {
+        IconClientEvent {
+            id,
+            timestamp: 0,
+            event: ClientEvent::Title(Some(title.to_owned())),
+        }
+    }
+
+    #[tokio::test]
+    async fn events_round_trip() {
+        let (a, b) = tokio::io::duplex(4096);
+        let mut sink = Box::pin(sender(a, Integrity::None));
+        sink.send(event(1, "one")).await.unwrap();
+        sink.send(event(2, "two")).await.unwrap();
+        drop(sink);
+        let received: Vec<IconClientEvent> = events(b, 4096, Integrity::None)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].id, 1);
+        assert!(matches!(&received[1].event, ClientEvent::Title(Some(t)) if t == "two"));
+    }
+
+    #[tokio::test]
+    async fn events_end_after_an_error() {
+        let (a, b) = tokio::io::duplex(4096);
+        let mut sink = Box::pin(sender(a, Integrity::None));
+        sink.send(event(1, "too long")).await.unwrap();
+        drop(sink);
+        let received: Vec<Result<IconClientEvent, Error>> =
+            events(b, 4, Integrity::None).collect().await;
+        assert!(matches!(&received[..], [Err(Error::Protocol(_))]));
+    }
+}
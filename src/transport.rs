@@ -0,0 +1,207 @@
+//! Ways for the agent and the daemon to reach each other
+//!
+//! Under Qubes OS, qrexec connects the stdin and stdout of the agent to
+//! those of the daemon, so neither needs a [`Transport`].  Otherwise, the
+//! daemon listens on a socket, and the agent connects to it.  Either way,
+//! the connection then becomes the stdin and stdout of the agent and of the
+//! daemon process serving it, see [`use_as_stdio`].
+
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd as _, OwnedFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+/// The credentials of a process, as the kernel reports them
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials {
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// What is known about the other end of a connection, for finding its VM
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    /// A process on the same machine
+    Process(Credentials),
+    /// A VM, by its context ID
+    Vsock { cid: u32 },
+    /// Nothing, as the kernel did not tell
+    Unknown,
+}
+
+/// A kind of socket the daemon listens on
+pub trait Transport: Sized {
+    /// Where the daemon listens and the agent connects to
+    type Address;
+
+    /// Listen for agents on `address`
+    fn bind(address: &Self::Address) -> io::Result<Self>;
+
+    /// Connect to the daemon listening on `address`
+    fn connect(address: &Self::Address) -> io::Result<OwnedFd>;
+
+    /// The next agent to connect
+    fn accept(&self) -> impl Future<Output = io::Result<(OwnedFd, Peer)>>;
+}
+
+/// Unix sockets, for when qrexec passes the connections of all VMs to a
+/// single socket, or a socket is passed by systemd
+pub struct Unix(tokio::net::UnixListener);
+
+impl Unix {
+    /// Accept agents on `listener`
+    pub fn new(listener: UnixListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        tokio::net::UnixListener::from_std(listener).map(Self)
+    }
+}
+
+impl Transport for Unix {
+    type Address = PathBuf;
+
+    fn bind(address: &PathBuf) -> io::Result<Self> {
+        Self::new(UnixListener::bind(address)?)
+    }
+
+    fn connect(address: &PathBuf) -> io::Result<OwnedFd> {
+        std::os::unix::net::UnixStream::connect(address).map(OwnedFd::from)
+    }
+
+    async fn accept(&self) -> io::Result<(OwnedFd, Peer)> {
+        let (stream, _) = self.0.accept().await?;
+        let peer = match stream.peer_cred() {
+            Ok(cred) => Peer::Process(Credentials {
+                pid: cred.pid(),
+                uid: cred.uid(),
+                gid: cred.gid(),
+            }),
+            Err(_) => Peer::Unknown,
+        };
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        Ok((stream.into(), peer))
+    }
+}
+
+/// `AF_VSOCK` sockets, for VMs under KVM or cloud-hypervisor, which have no
+/// qrexec
+#[cfg(feature = "vsock")]
+pub struct Vsock(tokio::io::unix::AsyncFd<socket2::Socket>);
+
+/// The address of an `AF_VSOCK` socket, written as `CID:PORT`.  The daemon
+/// listens on all CIDs if the CID is `any`.
+#[cfg(feature = "vsock")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VsockAddress {
+    pub cid: u32,
+    pub port: u32,
+}
+
+#[cfg(feature = "vsock")]
+impl VsockAddress {
+    /// `VMADDR_CID_ANY`
+    pub const ANY: u32 = u32::MAX;
+}
+
+#[cfg(feature = "vsock")]
+impl std::str::FromStr for VsockAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid vsock address {:?}, expected CID:PORT", s);
+        let (cid, port) = s.split_once(':').ok_or_else(invalid)?;
+        let cid = match cid {
+            "any" => Self::ANY,
+            cid => cid.parse().map_err(|_| invalid())?,
+        };
+        let port = port.parse().map_err(|_| invalid())?;
+        Ok(Self { cid, port })
+    }
+}
+
+#[cfg(feature = "vsock")]
+fn vsock_socket() -> io::Result<socket2::Socket> {
+    socket2::Socket::new(socket2::Domain::VSOCK, socket2::Type::STREAM, None)
+}
+
+#[cfg(feature = "vsock")]
+impl Transport for Vsock {
+    type Address = VsockAddress;
+
+    fn bind(address: &VsockAddress) -> io::Result<Self> {
+        let socket = vsock_socket()?;
+        socket.bind(&socket2::SockAddr::vsock(address.cid, address.port))?;
+        socket.listen(16)?;
+        socket.set_nonblocking(true)?;
+        tokio::io::unix::AsyncFd::new(socket).map(Self)
+    }
+
+    fn connect(address: &VsockAddress) -> io::Result<OwnedFd> {
+        let socket = vsock_socket()?;
+        socket.connect(&socket2::SockAddr::vsock(address.cid, address.port))?;
+        Ok(socket.into())
+    }
+
+    async fn accept(&self) -> io::Result<(OwnedFd, Peer)> {
+        loop {
+            let mut ready = self.0.readable().await?;
+            let (socket, address) = match ready.try_io(|listener| listener.get_ref().accept()) {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+            socket.set_nonblocking(false)?;
+            let peer = match address.as_vsock_address() {
+                Some((cid, _port)) => Peer::Vsock { cid },
+                None => Peer::Unknown,
+            };
+            return Ok((socket.into(), peer));
+        }
+    }
+}
+
+/// Make `connection` the stdin and stdout of this process, for an agent that
+/// connected to the daemon itself
+pub fn use_as_stdio(connection: OwnedFd) -> io::Result<()> {
+    for fd in [0, 1] {
+        // SAFETY: dup2() only replaces the descriptor `fd`, which is stdin or
+        // stdout and is not used until after this returns.  `connection`
+        // stays open for the duration of the call.
+        if unsafe { libc::dup2(connection.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// What the stdin and stdout of this process are connected to
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Kind {
+    /// A Unix socket
+    Unix,
+    /// A vsock socket
+    Vsock,
+    /// Anything else, such as the pipes of qrexec
+    Stdio,
+}
+
+impl Kind {
+    /// Find out what stdin is connected to
+    pub fn of_stdin() -> Self {
+        let stdin = io::stdin();
+        match socket2::SockRef::from(&stdin).local_addr() {
+            Ok(address) if address.domain() == socket2::Domain::UNIX => Self::Unix,
+            Ok(address) if address.domain() == socket2::Domain::VSOCK => Self::Vsock,
+            _ => Self::Stdio,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Unix => "unix",
+            Self::Vsock => "vsock",
+            Self::Stdio => "stdio",
+        }
+    }
+}